@@ -3,13 +3,88 @@
 
 use core::{arch::asm, panic::PanicInfo};
 
+const SYS_WRITE: u64 = 1;
+const SYS_BRK: u64 = 12;
+const SYS_EXIT: u64 = 60;
+const STDOUT: u64 = 1;
+
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
     loop {}
 }
 
+/// Writes `bytes` to `fd`, returning what the kernel put in rax.
+fn write(fd: u64, bytes: &[u8]) -> u64 {
+    let result: u64;
+    unsafe {
+        asm!(
+            "syscall",
+            inlateout("rax") SYS_WRITE => result,
+            in("rdi") fd,
+            in("rsi") bytes.as_ptr(),
+            in("rdx") bytes.len(),
+            out("rcx") _,
+            out("r11") _,
+        );
+    }
+    result
+}
+
+/// Moves the program break to `addr` (0 just asks for it) and returns the
+/// break the kernel ended up with.
+fn brk(addr: u64) -> u64 {
+    let result: u64;
+    unsafe {
+        asm!(
+            "syscall",
+            inlateout("rax") SYS_BRK => result,
+            in("rdi") addr,
+            out("rcx") _,
+            out("r11") _,
+        );
+    }
+    result
+}
+
+/// Grows the heap by `increment` bytes and returns the start of the new
+/// memory, or `None` if the kernel wouldn't move the break.
+fn sbrk(increment: u64) -> Option<*mut u8> {
+    let old_end = brk(0);
+    let new_end = old_end.checked_add(increment)?;
+    if brk(new_end) != new_end {
+        return None;
+    }
+    Some(old_end as *mut u8)
+}
+
+/// Ends the process with status `code`.
+fn exit(code: u64) -> ! {
+    unsafe {
+        asm!("syscall", in("rax") SYS_EXIT, in("rdi") code, options(noreturn));
+    }
+}
+
 #[unsafe(no_mangle)]
 pub unsafe extern "sysv64" fn _start() -> ! {
-    asm!("syscall");
-    loop {}
-}
\ No newline at end of file
+    write(STDOUT, b"hi\n");
+
+    // The heap must hold whatever is written to it
+    const HEAP_TEST_SIZE: usize = 8 * 1024;
+    match sbrk(HEAP_TEST_SIZE as u64) {
+        Some(heap) => {
+            let heap = unsafe { core::slice::from_raw_parts_mut(heap, HEAP_TEST_SIZE) };
+            for (i, byte) in heap.iter_mut().enumerate() {
+                *byte = i as u8;
+            }
+            if heap.iter().enumerate().all(|(i, &byte)| byte == i as u8) {
+                write(STDOUT, b"sbrk: 8 KiB ok\n");
+            } else {
+                write(STDOUT, b"sbrk: heap contents wrong\n");
+            }
+        }
+        None => {
+            write(STDOUT, b"sbrk: failed\n");
+        }
+    }
+    exit(0)
+}
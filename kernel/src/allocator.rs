@@ -1,5 +1,6 @@
 use alloc::alloc::{GlobalAlloc, Layout};
-use core::ptr::null_mut;
+use core::ptr::{null_mut, NonNull};
+use core::sync::atomic::{AtomicUsize, Ordering};
 use x86_64::{
     structures::paging::{
         mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
@@ -8,12 +9,26 @@ use x86_64::{
 };
 
 use linked_list_allocator::LockedHeap;
+use spin::Mutex;
+
+use crate::memory;
 
 #[global_allocator]
+static KERNEL_HEAP: KernelHeap = KernelHeap;
+
 static ALLOCATOR: LockedHeap = LockedHeap::empty();
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 4 * 1024 * 1024; // 4 MiB
+pub const HEAP_SIZE: usize = 4 * 1024 * 1024; // 4 MiB (initial size)
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024; // 64 MiB (default ceiling)
+
+/// The heap never grows past `HEAP_START + HEAP_LIMIT`.
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
+
+/// Sets how large the heap may grow, in bytes. Memory already mapped stays.
+pub fn set_heap_limit(bytes: usize) {
+    HEAP_LIMIT.store(bytes, Ordering::Relaxed);
+}
 
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
@@ -44,6 +59,226 @@ pub fn init_heap(
     Ok(())
 }
 
+/// Maps `extra_bytes` (rounded up to whole pages) right after the end of the
+/// heap and hands them to the allocator. Returns the new heap size.
+///
+/// Fails without changing anything if the heap would pass its limit. If
+/// mapping fails halfway, the pages mapped so far are kept.
+pub fn grow_heap(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    extra_bytes: usize,
+) -> Result<usize, MapToError<Size4KiB>> {
+    let extra_bytes = extra_bytes.next_multiple_of(4096);
+    let size = heap_usage().1;
+    if size + extra_bytes > HEAP_LIMIT.load(Ordering::Relaxed) {
+        return Err(MapToError::FrameAllocationFailed);
+    }
+
+    let heap_end = VirtAddr::new((HEAP_START + size) as u64);
+    let mut mapped = 0;
+    let mut result = Ok(());
+    for page in Page::<Size4KiB>::range(
+        Page::containing_address(heap_end),
+        Page::containing_address(heap_end + extra_bytes as u64),
+    ) {
+        let Some(frame) = frame_allocator.allocate_frame() else {
+            result = Err(MapToError::FrameAllocationFailed);
+            break;
+        };
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(err) => {
+                result = Err(err);
+                break;
+            }
+        }
+        mapped += 4096;
+    }
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut heap = ALLOCATOR.lock();
+        unsafe { heap.extend(mapped) };
+        result.map(|()| heap.size())
+    })
+}
+
+/// Bytes currently allocated through the global allocator, as requested
+/// (slab rounding and allocator overhead aren't counted).
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+/// Highest value `ALLOCATED_BYTES` has reached.
+static PEAK_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// Heap usage as seen by the users of the global allocator.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// Bytes of heap currently mapped.
+    pub size: usize,
+    /// Bytes currently allocated.
+    pub allocated: usize,
+    /// Most bytes allocated at any one time since boot.
+    pub peak: usize,
+}
+
+pub fn heap_stats() -> HeapStats {
+    HeapStats {
+        size: heap_usage().1,
+        allocated: ALLOCATED_BYTES.load(Ordering::Relaxed),
+        peak: PEAK_BYTES.load(Ordering::Relaxed),
+    }
+}
+
+/// Block sizes of the slab classes. Each is also the block alignment.
+const SLAB_SIZES: [usize; 8] = [8, 16, 32, 64, 128, 256, 512, 1024];
+
+/// Slabs are refilled by carving up one chunk of this size from the heap.
+const SLAB_CHUNK_SIZE: usize = 4096;
+
+/// A free slab block; the link lives in the block itself.
+struct FreeBlock {
+    next: Option<&'static mut FreeBlock>,
+}
+
+/// Free lists of the slab classes, one per entry of `SLAB_SIZES`.
+///
+/// Blocks never go back to the linked list heap: a freed block waits on its
+/// class's list for the next allocation of that size.
+static SLABS: Mutex<[Option<&'static mut FreeBlock>; SLAB_SIZES.len()]> =
+    Mutex::new([const { None }; SLAB_SIZES.len()]);
+
+/// Index of the smallest slab class fitting `layout`, or `None` if it's too
+/// large and goes to the linked list heap.
+fn slab_index(layout: &Layout) -> Option<usize> {
+    let required = layout.size().max(layout.align());
+    SLAB_SIZES.iter().position(|&size| size >= required)
+}
+
+/// The global allocator.
+///
+/// Small allocations (up to 1 KiB) come from slab classes of fixed-size
+/// blocks, which are fast and don't fragment. Everything else, including
+/// the chunks the slabs are cut from, comes from the linked list heap.
+///
+/// When the linked list heap is full, it's grown by at least the size of the
+/// request and the allocation retried once. Growing needs the installed
+/// kernel memory; before `memory::install`, or while that lock is held,
+/// allocation fails as it would with a fixed heap.
+struct KernelHeap;
+
+impl KernelHeap {
+    fn try_alloc(layout: Layout) -> Option<NonNull<u8>> {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            ALLOCATOR.lock().allocate_first_fit(layout).ok()
+        })
+    }
+
+    /// Allocates from the linked list heap, growing it if needed.
+    fn heap_alloc(layout: Layout) -> *mut u8 {
+        if let Some(ptr) = Self::try_alloc(layout) {
+            return ptr.as_ptr();
+        }
+
+        // Out of memory: room for the request, its alignment and the
+        // allocator's own bookkeeping
+        let extra_bytes = layout.size() + layout.align() + 4096;
+        let grown = memory::try_with_kernel_memory(|mapper, frame_allocator| {
+            grow_heap(mapper, frame_allocator, extra_bytes).is_ok()
+        });
+        if grown != Some(true) {
+            return null_mut();
+        }
+        Self::try_alloc(layout).map_or(null_mut(), NonNull::as_ptr)
+    }
+
+    /// Takes a block from slab class `index`, refilling the class from the
+    /// heap when it's empty.
+    fn slab_alloc(index: usize) -> *mut u8 {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut slabs = SLABS.lock();
+            if let Some(block) = slabs[index].take() {
+                slabs[index] = block.next.take();
+                return block as *mut FreeBlock as *mut u8;
+            }
+
+            // Empty: cut a new chunk into blocks, hand out the first and
+            // queue the rest
+            let chunk_layout = Layout::from_size_align(SLAB_CHUNK_SIZE, SLAB_CHUNK_SIZE).unwrap();
+            let chunk = Self::heap_alloc(chunk_layout);
+            if chunk.is_null() {
+                return null_mut();
+            }
+            let block_size = SLAB_SIZES[index];
+            for offset in (block_size..SLAB_CHUNK_SIZE).step_by(block_size).rev() {
+                unsafe { Self::slab_push(&mut slabs[index], chunk.add(offset)) };
+            }
+            chunk
+        })
+    }
+
+    /// Puts the block at `ptr` on the front of `list`.
+    ///
+    /// This function is unsafe because `ptr` must be a free block of the
+    /// list's class, unused from now on.
+    unsafe fn slab_push(list: &mut Option<&'static mut FreeBlock>, ptr: *mut u8) {
+        let block = ptr as *mut FreeBlock;
+        block.write(FreeBlock { next: list.take() });
+        *list = Some(&mut *block);
+    }
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = match slab_index(&layout) {
+            Some(index) => Self::slab_alloc(index),
+            None => Self::heap_alloc(layout),
+        };
+        if !ptr.is_null() {
+            let allocated = ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK_BYTES.fetch_max(allocated, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED_BYTES.fetch_sub(layout.size(), Ordering::Relaxed);
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            match slab_index(&layout) {
+                Some(index) => Self::slab_push(&mut SLABS.lock()[index], ptr),
+                None => ALLOCATOR.lock().deallocate(NonNull::new_unchecked(ptr), layout),
+            }
+        })
+    }
+}
+
+/// Called when an allocation fails even after trying to grow the heap.
+///
+/// Prints what was asked for and how full the heap is, then stops the
+/// machine. Only the lock-free panic serial path is used: the TTY allocates,
+/// so printing there could end up right back here.
+#[alloc_error_handler]
+fn alloc_error_handler(layout: Layout) -> ! {
+    x86_64::instructions::interrupts::disable();
+
+    let (used, size) = heap_usage();
+    panic_serial_println!(
+        "ALLOCATION ERROR: {} bytes (align {}), heap {} of {} bytes in use",
+        layout.size(),
+        layout.align(),
+        used,
+        size
+    );
+    crate::hlt_loop()
+}
+
+/// Returns `(used, size)` of the kernel heap in bytes.
+pub fn heap_usage() -> (usize, usize) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let heap = ALLOCATOR.lock();
+        (heap.used(), heap.size())
+    })
+}
+
 pub struct Dummy;
 
 unsafe impl GlobalAlloc for Dummy {
@@ -54,4 +289,42 @@ unsafe impl GlobalAlloc for Dummy {
     unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
         panic!("dealloc should be never called")
     }
-}
\ No newline at end of file
+}
+/// Checks that a freed slab block is the next one handed out for its size
+/// class, and that an allocation larger than the free heap grows the heap
+/// past its initial `HEAP_SIZE` instead of failing.
+///
+/// Run once at boot, after `memory::install` (growing needs it) and outside
+/// `memory::with_kernel_memory`.
+pub fn heap_self_test() -> bool {
+    let small = Layout::from_size_align(48, 8).unwrap();
+    let reused = unsafe {
+        let first = alloc::alloc::alloc(small);
+        alloc::alloc::dealloc(first, small);
+        let second = alloc::alloc::alloc(small);
+        let same = !first.is_null() && second == first;
+        if !second.is_null() {
+            alloc::alloc::dealloc(second, small);
+        }
+        same
+    };
+
+    // More than is free right now, so the allocation can only succeed by growing
+    let (used, size) = heap_usage();
+    let large = Layout::from_size_align(size - used + 1024 * 1024, 4096).unwrap();
+    let grown = unsafe {
+        let ptr = alloc::alloc::alloc(large);
+        if ptr.is_null() {
+            false
+        } else {
+            ptr.write_volatile(0xA5);
+            ptr.add(large.size() - 1).write_volatile(0x5A);
+            let touched = ptr.read_volatile() == 0xA5 && ptr.add(large.size() - 1).read_volatile() == 0x5A;
+            alloc::alloc::dealloc(ptr, large);
+            touched
+        }
+    };
+    let new_size = heap_usage().1;
+
+    reused && grown && new_size > size && new_size > HEAP_SIZE
+}
@@ -0,0 +1,312 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use simple_fatfs::io::prelude::*;
+use log::error;
+
+use crate::ide::{IDEError, IdeBlockDevice, MAX_SECTORS_LBA28};
+
+/// Capacidade padrão do cache, em setores
+pub const DEFAULT_CACHE_SECTORS: usize = 256;
+
+/// O que o cache precisa do dispositivo que ele envolve.
+pub trait SectorDevice {
+    /// Tamanho do setor lógico, em bytes
+    fn sector_size(&self) -> usize;
+    /// Lê `buffer.len() / sector_size()` setores consecutivos a partir de
+    /// `sector_idx` com um único pedido.
+    fn read_blocks(&mut self, sector_idx: u64, buffer: &mut [u8]) -> Result<(), IDEError>;
+    /// Escreve o setor `sector_idx`.
+    fn write_block(&mut self, sector_idx: u64, buffer: &[u8]) -> Result<(), IDEError>;
+}
+
+impl SectorDevice for IdeBlockDevice {
+    fn sector_size(&self) -> usize {
+        IdeBlockDevice::sector_size(self)
+    }
+
+    fn read_blocks(&mut self, sector_idx: u64, buffer: &mut [u8]) -> Result<(), IDEError> {
+        IdeBlockDevice::read_blocks(self, sector_idx, buffer)
+    }
+
+    fn write_block(&mut self, sector_idx: u64, buffer: &[u8]) -> Result<(), IDEError> {
+        IdeBlockDevice::write_block(self, sector_idx, buffer)
+    }
+}
+
+/// Um setor guardado no cache.
+struct CachedSector {
+    data: Box<[u8]>,
+    /// Modificado e ainda não escrito no drive
+    dirty: bool,
+    /// Valor de `clock` no último acesso; é a chave do setor em `lru`
+    last_used: u64,
+}
+
+/// Cache LRU de setores na frente de um `SectorDevice` (normalmente um
+/// `IdeBlockDevice`).
+///
+/// Leituras de setores em cache não vão ao drive, e setores vizinhos que
+/// faltam são buscados num único pedido. Escritas só marcam o setor como
+/// sujo; ele vai para o drive quando é despejado do cache, em `flush` ou
+/// quando o cache é destruído.
+pub struct CachedBlockDevice<D: SectorDevice = IdeBlockDevice> {
+    dev: D,
+    capacity: usize,
+    /// Setores em cache, indexados pela LBA relativa à partição
+    sectors: BTreeMap<u64, CachedSector>,
+    /// Setores em cache por ordem de último acesso (`last_used` -> LBA)
+    lru: BTreeMap<u64, u64>,
+    clock: u64,
+    /// Posição atual de cursor, em bytes
+    pos: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl<D: SectorDevice> CachedBlockDevice<D> {
+    /// Envolve `dev` num cache de `DEFAULT_CACHE_SECTORS` setores.
+    pub fn new(dev: D) -> Self {
+        Self::with_capacity(dev, DEFAULT_CACHE_SECTORS)
+    }
+
+    /// Envolve `dev` num cache de `capacity` setores (no mínimo um).
+    pub fn with_capacity(dev: D, capacity: usize) -> Self {
+        Self {
+            dev,
+            capacity: capacity.max(1),
+            sectors: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+            pos: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Quantos acessos foram servidos pelo cache e quantos foram ao drive.
+    pub fn stats(&self) -> (u64, u64) {
+        (self.hits, self.misses)
+    }
+
+    /// Escreve no drive todos os setores sujos, em ordem de LBA.
+    pub fn flush(&mut self) -> Result<(), IDEError> {
+        for (&sector_idx, cached) in self.sectors.iter_mut().filter(|(_, c)| c.dirty) {
+            self.dev.write_block(sector_idx, &cached.data)?;
+            cached.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Tira do cache o setor usado há mais tempo, escrevendo-o antes se sujo.
+    fn evict_one(&mut self) -> Result<(), IDEError> {
+        let Some((&last_used, &victim)) = self.lru.first_key_value() else {
+            return Ok(());
+        };
+
+        let cached = &self.sectors[&victim];
+        if cached.dirty {
+            self.dev.write_block(victim, &cached.data)?;
+        }
+        self.sectors.remove(&victim);
+        self.lru.remove(&last_used);
+        Ok(())
+    }
+
+    /// Marca `sector_idx` (já em cache) como o usado mais recentemente.
+    fn touch(&mut self, sector_idx: u64) -> &mut CachedSector {
+        self.clock += 1;
+        let cached = self.sectors.get_mut(&sector_idx).unwrap();
+        self.lru.remove(&cached.last_used);
+        cached.last_used = self.clock;
+        self.lru.insert(self.clock, sector_idx);
+        cached
+    }
+
+    /// Põe `data` no cache como o setor `sector_idx`, despejando outro se
+    /// o cache estiver cheio.
+    fn insert(&mut self, sector_idx: u64, data: Box<[u8]>) -> Result<(), IDEError> {
+        if self.sectors.len() >= self.capacity {
+            self.evict_one()?;
+        }
+        self.clock += 1;
+        self.sectors.insert(sector_idx, CachedSector { data, dirty: false, last_used: self.clock });
+        self.lru.insert(self.clock, sector_idx);
+        Ok(())
+    }
+
+    /// Garante que `sector_idx` está em cache e o devolve.
+    ///
+    /// Num miss, os setores seguintes até `last` que também faltam vêm junto,
+    /// num único pedido ao drive. Com `overwrite`, o chamador vai sobrescrever
+    /// o setor inteiro, então ele só é alocado, sem leitura.
+    fn sector(&mut self, sector_idx: u64, last: u64, overwrite: bool) -> Result<&mut CachedSector, IDEError> {
+        if self.sectors.contains_key(&sector_idx) {
+            self.hits += 1;
+            return Ok(self.touch(sector_idx));
+        }
+
+        let sector_size = self.dev.sector_size();
+        if overwrite {
+            self.misses += 1;
+            self.insert(sector_idx, vec![0u8; sector_size].into_boxed_slice())?;
+            return Ok(self.touch(sector_idx));
+        }
+
+        // A sequência não pode ser maior que o cache, senão ela mesma se despejaria
+        let run = (sector_idx..=last)
+            .take(self.capacity.min(MAX_SECTORS_LBA28))
+            .take_while(|idx| !self.sectors.contains_key(idx))
+            .count();
+
+        let mut data = vec![0u8; run * sector_size];
+        self.dev.read_blocks(sector_idx, &mut data)?;
+        self.misses += run as u64;
+        for (i, chunk) in data.chunks_exact(sector_size).enumerate() {
+            self.insert(sector_idx + i as u64, chunk.into())?;
+        }
+
+        Ok(self.touch(sector_idx))
+    }
+}
+
+impl<D: SectorDevice> Drop for CachedBlockDevice<D> {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            error!("Block cache: lost dirty sectors on drop: {}", err);
+        }
+    }
+}
+
+impl<D: SectorDevice> IOBase for CachedBlockDevice<D> {
+    type Error = IDEError;
+}
+
+impl<D: SectorDevice> Read for CachedBlockDevice<D> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IDEError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let sector_size = self.dev.sector_size();
+        let sector_idx = self.pos / sector_size as u64;
+        let offset = (self.pos % sector_size as u64) as usize;
+        let last = (self.pos + buf.len() as u64 - 1) / sector_size as u64;
+
+        let cached = self.sector(sector_idx, last, false)?;
+        let to_copy = core::cmp::min(buf.len(), sector_size - offset);
+        buf[..to_copy].copy_from_slice(&cached.data[offset..offset + to_copy]);
+        self.pos += to_copy as u64;
+        Ok(to_copy)
+    }
+}
+
+impl<D: SectorDevice> Write for CachedBlockDevice<D> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IDEError> {
+        let sector_size = self.dev.sector_size();
+        let sector_idx = self.pos / sector_size as u64;
+        let offset = (self.pos % sector_size as u64) as usize;
+        let to_copy = core::cmp::min(buf.len(), sector_size - offset);
+
+        let cached = self.sector(sector_idx, sector_idx, to_copy == sector_size)?;
+        cached.data[offset..offset + to_copy].copy_from_slice(&buf[..to_copy]);
+        cached.dirty = true;
+        self.pos += to_copy as u64;
+        Ok(to_copy)
+    }
+
+    fn flush(&mut self) -> Result<(), IDEError> {
+        CachedBlockDevice::flush(self)
+    }
+}
+
+impl<D: SectorDevice> Seek for CachedBlockDevice<D> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, IDEError> {
+        self.pos = match pos {
+            SeekFrom::Start(o) => o,
+            SeekFrom::Current(o) => (self.pos as i64 + o) as u64,
+            // Como no IdeBlockDevice, o tamanho do drive não é conhecido e a
+            // posição fica onde está
+            SeekFrom::End(_) => self.pos,
+        };
+        Ok(self.pos)
+    }
+}
+
+/// Dispositivo em memória que conta os pedidos de leitura e escrita, para
+/// o self-test.
+struct CountingDevice {
+    data: Vec<u8>,
+    reads: usize,
+    writes: usize,
+}
+
+const TEST_SECTOR_SIZE: usize = 512;
+
+impl SectorDevice for CountingDevice {
+    fn sector_size(&self) -> usize {
+        TEST_SECTOR_SIZE
+    }
+
+    fn read_blocks(&mut self, sector_idx: u64, buffer: &mut [u8]) -> Result<(), IDEError> {
+        let start = sector_idx as usize * TEST_SECTOR_SIZE;
+        buffer.copy_from_slice(&self.data[start..start + buffer.len()]);
+        self.reads += 1;
+        Ok(())
+    }
+
+    fn write_block(&mut self, sector_idx: u64, buffer: &[u8]) -> Result<(), IDEError> {
+        let start = sector_idx as usize * TEST_SECTOR_SIZE;
+        self.data[start..start + buffer.len()].copy_from_slice(buffer);
+        self.writes += 1;
+        Ok(())
+    }
+}
+
+/// Confere o cache contra um dispositivo em memória de 16 setores: uma
+/// leitura de 4 setores vira um só pedido, reler setores em cache não vai ao
+/// dispositivo, o menos usado é o despejado e escritas só chegam ao
+/// dispositivo no despejo ou no `flush`.
+pub fn cache_self_test() -> bool {
+    let data: Vec<u8> = (0..16 * TEST_SECTOR_SIZE).map(|i| (i / TEST_SECTOR_SIZE) as u8).collect();
+    let mut cache = CachedBlockDevice::with_capacity(CountingDevice { data, reads: 0, writes: 0 }, 4);
+    let mut buf = [0u8; 4 * TEST_SECTOR_SIZE];
+
+    // Setores 0-3 num único pedido
+    if cache.read_exact(&mut buf).is_err()
+        || cache.dev.reads != 1
+        || buf.chunks_exact(TEST_SECTOR_SIZE).enumerate().any(|(i, s)| s.iter().any(|&b| b != i as u8))
+    {
+        return false;
+    }
+
+    // Reler o setor 2 não vai ao dispositivo
+    let mut sector = [0u8; TEST_SECTOR_SIZE];
+    if cache.seek(SeekFrom::Start(2 * TEST_SECTOR_SIZE as u64)).is_err()
+        || cache.read_exact(&mut sector).is_err()
+        || cache.dev.reads != 1
+        || sector.iter().any(|&b| b != 2)
+    {
+        return false;
+    }
+
+    // Sobrescreve o setor 1 e lê o 8: o setor 0 (o menos usado) sai do cache
+    sector.fill(0xAA);
+    if cache.seek(SeekFrom::Start(TEST_SECTOR_SIZE as u64)).is_err()
+        || cache.write_all(&sector).is_err()
+        || cache.dev.writes != 0
+        || cache.seek(SeekFrom::Start(8 * TEST_SECTOR_SIZE as u64)).is_err()
+        || cache.read_exact(&mut sector).is_err()
+        || cache.dev.reads != 2
+        || cache.sectors.contains_key(&0)
+        || !cache.sectors.contains_key(&1)
+    {
+        return false;
+    }
+
+    if cache.flush().is_err() || cache.dev.writes != 1 {
+        return false;
+    }
+    let written = &cache.dev.data[TEST_SECTOR_SIZE..2 * TEST_SECTOR_SIZE];
+    written.iter().all(|&b| b == 0xAA) && cache.stats() == (5, 5)
+}
@@ -0,0 +1,117 @@
+//! Decoder for uncompressed 24-bit BMP images, enough for embedded logos.
+
+use embedded_graphics::pixelcolor::Rgb888;
+
+const FILE_HEADER_SIZE: usize = 14;
+/// BITMAPINFOHEADER; later versions only add fields after it.
+const INFO_HEADER_SIZE: usize = 40;
+const BI_RGB: u32 = 0;
+
+/// A parsed BMP borrowing its pixel data from the file bytes.
+pub struct Bmp<'a> {
+    width: usize,
+    height: usize,
+    /// Rows are stored bottom to top unless the header's height is negative
+    bottom_up: bool,
+    /// Bytes per stored row, padded to a multiple of 4
+    row_stride: usize,
+    pixels: &'a [u8],
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+impl<'a> Bmp<'a> {
+    /// Checks the headers and that the pixel data is all there.
+    pub fn parse(data: &'a [u8]) -> Result<Self, &'static str> {
+        if data.len() < FILE_HEADER_SIZE + INFO_HEADER_SIZE || &data[0..2] != b"BM" {
+            return Err("Not a BMP file");
+        }
+        let pixel_offset = read_u32(data, 10) as usize;
+        if (read_u32(data, 14) as usize) < INFO_HEADER_SIZE {
+            return Err("Unsupported BMP header");
+        }
+        let width = read_u32(data, 18) as i32;
+        let height = read_u32(data, 22) as i32;
+        if read_u16(data, 26) != 1 || read_u16(data, 28) != 24 || read_u32(data, 30) != BI_RGB {
+            return Err("Only uncompressed 24-bit BMPs are supported");
+        }
+        if width <= 0 || height == 0 {
+            return Err("Invalid BMP dimensions");
+        }
+
+        let width = width as usize;
+        let row_stride = (width * 3 + 3) & !3;
+        let size = row_stride * height.unsigned_abs() as usize;
+        let pixels = data
+            .get(pixel_offset..)
+            .and_then(|pixels| pixels.get(..size))
+            .ok_or("BMP pixel data truncated")?;
+
+        Ok(Self {
+            width,
+            height: height.unsigned_abs() as usize,
+            bottom_up: height > 0,
+            row_stride,
+            pixels,
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Color at column `x` of row `y`, counting rows from the top.
+    pub fn pixel(&self, x: usize, y: usize) -> Option<Rgb888> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        let row = if self.bottom_up { self.height - 1 - y } else { y };
+        let offset = row * self.row_stride + x * 3;
+        // Guardado como BGR
+        let bgr = &self.pixels[offset..offset + 3];
+        Some(Rgb888::new(bgr[2], bgr[1], bgr[0]))
+    }
+}
+
+/// A 2×2 bottom-up BMP: red and green on the top row, blue and white on
+/// the bottom one.
+const TEST_BMP: [u8; 70] = [
+    // File header: magic, file size, reserved, pixel data offset
+    b'B', b'M', 70, 0, 0, 0, 0, 0, 0, 0, 54, 0, 0, 0,
+    // BITMAPINFOHEADER: size, width, height, planes, bits per pixel,
+    // compression, image size, resolution, palette
+    40, 0, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0, 1, 0, 24, 0,
+    0, 0, 0, 0, 16, 0, 0, 0, 0x13, 0x0B, 0, 0, 0x13, 0x0B, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0,
+    // Bottom row (BGR), padded to 8 bytes: blue, white
+    0xFF, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0, 0,
+    // Top row: red, green
+    0x00, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0, 0,
+];
+
+/// Decodes `TEST_BMP` and checks every pixel, that reads past the edges
+/// give `None` and that a file one byte short is rejected.
+pub fn bmp_self_test() -> bool {
+    let Ok(image) = Bmp::parse(&TEST_BMP) else {
+        return false;
+    };
+    image.width() == 2
+        && image.height() == 2
+        && image.pixel(0, 0) == Some(Rgb888::new(0xFF, 0x00, 0x00))
+        && image.pixel(1, 0) == Some(Rgb888::new(0x00, 0xFF, 0x00))
+        && image.pixel(0, 1) == Some(Rgb888::new(0x00, 0x00, 0xFF))
+        && image.pixel(1, 1) == Some(Rgb888::new(0xFF, 0xFF, 0xFF))
+        && image.pixel(2, 0).is_none()
+        && image.pixel(0, 2).is_none()
+        && Bmp::parse(&TEST_BMP[..TEST_BMP.len() - 1]).is_err()
+}
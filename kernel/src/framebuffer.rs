@@ -1,17 +1,22 @@
 use core::{ptr, slice};
 use alloc::boxed::Box;
 use x86_64::{
-    structures::paging::{Mapper, Page, PageTableFlags, Size4KiB},
+    structures::paging::{mapper::TranslateResult, Mapper, Page, PageTableFlags, Size4KiB, Translate},
     VirtAddr
 };
 use embedded_graphics::{
     Pixel,
     draw_target::DrawTarget,
-    geometry::{OriginDimensions, Size},
+    geometry::{Dimensions, OriginDimensions, Point, Size},
     pixelcolor::{Rgb888, RgbColor},
+    primitives::{PointsIter, Rectangle},
 };
 
 use bootloader_api::info::{PixelFormat, FrameBufferInfo};
+use log::{info, warn};
+
+use crate::bmp::Bmp;
+use crate::memory;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Position {
@@ -28,6 +33,10 @@ pub struct Color {
 
 pub struct Display<'a> {
     shadow: Box<[u8]>,
+    /// Per pixel row, the first and last column drawn since the last flush
+    dirty_spans: Box<[Option<(usize, usize)>]>,
+    /// First and last pixel rows drawn since the last flush
+    dirty_rows: Option<(usize, usize)>,
     buffer: &'a mut [u8],
     info: FrameBufferInfo,
 }
@@ -35,6 +44,9 @@ pub struct Display<'a> {
 /// Atualiza os flags da região mapeada do framebuffer para forçar write combining
 /// e retorna um slice mutável para o framebuffer.
 ///
+/// Needs `memory::init_pat` to have run; without it the pages keep their
+/// memory type.
+///
 /// # Segurança
 /// - O framebuffer já deve estar mapeado pelo bootloader.
 /// - `framebuffer_virt_base` deve apontar para o início do mapeamento,
@@ -43,7 +55,7 @@ pub struct Display<'a> {
 pub unsafe fn remap_framebuffer_with_wc<'a>(
     framebuffer_virt_base: VirtAddr,
     framebuffer_size: usize,
-    mapper: &mut impl Mapper<Size4KiB>,
+    mapper: &mut (impl Mapper<Size4KiB> + Translate),
 ) -> &'a mut [u8] {
     // Define o range de páginas que cobrem o framebuffer
     let start = framebuffer_virt_base;
@@ -52,14 +64,30 @@ pub unsafe fn remap_framebuffer_with_wc<'a>(
     let end_page = Page::containing_address(end);
     let page_range = Page::range_inclusive(start_page, end_page);
 
-    // Define os novos flags para ativar Write Combining:
-    // BIT_3 equivale a PWT e BIT_7 equivale ao bit PAT (para páginas de 4KiB, por exemplo)
-    let wc_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::HUGE_PAGE;
+    if memory::write_combining_ready() {
+        // Write combining vem do PAT: o bit PAT (bit 7 numa PTE de 4 KiB) com
+        // PCD e PWT limpos escolhe a entrada que `init_pat` programou como WC
+        let wc_flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_EXECUTE
+            | memory::WRITE_COMBINING;
 
-    // Atualiza os flags de cada página no range
-    for page in page_range {
-        // update_flags atualiza os atributos da entrada de página sem desmapear
-        mapper.update_flags(page, wc_flags).expect("Couldn't update framebuffer page flags.").flush();
+        // Atualiza os flags de cada página no range
+        for page in page_range {
+            // update_flags atualiza os atributos da entrada de página sem desmapear
+            mapper.update_flags(page, wc_flags).expect("Couldn't update framebuffer page flags.").flush();
+        }
+
+        if let TranslateResult::Mapped { flags, .. } = mapper.translate(start) {
+            let memory_type = memory::pat_memory_type(flags);
+            if memory_type == memory::PAT_TYPE_WRITE_COMBINING {
+                info!("Framebuffer mapped write-combining");
+            } else {
+                warn!("Framebuffer memory type is {:#04x}, not write-combining", memory_type);
+            }
+        }
+    } else {
+        warn!("No PAT; framebuffer left with its default memory type");
     }
 
     // Cria um slice para o framebuffer mapeado
@@ -70,46 +98,151 @@ pub unsafe fn remap_framebuffer_with_wc<'a>(
 
 
 fn set_pixel_in(buf: &mut [u8], info: &FrameBufferInfo, position: Position, color: Color) {
+    let bytes_per_pixel = info.bytes_per_pixel;
     let byte_offset = {
         let line_offset = position.y * info.stride;
         let pixel_offset = line_offset + position.x;
-        pixel_offset * info.bytes_per_pixel
+        pixel_offset * bytes_per_pixel
     };
 
-    let pixel_buffer = &mut buf[byte_offset..];
-    match info.pixel_format {
-        PixelFormat::Rgb => {
+    let pixel_buffer = &mut buf[byte_offset..byte_offset + bytes_per_pixel];
+    match (info.pixel_format, bytes_per_pixel) {
+        // 16 bits: RGB565, vermelho nos bits altos (ou azul, no BGR)
+        (PixelFormat::Rgb, 2) => write_packed(pixel_buffer, color, [11, 5, 0]),
+        (PixelFormat::Bgr, 2) => write_packed(pixel_buffer, color, [0, 5, 11]),
+        (PixelFormat::Rgb, 3..) => {
             pixel_buffer[0] = color.red;
             pixel_buffer[1] = color.green;
             pixel_buffer[2] = color.blue;
+            // RGBA/RGBX: alpha (or padding) opaque
+            pixel_buffer[3..].fill(0xFF);
         }
-        PixelFormat::Bgr => {
+        (PixelFormat::Bgr, 3..) => {
             pixel_buffer[0] = color.blue;
             pixel_buffer[1] = color.green;
             pixel_buffer[2] = color.red;
+            pixel_buffer[3..].fill(0xFF);
         }
-        PixelFormat::U8 => {
+        (PixelFormat::U8, 1..) => {
             let gray = color.red / 3 + color.green / 3 + color.blue / 3;
             pixel_buffer[0] = gray;
         }
-        other => panic!("unknown pixel format {other:?}"),
+        (PixelFormat::Unknown { red_position, green_position, blue_position }, 1..=4) => {
+            write_packed(pixel_buffer, color, [red_position, green_position, blue_position])
+        }
+        (other, _) => panic!("unknown pixel format {other:?} with {bytes_per_pixel} bytes per pixel"),
+    }
+}
+
+/// Writes `color` as a little-endian pixel of `pixel.len()` bytes with the
+/// red, green and blue channels starting at the bit offsets in `positions`.
+///
+/// Each channel runs up to the next one (the highest up to the end of the
+/// pixel, at most 8 bits), so offsets 11/5/0 in 2 bytes give RGB565 and
+/// 16/8/0 in 4 bytes give BGRA.
+fn write_packed(pixel: &mut [u8], color: Color, positions: [u8; 3]) {
+    let bits = (pixel.len() * 8) as u32;
+    let channels = [color.red, color.green, color.blue];
+
+    let mut value: u32 = 0;
+    for (channel, &position) in channels.iter().zip(&positions) {
+        let position = position as u32;
+        let next = positions
+            .iter()
+            .map(|&other| other as u32)
+            .filter(|&other| other > position)
+            .min()
+            .unwrap_or(bits);
+        let width = next.saturating_sub(position).min(8);
+        if width == 0 || position >= bits {
+            continue;
+        }
+        value |= ((*channel as u32) >> (8 - width)) << position;
     }
+
+    pixel.copy_from_slice(&value.to_le_bytes()[..pixel.len()]);
 }
 
 impl<'a> Display<'a> {
     pub fn new_from_buffer(buffer: &'a mut [u8], info: &FrameBufferInfo) -> Self {
         let shadow = vec![0; buffer.len()].into_boxed_slice();
-        Self { shadow, buffer, info: info.clone() }
+        let dirty_spans = vec![None; info.height].into_boxed_slice();
+        let mut display = Self { shadow, dirty_spans, dirty_rows: None, buffer, info: info.clone() };
+        // The bootloader left something on screen, so the first flush writes it all
+        display.mark_all_dirty();
+        display
     }
 
+    /// Wraps the framebuffer without a shadow buffer, so nothing is allocated.
+    ///
+    /// Only for the panic path: just the `*_direct` methods may be used on
+    /// the result, since everything else goes through the (empty) shadow.
+    pub fn new_direct(buffer: &'a mut [u8], info: &FrameBufferInfo) -> Self {
+        Self {
+            shadow: Box::default(),
+            dirty_spans: Box::default(),
+            dirty_rows: None,
+            buffer,
+            info: *info,
+        }
+    }
+
+    /// Copies what changed in the shadow buffer to the framebuffer.
+    ///
+    /// Only the span of each row between the first and last column drawn
+    /// since the last flush is written.
     pub fn flush(&mut self) {
-        self.buffer.copy_from_slice(&self.shadow);
-    }    
+        let Some((first_row, last_row)) = self.dirty_rows.take() else {
+            return;
+        };
+        let bytes_per_pixel = self.info.bytes_per_pixel;
+        for y in first_row..=last_row {
+            let Some((left, right)) = self.dirty_spans[y].take() else {
+                continue;
+            };
+            let start = (y * self.info.stride + left) * bytes_per_pixel;
+            let end = (y * self.info.stride + right + 1) * bytes_per_pixel;
+            self.buffer[start..end].copy_from_slice(&self.shadow[start..end]);
+        }
+    }
 
     pub fn clear_buf(&mut self) {
         unsafe {
             ptr::write_bytes(self.shadow.as_mut_ptr(), 0, self.buffer.len());
         }
+        self.mark_all_dirty();
+    }
+
+    /// Blanks the screen: clears the shadow buffer and flushes it.
+    pub fn clear(&mut self) {
+        self.clear_buf();
+        self.flush();
+    }
+
+    pub fn width(&self) -> usize {
+        self.info.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.info.height
+    }
+
+    /// Pinta um pixel diretamente no framebuffer, sem passar pelo shadow buffer.
+    ///
+    /// Usado no caminho de pânico, onde não dá pra confiar no estado do heap.
+    pub fn set_pixel_direct(&mut self, position: Position, color: Color) {
+        if position.x < self.info.width && position.y < self.info.height {
+            set_pixel_in(self.buffer, &self.info, position, color);
+        }
+    }
+
+    /// Preenche o framebuffer inteiro com `color`, sem passar pelo shadow buffer.
+    pub fn fill_direct(&mut self, color: Color) {
+        for y in 0..self.info.height {
+            for x in 0..self.info.width {
+                set_pixel_in(self.buffer, &self.info, Position { x, y }, color);
+            }
+        }
     }
 
     pub fn draw_pixel(&mut self, Pixel(coordinates, color): Pixel<Rgb888>) {
@@ -126,10 +259,87 @@ impl<'a> Display<'a> {
                 blue: color.b(),
             };
             set_pixel_in(&mut self.shadow, &self.info, Position { x, y }, color);
+            self.mark_dirty(x, y, x, y);
+        }
+    }
+
+    /// Records that the pixels from (`left`, `top`) to (`right`, `bottom`),
+    /// inclusive, need to go out on the next flush.
+    fn mark_dirty(&mut self, left: usize, top: usize, right: usize, bottom: usize) {
+        for span in &mut self.dirty_spans[top..=bottom] {
+            *span = Some(match *span {
+                Some((old_left, old_right)) => (old_left.min(left), old_right.max(right)),
+                None => (left, right),
+            });
         }
+        self.dirty_rows = Some(match self.dirty_rows {
+            Some((old_first, old_last)) => (old_first.min(top), old_last.max(bottom)),
+            None => (top, bottom),
+        });
+    }
+
+    fn mark_all_dirty(&mut self) {
+        if self.info.width > 0 && self.info.height > 0 {
+            self.mark_dirty(0, 0, self.info.width - 1, self.info.height - 1);
+        }
+    }
+
+    /// Fills the `size` rectangle at `origin` with `color`, clipped to the
+    /// screen. Like `draw_pixel`, it draws into the shadow buffer.
+    pub fn fill_rect(&mut self, origin: Point, size: Size, color: Rgb888) {
+        let area = Rectangle::new(origin, size).intersection(&self.bounding_box());
+        let Some(bottom_right) = area.bottom_right() else {
+            return;
+        };
+        let (left, top) = (area.top_left.x as usize, area.top_left.y as usize);
+        let (right, bottom) = (bottom_right.x as usize, bottom_right.y as usize);
+
+        let color = Color { red: color.r(), green: color.g(), blue: color.b() };
+        let bytes_per_pixel = self.info.bytes_per_pixel;
+        let span = (right - left + 1) * bytes_per_pixel;
+        for y in top..=bottom {
+            // Pinta o primeiro pixel e replica os bytes pelo resto da linha
+            set_pixel_in(&mut self.shadow, &self.info, Position { x: left, y }, color);
+            let start = (y * self.info.stride + left) * bytes_per_pixel;
+            for offset in (bytes_per_pixel..span).step_by(bytes_per_pixel) {
+                self.shadow.copy_within(start..start + bytes_per_pixel, start + offset);
+            }
+        }
+        self.mark_dirty(left, top, right, bottom);
+    }
+
+    /// Draws a horizontal line `length` pixels long, starting at `start`
+    /// and going right.
+    pub fn draw_hline(&mut self, start: Point, length: u32, color: Rgb888) {
+        self.fill_rect(start, Size::new(length, 1), color);
+    }
+
+    /// Draws a vertical line `length` pixels long, starting at `start` and
+    /// going down.
+    pub fn draw_vline(&mut self, start: Point, length: u32, color: Rgb888) {
+        self.fill_rect(start, Size::new(1, length), color);
     }
 }
 
+/// Draws `image` centered on the display and flushes it, for a splash
+/// screen before the TTY takes over.
+pub fn draw_splash(display: &mut Display, image: &Bmp) -> Result<(), &'static str> {
+    let (width, height) = (image.width(), image.height());
+    if width > display.width() || height > display.height() {
+        return Err("Splash image larger than the screen");
+    }
+
+    let origin = Point::new(
+        ((display.width() - width) / 2) as i32,
+        ((display.height() - height) / 2) as i32,
+    );
+    let area = Rectangle::new(origin, Size::new(width as u32, height as u32));
+    let colors = (0..height).flat_map(|y| (0..width).map(move |x| image.pixel(x, y).unwrap_or(Rgb888::BLACK)));
+    let _ = display.fill_contiguous(&area, colors);
+    display.flush();
+    Ok(())
+}
+
 impl<'a> DrawTarget for Display<'a> {
     type Color = Rgb888;
     type Error = core::convert::Infallible;
@@ -144,10 +354,98 @@ impl<'a> DrawTarget for Display<'a> {
 
         Ok(())
     }
+
+    fn fill_contiguous<I>(&mut self, area: &Rectangle, colors: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Self::Color>,
+    {
+        let drawable = area.intersection(&self.bounding_box());
+        let Some(bottom_right) = drawable.bottom_right() else {
+            return Ok(());
+        };
+
+        // As cores cobrem a área inteira, inclusive o que fica fora da tela
+        for (point, color) in area.points().zip(colors) {
+            if drawable.contains(point) {
+                let position = Position { x: point.x as usize, y: point.y as usize };
+                let color = Color { red: color.r(), green: color.g(), blue: color.b() };
+                set_pixel_in(&mut self.shadow, &self.info, position, color);
+            }
+        }
+        self.mark_dirty(
+            drawable.top_left.x as usize,
+            drawable.top_left.y as usize,
+            bottom_right.x as usize,
+            bottom_right.y as usize,
+        );
+        Ok(())
+    }
+
+    fn fill_solid(&mut self, area: &Rectangle, color: Self::Color) -> Result<(), Self::Error> {
+        self.fill_rect(area.top_left, area.size, color);
+        Ok(())
+    }
 }
 
 impl<'a> OriginDimensions for Display<'a> {
     fn size(&self) -> Size {
         Size::new(self.info.width as u32, self.info.height as u32)
     }
-}
\ No newline at end of file
+}
+/// Packs one color in each supported format through `set_pixel_in` and
+/// checks the bytes, then fills two rectangles hanging off opposite corners
+/// of an 8×6 in-memory screen (stride 10) and checks that only their
+/// on-screen parts reach the framebuffer after a flush.
+pub fn framebuffer_self_test() -> bool {
+    let info = |pixel_format, bytes_per_pixel, width, height, stride| FrameBufferInfo {
+        byte_len: stride * height * bytes_per_pixel,
+        width,
+        height,
+        pixel_format,
+        bytes_per_pixel,
+        stride,
+    };
+
+    let color = Color { red: 0xFF, green: 0x80, blue: 0x10 };
+    let packings: [(PixelFormat, usize, &[u8]); 8] = [
+        (PixelFormat::Rgb, 2, &[0x02, 0xFC]), // RGB565
+        (PixelFormat::Bgr, 2, &[0x1F, 0x14]), // BGR565
+        (PixelFormat::Rgb, 3, &[0xFF, 0x80, 0x10]),
+        (PixelFormat::Rgb, 4, &[0xFF, 0x80, 0x10, 0xFF]),
+        (PixelFormat::Bgr, 4, &[0x10, 0x80, 0xFF, 0xFF]),
+        (PixelFormat::U8, 1, &[0x84]),
+        (PixelFormat::Unknown { red_position: 16, green_position: 8, blue_position: 0 }, 4, &[0x10, 0x80, 0xFF, 0x00]),
+        (PixelFormat::Unknown { red_position: 0, green_position: 8, blue_position: 16 }, 4, &[0xFF, 0x80, 0x10, 0x00]),
+    ];
+    for (pixel_format, bytes_per_pixel, expected) in packings {
+        // Segundo pixel de uma linha de dois: o primeiro tem que ficar intacto
+        let mut buf = [0u8; 8];
+        let info = info(pixel_format, bytes_per_pixel, 2, 1, 2);
+        set_pixel_in(&mut buf, &info, Position { x: 1, y: 0 }, color);
+        if buf[..bytes_per_pixel].iter().any(|&b| b != 0) || &buf[bytes_per_pixel..2 * bytes_per_pixel] != expected {
+            return false;
+        }
+    }
+
+    let info = info(PixelFormat::Bgr, 4, 8, 6, 10);
+    let mut framebuffer = vec![0u8; info.byte_len];
+    let mut display = Display::new_from_buffer(&mut framebuffer, &info);
+    display.fill_rect(Point::new(-2, -1), Size::new(5, 4), Rgb888::RED);
+    display.fill_rect(Point::new(6, 4), Size::new(10, 10), Rgb888::GREEN);
+    display.fill_rect(Point::new(20, 20), Size::new(4, 4), Rgb888::BLUE);
+    display.flush();
+
+    let pixel = |x: usize, y: usize| &framebuffer[(y * info.stride + x) * 4..][..4];
+    let (red, green, black) = ([0x00, 0x00, 0xFF, 0xFF], [0x00, 0xFF, 0x00, 0xFF], [0; 4]);
+    pixel(0, 0) == red
+        && pixel(2, 2) == red
+        && pixel(3, 2) == black
+        && pixel(2, 3) == black
+        && pixel(6, 4) == green
+        && pixel(7, 5) == green
+        && pixel(5, 5) == black
+        && pixel(6, 3) == black
+        // Stride padding past the right edge
+        && pixel(8, 5) == black
+        && pixel(9, 4) == black
+}
@@ -1,30 +1,42 @@
+use core::ops::Range;
 use spin::Mutex;
 use x86_64::VirtAddr;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
 use x86_64::instructions::segmentation::Segment;
 use lazy_static::lazy_static;
+use log::debug;
 
+// Every entry has its own stack, so a fault taken while handling another
+// one (e.g. a page fault in the double fault handler) can't overwrite the
+// stack it interrupted.
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
-pub const PAGE_FAULT_IST_INDEX: u16 = 0;
-pub const GENERAL_PROTECTION_FAULT_IST_INDEX: u16 = 0;
-pub const TIMER_INTERRUPT_INDEX: u16 = 1;
-pub const KEYBOARD_INTERRUPT_INDEX: u16 = 0;
+pub const TIMER_INTERRUPT_INDEX: u16 = 1; // Switched to each thread's kernel stack
+pub const PAGE_FAULT_IST_INDEX: u16 = 2;
+pub const GENERAL_PROTECTION_FAULT_IST_INDEX: u16 = 3;
+pub const IRQ_INTERRUPT_INDEX: u16 = 4;
+
+const IST_STACK_SIZE: usize = 4096 * 5;
+
+/// Reserves a new static stack and evaluates to its end address.
+macro_rules! ist_stack {
+    () => {{
+        static mut STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
+
+        let stack_start = VirtAddr::from_ptr(&raw const STACK);
+        stack_start + IST_STACK_SIZE as u64
+    }};
+}
 
 lazy_static! {
     static ref TSS: Mutex<TaskStateSegment> = {
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(&raw const STACK);
-            let stack_end = stack_start + STACK_SIZE.try_into().unwrap();
-            stack_end
-        };
-
-        tss.interrupt_stack_table[TIMER_INTERRUPT_INDEX as usize] =
-            tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize];
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = ist_stack!();
+        // Used until the scheduler runs the first thread
+        tss.interrupt_stack_table[TIMER_INTERRUPT_INDEX as usize] = ist_stack!();
+        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = ist_stack!();
+        tss.interrupt_stack_table[GENERAL_PROTECTION_FAULT_IST_INDEX as usize] = ist_stack!();
+        tss.interrupt_stack_table[IRQ_INTERRUPT_INDEX as usize] = ist_stack!();
 
         Mutex::new(tss)
     };
@@ -39,14 +51,45 @@ pub fn set_interrupt_stack_table(index: usize, stack_end: VirtAddr) {
     TSS.lock().interrupt_stack_table[index] = stack_end;
 }
 
+/// Every IST index the IDT uses.
+const IST_INDICES: [u16; 5] = [
+    DOUBLE_FAULT_IST_INDEX,
+    TIMER_INTERRUPT_INDEX,
+    PAGE_FAULT_IST_INDEX,
+    GENERAL_PROTECTION_FAULT_IST_INDEX,
+    IRQ_INTERRUPT_INDEX,
+];
+
+/// Addresses covered by the stack the TSS has for IST entry `index`,
+/// assuming it's `IST_STACK_SIZE` long.
+pub fn ist_range(index: u16) -> Range<u64> {
+    // The scheduler rewrites the TSS from the timer interrupt
+    let stack_end = x86_64::instructions::interrupts::without_interrupts(|| {
+        TSS.lock().interrupt_stack_table[index as usize].as_u64()
+    });
+    stack_end - IST_STACK_SIZE as u64..stack_end
+}
+
+/// Checks that no two IST entries share a slot or overlap, so a fault
+/// nested inside another handler always lands on a stack of its own.
+pub fn ist_self_test() -> bool {
+    let ranges = IST_INDICES.map(ist_range);
+    IST_INDICES.iter().enumerate().all(|(i, index)| !IST_INDICES[..i].contains(index))
+        && ranges.iter().enumerate().all(|(i, a)| {
+            !a.is_empty() && ranges[..i].iter().all(|b| a.end <= b.start || b.end <= a.start)
+        })
+}
+
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.append(Descriptor::kernel_code_segment());
         let data_selector = gdt.append(Descriptor::kernel_data_segment());
         let tss_selector = gdt.append(Descriptor::tss_segment(unsafe {tss_reference()}));
-        let user_code_selector = gdt.append(Descriptor::user_code_segment());
+        // sysretq loads SS from STAR[63:48] + 8 and CS from STAR[63:48] + 16,
+        // so user data has to come right before user code
         let user_data_selector = gdt.append(Descriptor::user_data_segment());
+        let user_code_selector = gdt.append(Descriptor::user_code_segment());
         (gdt, Selectors { code_selector, data_selector, tss_selector, user_code_selector, user_data_selector })
     };
 }
@@ -65,7 +108,7 @@ pub fn init() {
     use x86_64::instructions::segmentation::{CS, DS};
 
     GDT.0.load();
-    serial_println!("Global Descriptor Table defined!");
+    debug!("Global Descriptor Table defined!");
 
     unsafe {
         CS::set_reg(GDT.1.code_selector);
@@ -73,7 +116,7 @@ pub fn init() {
         load_tss(GDT.1.tss_selector);
     }
 
-    serial_println!("CS, DS and TSS loaded!");
+    debug!("CS, DS and TSS loaded!");
 }
 
 pub fn get_kernel_segments() -> (SegmentSelector, SegmentSelector) {
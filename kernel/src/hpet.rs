@@ -0,0 +1,111 @@
+//! High Precision Event Timer: a free-running counter with a period of a
+//! few nanoseconds, for timestamps and short precise delays.
+
+use acpi::{hpet::HpetInfo, AcpiTables};
+use conquer_once::spin::OnceCell;
+use log::{info, warn};
+use x86_64::structures::paging::{FrameAllocator, OffsetPageTable, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::interrupts::AcpiHandlerImpl;
+use crate::memory;
+
+const REG_CAPABILITIES: u64 = 0x000;
+const REG_CONFIGURATION: u64 = 0x010;
+const REG_MAIN_COUNTER: u64 = 0x0F0;
+/// Size of the register block
+const MMIO_SIZE: u64 = 0x400;
+
+/// COUNT_SIZE_CAP: the main counter is 64 bits wide
+const CAP_64BIT_COUNTER: u64 = 1 << 13;
+const CONFIG_ENABLE: u64 = 1 << 0;
+const FEMTOSECONDS_PER_NS: u128 = 1_000_000;
+/// The spec caps the counter period at 100 ns
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+struct Hpet {
+    registers: VirtAddr,
+    /// Counter period in femtoseconds
+    period_fs: u64,
+}
+
+impl Hpet {
+    unsafe fn read(&self, register: u64) -> u64 {
+        core::ptr::read_volatile((self.registers + register).as_ptr::<u64>())
+    }
+
+    unsafe fn write(&self, register: u64, value: u64) {
+        core::ptr::write_volatile((self.registers + register).as_mut_ptr::<u64>(), value)
+    }
+}
+
+static HPET: OnceCell<Hpet> = OnceCell::uninit();
+
+/// Finds the HPET in the ACPI tables, maps its registers and starts the main
+/// counter. Logs and leaves the HPET unavailable if there is none.
+///
+/// A 32-bit main counter wraps every few minutes and `now_ns` has no way to
+/// notice, so such an HPET is left unused as well.
+pub unsafe fn init(
+    rsdp: usize,
+    physical_memory_offset: VirtAddr,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) {
+    let handler = AcpiHandlerImpl::new(physical_memory_offset);
+    let Ok(hpet_info) = AcpiTables::from_rsdp(handler, rsdp)
+        .and_then(|tables| HpetInfo::new(&tables))
+    else {
+        info!("No HPET table; HPET unavailable");
+        return;
+    };
+
+    let phys = PhysAddr::new(hpet_info.base_address as u64);
+    let registers = match memory::map_physical_mmio(phys, MMIO_SIZE, mapper, frame_allocator) {
+        Ok(registers) => registers,
+        Err(err) => {
+            warn!("HPET at {:#x} could not be mapped: {:?}", phys.as_u64(), err);
+            return;
+        }
+    };
+
+    let mut hpet = Hpet { registers, period_fs: 0 };
+    let capabilities = hpet.read(REG_CAPABILITIES);
+    hpet.period_fs = capabilities >> 32;
+    if hpet.period_fs == 0 || hpet.period_fs > MAX_PERIOD_FS {
+        warn!("HPET reports an invalid period ({} fs); ignoring it", hpet.period_fs);
+        return;
+    }
+    if capabilities & CAP_64BIT_COUNTER == 0 {
+        warn!("HPET has a 32-bit main counter that would wrap; ignoring it");
+        return;
+    }
+
+    let config = hpet.read(REG_CONFIGURATION);
+    hpet.write(REG_CONFIGURATION, config | CONFIG_ENABLE);
+
+    info!("HPET: {} MHz, 64-bit counter", 1_000_000_000 / hpet.period_fs);
+    let _ = HPET.try_init_once(|| hpet);
+}
+
+/// Whether `init` found and started an HPET.
+pub fn is_available() -> bool {
+    HPET.is_initialized()
+}
+
+/// Nanoseconds the HPET main counter has been running, or `None` without
+/// an HPET.
+pub fn now_ns() -> Option<u64> {
+    let hpet = HPET.try_get().ok()?;
+    let counter = unsafe { hpet.read(REG_MAIN_COUNTER) };
+    Some((counter as u128 * hpet.period_fs as u128 / FEMTOSECONDS_PER_NS) as u64)
+}
+
+/// Spins for at least `ns` nanoseconds. Fails right away without an HPET.
+pub fn busy_wait_ns(ns: u64) -> Result<(), &'static str> {
+    let start = now_ns().ok_or("HPET unavailable")?;
+    while now_ns().unwrap_or(u64::MAX).wrapping_sub(start) < ns {
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
@@ -1,14 +1,50 @@
 use alloc::string::{String, ToString};
+use alloc::vec::Vec;
 use x86_64::instructions::port::Port;
 use simple_fatfs::*;
 use simple_fatfs::io::prelude::*;
 use core::arch::asm;
 
+use core::sync::atomic::{AtomicBool, Ordering};
+use log::warn;
+
+use crate::block_cache::CachedBlockDevice;
+use crate::interrupts;
+use crate::process::{self, WaitQueue};
+
 #[derive(Debug)]
 pub struct IdeDevice {
     pub channel: &'static str,
     pub drive: &'static str,
+    /// Porta base do canal (0x1F0 ou 0x170)
+    pub channel_base: u16,
+    /// `true` para o drive master, `false` para o slave
+    pub master: bool,
     pub model: [u8; 40],
+    /// Tamanho do setor lógico em bytes, lido do IDENTIFY
+    pub sector_size: usize,
+}
+
+/// Tamanho de setor usado quando o drive não informa outro
+pub const DEFAULT_SECTOR_SIZE: usize = 512;
+/// Maior setor lógico suportado (drives Advanced Format usam 4096)
+pub const MAX_SECTOR_SIZE: usize = 4096;
+
+/// Extrai o tamanho do setor lógico dos dados do IDENTIFY.
+///
+/// A word 106 só é válida com o bit 15 limpo e o bit 14 setado; o bit 12
+/// indica que o setor lógico é maior que 256 words, e nesse caso as words
+/// 117-118 trazem o tamanho em words.
+fn logical_sector_size(identify_data: &[u16; 256]) -> usize {
+    let word106 = identify_data[106];
+    if word106 & 0xC000 == 0x4000 && word106 & (1 << 12) != 0 {
+        let words = (identify_data[117] as u32) | ((identify_data[118] as u32) << 16);
+        let bytes = words as usize * 2;
+        if (DEFAULT_SECTOR_SIZE..=MAX_SECTOR_SIZE).contains(&bytes) && bytes % DEFAULT_SECTOR_SIZE == 0 {
+            return bytes;
+        }
+    }
+    DEFAULT_SECTOR_SIZE
 }
 
 #[inline(always)]
@@ -56,12 +92,11 @@ pub fn detect_ide_devices() -> [Option<IdeDevice>; 4] {
                     continue; // Nada conectado
                 }
 
-                // Aguarde até que DRQ esteja setado e BSY limpo
-                loop {
-                    let s = port_status.read();
-                    if s & 0x80 != 0 { continue; } // BSY
-                    if s & 0x08 != 0 { break; }    // DRQ
-                    if s & 0x01 != 0 { break; }    // ERR
+                // Aguarde até que DRQ (ou ERR) esteja setado e BSY limpo;
+                // um drive que não responde é tratado como ausente
+                if let Err(err) = wait_status(io_base) {
+                    warn!("IDE {} {}: {}", channel_name, drive_name, err);
+                    continue;
                 }
 
                 // Leia os 256 words (512 bytes)
@@ -81,7 +116,10 @@ pub fn detect_ide_devices() -> [Option<IdeDevice>; 4] {
                 devices[index] = Some(IdeDevice {
                     channel: channel_name,
                     drive: drive_name,
+                    channel_base: io_base,
+                    master: is_master,
                     model: model_bytes,
+                    sector_size: logical_sector_size(&identify_data),
                 });
             }
         }
@@ -90,98 +128,465 @@ pub fn detect_ide_devices() -> [Option<IdeDevice>; 4] {
     devices
 }
 
-/// Lê um setor (512 bytes) do canal IDE primário ou secundário.
-/// `channel_base` = 0x1F0 (primário) ou 0x170 (secundário)
-/// `lba`: setor lógico (48‑bit, mas aqui só usa 28 bits)
-/// `buffer`: &mut [u8;512]
-pub fn read_sector(channel_base: u16, lba: u32, buffer: &mut [u8;512]) -> Result<(), ()> {
-    let ctrl_base = if channel_base == 0x1F0 { 0x3F6 } else { 0x376 };
+/// Verifica se `buffer` tem o tamanho de um setor lógico suportado.
+fn is_sector_sized(buffer: &[u8]) -> bool {
+    (DEFAULT_SECTOR_SIZE..=MAX_SECTOR_SIZE).contains(&buffer.len())
+        && buffer.len() % DEFAULT_SECTOR_SIZE == 0
+}
+
+/// Primeira LBA que não cabe no endereçamento de 28 bits (128 GiB com
+/// setores de 512 bytes).
+pub const LBA28_LIMIT: u64 = 1 << 28;
+
+// Comandos ATA de PIO
+const CMD_READ_SECTORS: u8 = 0x20;
+const CMD_READ_SECTORS_EXT: u8 = 0x24;
+const CMD_WRITE_SECTORS: u8 = 0x30;
+const CMD_WRITE_SECTORS_EXT: u8 = 0x34;
+
+/// Quanto esperar o drive responder antes de desistir dele.
+const IDE_TIMEOUT_MS: u64 = 1000;
+/// Limite de leituras do status em `wait_status`, para quando `uptime_ms`
+/// não anda (interrupções desligadas, timer ainda sem calibrar). Cada leitura
+/// de porta leva cerca de 1 µs, então isso dá mais ou menos `IDE_TIMEOUT_MS`.
+const IDE_MAX_STATUS_POLLS: u64 = 1_000_000;
+
+/// Erro de um drive que não respondeu dentro de `IDE_TIMEOUT_MS`.
+fn timeout_error() -> IDEError {
+    IDEError::new(IDEErrorKind::Interrupted, Some("IDE drive timed out".to_string()))
+}
+
+/// Erro de um pedido com buffer ou LBA inválidos.
+fn invalid_request() -> IDEError {
+    IDEError::new(IDEErrorKind::InvalidData, Some("Invalid IDE sector request".to_string()))
+}
+
+/// Espera o drive baixar BSY e levantar DRQ ou ERR; devolve o status lido.
+///
+/// Um canal vazio costuma ler 0xFF (BSY nunca baixa), então o prazo usa o
+/// contador de ticks e o loop desiste depois de `IDE_TIMEOUT_MS`, ou depois
+/// de `IDE_MAX_STATUS_POLLS` leituras se o contador estiver parado.
+unsafe fn wait_status(channel_base: u16) -> Result<u8, IDEError> {
+    let mut status_port = Port::<u8>::new(channel_base + 7);
+    let deadline = crate::interrupts::uptime_ms() + IDE_TIMEOUT_MS;
+    for _ in 0..IDE_MAX_STATUS_POLLS {
+        let status = status_port.read();
+        if status & 0x80 == 0 && status & 0x09 != 0 {
+            return Ok(status);
+        }
+        if crate::interrupts::uptime_ms() >= deadline {
+            break;
+        }
+    }
+    Err(timeout_error())
+}
+
+/// Espera o drive baixar BSY e levantar DRQ.
+unsafe fn wait_data_ready(channel_base: u16) -> Result<(), IDEError> {
+    if wait_status(channel_base)? & 0x01 != 0 {
+        return Err(IDEError::new(IDEErrorKind::General, Some("IDE drive reported an error".to_string())));
+    }
+    Ok(())
+}
+
+/// Estado da interrupção de um canal IDE.
+struct ChannelIrq {
+    /// A IRQ do canal chegou e ainda não foi consumida
+    pending: AtomicBool,
+    /// Threads dormindo à espera da IRQ
+    waiters: WaitQueue,
+}
 
+impl ChannelIrq {
+    const fn new() -> Self {
+        Self { pending: AtomicBool::new(false), waiters: WaitQueue::new() }
+    }
+}
+
+static PRIMARY_IRQ: ChannelIrq = ChannelIrq::new();
+static SECONDARY_IRQ: ChannelIrq = ChannelIrq::new();
+/// `enable_irqs` já rodou; antes disso toda espera é por polling
+static IRQS_ENABLED: AtomicBool = AtomicBool::new(false);
+
+fn channel_irq(channel_base: u16) -> Option<&'static ChannelIrq> {
+    match channel_base {
+        0x1F0 => Some(&PRIMARY_IRQ),
+        0x170 => Some(&SECONDARY_IRQ),
+        _ => None,
+    }
+}
+
+/// Liga as IRQs 14 e 15 dos canais IDE. Depois disso, threads que fazem I/O
+/// dormem até o drive interromper em vez de ficar consultando o status.
+pub fn enable_irqs() {
     unsafe {
-        // Seleciona drive Master no canal
-        Port::<u8>::new(channel_base as u16 + 6).write(0xE0 | ((lba >> 24) & 0x0F) as u8);
-        io_wait();
+        // Limpa nIEN no registrador de controle de cada canal
+        Port::<u8>::new(0x3F6).write(0);
+        Port::<u8>::new(0x376).write(0);
+    }
+    interrupts::register_irq(interrupts::IDE_PRIMARY_IRQ, primary_irq);
+    interrupts::register_irq(interrupts::IDE_SECONDARY_IRQ, secondary_irq);
+    IRQS_ENABLED.store(true, Ordering::Release);
+}
+
+fn primary_irq() {
+    handle_channel_irq(0x1F0, &PRIMARY_IRQ);
+}
+
+fn secondary_irq() {
+    handle_channel_irq(0x170, &SECONDARY_IRQ);
+}
+
+fn handle_channel_irq(channel_base: u16, state: &'static ChannelIrq) {
+    // Ler o status confirma a interrupção para o drive
+    unsafe { Port::<u8>::new(channel_base + 7).read() };
+    state.pending.store(true, Ordering::Release);
+    state.waiters.wake_all();
+}
+
+/// Descarta uma IRQ antiga do canal antes de mandar um comando novo.
+fn clear_pending_irq(channel_base: u16) {
+    if let Some(state) = channel_irq(channel_base) {
+        state.pending.store(false, Ordering::Release);
+    }
+}
+
+/// Dorme até a próxima IRQ do canal, quando dá para dormir: as IRQs estão
+/// ligadas, as interrupções habilitadas e quem chama é uma thread. Fora
+/// disso (boot, por exemplo) não faz nada e o polling em `wait_data_ready`
+/// cuida da espera.
+///
+/// Uma IRQ que se perdeu (ou que o drive nunca manda) não trava a thread:
+/// depois de `IDE_TIMEOUT_MS` a espera termina com erro de timeout.
+fn wait_irq(channel_base: u16) -> Result<(), IDEError> {
+    if !IRQS_ENABLED.load(Ordering::Acquire)
+        || !x86_64::instructions::interrupts::are_enabled()
+        || process::current_pid().is_none()
+    {
+        return Ok(());
+    }
+    let Some(state) = channel_irq(channel_base) else {
+        return Ok(());
+    };
+    let timeout_ticks = (crate::interrupts::timer_frequency() * IDE_TIMEOUT_MS / 1000).max(1);
+    process::wait_until_timeout(&state.waiters, timeout_ticks, || {
+        state.pending.swap(false, Ordering::AcqRel).then_some(())
+    })
+    .ok_or_else(timeout_error)
+}
+
+/// Lê `buffer.len()` bytes da porta de dados, em palavras de 16 bits.
+unsafe fn pio_read(channel_base: u16, buffer: &mut [u8]) {
+    let mut data = Port::<u16>::new(channel_base);
+    let ptr = buffer.as_mut_ptr() as *mut u16;
+    for i in 0..buffer.len() / 2 {
+        let w = data.read();
+        core::ptr::write_volatile(ptr.add(i), w);
+    }
+}
+
+/// Escreve `buffer` na porta de dados, em palavras de 16 bits.
+unsafe fn pio_write(channel_base: u16, buffer: &[u8]) {
+    let data = buffer.as_ptr() as *const u16;
+    let mut port_data = Port::<u16>::new(channel_base);
+    for i in 0..buffer.len() / 2 {
+        let w = core::ptr::read_volatile(data.add(i));
+        port_data.write(w);
+    }
+}
+
+/// Maior número de setores num comando de 28 bits (sector count 0 = 256).
+pub const MAX_SECTORS_LBA28: usize = 256;
+/// Maior número de setores num comando de 48 bits (sector count 0 = 65536).
+pub const MAX_SECTORS_LBA48: usize = 65536;
+
+/// Bit 4 do registrador de seleção de drive: 0 = master, 1 = slave.
+fn drive_bit(master: bool) -> u8 {
+    if master { 0 } else { 0x10 }
+}
+
+/// Programa os registradores para um comando de 28 bits de `count` setores
+/// (0 significa 256).
+unsafe fn setup_lba28(channel_base: u16, master: bool, lba: u32, count: u8) {
+    clear_pending_irq(channel_base);
+
+    // Seleciona o drive no canal (0xE0 master, 0xF0 slave)
+    Port::<u8>::new(channel_base + 6).write(0xE0 | drive_bit(master) | ((lba >> 24) & 0x0F) as u8);
+    io_wait();
 
-        // Preenche registradores
-        Port::<u8>::new(channel_base + 2).write(1);             // sector count = 1
-        Port::<u8>::new(channel_base + 3).write((lba & 0xFF) as u8);
-        Port::<u8>::new(channel_base + 4).write(((lba >> 8) & 0xFF) as u8);
-        Port::<u8>::new(channel_base + 5).write(((lba >> 16) & 0xFF) as u8);
+    // Preenche registradores
+    Port::<u8>::new(channel_base + 2).write(count);
+    Port::<u8>::new(channel_base + 3).write((lba & 0xFF) as u8);
+    Port::<u8>::new(channel_base + 4).write(((lba >> 8) & 0xFF) as u8);
+    Port::<u8>::new(channel_base + 5).write(((lba >> 16) & 0xFF) as u8);
+}
+
+/// Programa os registradores para um comando de 48 bits de `count` setores
+/// (0 significa 65536).
+///
+/// Cada registrador é um FIFO de dois bytes: primeiro vão os bytes altos
+/// (sector count 15:8, LBA 47:24), depois os baixos.
+unsafe fn setup_lba48(channel_base: u16, master: bool, lba: u64, count: u16) {
+    clear_pending_irq(channel_base);
+
+    // Modo LBA (0x40 master, 0x50 slave); os bits 3:0 não são usados em 48 bits
+    Port::<u8>::new(channel_base + 6).write(0x40 | drive_bit(master));
+    io_wait();
+
+    let mut sector_count = Port::<u8>::new(channel_base + 2);
+    let mut lba_low = Port::<u8>::new(channel_base + 3);
+    let mut lba_mid = Port::<u8>::new(channel_base + 4);
+    let mut lba_high = Port::<u8>::new(channel_base + 5);
+
+    sector_count.write((count >> 8) as u8);
+    lba_low.write((lba >> 24) as u8);
+    lba_mid.write((lba >> 32) as u8);
+    lba_high.write((lba >> 40) as u8);
+
+    sector_count.write(count as u8);
+    lba_low.write(lba as u8);
+    lba_mid.write((lba >> 8) as u8);
+    lba_high.write((lba >> 16) as u8);
+}
+
+/// Lê um setor do canal IDE primário ou secundário.
+/// `channel_base` = 0x1F0 (primário) ou 0x170 (secundário)
+/// `master`: `true` para o drive master, `false` para o slave
+/// `lba`: setor lógico (28 bits; para mais, ver `read_sector_lba48`)
+/// `buffer`: exatamente um setor lógico (512 ou 4096 bytes, conforme o drive)
+pub fn read_sector(channel_base: u16, master: bool, lba: u32, buffer: &mut [u8]) -> Result<(), IDEError> {
+    if !is_sector_sized(buffer) || lba as u64 >= LBA28_LIMIT {
+        return Err(invalid_request());
+    }
+
+    unsafe {
+        setup_lba28(channel_base, master, lba, 1);
 
         // Envia comando READ SECTOR (0x20)
-        Port::<u8>::new(channel_base + 7).write(0x20);
+        Port::<u8>::new(channel_base + 7).write(CMD_READ_SECTORS);
         io_wait();
 
-        // Poll até DRQ=1 e BSY=0
-        let mut status;
-        loop {
-            status = Port::<u8>::new(channel_base + 7).read();
-            if status & 0x80 == 0 && status & 0x08 != 0 { break; }
-        }
-
-        // Lê 256 palavras de 16‐bits = 512 bytes
-        let mut data = Port::<u16>::new(channel_base);
-        let ptr = buffer.as_mut_ptr() as *mut u16;
-        for i in 0..256 {
-            let w = data.read();
-            core::ptr::write_volatile(ptr.add(i as usize), w);
-        }
+        // Poll até DRQ=1 e BSY=0, depois lê o setor inteiro
+        wait_irq(channel_base)?;
+        wait_data_ready(channel_base)?;
+        pio_read(channel_base, buffer);
     }
 
     Ok(())
 }
 
-/// Escreve um setor (512 bytes) no canal IDE.
+/// Escreve um setor no canal IDE.
 /// Mesma assinatura de `read_sector`, mas envia comando WRITE (0x30).
-pub fn write_sector(channel_base: u16, lba: u32, buffer: &[u8;512]) -> Result<(), ()> {
-    let ctrl_base = if channel_base == 0x1F0 { 0x3F6 } else { 0x376 };
+pub fn write_sector(channel_base: u16, master: bool, lba: u32, buffer: &[u8]) -> Result<(), IDEError> {
+    if !is_sector_sized(buffer) || lba as u64 >= LBA28_LIMIT {
+        return Err(invalid_request());
+    }
 
     unsafe {
-        Port::<u8>::new(channel_base + 6).write(0xE0 | ((lba >> 24) & 0x0F) as u8);
+        setup_lba28(channel_base, master, lba, 1);
+
+        Port::<u8>::new(channel_base + 7).write(CMD_WRITE_SECTORS);
         io_wait();
 
-        Port::<u8>::new(channel_base + 2).write(1);
-        Port::<u8>::new(channel_base + 3).write((lba & 0xFF) as u8);
-        Port::<u8>::new(channel_base + 4).write(((lba >> 8) & 0xFF) as u8);
-        Port::<u8>::new(channel_base + 5).write(((lba >> 16) & 0xFF) as u8);
+        // Poll até DRQ pronto
+        wait_data_ready(channel_base)?;
+        pio_write(channel_base, buffer);
+        wait_irq(channel_base)?;
+    }
 
-        Port::<u8>::new(channel_base + 7).write(0x30);
+    Ok(())
+}
+
+/// Como `read_sector`, mas com LBA de 48 bits (READ SECTORS EXT, 0x24).
+pub fn read_sector_lba48(channel_base: u16, master: bool, lba: u64, buffer: &mut [u8]) -> Result<(), IDEError> {
+    if !is_sector_sized(buffer) || lba >= 1 << 48 {
+        return Err(invalid_request());
+    }
+
+    unsafe {
+        setup_lba48(channel_base, master, lba, 1);
+        Port::<u8>::new(channel_base + 7).write(CMD_READ_SECTORS_EXT);
         io_wait();
 
-        // Poll até DRQ pronto
-        loop {
-            let s = Port::<u8>::new(channel_base + 7).read();
-            if s & 0x80 == 0 && s & 0x08 != 0 { break; }
-        }
+        wait_irq(channel_base)?;
+
+        wait_data_ready(channel_base)?;
+        pio_read(channel_base, buffer);
+    }
+
+    Ok(())
+}
+
+/// Como `write_sector`, mas com LBA de 48 bits (WRITE SECTORS EXT, 0x34).
+pub fn write_sector_lba48(channel_base: u16, master: bool, lba: u64, buffer: &[u8]) -> Result<(), IDEError> {
+    if !is_sector_sized(buffer) || lba >= 1 << 48 {
+        return Err(invalid_request());
+    }
+
+    unsafe {
+        setup_lba48(channel_base, master, lba, 1);
+        Port::<u8>::new(channel_base + 7).write(CMD_WRITE_SECTORS_EXT);
+        io_wait();
 
-        // Escreve 256 palavras de 16‐bits
-        let data = buffer.as_ptr() as *const u16;
-        let mut port_data = Port::<u16>::new(channel_base);
-        for i in 0..256 {
-            let w = core::ptr::read_volatile(data.add(i));
-            port_data.write(w);
+        wait_data_ready(channel_base)?;
+        pio_write(channel_base, buffer);
+        wait_irq(channel_base)?;
+    }
+
+    Ok(())
+}
+
+/// Lê o setor `lba`, usando o comando de 28 bits quando ele alcança e o de
+/// 48 bits acima disso.
+pub fn read_lba(channel_base: u16, master: bool, lba: u64, buffer: &mut [u8]) -> Result<(), IDEError> {
+    if lba < LBA28_LIMIT {
+        read_sector(channel_base, master, lba as u32, buffer)
+    } else {
+        read_sector_lba48(channel_base, master, lba, buffer)
+    }
+}
+
+/// Escreve o setor `lba`; ver `read_lba`.
+pub fn write_lba(channel_base: u16, master: bool, lba: u64, buffer: &[u8]) -> Result<(), IDEError> {
+    if lba < LBA28_LIMIT {
+        write_sector(channel_base, master, lba as u32, buffer)
+    } else {
+        write_sector_lba48(channel_base, master, lba, buffer)
+    }
+}
+
+/// Tamanho de cada setor quando `len` bytes são divididos em `count` setores,
+/// se isso der um setor lógico suportado.
+fn sector_size_for(len: usize, count: usize) -> Option<usize> {
+    if count == 0 || len % count != 0 {
+        return None;
+    }
+    let sector_size = len / count;
+    if (DEFAULT_SECTOR_SIZE..=MAX_SECTOR_SIZE).contains(&sector_size)
+        && sector_size % DEFAULT_SECTOR_SIZE == 0
+    {
+        Some(sector_size)
+    } else {
+        None
+    }
+}
+
+/// Programa a LBA e o sector count para `count` setores a partir de `lba` e
+/// envia o comando, escolhendo entre a forma de 28 e a de 48 bits.
+unsafe fn issue_transfer(channel_base: u16, master: bool, lba: u64, count: usize, write: bool) -> Result<(), IDEError> {
+    let end = lba.checked_add(count as u64).ok_or_else(invalid_request)?;
+    let command = if end <= LBA28_LIMIT && count <= MAX_SECTORS_LBA28 {
+        setup_lba28(channel_base, master, lba as u32, count as u8);
+        if write { CMD_WRITE_SECTORS } else { CMD_READ_SECTORS }
+    } else if end <= 1 << 48 && count <= MAX_SECTORS_LBA48 {
+        setup_lba48(channel_base, master, lba, count as u16);
+        if write { CMD_WRITE_SECTORS_EXT } else { CMD_READ_SECTORS_EXT }
+    } else {
+        return Err(invalid_request());
+    };
+
+    Port::<u8>::new(channel_base + 7).write(command);
+    io_wait();
+    Ok(())
+}
+
+/// Lê `count` setores consecutivos a partir de `lba` com um único comando.
+/// `buffer` deve ter exatamente `count` setores lógicos.
+///
+/// O drive levanta DRQ uma vez por setor, então a transferência PIO é feita
+/// setor a setor, mas sem o custo de um comando novo para cada um.
+pub fn read_sectors(channel_base: u16, master: bool, lba: u64, count: usize, buffer: &mut [u8]) -> Result<(), IDEError> {
+    let sector_size = sector_size_for(buffer.len(), count).ok_or_else(invalid_request)?;
+
+    unsafe {
+        issue_transfer(channel_base, master, lba, count, false)?;
+        for sector in buffer.chunks_exact_mut(sector_size) {
+            wait_irq(channel_base)?;
+            wait_data_ready(channel_base)?;
+            pio_read(channel_base, sector);
         }
     }
 
     Ok(())
 }
 
-/// Tamanho fixo de cada setor em bytes
-const SECTOR_SIZE: usize = 512;
+/// Escreve `count` setores consecutivos a partir de `lba` com um único
+/// comando; ver `read_sectors`.
+pub fn write_sectors(channel_base: u16, master: bool, lba: u64, count: usize, buffer: &[u8]) -> Result<(), IDEError> {
+    let sector_size = sector_size_for(buffer.len(), count).ok_or_else(invalid_request)?;
+
+    unsafe {
+        issue_transfer(channel_base, master, lba, count, true)?;
+        for sector in buffer.chunks_exact(sector_size) {
+            wait_data_ready(channel_base)?;
+            pio_write(channel_base, sector);
+            wait_irq(channel_base)?;
+        }
+    }
+
+    Ok(())
+}
 
 /// Um "device" que o simple-fatfs pode usar.
 /// Internamente faz read/write de setores via PIO IDE.
 pub struct IdeBlockDevice {
+    /// Porta base do canal IDE (0x1F0 ou 0x170)
+    channel_base: u16,
+    /// Drive master (`true`) ou slave (`false`) no canal
+    master: bool,
     /// LBA de início da partição (boot sector)
     lba_start: u64,
     /// Posição atual de cursor, em bytes
     pos: u64,
+    /// Tamanho do setor lógico do drive, em bytes
+    sector_size: usize,
 }
 
 impl IdeBlockDevice {
-    /// Cria um novo bloco iniciando na LBA `lba_start`.
-    pub fn new(lba_start: u64) -> Self {
-        Self { lba_start, pos: 0 }
+    /// Cria um novo bloco no drive `master`/slave do canal `channel_base`,
+    /// iniciando na LBA `lba_start`, com setores de 512 bytes.
+    pub fn new(channel_base: u16, master: bool, lba_start: u64) -> Self {
+        Self::with_sector_size(channel_base, master, lba_start, DEFAULT_SECTOR_SIZE)
+    }
+
+    /// Como `new`, para um drive com setores lógicos de `sector_size` bytes
+    /// (ver `IdeDevice::sector_size`).
+    pub fn with_sector_size(channel_base: u16, master: bool, lba_start: u64, sector_size: usize) -> Self {
+        let sector_size = if (DEFAULT_SECTOR_SIZE..=MAX_SECTOR_SIZE).contains(&sector_size) {
+            sector_size
+        } else {
+            DEFAULT_SECTOR_SIZE
+        };
+        Self { channel_base, master, lba_start, pos: 0, sector_size }
+    }
+
+    pub fn channel_base(&self) -> u16 {
+        self.channel_base
+    }
+
+    pub fn is_master(&self) -> bool {
+        self.master
+    }
+
+    pub fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    /// Lê o setor `sector_idx` da partição (relativo a `lba_start`).
+    pub fn read_block(&self, sector_idx: u64, buffer: &mut [u8]) -> Result<(), IDEError> {
+        read_lba(self.channel_base, self.master, self.lba_start + sector_idx, buffer)
+    }
+
+    /// Lê `buffer.len() / sector_size()` setores a partir de `sector_idx`
+    /// com um único comando (ver `read_sectors`).
+    pub fn read_blocks(&self, sector_idx: u64, buffer: &mut [u8]) -> Result<(), IDEError> {
+        let count = buffer.len() / self.sector_size;
+        read_sectors(self.channel_base, self.master, self.lba_start + sector_idx, count, buffer)
+    }
+
+    /// Escreve o setor `sector_idx` da partição (relativo a `lba_start`).
+    pub fn write_block(&self, sector_idx: u64, buffer: &[u8]) -> Result<(), IDEError> {
+        write_lba(self.channel_base, self.master, self.lba_start + sector_idx, buffer)
     }
 }
 
@@ -190,7 +595,7 @@ pub struct IDEError {
     message: Option<String>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum IDEErrorKind {
     General,
     NotFound,
@@ -260,7 +665,7 @@ impl IOError for IDEError {
     }
 
     fn kind(&self) -> Self::Kind {
-        todo!()
+        self.kind.clone()
     }
 }
 
@@ -279,11 +684,15 @@ pub struct PartitionEntry {
     pub num_sectors: u32,
 }
 
-/// Lê o setor 0 (MBR) e retorna as 4 entradas de partição
-pub fn read_partition_table() -> [PartitionEntry; 4] {
-    let mut mbr = [0u8; 512];
-    // canal primário master, LBA 0
-    crate::ide::read_sector(0x1F0, 0, &mut mbr).unwrap();
+/// Lê o setor 0 (MBR) do drive `master`/slave do canal `channel_base` e
+/// retorna as 4 entradas de partição.
+///
+/// `sector_size` é o tamanho do setor lógico do drive; o MBR ocupa sempre os
+/// primeiros 512 bytes do setor 0, e as LBAs das entradas são em setores lógicos.
+pub fn read_partition_table(channel_base: u16, master: bool, sector_size: usize) -> Result<[PartitionEntry; 4], IDEError> {
+    let mut sector = [0u8; MAX_SECTOR_SIZE];
+    crate::ide::read_sector(channel_base, master, 0, &mut sector[..sector_size])?;
+    let mbr = &sector[..512];
 
     let mut parts = [PartitionEntry {
         boot_flag:   0,
@@ -305,20 +714,209 @@ pub fn read_partition_table() -> [PartitionEntry; 4] {
             num_sectors: u32::from_le_bytes([mbr[off+12], mbr[off+13], mbr[off+14], mbr[off+15]]),
         };
     }
-    parts
+    Ok(parts)
+}
+
+/// Tipo de partição do MBR protetor que indica um disco GPT
+pub const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+/// Maior número de entradas GPT aceitas (o padrão é 128)
+const GPT_MAX_ENTRIES: u32 = 1024;
+
+/// GUID como gravado no disco (os três primeiros campos em little-endian).
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub fn is_zero(&self) -> bool {
+        self.0.iter().all(|&b| b == 0)
+    }
+}
+
+impl core::fmt::Display for Guid {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-",
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            u16::from_le_bytes([b[4], b[5]]),
+            u16::from_le_bytes([b[6], b[7]]),
+            b[8],
+            b[9]
+        )?;
+        for byte in &b[10..] {
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl core::fmt::Debug for Guid {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Display::fmt(self, f)
+    }
+}
+
+/// Uma entrada em uso da tabela de partições GPT
+#[derive(Debug, Clone, Copy)]
+pub struct GptEntry {
+    pub type_guid:   Guid,
+    pub unique_guid: Guid,
+    pub first_lba:   u64,
+    /// Última LBA da partição (inclusiva)
+    pub last_lba:    u64,
+    pub attributes:  u64,
+}
+
+/// Tabela de partições de um drive, no formato em que foi encontrada
+#[derive(Debug)]
+pub enum PartitionTable {
+    Mbr([PartitionEntry; 4]),
+    Gpt(Vec<GptEntry>),
+}
+
+fn le_u32(bytes: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(bytes[off..off + 4].try_into().unwrap())
+}
+
+fn le_u64(bytes: &[u8], off: usize) -> u64 {
+    u64::from_le_bytes(bytes[off..off + 8].try_into().unwrap())
+}
+
+/// Avança um CRC32 (IEEE, o da GPT) sobre `bytes`. O estado começa em
+/// `!0` e o CRC final é o estado invertido.
+fn crc32_update(mut crc: u32, bytes: &[u8]) -> u32 {
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+/// Campos do cabeçalho GPT usados para achar o array de entradas
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct GptHeader {
+    entries_lba: u64,
+    num_entries: u32,
+    entry_size:  usize,
+    entries_crc: u32,
+}
+
+/// Valida e decodifica o cabeçalho GPT em `sector` (um setor lógico
+/// inteiro): assinatura, tamanhos e o CRC32 do próprio cabeçalho.
+fn parse_gpt_header(sector: &[u8]) -> Result<GptHeader, IDEError> {
+    if sector.len() < 92 || &sector[..8] != GPT_SIGNATURE {
+        return Err(IDEError::new(IDEErrorKind::InvalidData, Some("Missing GPT signature".to_string())));
+    }
+
+    let header_size = le_u32(sector, 12) as usize;
+    let header = GptHeader {
+        entries_lba: le_u64(sector, 72),
+        num_entries: le_u32(sector, 80),
+        entry_size:  le_u32(sector, 84) as usize,
+        entries_crc: le_u32(sector, 88),
+    };
+    // O tamanho da entrada é 128 * 2^n, e cada setor tem um número inteiro delas
+    if header_size < 92
+        || header_size > sector.len()
+        || header.num_entries > GPT_MAX_ENTRIES
+        || header.entry_size < 128
+        || !header.entry_size.is_power_of_two()
+        || header.entry_size > sector.len()
+    {
+        return Err(IDEError::new(IDEErrorKind::InvalidData, Some("Malformed GPT header".to_string())));
+    }
+
+    // O CRC é calculado com o próprio campo (offset 16) zerado
+    let crc = crc32_update(!0, &sector[..16]);
+    let crc = crc32_update(crc, &[0; 4]);
+    let crc = !crc32_update(crc, &sector[20..header_size]);
+    if crc != le_u32(sector, 16) {
+        return Err(IDEError::new(IDEErrorKind::InvalidData, Some("GPT header CRC mismatch".to_string())));
+    }
+    Ok(header)
+}
+
+/// Decodifica uma entrada do array da GPT; `None` se ela não está em uso
+/// (tipo zerado).
+fn parse_gpt_entry(raw: &[u8]) -> Option<GptEntry> {
+    let entry = GptEntry {
+        type_guid:   Guid(raw[0..16].try_into().unwrap()),
+        unique_guid: Guid(raw[16..32].try_into().unwrap()),
+        first_lba:   le_u64(raw, 32),
+        last_lba:    le_u64(raw, 40),
+        attributes:  le_u64(raw, 48),
+    };
+    (!entry.type_guid.is_zero()).then_some(entry)
+}
+
+/// Lê o cabeçalho GPT na LBA 1 e o array de entradas para onde ele aponta.
+///
+/// O cabeçalho e o array são conferidos pelos seus CRC32. Entradas com tipo
+/// zerado (não usadas) são omitidas.
+pub fn read_gpt(channel_base: u16, master: bool, sector_size: usize) -> Result<Vec<GptEntry>, IDEError> {
+    let mut sector = [0u8; MAX_SECTOR_SIZE];
+    let sector = &mut sector[..sector_size];
+
+    read_lba(channel_base, master, 1, sector)?;
+    let header = parse_gpt_header(sector)?;
+
+    let entries_per_sector = sector_size / header.entry_size;
+    let mut entries = Vec::new();
+    let mut crc = !0;
+    let mut lba = header.entries_lba;
+    let mut remaining = header.num_entries as usize;
+    while remaining > 0 {
+        read_lba(channel_base, master, lba, sector)?;
+        let count = remaining.min(entries_per_sector);
+        crc = crc32_update(crc, &sector[..count * header.entry_size]);
+        entries.extend(sector.chunks_exact(header.entry_size).take(count).filter_map(parse_gpt_entry));
+        remaining -= count;
+        lba += 1;
+    }
+    if !crc != header.entries_crc {
+        return Err(IDEError::new(IDEErrorKind::InvalidData, Some("GPT entry array CRC mismatch".to_string())));
+    }
+
+    Ok(entries)
+}
+
+/// Lê a tabela de partições do drive, usando a GPT quando o MBR é só o
+/// MBR protetor (uma entrada do tipo 0xEE).
+pub fn read_partitions(channel_base: u16, master: bool, sector_size: usize) -> Result<PartitionTable, IDEError> {
+    let mbr = read_partition_table(channel_base, master, sector_size)?;
+    if mbr.iter().any(|part| part.part_type == MBR_TYPE_GPT_PROTECTIVE) {
+        return read_gpt(channel_base, master, sector_size).map(PartitionTable::Gpt);
+    }
+    Ok(PartitionTable::Mbr(mbr))
 }
 
 
 impl Read for IdeBlockDevice {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, IDEError> {
         // Calcule qual setor e offset interno
-        let sector_idx = (self.pos / SECTOR_SIZE as u64) as u32;
-        let offset = (self.pos % SECTOR_SIZE as u64) as usize;
-        let mut sector = [0u8; SECTOR_SIZE];
-        read_sector(0x1F0, self.lba_start as u32 + sector_idx, &mut sector)
-            .map_err(|_| IDEError::new(IDEErrorKind::General, Some("Something Wrong".to_string())))?;
+        let sector_size = self.sector_size;
+        let sector_idx = self.pos / sector_size as u64;
+        let offset = (self.pos % sector_size as u64) as usize;
+
+        // Pedido alinhado cobrindo vários setores: lê todos direto no buffer
+        let whole = core::cmp::min(buf.len() / sector_size, MAX_SECTORS_LBA28);
+        if offset == 0 && whole > 1 {
+            let len = whole * sector_size;
+            read_sectors(self.channel_base, self.master, self.lba_start + sector_idx, whole, &mut buf[..len])?;
+            self.pos += len as u64;
+            return Ok(len);
+        }
+
+        let mut sector = [0u8; MAX_SECTOR_SIZE];
+        let sector = &mut sector[..sector_size];
+        read_lba(self.channel_base, self.master, self.lba_start + sector_idx, sector)?;
         // Copia a parte relevante
-        let to_copy = core::cmp::min(buf.len(), SECTOR_SIZE - offset);
+        let to_copy = core::cmp::min(buf.len(), sector_size - offset);
         buf[..to_copy].copy_from_slice(&sector[offset..offset + to_copy]);
         self.pos += to_copy as u64;
         Ok(to_copy)
@@ -327,16 +925,26 @@ impl Read for IdeBlockDevice {
 
 impl Write for IdeBlockDevice {
     fn write(&mut self, buf: &[u8]) -> Result<usize, IDEError> {
-        let sector_idx = (self.pos / SECTOR_SIZE as u64) as u32;
-        let offset = (self.pos % SECTOR_SIZE as u64) as usize;
-        let mut sector = [0u8; SECTOR_SIZE];
+        let sector_size = self.sector_size;
+        let sector_idx = self.pos / sector_size as u64;
+        let offset = (self.pos % sector_size as u64) as usize;
+
+        // Setores inteiros não precisam ser lidos antes
+        let whole = core::cmp::min(buf.len() / sector_size, MAX_SECTORS_LBA28);
+        if offset == 0 && whole > 1 {
+            let len = whole * sector_size;
+            write_sectors(self.channel_base, self.master, self.lba_start + sector_idx, whole, &buf[..len])?;
+            self.pos += len as u64;
+            return Ok(len);
+        }
+
+        let mut sector = [0u8; MAX_SECTOR_SIZE];
+        let sector = &mut sector[..sector_size];
         // Primeiro lê o setor inteiro se for um write parcial
-        read_sector(0x1F0, self.lba_start as u32 + sector_idx, &mut sector)
-            .map_err(|_| IDEError::new(IDEErrorKind::General, Some("Something Wrong".to_string())))?;
-        let to_copy = core::cmp::min(buf.len(), SECTOR_SIZE - offset);
+        read_lba(self.channel_base, self.master, self.lba_start + sector_idx, sector)?;
+        let to_copy = core::cmp::min(buf.len(), sector_size - offset);
         sector[offset..offset + to_copy].copy_from_slice(&buf[..to_copy]);
-        write_sector(0x1F0, self.lba_start as u32 + sector_idx, &sector)
-            .map_err(|_| IDEError::new(IDEErrorKind::General, Some("Something Wrong".to_string())))?;
+        write_lba(self.channel_base, self.master, self.lba_start + sector_idx, sector)?;
         self.pos += to_copy as u64;
         Ok(to_copy)
     }
@@ -361,16 +969,47 @@ impl Seek for IdeBlockDevice {
     }
 }
 
+/// Tipos de partição MBR usados por FAT12/16/32
+const MBR_FAT_TYPES: [u8; 6] = [0x01, 0x04, 0x06, 0x0B, 0x0C, 0x0E];
+/// "Microsoft basic data" (EBD0A0A2-B9E5-4433-87C0-68B6B72699C7), como gravado no disco
+const GPT_BASIC_DATA: Guid = Guid([
+    0xA2, 0xA0, 0xD0, 0xEB, 0xE5, 0xB9, 0x33, 0x44, 0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7,
+]);
+/// EFI System Partition (C12A7328-F81F-11D2-BA4B-00A0C93EC93B), sempre FAT
+const GPT_EFI_SYSTEM: Guid = Guid([
+    0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B,
+]);
+
+/// LBA da primeira partição do drive cujo tipo indica FAT, se houver.
+pub fn find_fat_partition(device: &IdeDevice) -> Option<u64> {
+    match read_partitions(device.channel_base, device.master, device.sector_size).ok()? {
+        PartitionTable::Mbr(parts) => parts.iter()
+            .find(|part| MBR_FAT_TYPES.contains(&part.part_type) && part.num_sectors != 0)
+            .map(|part| part.lba_start as u64),
+        PartitionTable::Gpt(entries) => entries.iter()
+            .find(|entry| entry.type_guid == GPT_BASIC_DATA || entry.type_guid == GPT_EFI_SYSTEM)
+            .map(|entry| entry.first_lba),
+    }
+}
+
 /// Monta o sistema de arquivos FAT e demonstra leitura do diretório raiz.
-pub fn mount_and_list(lba_start: u64) {
+/// `device` é o drive detectado (canal, master/slave e tamanho de setor);
+/// `lba_start` é a LBA da partição.
+pub fn mount_and_list(device: &IdeDevice, lba_start: u64) -> Result<(), FSError<IDEError>> {
     // Cria o dispositivo de bloco iniciando na partição LBA
-    let mut dev = IdeBlockDevice::new(lba_start);
+    // e um cache de setores na frente dele
+    let mut dev = CachedBlockDevice::new(IdeBlockDevice::with_sector_size(
+        device.channel_base,
+        device.master,
+        lba_start,
+        device.sector_size,
+    ));
 
     // Monta o filesystem FAT (detecta FAT12/16/32) :contentReference[oaicite:1]{index=1}
-    let mut fs = FileSystem::from_storage(&mut dev).unwrap();
+    let mut fs = FileSystem::from_storage(&mut dev)?;
 
     // Lê e imprime cada entry no diretório raiz
-    let entries = fs.read_dir(PathBuf::from("/")).unwrap();
+    let entries = fs.read_dir(PathBuf::from("/"))?;
     for entry in entries {
         if entry.path().is_dir() {
             kprintln!("Dir: {:?}", entry.path());
@@ -381,4 +1020,50 @@ pub fn mount_and_list(lba_start: u64) {
             );
         }
     }
-}
\ No newline at end of file
+    Ok(())
+}
+/// Testes sem hardware: o mapeamento de `IDEError::kind` e a leitura de um
+/// cabeçalho e de uma entrada GPT montados em memória, inclusive com o CRC
+/// do cabeçalho errado.
+pub fn ide_self_test() -> bool {
+    let kinds = timeout_error().kind() == &IDEErrorKind::Interrupted
+        && invalid_request().kind() == &IDEErrorKind::InvalidData
+        && IOError::kind(&<IDEError as IOError>::new(IDEErrorKind::NotFound, "x")) == IDEErrorKind::NotFound
+        && IDEErrorKind::new_unexpected_eof() == IDEErrorKind::UnexpectedEOF
+        && IDEErrorKind::new_interrupted() == IDEErrorKind::Interrupted
+        && IDEErrorKind::new_invalid_data() == IDEErrorKind::InvalidData;
+
+    // Uma entrada "basic data" nas LBAs 2048..=4095, e uma sem uso
+    let mut entry = [0u8; 128];
+    entry[..16].copy_from_slice(&GPT_BASIC_DATA.0);
+    entry[16] = 0x5A;
+    entry[32..40].copy_from_slice(&2048u64.to_le_bytes());
+    entry[40..48].copy_from_slice(&4095u64.to_le_bytes());
+    let parsed_entry = parse_gpt_entry(&entry).is_some_and(|parsed| {
+        parsed.type_guid == GPT_BASIC_DATA && parsed.first_lba == 2048 && parsed.last_lba == 4095
+    }) && parse_gpt_entry(&[0u8; 128]).is_none();
+
+    // Cabeçalho de 92 bytes apontando para 4 entradas de 128 bytes na LBA 2
+    let mut sector = [0u8; 512];
+    sector[..8].copy_from_slice(GPT_SIGNATURE);
+    sector[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+    sector[12..16].copy_from_slice(&92u32.to_le_bytes());
+    sector[72..80].copy_from_slice(&2u64.to_le_bytes());
+    sector[80..84].copy_from_slice(&4u32.to_le_bytes());
+    sector[84..88].copy_from_slice(&128u32.to_le_bytes());
+    sector[88..92].copy_from_slice(&0x1234_5678u32.to_le_bytes());
+    let crc = !crc32_update(!0, &sector[..92]);
+    sector[16..20].copy_from_slice(&crc.to_le_bytes());
+
+    let expected = GptHeader { entries_lba: 2, num_entries: 4, entry_size: 128, entries_crc: 0x1234_5678 };
+    let parsed_header = matches!(parse_gpt_header(&sector), Ok(header) if header == expected);
+
+    // Muda um campo sem refazer o CRC
+    sector[80] = 5;
+    let crc_mismatch = matches!(parse_gpt_header(&sector), Err(err) if err.kind() == &IDEErrorKind::InvalidData);
+
+    // CRC32 de "123456789" é o valor de referência 0xCBF43926
+    let crc_reference = !crc32_update(!0, b"123456789") == 0xCBF4_3926;
+
+    kinds && parsed_entry && parsed_header && crc_mismatch && crc_reference
+}
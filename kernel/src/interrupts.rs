@@ -1,21 +1,321 @@
 use core::arch::{asm, naked_asm};
 use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use alloc::boxed::Box;
-use x2apic::lapic::{xapic_base, LocalApic, LocalApicBuilder};
+use alloc::vec::Vec;
+use x2apic::lapic::{xapic_base, IpiAllShorthand, LocalApic, LocalApicBuilder, TimerDivide, TimerMode};
 use x2apic::ioapic::{IoApic, IrqFlags, IrqMode, RedirectionTableEntry};
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 use acpi::{AcpiTables, AcpiHandler, PhysicalMapping};
+use acpi::platform::interrupt::{InterruptSourceOverride, Polarity, TriggerMode};
 use x86_64::structures::idt::PageFaultErrorCode;
 use x86_64::instructions::port::Port;
 use lazy_static::lazy_static;
 use x86_64::structures::paging::{FrameAllocator, Mapper, Size4KiB};
-use x86_64::{PhysAddr, VirtAddr};
-use crate::{gdt, process};
+use x86_64::instructions::tlb;
+use x86_64::{PhysAddr, PrivilegeLevel, VirtAddr};
+use spin::Mutex;
+use log::{debug, error, info, warn};
+use crate::{gdt, memory, process};
 use crate::process::Context;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
+/// IOAPIC lines are delivered on vectors `IRQ_VECTOR_BASE + irq`.
+pub const IRQ_VECTOR_BASE: u8 = 48;
+/// Number of IOAPIC lines handlers can be registered for.
+pub const IRQ_COUNT: usize = 24;
+
+pub const KEYBOARD_IRQ: u8 = 1;
+pub const COM1_IRQ: u8 = 4;
+/// Shared by COM2 and COM4, as COM1_IRQ is by COM1 and COM3
+pub const COM2_IRQ: u8 = 3;
+pub const MOUSE_IRQ: u8 = 12;
+pub const IDE_PRIMARY_IRQ: u8 = 14;
+pub const IDE_SECONDARY_IRQ: u8 = 15;
+
+/// Vector of the TLB shootdown IPI (see `flush_tlb_all_cores`).
+pub const TLB_SHOOTDOWN_VECTOR: u8 = 0xF0;
+/// Vector of the software interrupt behind `process::yield_now`.
+pub const YIELD_VECTOR: u8 = 0xF1;
+
+/// Handlers registered with `register_irq`, indexed by IRQ line.
+static IRQ_HANDLERS: Mutex<[Option<fn()>; IRQ_COUNT]> = Mutex::new([None; IRQ_COUNT]);
+
+/// Where an IRQ line is wired: its global system interrupt (GSI) and the
+/// polarity/trigger mode the IOAPIC must use for it.
+#[derive(Debug, Clone, Copy)]
+struct IrqRoute {
+    gsi: u32,
+    flags: IrqFlags,
+}
+
+/// Routes indexed by IRQ line. Defaults to the ISA conventions: identity
+/// GSI, edge-triggered, active high; the MADT's interrupt source overrides
+/// replace them in `apply_overrides`.
+static IRQ_ROUTES: Mutex<[IrqRoute; IRQ_COUNT]> = Mutex::new({
+    let mut routes = [IrqRoute { gsi: 0, flags: IrqFlags::empty() }; IRQ_COUNT];
+    let mut irq = 0;
+    while irq < IRQ_COUNT {
+        routes[irq].gsi = irq as u32;
+        irq += 1;
+    }
+    routes
+});
+
+/// Applies the MADT's interrupt source overrides to `IRQ_ROUTES`.
+fn apply_overrides(overrides: &[InterruptSourceOverride]) {
+    let mut routes = IRQ_ROUTES.lock();
+    for source_override in overrides {
+        let Some(route) = routes.get_mut(source_override.isa_source as usize) else {
+            continue;
+        };
+
+        // "Same as bus" means the ISA defaults: edge-triggered, active high
+        let mut flags = IrqFlags::empty();
+        if let Polarity::ActiveLow = source_override.polarity {
+            flags |= IrqFlags::LOW_ACTIVE;
+        }
+        if let TriggerMode::Level = source_override.trigger_mode {
+            flags |= IrqFlags::LEVEL_TRIGGERED;
+        }
+
+        *route = IrqRoute { gsi: source_override.global_system_interrupt, flags };
+        info!(
+            "IRQ {} -> GSI {} ({:?})",
+            source_override.isa_source,
+            source_override.global_system_interrupt,
+            flags
+        );
+    }
+}
+
+/// Makes `handler` run whenever IRQ line `irq` fires, replacing any handler
+/// registered before, and unmasks the line if the IOAPIC is up already.
+///
+/// Handlers run in interrupt context with interrupts disabled; the EOI is
+/// sent for them after they return.
+pub fn register_irq(irq: u8, handler: fn()) {
+    assert!((irq as usize) < IRQ_COUNT, "IRQ {} out of range", irq);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        IRQ_HANDLERS.lock()[irq as usize] = Some(handler);
+        unsafe { route_irq(irq) };
+    });
+}
+
+/// Entry point shared by every IRQ vector; `IRQ` tells which line fired.
+extern "x86-interrupt" fn irq_stub<const IRQ: u8>(_stack_frame: InterruptStackFrame) {
+    dispatch_irq(IRQ);
+}
+
+/// Stops IRQ line `irq` from being delivered, leaving its handler in place.
+pub fn mask_irq(irq: u8) {
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        with_irq_input(irq, |ioapic, input| ioapic.disable_irq(input));
+    });
+}
+
+/// Lets IRQ line `irq` be delivered again.
+pub fn unmask_irq(irq: u8) {
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        with_irq_input(irq, |ioapic, input| ioapic.enable_irq(input));
+    });
+}
+
+/// Whether IRQ line `irq` is masked in its redirection entry, or `None` if
+/// no IOAPIC handles it.
+pub fn is_irq_masked(irq: u8) -> Option<bool> {
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        with_irq_input(irq, |ioapic, input| {
+            ioapic.table_entry(input).flags().contains(IrqFlags::MASKED)
+        })
+    })
+}
+
+/// Removes the handler of IRQ line `irq` and masks the line.
+pub fn unregister_irq(irq: u8) {
+    mask_irq(irq);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        IRQ_HANDLERS.lock()[irq as usize] = None;
+    });
+}
+
+/// Calls the handler registered for `irq`, if any, and acknowledges it.
+///
+/// A level-triggered line stays asserted until its device is serviced, so
+/// it's masked while the handler runs and unmasked after the EOI; otherwise
+/// it could fire again before the handler had a chance to clear it.
+fn dispatch_irq(irq: u8) {
+    count_interrupt(IRQ_VECTOR_BASE + irq);
+
+    let level_triggered = IRQ_ROUTES.lock()[irq as usize].flags.contains(IrqFlags::LEVEL_TRIGGERED);
+    if level_triggered {
+        mask_irq(irq);
+    }
+
+    // Copy the handler out so it runs without the table locked
+    let handler = IRQ_HANDLERS.lock()[irq as usize];
+    if let Some(handler) = handler {
+        handler();
+    }
+
+    end_of_interrupt();
+    if level_triggered && handler.is_some() {
+        unmask_irq(irq);
+    }
+}
+
+const IRQ_STUBS: [extern "x86-interrupt" fn(InterruptStackFrame); IRQ_COUNT] = [
+    irq_stub::<0>, irq_stub::<1>, irq_stub::<2>, irq_stub::<3>,
+    irq_stub::<4>, irq_stub::<5>, irq_stub::<6>, irq_stub::<7>,
+    irq_stub::<8>, irq_stub::<9>, irq_stub::<10>, irq_stub::<11>,
+    irq_stub::<12>, irq_stub::<13>, irq_stub::<14>, irq_stub::<15>,
+    irq_stub::<16>, irq_stub::<17>, irq_stub::<18>, irq_stub::<19>,
+    irq_stub::<20>, irq_stub::<21>, irq_stub::<22>, irq_stub::<23>,
+];
+
+/// Line the IRQ self-test borrows: LPT1's, which nothing here drives.
+const SELF_TEST_IRQ: u8 = 7;
+
+static SELF_TEST_IRQ_HITS: AtomicU64 = AtomicU64::new(0);
+/// Stack pointer seen by the last run of `self_test_irq`.
+static SELF_TEST_IRQ_RSP: AtomicU64 = AtomicU64::new(0);
+
+fn self_test_irq() {
+    let rsp: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) rsp) };
+    SELF_TEST_IRQ_RSP.store(rsp, Ordering::Relaxed);
+    SELF_TEST_IRQ_HITS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Checks the IRQ layer: GSI lookup across two made-up IOAPICs, an
+/// override remapping IRQ 0 to GSI 2, and on a spare line that registering
+/// programs its vector and flags, that mask/unmask read back, and that
+/// raising its vector runs the handler once on the IRQ stack.
+pub fn irq_self_test() -> bool {
+    use x86_64::instructions::interrupts::without_interrupts;
+
+    // Dois IOAPICs: o segundo atende as GSIs 24..48
+    let lookup = ioapic_input(0, 24, 5) == Some(5)
+        && ioapic_input(0, 24, 30).is_none()
+        && ioapic_input(24, 24, 30) == Some(6)
+        && ioapic_input(24, 24, 48).is_none();
+
+    let overridden = without_interrupts(|| {
+        let saved = *IRQ_ROUTES.lock();
+        apply_overrides(&[InterruptSourceOverride {
+            isa_source: 0,
+            global_system_interrupt: 2,
+            polarity: Polarity::ActiveLow,
+            trigger_mode: TriggerMode::Level,
+        }]);
+        let route = IRQ_ROUTES.lock()[0];
+        *IRQ_ROUTES.lock() = saved;
+        route.gsi == 2 && route.flags == IrqFlags::LOW_ACTIVE | IrqFlags::LEVEL_TRIGGERED
+    });
+    if !lookup || !overridden {
+        return false;
+    }
+
+    register_irq(SELF_TEST_IRQ, self_test_irq);
+    let route = IRQ_ROUTES.lock()[SELF_TEST_IRQ as usize];
+    let routed = without_interrupts(|| unsafe {
+        with_irq_input(SELF_TEST_IRQ, |ioapic, input| {
+            let entry = ioapic.table_entry(input);
+            entry.vector() == IRQ_VECTOR_BASE + SELF_TEST_IRQ
+                && entry.dest() == IOAPIC_DEST
+                && entry.flags() & (IrqFlags::LOW_ACTIVE | IrqFlags::LEVEL_TRIGGERED) == route.flags
+        })
+    }) == Some(true);
+
+    let unmasked = is_irq_masked(SELF_TEST_IRQ) == Some(false);
+    mask_irq(SELF_TEST_IRQ);
+    let masked = is_irq_masked(SELF_TEST_IRQ) == Some(true);
+    unmask_irq(SELF_TEST_IRQ);
+    let toggled = unmasked && masked && is_irq_masked(SELF_TEST_IRQ) == Some(false);
+
+    let hits = SELF_TEST_IRQ_HITS.load(Ordering::Relaxed);
+    unsafe { asm!("int {vector}", vector = const IRQ_VECTOR_BASE + SELF_TEST_IRQ) };
+    let dispatched = SELF_TEST_IRQ_HITS.load(Ordering::Relaxed) == hits + 1
+        && gdt::ist_range(gdt::IRQ_INTERRUPT_INDEX).contains(&SELF_TEST_IRQ_RSP.load(Ordering::Relaxed));
+
+    unregister_irq(SELF_TEST_IRQ);
+    routed && toggled && dispatched && is_irq_masked(SELF_TEST_IRQ) == Some(true)
+}
+
+// Vetores fixos das exceções da CPU
+const DIVIDE_ERROR_VECTOR: u8 = 0;
+const DEBUG_VECTOR: u8 = 1;
+const NMI_VECTOR: u8 = 2;
+const BREAKPOINT_VECTOR: u8 = 3;
+const OVERFLOW_VECTOR: u8 = 4;
+const BOUND_RANGE_VECTOR: u8 = 5;
+const INVALID_OPCODE_VECTOR: u8 = 6;
+const DEVICE_NOT_AVAILABLE_VECTOR: u8 = 7;
+const DOUBLE_FAULT_VECTOR: u8 = 8;
+const STACK_SEGMENT_FAULT_VECTOR: u8 = 12;
+const GENERAL_PROTECTION_FAULT_VECTOR: u8 = 13;
+const PAGE_FAULT_VECTOR: u8 = 14;
+const ALIGNMENT_CHECK_VECTOR: u8 = 17;
+const MACHINE_CHECK_VECTOR: u8 = 18;
+const SIMD_FLOATING_POINT_VECTOR: u8 = 19;
+
+/// How many times each IDT vector has fired since boot.
+static INTERRUPT_COUNTS: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+
+#[inline(always)]
+fn count_interrupt(vector: u8) {
+    INTERRUPT_COUNTS[vector as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns how many times each vector has fired, indexed by vector number.
+pub fn interrupt_stats() -> [u64; 256] {
+    let mut counts = [0; 256];
+    for (count, counter) in counts.iter_mut().zip(INTERRUPT_COUNTS.iter()) {
+        *count = counter.load(Ordering::Relaxed);
+    }
+    counts
+}
+
+/// Same as `interrupt_stats`.
+pub fn stats() -> [u64; 256] {
+    interrupt_stats()
+}
+
+/// Executes `int3` a few times and checks that the breakpoint counter rose
+/// by exactly that many.
+pub fn breakpoint_self_test() -> bool {
+    const HITS: u64 = 3;
+    let before = interrupt_stats()[BREAKPOINT_VECTOR as usize];
+    for _ in 0..HITS {
+        x86_64::instructions::interrupts::int3();
+    }
+    interrupt_stats()[BREAKPOINT_VECTOR as usize] - before == HITS
+}
+
+/// Raises `#OF` and checks that the overflow handler logged it and returned
+/// here. `into` is invalid in 64-bit mode (it raises `#UD`), so the vector
+/// is raised with `int 4` instead.
+pub fn overflow_self_test() -> bool {
+    let before = interrupt_stats()[OVERFLOW_VECTOR as usize];
+    unsafe { asm!("int {vector}", vector = const OVERFLOW_VECTOR) };
+    interrupt_stats()[OVERFLOW_VECTOR as usize] - before == 1
+}
+
+/// Prints every vector that has fired at least once to the serial port.
+///
+/// Useful for spotting interrupt storms, e.g. a level-triggered line that
+/// keeps re-firing. Ctrl+T on the keyboard or serial console calls this.
+pub fn dump_stats() {
+    serial_println!("Interrupt statistics (vector: count):");
+    for (vector, count) in interrupt_stats().iter().enumerate() {
+        if *count != 0 {
+            serial_println!("  {:3}: {}", vector, count);
+        }
+    }
+}
+
 
 
 pub fn disable_pic() {
@@ -101,44 +401,178 @@ impl AcpiHandler for AcpiHandlerImpl {
 static mut LAPIC: Option<NonNull<LocalApic>> = None;
 static mut LAPIC_ID: u32 = 0;
 
-static mut IOAPIC: Option<NonNull<IoApic>> = None;
+/// An IOAPIC and the range of GSIs wired to its inputs.
+struct IoApicController {
+    ioapic: IoApic,
+    gsi_base: u32,
+    inputs: u32,
+}
+
+impl IoApicController {
+    fn input_for(&self, gsi: u32) -> Option<u8> {
+        ioapic_input(self.gsi_base, self.inputs, gsi)
+    }
+}
+
+/// The input pin GSI `gsi` is wired to on an IOAPIC with `inputs` pins
+/// starting at `gsi_base`, or `None` if it isn't one of them.
+fn ioapic_input(gsi_base: u32, inputs: u32, gsi: u32) -> Option<u8> {
+    (gsi_base..gsi_base + inputs).contains(&gsi).then(|| (gsi - gsi_base) as u8)
+}
+
+// Only reached through the `IOAPICS` lock.
+unsafe impl Send for IoApicController {}
+
+/// Every IOAPIC in the system, sorted by GSI base.
+static IOAPICS: Mutex<Vec<IoApicController>> = Mutex::new(Vec::new());
+static mut IOAPIC_DEST: u8 = 0;
+
+/// How the local APIC's registers are reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicMode {
+    /// Memory-mapped registers at the xAPIC base address.
+    XApic,
+    /// MSRs 0x800 and up; needed for APIC IDs above 255.
+    X2Apic,
+}
+
+/// CPUID leaf 1, ECX bit 21: the CPU supports x2APIC mode.
+const CPUID_1_ECX_X2APIC: u32 = 1 << 21;
+
+/// Picks the APIC mode from the ECX value of CPUID leaf 1.
+fn apic_mode_for(cpuid_1_ecx: u32) -> ApicMode {
+    if cpuid_1_ecx & CPUID_1_ECX_X2APIC != 0 {
+        ApicMode::X2Apic
+    } else {
+        ApicMode::XApic
+    }
+}
+
+/// Checks `apic_mode_for` against made-up CPUID values with and without the
+/// x2APIC bit, and that the CPU's real value gives the mode `init_lapic` chose.
+pub fn apic_mode_self_test() -> bool {
+    apic_mode_for(CPUID_1_ECX_X2APIC) == ApicMode::X2Apic
+        && apic_mode_for(u32::MAX) == ApicMode::X2Apic
+        && apic_mode_for(0) == ApicMode::XApic
+        && apic_mode_for(!CPUID_1_ECX_X2APIC) == ApicMode::XApic
+        && apic_mode_for(core::arch::x86_64::__cpuid(1).ecx) == apic_mode()
+}
+
+static X2APIC_ENABLED: AtomicBool = AtomicBool::new(false);
 
+/// The mode `init_lapic` put the local APIC in.
+pub fn apic_mode() -> ApicMode {
+    if X2APIC_ENABLED.load(Ordering::Relaxed) {
+        ApicMode::X2Apic
+    } else {
+        ApicMode::XApic
+    }
+}
+
+/// Enables the local APIC, in x2APIC mode when the CPU supports it and
+/// through the memory-mapped xAPIC registers otherwise.
 pub unsafe fn init_lapic(lapic_phys: usize, physical_memory_offset: u64) {
     let lapic_virtual = lapic_phys as u64 + physical_memory_offset;
+    let mode = apic_mode_for(core::arch::x86_64::__cpuid(1).ecx);
 
-    let mut lapic = LocalApicBuilder::new()
+    let mut builder = LocalApicBuilder::new();
+    builder
         .timer_vector(InterruptIndex::Timer.as_usize())
         .error_vector(InterruptIndex::Error.as_usize())
-        .spurious_vector(InterruptIndex::Spurious.as_usize())
-        .set_xapic_base(lapic_virtual)
-        .build()
-        .expect("Failed to build LocalApic");
+        .spurious_vector(InterruptIndex::Spurious.as_usize());
+    if mode == ApicMode::XApic {
+        // In x2APIC mode the registers are MSRs and the MMIO base is unused
+        builder.set_xapic_base(lapic_virtual);
+    }
+    let mut lapic = builder.build().expect("Failed to build LocalApic");
+    X2APIC_ENABLED.store(mode == ApicMode::X2Apic, Ordering::Relaxed);
+    info!("LAPIC mode: {:?}", mode);
 
     lapic.enable();
     LAPIC_ID = lapic.id();
+    calibrate_apic_timer(&mut lapic);
+    if program_timer(&mut lapic, DEFAULT_TIMER_HZ).is_err() {
+        // Calibration failed: keep the builder's count, whatever rate it gives
+        lapic.set_timer_mode(TimerMode::Periodic);
+        lapic.set_timer_initial(10_000_000);
+        lapic.enable_timer();
+    }
 
     let boxed = Box::leak(Box::new(lapic));
     LAPIC = Some(NonNull::from(boxed));
 }
 
-pub unsafe fn init_ioapic(ioapic_phys: usize, physical_memory_offset: u64, irq_offset: u8, lapic_id: u8) {
-    let ioapic_virtual = ioapic_phys as u64 + physical_memory_offset;
+pub unsafe fn init_ioapic(
+    io_apics: &[acpi::platform::interrupt::IoApic],
+    physical_memory_offset: u64,
+    irq_offset: u8,
+    lapic_id: u8,
+) {
+    let mut controllers = IOAPICS.lock();
+    for io_apic in io_apics {
+        let ioapic_virtual = io_apic.address as u64 + physical_memory_offset;
+
+        // Todas as linhas começam mascaradas
+        let mut ioapic = IoApic::new(ioapic_virtual);
+        ioapic.init(irq_offset);
+        let inputs = ioapic.max_table_entry() as u32 + 1;
+
+        info!(
+            "IOAPIC {}: GSIs {}..{}",
+            io_apic.id,
+            io_apic.global_system_interrupt_base,
+            io_apic.global_system_interrupt_base + inputs
+        );
+        controllers.push(IoApicController {
+            ioapic,
+            gsi_base: io_apic.global_system_interrupt_base,
+            inputs,
+        });
+    }
+    controllers.sort_unstable_by_key(|controller| controller.gsi_base);
+    IOAPIC_DEST = lapic_id;
+    drop(controllers);
+
+    // Handlers registered before the IOAPIC existed
+    for irq in 0..IRQ_COUNT as u8 {
+        if IRQ_HANDLERS.lock()[irq as usize].is_some() {
+            route_irq(irq);
+        }
+    }
+}
 
-    let mut ioapic = IoApic::new(ioapic_virtual);
-    ioapic.init(irq_offset);
+/// Points IRQ line `irq` at its vector on the boot CPU and unmasks it, on
+/// the IOAPIC input its route's GSI lands on. Does nothing before
+/// `init_ioapic`.
+unsafe fn route_irq(irq: u8) {
+    let route = IRQ_ROUTES.lock()[irq as usize];
 
-    // Configuração de exemplo para IRQ1 (teclado)
+    // A polaridade e o modo de disparo vêm da rota. Errar aqui (ex.: linha
+    // ISA configurada como level-triggered/low-active) faz o IOAPIC ver a
+    // linha "presa": a IRQ dispara de novo logo após o EOI.
     let mut entry = RedirectionTableEntry::default();
-    entry.set_vector(InterruptIndex::Keyboard.as_u8());
+    entry.set_vector(IRQ_VECTOR_BASE + irq);
     entry.set_mode(IrqMode::Fixed);
-    entry.set_flags(IrqFlags::LEVEL_TRIGGERED | IrqFlags::LOW_ACTIVE);
-    entry.set_dest(lapic_id);
-
-    ioapic.set_table_entry(1, entry);
-    ioapic.enable_irq(1);
+    entry.set_flags(route.flags);
+    entry.set_dest(IOAPIC_DEST);
+
+    let routed = with_irq_input(irq, |ioapic, input| {
+        ioapic.set_table_entry(input, entry);
+        ioapic.enable_irq(input);
+    });
+    if routed.is_none() && !IOAPICS.lock().is_empty() {
+        warn!("IRQ {}: no IOAPIC handles GSI {}", irq, route.gsi);
+    }
+}
 
-    let boxed = Box::leak(Box::new(ioapic));
-    IOAPIC = Some(NonNull::from(boxed));
+/// Runs `f` with the IOAPIC and input pin that IRQ line `irq` is routed to.
+/// Returns `None` if no IOAPIC handles its GSI (or none is initialized).
+unsafe fn with_irq_input<R>(irq: u8, f: impl FnOnce(&mut IoApic, u8) -> R) -> Option<R> {
+    let gsi = IRQ_ROUTES.lock()[irq as usize].gsi;
+    let mut controllers = IOAPICS.lock();
+    let controller = controllers.iter_mut().find(|controller| controller.input_for(gsi).is_some())?;
+    let input = controller.input_for(gsi)?;
+    Some(f(&mut controller.ioapic, input))
 }
 
 pub unsafe fn init_apic(
@@ -153,24 +587,188 @@ pub unsafe fn init_apic(
 
     match platform.interrupt_model {
         acpi::InterruptModel::Apic(apic) => {
-            let ioapic_addr = apic.io_apics[0].address as usize;
             let lapic_addr = apic.local_apic_address as usize;
 
+            apply_overrides(&apic.interrupt_source_overrides);
             init_lapic(lapic_addr, physical_memory_offset.as_u64());
-            init_ioapic(ioapic_addr, physical_memory_offset.as_u64(), 32, get_current_lapic_id()); // irq_offset 32
+            init_ioapic(&apic.io_apics, physical_memory_offset.as_u64(), IRQ_VECTOR_BASE, get_current_lapic_id());
         }
         _ => panic!("Unsupported APIC model"),
     }
 
+    register_irq(KEYBOARD_IRQ, keyboard_irq);
+
     disable_pic();
 }
 
+/// APIC timer ticks per millisecond (divide by 1), measured at boot.
+static APIC_TIMER_TICKS_PER_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Frequency of the APIC timer in Hz, with the divider at 1. Zero until the
+/// LAPIC is initialized.
+pub fn apic_timer_hz() -> u64 {
+    APIC_TIMER_TICKS_PER_MS.load(Ordering::Relaxed) * 1000
+}
+
+const PIT_FREQUENCY: u64 = 1_193_182;
+/// How long clocks are calibrated against the PIT (the APIC timer here, the
+/// TSC in `tsc`).
+pub const CALIBRATION_MS: u64 = 10;
+/// Longest wait one PIT countdown covers (the 16-bit count runs out at ~54 ms).
+const PIT_MAX_WAIT_MS: u64 = 50;
+
+/// Busy-waits `ms` milliseconds on PIT channel 0, in one-shot countdowns.
+///
+/// Must run with IRQ0 masked, since the PIT is polled, not used through its
+/// interrupt.
+pub unsafe fn pit_wait_ms(ms: u64) {
+    let mut pit_command = Port::<u8>::new(0x43);
+    let mut pit_channel_0 = Port::<u8>::new(0x40);
+
+    let mut remaining = ms;
+    while remaining > 0 {
+        let step = remaining.min(PIT_MAX_WAIT_MS);
+        remaining -= step;
+
+        // Channel 0, lobyte/hibyte, mode 0: OUT goes high at the terminal count
+        let count = (PIT_FREQUENCY * step / 1000) as u16;
+        pit_command.write(0b0011_0000);
+        pit_channel_0.write(count as u8);
+        pit_channel_0.write((count >> 8) as u8);
+
+        // Read-back command: latch channel 0's status; bit 7 is OUT
+        loop {
+            pit_command.write(0b1110_0010);
+            if pit_channel_0.read() & 0x80 != 0 {
+                break;
+            }
+        }
+    }
+}
+
+/// Measures the APIC timer against the PIT: counts how far the timer
+/// decrements during 10 ms on PIT channel 0. The timer is left stopped.
+///
+/// Must run with interrupts disabled and IRQ0 masked.
+unsafe fn calibrate_apic_timer(lapic: &mut LocalApic) {
+    lapic.disable_timer();
+    lapic.set_timer_divide(TimerDivide::Div1);
+    lapic.set_timer_mode(TimerMode::OneShot);
+    lapic.set_timer_initial(u32::MAX);
+
+    pit_wait_ms(CALIBRATION_MS);
+    let elapsed = u32::MAX - lapic.timer_current();
+
+    APIC_TIMER_TICKS_PER_MS.store(elapsed as u64 / CALIBRATION_MS, Ordering::Relaxed);
+    info!("APIC timer: {} Hz", apic_timer_hz());
+}
+
+/// Scheduler tick rate set up at boot.
+pub const DEFAULT_TIMER_HZ: u64 = 100;
+
+/// Current timer interrupt rate; zero if it isn't known.
+static TIMER_HZ: AtomicU64 = AtomicU64::new(0);
+
+/// Timer interrupts since boot.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Uptime and tick count when the timer frequency last changed; `uptime_ms`
+/// counts from there at the current rate.
+static UPTIME_BASE_MS: AtomicU64 = AtomicU64::new(0);
+static UPTIME_BASE_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of timer interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Milliseconds since the timer started, with the resolution of one tick.
+pub fn uptime_ms() -> u64 {
+    let hz = timer_frequency();
+    let base_ms = UPTIME_BASE_MS.load(Ordering::Relaxed);
+    if hz == 0 {
+        return base_ms;
+    }
+    let elapsed_ticks = ticks() - UPTIME_BASE_TICKS.load(Ordering::Relaxed);
+    base_ms + elapsed_ticks * 1000 / hz
+}
+
+/// How many timer interrupts fire per second.
+pub fn timer_frequency() -> u64 {
+    TIMER_HZ.load(Ordering::Relaxed)
+}
+
+/// Makes the timer interrupt (and so the scheduler) fire `hz` times per
+/// second. Needs the calibrated APIC timer.
+pub fn set_timer_frequency(hz: u64) -> Result<(), &'static str> {
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        match LAPIC {
+            Some(mut lapic_ptr) => program_timer(lapic_ptr.as_mut(), hz),
+            None => Err("LAPIC not initialized"),
+        }
+    })
+}
+
+unsafe fn program_timer(lapic: &mut LocalApic, hz: u64) -> Result<(), &'static str> {
+    if hz == 0 {
+        return Err("Timer frequency must be nonzero");
+    }
+    let ticks_per_ms = APIC_TIMER_TICKS_PER_MS.load(Ordering::Relaxed);
+    if ticks_per_ms == 0 {
+        return Err("APIC timer not calibrated");
+    }
+    let initial = (ticks_per_ms * 1000 / hz).clamp(1, u32::MAX as u64) as u32;
+
+    lapic.disable_timer();
+    lapic.set_timer_divide(TimerDivide::Div1);
+    lapic.set_timer_mode(TimerMode::Periodic);
+    lapic.set_timer_initial(initial);
+    lapic.enable_timer();
+
+    // Ticks from now on count at the new rate
+    UPTIME_BASE_MS.store(uptime_ms(), Ordering::Relaxed);
+    UPTIME_BASE_TICKS.store(ticks(), Ordering::Relaxed);
+    TIMER_HZ.store(hz, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Sets the timer to 1000 Hz, a rate other than the default, counts its
+/// interrupts over 100 ms of PIT time and checks that about 100 arrived,
+/// then goes back to `DEFAULT_TIMER_HZ`.
+///
+/// Needs interrupts enabled. Run once at boot, before any thread is queued,
+/// so the ticks don't switch away from the boot context.
+pub fn timer_self_test() -> bool {
+    const TEST_HZ: u64 = 1000;
+    const MEASURE_MS: u64 = 100;
+
+    if set_timer_frequency(TEST_HZ).is_err() {
+        return false;
+    }
+    let before = ticks();
+    let tsc_before = crate::tsc::now_ns();
+    unsafe { pit_wait_ms(MEASURE_MS) };
+    let counted = ticks() - before;
+    let tsc_ms = (crate::tsc::now_ns() - tsc_before) / 1_000_000;
+    let restored = set_timer_frequency(DEFAULT_TIMER_HZ).is_ok();
+
+    info!(
+        "Timer: {} ticks in {} ms at {} Hz ({} ms by the TSC)",
+        counted, MEASURE_MS, TEST_HZ, tsc_ms
+    );
+    // The calibration only measured 10 ms, so allow a few ticks either way
+    let expected = TEST_HZ * MEASURE_MS / 1000;
+    restored && counted.abs_diff(expected) <= expected / 20
+}
+
 pub fn get_current_lapic_id() -> u8 {
     unsafe { LAPIC_ID as u8 }
 }
 
 
-pub fn send_eoi() {
+/// Signals the end of the current interrupt to the local APIC, through
+/// whichever register interface `init_lapic` chose.
+pub fn end_of_interrupt() {
     unsafe {
         if let Some(mut apic_ptr) = LAPIC {
             let lapic = apic_ptr.as_mut();
@@ -179,11 +777,89 @@ pub fn send_eoi() {
     }
 }
 
+/// Sends a fixed IPI with `vector` to the CPU whose LAPIC ID is `dest_lapic_id`.
+pub fn send_ipi(dest_lapic_id: u32, vector: u8) {
+    // No modo xAPIC o destino fica nos bits 24..31 da metade alta do ICR
+    let dest = match apic_mode() {
+        ApicMode::X2Apic => dest_lapic_id,
+        ApicMode::XApic => dest_lapic_id << 24,
+    };
+    unsafe {
+        if let Some(mut apic_ptr) = LAPIC {
+            apic_ptr.as_mut().send_ipi(vector, dest);
+        }
+    }
+}
+
+/// Sends a fixed IPI with `vector` to every CPU except the current one.
+pub fn broadcast_ipi(vector: u8) {
+    unsafe {
+        if let Some(mut apic_ptr) = LAPIC {
+            apic_ptr.as_mut().send_ipi_all(vector, IpiAllShorthand::AllExcludingSelf);
+        }
+    }
+}
+
+/// Number of CPUs taking interrupts. Only the BSP for now; whoever brings
+/// up the APs must bump this so shootdowns wait for them.
+static ONLINE_CPUS: AtomicUsize = AtomicUsize::new(1);
+
+/// Serializes shootdowns, since there's a single `SHOOTDOWN_ADDR` slot.
+static SHOOTDOWN_LOCK: Mutex<()> = Mutex::new(());
+static SHOOTDOWN_ADDR: AtomicU64 = AtomicU64::new(0);
+static SHOOTDOWN_ACKS: AtomicUsize = AtomicUsize::new(0);
+
+/// Invalidates the TLB entry of `addr` on this CPU and on every other CPU,
+/// returning once all of them have done it.
+///
+/// Must be called with interrupts enabled: while this CPU spins on the lock,
+/// it still has to answer a shootdown sent by whoever holds it.
+pub fn flush_tlb_all_cores(addr: VirtAddr) {
+    tlb::flush(addr);
+
+    let others = ONLINE_CPUS.load(Ordering::Acquire) - 1;
+    if others == 0 {
+        return;
+    }
+
+    let _guard = SHOOTDOWN_LOCK.lock();
+    SHOOTDOWN_ADDR.store(addr.as_u64(), Ordering::Release);
+    SHOOTDOWN_ACKS.store(0, Ordering::Release);
+    broadcast_ipi(TLB_SHOOTDOWN_VECTOR);
+    while SHOOTDOWN_ACKS.load(Ordering::Acquire) < others {
+        core::hint::spin_loop();
+    }
+}
+
+extern "x86-interrupt" fn tlb_shootdown_handler(_stack_frame: InterruptStackFrame) {
+    count_interrupt(TLB_SHOOTDOWN_VECTOR);
+    tlb::flush(VirtAddr::new(SHOOTDOWN_ADDR.load(Ordering::Acquire)));
+    SHOOTDOWN_ACKS.fetch_add(1, Ordering::AcqRel);
+    end_of_interrupt();
+}
+
+/// Sends a shootdown IPI to this CPU and checks that the handler ran.
+///
+/// Needs interrupts enabled. Run once at boot to catch a broken ICR setup
+/// before SMP depends on it.
+pub fn ipi_self_test() -> bool {
+    let before = INTERRUPT_COUNTS[TLB_SHOOTDOWN_VECTOR as usize].load(Ordering::Relaxed);
+    send_ipi(get_current_lapic_id() as u32, TLB_SHOOTDOWN_VECTOR);
+
+    // A self-IPI arrives almost immediately, but give it some slack
+    for _ in 0..1_000_000 {
+        if INTERRUPT_COUNTS[TLB_SHOOTDOWN_VECTOR as usize].load(Ordering::Relaxed) != before {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    false
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
-    Keyboard,
     Error,
     Spurious,
 }
@@ -202,55 +878,94 @@ lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
         idt.breakpoint.set_handler_fn(breakpoint_handler);
-        serial_println!("IDT - Breakpoint loaded");
+        debug!("IDT - Breakpoint loaded");
 
         unsafe {
             idt.double_fault.set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
-        serial_println!("IDT - Double Fault loaded");
+        debug!("IDT - Double Fault loaded");
 
         unsafe {
             idt.page_fault.set_handler_fn(page_fault_handler)
                 .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
         }
-        serial_println!("IDT - Page Fault loaded");
+        debug!("IDT - Page Fault loaded");
 
         unsafe {
             idt[InterruptIndex::Timer.as_u8()]
                 .set_handler_fn(timer_interrupt_handler)
                 .set_stack_index(gdt::TIMER_INTERRUPT_INDEX);
         }
-        serial_println!("IDT - APIC - Timer loaded");
+        debug!("IDT - APIC - Timer loaded");
 
         idt[InterruptIndex::Spurious.as_u8()]
             .set_handler_fn(spurious_interrupt_handler);
-        serial_println!("IDT - APIC - Spurious loaded");
+        debug!("IDT - APIC - Spurious loaded");
 
         idt[InterruptIndex::Error.as_u8()]
             .set_handler_fn(error_interrupt_handler);
-        serial_println!("IDT - APIC - Error loaded");
+        debug!("IDT - APIC - Error loaded");
+        for (irq, stub) in IRQ_STUBS.iter().enumerate() {
+            unsafe {
+                idt[IRQ_VECTOR_BASE + irq as u8]
+                    .set_handler_fn(*stub)
+                    .set_stack_index(gdt::IRQ_INTERRUPT_INDEX);
+            }
+        }
+        debug!("IDT - IOAPIC - IRQ lines loaded");
+
+        idt[TLB_SHOOTDOWN_VECTOR].set_handler_fn(tlb_shootdown_handler);
+        debug!("IDT - IPI - TLB Shootdown loaded");
+
+        // Same IST as the timer: the context must land on the thread's own
+        // kernel stack for the switch to work
         unsafe {
-            idt[InterruptIndex::Keyboard.as_u8()]
-                .set_handler_fn(keyboard_interrupt_handler)
-                .set_stack_index(gdt::KEYBOARD_INTERRUPT_INDEX);
+            idt[YIELD_VECTOR]
+                .set_handler_fn(yield_interrupt_handler)
+                .set_stack_index(gdt::TIMER_INTERRUPT_INDEX);
         }
-        serial_println!("IDT - IOAPIC - Keyboard loaded");
+        debug!("IDT - Yield loaded");
+
         // Adicionando exceções
         idt.divide_error.set_handler_fn(divide_error_handler);
-        serial_println!("IDT - Divide Error loaded");
+        debug!("IDT - Divide Error loaded");
 
         idt.debug.set_handler_fn(debug_handler);
-        serial_println!("IDT - Debug loaded");
+        debug!("IDT - Debug loaded");
 
         idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
-        serial_println!("IDT - Invalid Opcode loaded");
+        debug!("IDT - Invalid Opcode loaded");
 
         unsafe {
             idt.general_protection_fault.set_handler_fn(general_protection_fault_handler)
                 .set_stack_index(gdt::GENERAL_PROTECTION_FAULT_IST_INDEX);
         }
-        serial_println!("IDT - General Protection Fault loaded");
+        debug!("IDT - General Protection Fault loaded");
+
+        idt.non_maskable_interrupt.set_handler_fn(nmi_handler);
+        debug!("IDT - NMI loaded");
+
+        idt.overflow.set_handler_fn(overflow_handler);
+        debug!("IDT - Overflow loaded");
+
+        idt.bound_range_exceeded.set_handler_fn(bound_range_handler);
+        debug!("IDT - Bound Range Exceeded loaded");
+
+        idt.device_not_available.set_handler_fn(device_not_available_handler);
+        debug!("IDT - Device Not Available loaded");
+
+        idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+        debug!("IDT - Stack Segment Fault loaded");
+
+        idt.alignment_check.set_handler_fn(alignment_check_handler);
+        debug!("IDT - Alignment Check loaded");
+
+        idt.machine_check.set_handler_fn(machine_check_handler);
+        debug!("IDT - Machine Check loaded");
+
+        idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
+        debug!("IDT - SIMD Floating Point loaded");
 
         idt
     };
@@ -263,114 +978,144 @@ pub fn init_idt() {
 extern "x86-interrupt" fn spurious_interrupt_handler(
     _stack_frame: InterruptStackFrame)
 {
-    serial_println!("Spurious Interrupt");
-    send_eoi();
+    count_interrupt(InterruptIndex::Spurious.as_u8());
+    warn!("Spurious Interrupt");
+    end_of_interrupt();
 }
 
 extern "x86-interrupt" fn error_interrupt_handler(
     _stack_frame: InterruptStackFrame)
 {
-    serial_println!("APIC Error Interrupt");
-    send_eoi();
+    count_interrupt(InterruptIndex::Error.as_u8());
+    error!("APIC Error Interrupt");
+    end_of_interrupt();
 }
 
 extern "x86-interrupt" fn breakpoint_handler(
     stack_frame: InterruptStackFrame)
 {
+    count_interrupt(BREAKPOINT_VECTOR);
     kprintln!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
 extern "C" fn timer_handler(context_addr: usize) -> usize {
+    count_interrupt(InterruptIndex::Timer.as_u8());
+    TICKS.fetch_add(1, Ordering::Relaxed);
     let next_stack = process::schedule_next(context_addr);
 
-    send_eoi();
+    end_of_interrupt();
     next_stack
 }
 
-#[naked]
-pub extern "x86-interrupt" fn timer_interrupt_handler (
-   _stack_frame: InterruptStackFrame) {
-  unsafe {
-    naked_asm!(
-        // Disable interrupts
-        "cli",
-        // Push registers
-        "push rax",
-        "push rbx",
-        "push rcx",
-        "push rdx",
-    
-        "push rdi",
-        "push rsi",
-        "push rbp",
-        "push r8",
-    
-        "push r9",
-        "push r10",
-        "push r11",
-        "push r12",
-    
-        "push r13",
-        "push r14",
-        "push r15",
-    
-        // First argument in rdi with C calling convention
-        "mov rdi, rsp",
-        // Call the hander function
-        "call {handler}",
-        // New: stack pointer is in RAX
-        "cmp rax, 0",
-        "je 2f",        // if rax != 0 {
-        "mov rsp, rax", //   rsp = rax;
-        "2:",           // }
-    
-        // Pop scratch registers
-        "pop r15",
-        "pop r14",
-        "pop r13",
-    
-        "pop r12",
-        "pop r11",
-        "pop r10",
-        "pop r9",
-    
-        "pop r8",
-        "pop rbp",
-        "pop rsi",
-        "pop rdi",
-    
-        "pop rdx",
-        "pop rcx",
-        "pop rbx",
-        "pop rax",
-        // Enable interrupts
-        "sti",
-        // Interrupt return
-        "iretq",
-        // Note: Getting the handler pointer here using `sym` operand, because
-        // an `in` operand would clobber a register that we need to save, and we
-        // can't have two asm blocks
-        handler = sym timer_handler,
-    );
-  }
+/// Defines a naked interrupt entry that saves the registers as a `Context`
+/// on the current stack, calls `$handler(context_addr) -> usize` and, if
+/// that returns non-zero, switches to the stack (and so the thread) it
+/// points to before restoring registers.
+macro_rules! context_switch_entry {
+    ($name:ident, $handler:ident) => {
+        #[naked]
+        pub extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+          unsafe {
+            naked_asm!(
+                // Disable interrupts
+                "cli",
+                // Push registers
+                "push rax",
+                "push rbx",
+                "push rcx",
+                "push rdx",
+
+                "push rdi",
+                "push rsi",
+                "push rbp",
+                "push r8",
+
+                "push r9",
+                "push r10",
+                "push r11",
+                "push r12",
+
+                "push r13",
+                "push r14",
+                "push r15",
+
+                // First argument in rdi with C calling convention
+                "mov rdi, rsp",
+                // Call the hander function
+                "call {handler}",
+                // New: stack pointer is in RAX
+                "cmp rax, 0",
+                "je 2f",        // if rax != 0 {
+                "mov rsp, rax", //   rsp = rax;
+                "2:",           // }
+
+                // Pop scratch registers
+                "pop r15",
+                "pop r14",
+                "pop r13",
+
+                "pop r12",
+                "pop r11",
+                "pop r10",
+                "pop r9",
+
+                "pop r8",
+                "pop rbp",
+                "pop rsi",
+                "pop rdi",
+
+                "pop rdx",
+                "pop rcx",
+                "pop rbx",
+                "pop rax",
+                // Enable interrupts
+                "sti",
+                // Interrupt return
+                "iretq",
+                // Note: Getting the handler pointer here using `sym` operand, because
+                // an `in` operand would clobber a register that we need to save, and we
+                // can't have two asm blocks
+                handler = sym $handler,
+            );
+          }
+        }
+    };
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(
-    _stack_frame: InterruptStackFrame)
-{
-    use x86_64::instructions::port::Port;
+context_switch_entry!(timer_interrupt_handler, timer_handler);
+context_switch_entry!(yield_interrupt_handler, yield_handler);
 
-    let mut port = Port::new(0x60); // Porta padrão do teclado
-    let scancode: u8 = unsafe { port.read() };
+/// Software interrupt raised by `process::yield_now`. Unlike the timer it
+/// doesn't count a tick, and needs no EOI since the LAPIC isn't involved.
+extern "C" fn yield_handler(context_addr: usize) -> usize {
+    count_interrupt(YIELD_VECTOR);
+    process::schedule_next(context_addr)
+}
+
+/// Gives up the rest of the current thread's time slice.
+pub fn yield_cpu() {
+    unsafe { asm!("int {vector}", vector = const YIELD_VECTOR) };
+}
 
-    crate::task::keyboard::add_scancode(scancode);
+fn keyboard_irq() {
+    let mut status_port = Port::<u8>::new(0x64); // Status do controlador PS/2
+    let mut port = Port::new(0x60); // Porta padrão do teclado
 
-    send_eoi(); // Sempre sinalize o fim
+    // Só lê se o buffer de saída estiver cheio (bit 0); uma leitura sem dado
+    // devolveria o último scancode de novo. Ler a porta 0x60 é o que baixa a
+    // linha da IRQ, então isso tem que acontecer antes do EOI. Bytes com o
+    // bit 5 ligado são do mouse e ficam para o handler da IRQ12.
+    let status = unsafe { status_port.read() };
+    if status & 0x01 != 0 && status & 0x20 == 0 {
+        let scancode: u8 = unsafe { port.read() };
+        crate::task::keyboard::add_scancode(scancode);
+    }
 }
 
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame, _error_code: u64) -> !
 {
+    count_interrupt(DOUBLE_FAULT_VECTOR);
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
@@ -380,27 +1125,52 @@ extern "x86-interrupt" fn page_fault_handler(
 ) {
     use x86_64::registers::control::Cr2;
 
+    count_interrupt(PAGE_FAULT_VECTOR);
+
+    let accessed_address = Cr2::read_raw();
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE)
+        && memory::handle_cow_fault(VirtAddr::new_truncate(accessed_address)) {
+        // Write to a copy-on-write page: now backed by a private copy
+        return;
+    }
+    if process::handle_page_fault(VirtAddr::new_truncate(accessed_address), error_code) {
+        // Page mapped on demand: retry the faulting instruction
+        return;
+    }
+
     kprintln!("EXCEPTION: PAGE FAULT");
-    kprintln!("Accessed Address: {:?}", Cr2::read());
+    kprintln!("Accessed Address: {:#x}", accessed_address);
     kprintln!("Error Code: {:?}", error_code);
+    memory::try_with_kernel_memory(|mapper, _| {
+        memory::dump_mapping(mapper, VirtAddr::new_truncate(accessed_address));
+    });
     kprintln!("{:#?}", stack_frame);
+
+    if error_code.contains(PageFaultErrorCode::USER_MODE) {
+        kprintln!("Killing the faulting thread");
+        process::thread_exit();
+    }
+    panic!("Unrecoverable page fault in kernel mode");
 }
 
 extern "x86-interrupt" fn divide_error_handler(
     stack_frame: InterruptStackFrame)
 {
+    count_interrupt(DIVIDE_ERROR_VECTOR);
     kprintln!("EXCEPTION: DIVIDE BY ZERO\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn debug_handler(
     stack_frame: InterruptStackFrame)
 {
+    count_interrupt(DEBUG_VECTOR);
     kprintln!("EXCEPTION: DEBUG\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn invalid_opcode_handler(
     stack_frame: InterruptStackFrame)
 {
+    count_interrupt(INVALID_OPCODE_VECTOR);
     kprintln!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
 }
 
@@ -409,7 +1179,88 @@ extern "x86-interrupt" fn general_protection_fault_handler(
     error_code: u64,
 )
 {
+    count_interrupt(GENERAL_PROTECTION_FAULT_VECTOR);
     kprintln!("EXCEPTION: GENERAL PROTECTION FAULT");
     kprintln!("Error Code: {:#x}", error_code); 
     kprintln!("{:#?}", stack_frame); 
 }
+
+extern "x86-interrupt" fn nmi_handler(
+    stack_frame: InterruptStackFrame)
+{
+    count_interrupt(NMI_VECTOR);
+    // An NMI can land while the console lock is held, so only the lock-free
+    // serial path is safe here
+    panic_serial_println!("EXCEPTION: NON-MASKABLE INTERRUPT\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn overflow_handler(
+    stack_frame: InterruptStackFrame)
+{
+    count_interrupt(OVERFLOW_VECTOR);
+    kprintln!("EXCEPTION: OVERFLOW\n{:#?}", stack_frame);
+}
+
+/// Ends a fault nothing can fix: returning would just run the faulting
+/// instruction again. A user thread is killed; a fault in the kernel panics.
+fn fatal_fault(name: &str, stack_frame: &InterruptStackFrame, error_code: Option<u64>) -> ! {
+    if stack_frame.code_segment.rpl() == PrivilegeLevel::Ring3 {
+        kprintln!("EXCEPTION: {}", name);
+        if let Some(error_code) = error_code {
+            kprintln!("Error Code: {:#x}", error_code);
+        }
+        kprintln!("{:#?}", stack_frame);
+        kprintln!("Killing the faulting thread");
+        process::thread_exit();
+    }
+    match error_code {
+        Some(error_code) => panic!("EXCEPTION: {}\nError Code: {:#x}\n{:#?}", name, error_code, stack_frame),
+        None => panic!("EXCEPTION: {}\n{:#?}", name, stack_frame),
+    }
+}
+
+extern "x86-interrupt" fn bound_range_handler(
+    stack_frame: InterruptStackFrame)
+{
+    count_interrupt(BOUND_RANGE_VECTOR);
+    fatal_fault("BOUND RANGE EXCEEDED", &stack_frame, None);
+}
+
+extern "x86-interrupt" fn device_not_available_handler(
+    stack_frame: InterruptStackFrame)
+{
+    count_interrupt(DEVICE_NOT_AVAILABLE_VECTOR);
+    fatal_fault("DEVICE NOT AVAILABLE", &stack_frame, None);
+}
+
+extern "x86-interrupt" fn stack_segment_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+)
+{
+    count_interrupt(STACK_SEGMENT_FAULT_VECTOR);
+    fatal_fault("STACK SEGMENT FAULT", &stack_frame, Some(error_code));
+}
+
+extern "x86-interrupt" fn alignment_check_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+)
+{
+    count_interrupt(ALIGNMENT_CHECK_VECTOR);
+    fatal_fault("ALIGNMENT CHECK", &stack_frame, Some(error_code));
+}
+
+extern "x86-interrupt" fn machine_check_handler(
+    stack_frame: InterruptStackFrame) -> !
+{
+    count_interrupt(MACHINE_CHECK_VECTOR);
+    panic!("EXCEPTION: MACHINE CHECK\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn simd_floating_point_handler(
+    stack_frame: InterruptStackFrame)
+{
+    count_interrupt(SIMD_FLOATING_POINT_VECTOR);
+    fatal_fault("SIMD FLOATING POINT", &stack_frame, None);
+}
@@ -0,0 +1,105 @@
+//! Backend for the `log` crate.
+//!
+//! Records go to the serial port and, optionally, the active TTY, as
+//! `[LEVEL module::path] message`. The maximum level can be changed at any
+//! time with `set_level`.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use log::{error, info, Level, LevelFilter, Log, Metadata, Record};
+
+/// Level used until someone calls `set_level`.
+pub const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+struct KernelLogger {
+    to_serial: AtomicBool,
+    to_tty: AtomicBool,
+    to_counter: AtomicBool, // Only while `logger_self_test` runs
+}
+
+static LOGGER: KernelLogger = KernelLogger {
+    to_serial: AtomicBool::new(true),
+    to_tty: AtomicBool::new(false),
+    to_counter: AtomicBool::new(false),
+};
+
+/// Sink for `logger_self_test`: counts the records it gets, by level.
+struct CountingSink {
+    counts: [AtomicUsize; 6], // Indexed by `Level as usize`, which starts at 1
+}
+
+impl CountingSink {
+    fn record(&self, level: Level) {
+        self.counts[level as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn count(&self, level: Level) -> usize {
+        self.counts[level as usize].load(Ordering::Relaxed)
+    }
+}
+
+static COUNTER: CountingSink = CountingSink { counts: [const { AtomicUsize::new(0) }; 6] };
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let module = record.module_path().unwrap_or("?");
+
+        if self.to_serial.load(Ordering::Relaxed) {
+            serial_println!("[{:5} {}] {}", record.level(), module, record.args());
+        }
+        // Not through `kprintln!`, which would print it to serial again
+        if self.to_tty.load(Ordering::Relaxed) {
+            crate::tty::print_to_console(format_args!("[{:5} {}] {}\n", record.level(), module, record.args()));
+        }
+        if self.to_counter.load(Ordering::Relaxed) {
+            COUNTER.record(record.level());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the logger at `DEFAULT_LEVEL`. Call once, as early as possible.
+pub fn init() {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(DEFAULT_LEVEL);
+    }
+}
+
+/// Drops every record less severe than `level`.
+pub fn set_level(level: LevelFilter) {
+    log::set_max_level(level);
+}
+
+/// Chooses where records go. Printing to the TTY is off by default, since it
+/// is slow and the screen belongs to the console.
+pub fn set_targets(serial: bool, tty: bool) {
+    LOGGER.to_serial.store(serial, Ordering::Relaxed);
+    LOGGER.to_tty.store(tty, Ordering::Relaxed);
+}
+
+/// Sets the level to `Warn` and checks that `info!` is dropped while
+/// `error!` still goes through, counting records in a test sink instead of
+/// printing them. The previous level and targets are restored afterwards.
+pub fn logger_self_test() -> bool {
+    let level = log::max_level();
+    let (serial, tty) = (LOGGER.to_serial.load(Ordering::Relaxed), LOGGER.to_tty.load(Ordering::Relaxed));
+    let (infos, errors) = (COUNTER.count(Level::Info), COUNTER.count(Level::Error));
+
+    set_targets(false, false);
+    LOGGER.to_counter.store(true, Ordering::Relaxed);
+    set_level(LevelFilter::Warn);
+    info!("logger self-test: suppressed");
+    error!("logger self-test: emitted");
+    set_level(level);
+    LOGGER.to_counter.store(false, Ordering::Relaxed);
+    set_targets(serial, tty);
+
+    COUNTER.count(Level::Info) == infos && COUNTER.count(Level::Error) == errors + 1
+}
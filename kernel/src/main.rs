@@ -2,6 +2,7 @@
 #![no_main]
 #![feature(abi_x86_interrupt)]
 #![feature(naked_functions)]
+#![feature(alloc_error_handler)]
 
 #[macro_use]
 extern crate alloc;
@@ -10,7 +11,10 @@ extern crate alloc;
 mod serial;
 #[macro_use]
 mod tty;
+mod logger;
 mod framebuffer;
+mod bmp;
+mod panic_screen;
 
 mod gdt;
 mod interrupts;
@@ -21,14 +25,21 @@ mod task;
 mod process;
 mod syscall;
 
+mod pci;
+mod power;
+mod hpet;
+mod tsc;
+mod rtc;
 mod ide;
+mod block_cache;
 
-use core::{arch::asm, panic::PanicInfo};
+use core::{arch::asm, panic::PanicInfo, sync::atomic::{AtomicBool, Ordering}};
 
-use bootloader_api::{config::Mapping, BootloaderConfig};
+use bootloader_api::{config::Mapping, info::MemoryRegionKind, BootloaderConfig};
 use memory::BootInfoFrameAllocator;
 use task::{executor::Executor, Task};
-use x86_64::{instructions::port::Port, VirtAddr};
+use x86_64::VirtAddr;
+use log::{info, warn};
 
 pub const BOOTLOADER_CONFIG: BootloaderConfig = {
     let mut config = BootloaderConfig::new_default();
@@ -38,6 +49,10 @@ pub const BOOTLOADER_CONFIG: BootloaderConfig = {
 
 bootloader_api::entry_point!(kernel_main, config=&BOOTLOADER_CONFIG);
 
+/// Logo shown centered on the screen while the kernel boots.
+static SPLASH_LOGO: &[u8] = include_bytes!("../assets/logo.bmp");
+static INIT_PROGRAM: &[u8] = include_bytes!("../../target/x86_64-unknown-none/debug/hello");
+
 async fn async_number() -> u32 {
     42
 }
@@ -52,7 +67,7 @@ fn kernel_thread_main() {
     kprintln!("Kernel thread start");
 
     // Launch another kernel thread
-    process::new_kernel_thread(test_kernel_fn2);
+    process::new_kernel_thread(test_kernel_fn2, process::DEFAULT_PRIORITY);
 
     loop {
         kprintln!("<< 1 >>");
@@ -68,62 +83,31 @@ fn test_kernel_fn2() {
     }
 }
 
-unsafe fn pci_config_read(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
-    let address: u32 =
-        (1 << 31) | // habilita
-        ((bus as u32) << 16) |
-        ((device as u32) << 11) |
-        ((function as u32) << 8) |
-        ((offset as u32) & 0xFC);
-
-    let mut port_cf8 = Port::new(0xCF8);
-    let mut port_cfc = Port::new(0xCFC);
-    port_cf8.write(address);
-    port_cfc.read()
-}
-
-pub unsafe fn scan_pci() {
-    for bus in 0..=255 {
-        for device in 0..32 {
-            for function in 0..8 {
-                let data = pci_config_read(bus, device, function, 0);
-                let vendor_id = (data & 0xFFFF) as u16;
-                let device_id = ((data >> 16) & 0xFFFF) as u16;
-
-                if vendor_id != 0xFFFF {
-                    kprintln!(
-                        "PCI Device encontrado: Bus {:02x}, Dev {:02x}, Func {:x} => Vendor {:04x}, Device {:04x}",
-                        bus, device, function, vendor_id, device_id
-                    );
-                }
-
-                // Apenas a função 0 existe, a menos que seja um dispositivo multifunção
-                if function == 0 {
-                    let header_type = (pci_config_read(bus, device, function, 0x0C) >> 16) & 0xFF;
-                    if (header_type & 0x80) == 0 {
-                        break;
-                    }
-                }
-            }
-        }
-    }
-}
-
-
 fn kernel_main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
+    logger::init();
+    if logger::logger_self_test() {
+        info!("Logger self-test passed");
+    } else {
+        warn!("Logger self-test failed; the level filter is ignored");
+    }
+    if let Some(framebuffer) = boot_info.framebuffer.as_ref() {
+        panic_screen::register_framebuffer(framebuffer.buffer(), framebuffer.info());
+    }
     gdt::init();
     interrupts::init_idt();
+    process::enable_fpu();
+    syscall::init();
 
-    serial_println!("Loading memory mapping and frame allocator...");
+    info!("Loading memory mapping and frame allocator...");
 
     let physical_memory_offset = boot_info.physical_memory_offset.into_option().unwrap();
     let phys_mem_offset = VirtAddr::new(physical_memory_offset );
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
     let mut frame_allocator = unsafe {BootInfoFrameAllocator::init(&boot_info.memory_regions)};
-    serial_println!("Loaded!");
+    info!("Loaded!");
     allocator::init_heap(&mut mapper, &mut frame_allocator)
     .expect("heap initialization failed");
-    serial_println!("Heap initialized!");
+    info!("Heap initialized!");
 
     let rsdp: Option<u64> = boot_info.rsdp_addr.take();
 
@@ -132,12 +116,58 @@ fn kernel_main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
         interrupts::init_apic(rsdp.expect("Couldn't get rsdp addr.") as usize, phys_mem_offset, &mut mapper, &mut frame_allocator);
     }
 
-    serial_println!("APIC (IO|LAPIC) initialized!");
+    info!("APIC (IO|LAPIC) initialized!");
+    if interrupts::apic_mode_self_test() {
+        info!("APIC mode self-test passed");
+    } else {
+        warn!("APIC mode self-test failed; the x2APIC check is wrong");
+    }
+
+    unsafe {
+        pci::init_ecam(rsdp.expect("Couldn't get rsdp addr.") as usize, phys_mem_offset, &mut mapper, &mut frame_allocator);
+    }
+    unsafe {
+        hpet::init(rsdp.expect("Couldn't get rsdp addr.") as usize, phys_mem_offset, &mut mapper, &mut frame_allocator);
+    }
+    unsafe {
+        tsc::init();
+    }
+    unsafe {
+        power::init(rsdp.expect("Couldn't get rsdp addr.") as usize, phys_mem_offset);
+    }
+
+    if task::keyboard::layout_self_test() {
+        info!("Keyboard layout self-test passed");
+    } else {
+        warn!("Keyboard layout self-test failed; keys decode the same under every layout");
+    }
+    if task::mouse::decode_self_test() {
+        info!("Mouse decode self-test passed");
+    } else {
+        warn!("Mouse decode self-test failed; packets decode wrong");
+    }
+    // Interrupts are still off, so the keyboard handler can't eat the replies
+    match task::mouse::init() {
+        Ok(()) => info!("PS/2 mouse initialized!"),
+        Err(err) => warn!("PS/2 mouse unavailable: {}", err),
+    }
+    serial::enable_receive_interrupt();
+
+    // Boot and ACPI parsing are done; give their memory to the allocator
+    let reclaimed_frames = unsafe {
+        memory::reclaim(&mut frame_allocator, MemoryRegionKind::Bootloader)
+            + memory::reclaim(&mut frame_allocator, memory::ACPI_RECLAIMABLE_UEFI)
+            + memory::reclaim(&mut frame_allocator, memory::ACPI_RECLAIMABLE_BIOS)
+    };
+    info!("Reclaimed {} frames of boot memory", reclaimed_frames);
 
     let fb_info = boot_info.framebuffer.as_ref().unwrap();
     let fb_addr = VirtAddr::new(fb_info.buffer().as_ptr() as u64);
     let fb_size = fb_info.buffer().len();
 
+    unsafe {
+        memory::init_pat();
+    }
     let fb_buf = unsafe {
         framebuffer::remap_framebuffer_with_wc(
             fb_addr,
@@ -149,41 +179,247 @@ fn kernel_main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
     // let ptr = fb_addr.as_mut_ptr::<u8>();
     // let fb_buf = unsafe { slice::from_raw_parts_mut(ptr, fb_size) } ;
 
-    serial_println!("Framebuffer with WC loaded!");
+    info!("Framebuffer with WC loaded!");
+    if framebuffer::framebuffer_self_test() {
+        info!("Framebuffer self-test passed");
+    } else {
+        warn!("Framebuffer self-test failed; pixels may come out wrong");
+    }
+    if bmp::bmp_self_test() {
+        info!("BMP self-test passed");
+    } else {
+        warn!("BMP self-test failed; the splash may be garbled");
+    }
+
+    let mut display = framebuffer::Display::new_from_buffer(fb_buf, &fb_info.info());
+    if let Err(err) = bmp::Bmp::parse(SPLASH_LOGO).and_then(|logo| framebuffer::draw_splash(&mut display, &logo)) {
+        warn!("Boot splash not drawn: {}", err);
+    }
 
     x86_64::instructions::interrupts::enable();    
-    serial_println!("System interrupts enabled!");
+    info!("System interrupts enabled!");
+    info!("RTC time: {}", rtc::now());
 
-    let display = framebuffer::Display::new_from_buffer(fb_buf, &fb_info.info());
-    let tty0 = tty::TTY::new(display);
-    tty::activate_tty(tty0);
+    if interrupts::ipi_self_test() {
+        info!("IPI self-test passed");
+    } else {
+        warn!("IPI self-test failed; the shootdown handler never ran");
+    }
+    if interrupts::breakpoint_self_test() {
+        info!("Breakpoint self-test passed");
+    } else {
+        warn!("Breakpoint self-test failed; interrupt counters are off");
+    }
+    if interrupts::overflow_self_test() {
+        info!("Overflow self-test passed");
+    } else {
+        warn!("Overflow self-test failed; #OF never reached its handler");
+    }
+    if gdt::ist_self_test() {
+        info!("IST self-test passed");
+    } else {
+        warn!("IST self-test failed; two exception stacks overlap");
+    }
+    if interrupts::irq_self_test() {
+        info!("IRQ self-test passed");
+    } else {
+        warn!("IRQ self-test failed; IRQ routing or masking is broken");
+    }
+    if interrupts::timer_self_test() {
+        info!("Timer self-test passed");
+    } else {
+        warn!("Timer self-test failed; the tick rate is off");
+    }
+    if tsc::tsc_self_test() {
+        info!("TSC self-test passed");
+    } else {
+        warn!("TSC self-test failed; tsc::now_ns disagrees with the PIT");
+    }
+    if rtc::rtc_self_test() {
+        info!("RTC self-test passed");
+    } else {
+        warn!("RTC self-test failed; BCD or 12-hour times decode wrong");
+    }
+
+    tty::init(display);
     kprintln!("TTY Initialized!");
+    if tty::tty_self_test() {
+        info!("TTY self-test passed");
+    } else {
+        warn!("TTY self-test failed; escape sequences or wrapping are broken");
+    }
 
-    unsafe { scan_pci();}
-    for device in ide::detect_ide_devices().iter().flatten() {
+    if pci::pci_self_test() {
+        info!("PCI self-test passed");
+    } else {
+        warn!("PCI self-test failed; config-space decoding is broken");
+    }
+    for device in pci::devices() {
+        kprintln!(
+            "PCI Device encontrado: Bus {:02x}, Dev {:02x}, Func {:x} => {} ({:04x}:{:04x})",
+            device.bus, device.device, device.function, device.class_name(),
+            device.vendor_id, device.device_id
+        );
+    }
+    if ide::ide_self_test() {
+        info!("IDE self-test passed");
+    } else {
+        warn!("IDE self-test failed; error kinds or GPT parsing are broken");
+    }
+    let ide_devices = ide::detect_ide_devices();
+    for device in ide_devices.iter().flatten() {
         let model_str = core::str::from_utf8(&device.model).unwrap_or("???").trim();
         kprintln!(
-            "Dispositivo IDE: {} {} - Modelo: {}",
+            "Dispositivo IDE: {} {} - Modelo: {} - Setor: {} bytes",
             device.channel,
             device.drive,
-            model_str
+            model_str,
+            device.sector_size
         );
     }
+    ide::enable_irqs();
 
-    process::new_user_thread(
-        include_bytes!("../../target/x86_64-unknown-none/debug/hello"),
-        &mut mapper,
-        &mut frame_allocator
-    );
+    let fat_partition = ide_devices.iter().flatten()
+        .find_map(|device| ide::find_fat_partition(device).map(|lba| (device, lba)));
+    if let Some((device, lba_start)) = fat_partition {
+        if let Err(err) = ide::mount_and_list(device, lba_start) {
+            kprintln!("Could not mount the FAT partition: {}", err);
+        }
+    }
+
+    // The scheduler never switches back to this boot context: the first timer
+    // tick after a thread is queued abandons it. Everything left to do at boot
+    // happens with interrupts off so none of it is lost to that switch.
+    x86_64::instructions::interrupts::without_interrupts(move || {
+        process::new_kernel_thread(executor_main, process::DEFAULT_PRIORITY);
+        process::new_kernel_thread(tty::cursor_blink_thread, process::DEFAULT_PRIORITY);
+        process::new_kernel_thread(self_test_thread, process::DEFAULT_PRIORITY);
+
+        if let Err(err) = process::spawn_init(
+            INIT_PROGRAM,
+            &mut mapper,
+            &mut frame_allocator
+        ) {
+            kprintln!("Continuing without a user process: {}", err);
+        }
+
+        // From here on, the page fault handler and threads reach these globally
+        memory::install(mapper, frame_allocator);
+
+        if memory::vma::vma_self_test() {
+            info!("Address space self-test passed");
+        } else {
+            warn!("Address space self-test failed; reserved regions may overlap");
+        }
+        if memory::huge_page_self_test() {
+            info!("Huge page self-test passed");
+        } else {
+            warn!("Huge page self-test failed; 2 MiB mappings are broken");
+        }
+        if memory::contiguous_self_test() {
+            info!("Contiguous frame self-test passed");
+        } else {
+            warn!("Contiguous frame self-test failed; DMA buffers may overlap other frames");
+        }
+        if memory::refcount_self_test() {
+            info!("Frame refcount self-test passed");
+        } else {
+            warn!("Frame refcount self-test failed; shared frames are freed early or leak");
+        }
+        if memory::reclaim_self_test() {
+            info!("Reclaim self-test passed");
+        } else {
+            warn!("Reclaim self-test failed; the free frame count is off");
+        }
+        if memory::translate_self_test() {
+            info!("Translation self-test passed");
+        } else {
+            warn!("Translation self-test failed; the page walk and the mapper disagree");
+        }
+        if memory::mapper_self_test() {
+            info!("Page mapping self-test passed");
+        } else {
+            warn!("Page mapping self-test failed; failed mappings leave pages behind");
+        }
+        if process::entry_point_self_test(INIT_PROGRAM) {
+            info!("ELF entry point self-test passed");
+        } else {
+            warn!("ELF entry point self-test failed; bogus entry points are accepted");
+        }
+        if allocator::heap_self_test() {
+            info!("Heap self-test passed");
+        } else {
+            warn!("Heap self-test failed; slab blocks aren't reused or the heap can't grow");
+        }
+        if memory::cow_self_test() {
+            info!("Copy-on-write self-test passed");
+        } else {
+            warn!("Copy-on-write self-test failed; forked pages don't diverge");
+        }
+        if block_cache::cache_self_test() {
+            info!("Block cache self-test passed");
+        } else {
+            warn!("Block cache self-test failed; cached reads still reach the drive");
+        }
+        if process::user_access_self_test() {
+            info!("User access self-test passed");
+        } else {
+            warn!("User access self-test failed; syscalls reject untouched stack pages");
+        }
 
+        let mem_stats = memory::stats();
+        kprintln!(
+            "Memory: {} of {} frames in use ({} on free list), heap {} of {} bytes",
+            mem_stats.allocated_frames,
+            mem_stats.total_frames,
+            mem_stats.free_list_frames,
+            mem_stats.heap_used,
+            mem_stats.heap_size
+        );
+        let heap_stats = allocator::heap_stats();
+        kprintln!(
+            "Heap: {} bytes allocated (peak {}) of {} mapped",
+            heap_stats.allocated,
+            heap_stats.peak,
+            heap_stats.size
+        );
+
+        kprintln!("Welcome to Aurora OS!");
+    });
+
+    hlt_loop()
+}
+
+/// Runs the async executor as an ordinary kernel thread, so it gets scheduled
+/// alongside user processes.
+fn executor_main() {
     let mut executor = Executor::new();
     executor.spawn(Task::new(example_task()));
+    executor.spawn(Task::new(task::deferred::run_deferred_work()));
     executor.spawn(Task::new(task::keyboard::print_keypresses())); // new
+    executor.spawn(Task::new(task::mouse::print_mouse_events()));
+    executor.spawn(Task::new(task::serial::forward_serial_input()));
     executor.run();
+}
 
-    kprintln!("Welcome to Aurora OS!");
-
-    hlt_loop()
+/// Runs the self-tests that sleep, block or yield, which need a thread to
+/// come back to.
+fn self_test_thread() {
+    if process::scheduler_self_test() {
+        info!("Scheduler self-test passed");
+    } else {
+        warn!("Scheduler self-test failed; sleeping or blocked threads miss wake-ups or signals");
+    }
+    if syscall::syscall_self_test() {
+        info!("Syscall self-test passed");
+    } else {
+        warn!("Syscall self-test failed; unknown numbers or exit codes are mishandled");
+    }
+    if task::deferred::deferred_self_test() {
+        info!("Deferred work self-test passed");
+    } else {
+        warn!("Deferred work self-test failed; work queued from interrupts is lost or repeated");
+    }
 }
 
 pub fn hlt_loop() -> ! {
@@ -192,9 +428,30 @@ pub fn hlt_loop() -> ! {
     }
 }
 
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
 /// This function is called on panic.
+///
+/// Nothing on this path may allocate: the panic could have come from a
+/// corrupted heap or an OOM, and allocating here would just panic again.
+/// Output goes through the lock-free serial writer and the panic screen,
+/// which renders straight from `PanicInfo` into the framebuffer. It doesn't
+/// go through the TTY or its lock, so a wedged TTY can't hide a panic.
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    kprintln!("{}", info);
+    x86_64::instructions::interrupts::disable();
+    let registers = panic_screen::Registers::capture();
+
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        // Panic inside the panic handler: don't touch the display again
+        panic_serial_println!("KERNEL PANIC (nested): {}", info);
+        hlt_loop();
+    }
+
+    panic_serial_println!("KERNEL PANIC: {}", info);
+    if !unsafe { panic_screen::show(info, &registers) } {
+        panic_serial_println!("No framebuffer; panic screen not drawn");
+    }
+
     hlt_loop();
 }
\ No newline at end of file
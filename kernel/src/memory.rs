@@ -1,12 +1,33 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use alloc::vec::Vec;
 use x86_64::{
-    structures::paging::{mapper::MapToError, FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB}, PhysAddr, VirtAddr
+    align_up,
+    structures::paging::{mapper::{FlagUpdateError, MappedFrame, MapToError, TranslateResult, UnmapError}, FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB, Translate}, PhysAddr, VirtAddr
 };
 
 use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
+use spin::Mutex;
+use log::error;
+
+pub mod vma;
+
+/// Translates the given virtual address to the mapped physical address, or
+/// `None` if the address is not mapped.
+///
+/// Delegates to the `Translate` implementation of `OffsetPageTable`, which
+/// handles 4 KiB, 2 MiB and 1 GiB pages. Prefer this over `translate_addr`
+/// once the mapper exists.
+pub fn translate_with_mapper(mapper: &OffsetPageTable, addr: VirtAddr) -> Option<PhysAddr> {
+    mapper.translate_addr(addr)
+}
 
 /// Translates the given virtual address to the mapped physical address, or
 /// `None` if the address is not mapped.
 ///
+/// Walks the active page tables by hand, so it also works during early boot
+/// before the `OffsetPageTable` is created; afterwards use
+/// `translate_with_mapper`.
+///
 /// This function is unsafe because the caller must guarantee that the
 /// complete physical memory is mapped to virtual memory at the passed
 /// `physical_memory_offset`.
@@ -16,8 +37,6 @@ pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: VirtAddr)
     translate_addr_inner(addr, physical_memory_offset)
 }
 
-// in src/memory.rs
-
 /// Private function that is called by `translate_addr`.
 ///
 /// This function is safe to limit the scope of `unsafe` because Rust treats
@@ -38,7 +57,7 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr)
     let mut frame = level_4_table_frame;
 
     // traverse the multi-level page table
-    for &index in &table_indexes {
+    for (level, &index) in table_indexes.iter().enumerate() {
         // convert the frame into a page table reference
         let virt = physical_memory_offset + frame.start_address().as_u64();
         let table_ptr: *const PageTable = virt.as_ptr();
@@ -49,7 +68,12 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr)
         frame = match entry.frame() {
             Ok(frame) => frame,
             Err(FrameError::FrameNotPresent) => return None,
-            Err(FrameError::HugeFrame) => panic!("huge pages not supported"),
+            Err(FrameError::HugeFrame) => {
+                // A huge entry maps the rest of the address directly: 1 GiB
+                // pages live in the level 3 table, 2 MiB pages in level 2
+                let page_size: u64 = if level == 1 { 1 << 30 } else { Size2MiB::SIZE };
+                return Some(entry.addr() + (addr.as_u64() & (page_size - 1)));
+            }
         };
     }
 
@@ -64,51 +88,727 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr)
 /// `physical_memory_offset`. Also, this function must be only called once
 /// to avoid aliasing `&mut` references (which is undefined behavior).
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    use x86_64::registers::model_specific::{Efer, EferFlags};
+
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
     unsafe {
+        // Without NXE, NO_EXECUTE is a reserved bit and mapping with it faults
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+
         let level_4_table = active_level_4_table(physical_memory_offset);
         OffsetPageTable::new(level_4_table, physical_memory_offset)
     }
 }
 
+/// Virtual address where the bootloader mapped the complete physical memory.
+/// Set once by `init`.
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the virtual address through which physical address 0 is accessible.
+pub fn physical_memory_offset() -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+}
+
+/// Makes the device memory at `phys..phys + size` reachable, uncached,
+/// through the physical memory mapping and returns its virtual address.
+///
+/// The bootloader only maps what appears in the memory map, so MMIO regions
+/// like the HPET or PCIe ECAM may be missing and get mapped here. When RAM
+/// extends past them they are already in the bootloader's write-back
+/// mapping instead; those pages are switched to uncached in place. If that
+/// mapping uses a huge page, the whole huge page becomes uncached.
+pub unsafe fn map_physical_mmio(
+    phys: PhysAddr,
+    size: u64,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let offset = physical_memory_offset();
+    let uncached = PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE
+        | uncached;
+
+    let first = PhysFrame::<Size4KiB>::containing_address(phys);
+    let last = PhysFrame::<Size4KiB>::containing_address(phys + (size.max(1) - 1));
+    for frame in PhysFrame::range_inclusive(first, last) {
+        let page = Page::<Size4KiB>::containing_address(offset + frame.start_address().as_u64());
+        let (mapped, old_flags) = match mapper.translate(page.start_address()) {
+            TranslateResult::NotMapped => {
+                mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+                continue;
+            }
+            TranslateResult::InvalidFrameAddress(_) => continue,
+            TranslateResult::Mapped { frame, flags, .. } => (frame, flags),
+        };
+        if old_flags.contains(uncached) {
+            continue;
+        }
+        // Translate just found the entry, so updating it can't fail
+        let _ = match mapped {
+            MappedFrame::Size4KiB(_) => mapper.update_flags(page, old_flags | uncached).map(|f| f.flush()),
+            MappedFrame::Size2MiB(_) => mapper
+                .update_flags(Page::<Size2MiB>::containing_address(page.start_address()), old_flags | uncached)
+                .map(|f| f.flush()),
+            MappedFrame::Size1GiB(_) => mapper
+                .update_flags(Page::<Size1GiB>::containing_address(page.start_address()), old_flags | uncached)
+                .map(|f| f.flush()),
+        };
+    }
+    Ok(offset + phys.as_u64())
+}
+
+/// IA32_PAT: eight memory types, one byte each, picked by a page's
+/// PAT/PCD/PWT bits.
+const IA32_PAT: u32 = 0x277;
+const CPUID_1_EDX_PAT: u32 = 1 << 16;
+
+pub const PAT_TYPE_WRITE_COMBINING: u8 = 0x01;
+/// PAT entry `init_pat` turns into write-combining. Out of reset, entries
+/// 4-7 repeat 0-3, so nothing else selects entry 4 (write-back, like 0).
+const PAT_WRITE_COMBINING_INDEX: u64 = 4;
+
+/// Bit 7 of a 4 KiB page table entry selects the upper half of the PAT. It
+/// is the same bit as `HUGE_PAGE` in the higher levels, so only use it on
+/// level 1 entries.
+pub const PTE_PAT: PageTableFlags = PageTableFlags::from_bits_truncate(1 << 7);
+/// Cache flags of a 4 KiB page that select write-combining, once `init_pat`
+/// succeeded: PAT set, PCD and PWT clear, which picks entry 4.
+pub const WRITE_COMBINING: PageTableFlags = PTE_PAT;
+
+static WRITE_COMBINING_READY: AtomicBool = AtomicBool::new(false);
+
+/// Programs PAT entry 4 as write-combining so pages mapped with
+/// `WRITE_COMBINING` get that memory type. Returns `false` if the CPU has no
+/// PAT.
+pub unsafe fn init_pat() -> bool {
+    use core::arch::x86_64::__cpuid;
+    use x86_64::{instructions::tlb, registers::model_specific::Msr};
+
+    if __cpuid(1).edx & CPUID_1_EDX_PAT == 0 {
+        return false;
+    }
+
+    let mut pat_msr = Msr::new(IA32_PAT);
+    let shift = PAT_WRITE_COMBINING_INDEX * 8;
+    let pat = (pat_msr.read() & !(0xFF << shift)) | (PAT_TYPE_WRITE_COMBINING as u64) << shift;
+    pat_msr.write(pat);
+
+    // The SDM asks for caches and TLBs to be flushed after changing the PAT,
+    // so no line is left cached under the old type
+    core::arch::asm!("wbinvd", options(nostack, preserves_flags));
+    tlb::flush_all();
+
+    WRITE_COMBINING_READY.store(true, Ordering::Release);
+    true
+}
+
+/// Whether `init_pat` set up the `WRITE_COMBINING` memory type.
+pub fn write_combining_ready() -> bool {
+    WRITE_COMBINING_READY.load(Ordering::Acquire)
+}
+
+/// The PAT memory type (0x00 UC, 0x01 WC, 0x04 WT, 0x05 WP, 0x06 WB,
+/// 0x07 UC-) a 4 KiB page with `flags` gets.
+pub fn pat_memory_type(flags: PageTableFlags) -> u8 {
+    use x86_64::registers::model_specific::Msr;
+
+    let index = (flags.contains(PTE_PAT) as u64) << 2
+        | (flags.contains(PageTableFlags::NO_CACHE) as u64) << 1
+        | flags.contains(PageTableFlags::WRITE_THROUGH) as u64;
+    let pat = unsafe { Msr::new(IA32_PAT).read() };
+    (pat >> (index * 8)) as u8
+}
+
+/// The kernel's mapper and frame allocator, once boot hands them over with
+/// `install`.
+static KERNEL_MEMORY: Mutex<Option<(OffsetPageTable<'static>, BootInfoFrameAllocator)>> =
+    Mutex::new(None);
+
+/// Makes the mapper and frame allocator reachable from code that can't have
+/// them passed in, like the page fault handler. Call once, when `kernel_main`
+/// is done using them directly.
+pub fn install(mapper: OffsetPageTable<'static>, frame_allocator: BootInfoFrameAllocator) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        *KERNEL_MEMORY.lock() = Some((mapper, frame_allocator));
+    });
+}
+
+/// Runs `f` with the kernel mapper and frame allocator, with interrupts
+/// disabled. Returns `None` if `install` hasn't been called yet.
+pub fn with_kernel_memory<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BootInfoFrameAllocator) -> R,
+) -> Option<R> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        KERNEL_MEMORY.lock().as_mut().map(|(mapper, frame_allocator)| f(mapper, frame_allocator))
+    })
+}
+
+/// Like `with_kernel_memory`, but returns `None` instead of spinning when the
+/// lock is taken. Exception handlers must use this: the code they interrupted
+/// may be the one holding the lock.
+pub fn try_with_kernel_memory<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BootInfoFrameAllocator) -> R,
+) -> Option<R> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        KERNEL_MEMORY.try_lock()?.as_mut().map(|(mapper, frame_allocator)| f(mapper, frame_allocator))
+    })
+}
+
+/// Marks the end of the free frame list.
+const FREE_LIST_END: u64 = u64::MAX;
+
 /// A FrameAllocator that returns usable frames from the bootloader's memory map.
+///
+/// Deallocated frames go to an intrusive free list: each free frame stores
+/// the physical address of the next one in its first 8 bytes, accessed
+/// through the physical memory mapping. Freed frames are handed out again
+/// (most recently freed first) before the memory map is scanned further.
+///
+/// The allocator does no locking of its own. Whoever shares it must wrap it
+/// in a lock and keep interrupts disabled while holding it, so an interrupt
+/// handler can't observe the free list half-updated.
 pub struct BootInfoFrameAllocator {
     memory_regions: &'static MemoryRegions,
-    next: usize,
+    region: usize,  // Index of the memory region frames are taken from
+    offset: u64,    // Offset of the next unused frame inside that region
+    free_list: u64, // Physical address of the first free frame
+    total_frames: usize,     // Usable frames in the memory map
+    allocated_frames: usize, // Frames currently handed out
+    free_frames: usize,      // Frames on the free list
+    reclaimed: Vec<MemoryRegionKind>, // Region kinds already given to `reclaim`
+    refcounts: &'static mut [u16], // Mappings per frame, indexed by frame number
+    refcounts_phys: core::ops::Range<u64>, // Usable memory holding `refcounts`
+}
+
+/// Snapshot of physical memory and heap usage.
+#[derive(Debug, Clone, Copy)]
+pub struct MemStats {
+    /// Usable frames in the bootloader's memory map.
+    pub total_frames: usize,
+    /// Frames handed out and not yet deallocated.
+    pub allocated_frames: usize,
+    /// Frames waiting on the free list.
+    pub free_list_frames: usize,
+    /// Bytes of the kernel heap currently allocated.
+    pub heap_used: usize,
+    /// Total size of the kernel heap in bytes.
+    pub heap_size: usize,
 }
 
+/// Returns current memory usage. Frame counts are zero until `install`.
+pub fn stats() -> MemStats {
+    let (total_frames, allocated_frames, free_list_frames) = with_kernel_memory(|_, frame_allocator| {
+        (frame_allocator.total_frames, frame_allocator.allocated_frames, frame_allocator.free_frames)
+    }).unwrap_or((0, 0, 0));
+    let (heap_used, heap_size) = crate::allocator::heap_usage();
+
+    MemStats { total_frames, allocated_frames, free_list_frames, heap_used, heap_size }
+}
+
+// The memory map is only ever read, and once installed the allocator is only
+// reached through the `KERNEL_MEMORY` lock.
+unsafe impl Send for BootInfoFrameAllocator {}
+
 impl BootInfoFrameAllocator {
     /// Create a FrameAllocator from the passed memory map.
     ///
+    /// The frame reference counts are stored at the start of the first usable
+    /// region large enough for them, reached through the physical memory
+    /// mapping, so `memory::init` must run first.
+    ///
     /// This function is unsafe because the caller must guarantee that the passed
     /// memory map is valid. The main requirement is that all frames that are marked
     /// as `USABLE` in it are really unused.
     pub unsafe fn init(memory_regions: &'static MemoryRegions) -> Self {
+        // One counter for every frame the allocator could ever hand out,
+        // including regions that `reclaim` may add later
+        let top = memory_regions.iter()
+            .filter(|r| r.kind == MemoryRegionKind::Usable
+                || r.kind == MemoryRegionKind::Bootloader
+                || r.kind == ACPI_RECLAIMABLE_UEFI
+                || r.kind == ACPI_RECLAIMABLE_BIOS)
+            .map(|r| r.end)
+            .max()
+            .unwrap_or(0);
+        let frame_count = (top / 4096) as usize;
+        let table_size = align_up((frame_count * core::mem::size_of::<u16>()) as u64, 4096);
+
+        let (refcounts, refcounts_phys) = match memory_regions.iter()
+            .find(|r| r.kind == MemoryRegionKind::Usable && r.end - r.start >= table_size)
+        {
+            Some(region) => {
+                let ptr: *mut u16 = (physical_memory_offset() + region.start).as_mut_ptr();
+                core::ptr::write_bytes(ptr, 0, frame_count);
+                (core::slice::from_raw_parts_mut(ptr, frame_count), region.start..region.start + table_size)
+            }
+            // No room: frames are then freed on their first deallocation
+            None => (&mut [][..], 0..0),
+        };
+
+        let total_frames = memory_regions.iter()
+            .filter(|r| r.kind == MemoryRegionKind::Usable)
+            .map(|r| ((r.end - r.start) / 4096) as usize)
+            .sum::<usize>() - ((refcounts_phys.end - refcounts_phys.start) / 4096) as usize;
+
         BootInfoFrameAllocator {
             memory_regions,
-            next: 0,
+            region: 0,
+            offset: 0,
+            free_list: FREE_LIST_END,
+            total_frames,
+            allocated_frames: 0,
+            free_frames: 0,
+            reclaimed: Vec::new(),
+            refcounts,
+            refcounts_phys,
+        }
+    }
+
+    /// Moves `offset` past the refcount table if it points into it.
+    fn skip_refcounts(&mut self, region_start: u64) {
+        if self.refcounts_phys.contains(&(region_start + self.offset)) {
+            self.offset = self.refcounts_phys.end - region_start;
+        }
+    }
+
+    fn refcount_index(frame: PhysFrame) -> usize {
+        (frame.start_address().as_u64() / 4096) as usize
+    }
+
+    /// Records one more mapping of `frame` and returns the new count.
+    pub fn inc_ref(&mut self, frame: PhysFrame) -> u16 {
+        match self.refcounts.get_mut(Self::refcount_index(frame)) {
+            Some(count) => {
+                *count = count.saturating_add(1);
+                *count
+            }
+            None => 1,
+        }
+    }
+
+    /// Records one mapping of `frame` less and returns the new count. The
+    /// frame is unused once this reaches zero.
+    ///
+    /// Returns `None`, changing nothing, if the count already is zero: the
+    /// frame is free, so whoever gives up a reference to it is freeing it twice.
+    pub fn dec_ref(&mut self, frame: PhysFrame) -> Option<u16> {
+        match self.refcounts.get_mut(Self::refcount_index(frame)) {
+            Some(0) => None,
+            Some(count) => {
+                *count -= 1;
+                Some(*count)
+            }
+            None => Some(0),
+        }
+    }
+
+    /// Number of mappings of `frame`.
+    pub fn ref_count(&self, frame: PhysFrame) -> u16 {
+        self.refcounts.get(Self::refcount_index(frame)).copied().unwrap_or(0)
+    }
+
+    fn set_ref(&mut self, frame: PhysFrame, count: u16) {
+        if let Some(slot) = self.refcounts.get_mut(Self::refcount_index(frame)) {
+            *slot = count;
+        }
+    }
+
+    /// Hands out the next never-used frame from the usable regions of the
+    /// memory map, in address order.
+    ///
+    /// The position in the memory map is kept between calls, so this runs in
+    /// constant time instead of rescanning the map from the start.
+    fn next_usable_frame(&mut self) -> Option<PhysFrame> {
+        while let Some(region) = self.memory_regions.get(self.region) {
+            if region.kind == MemoryRegionKind::Usable {
+                self.skip_refcounts(region.start);
+                let addr = region.start + self.offset;
+                if addr < region.end {
+                    self.offset += 4096;
+                    return Some(PhysFrame::containing_address(PhysAddr::new(addr)));
+                }
+            }
+            // Region exhausted (or not usable): move on to the next one
+            self.region += 1;
+            self.offset = 0;
         }
+        None
     }
 
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        // get usable regions from memory map
-        let regions = self.memory_regions.iter();
-        let usable_regions = regions
-            .filter(|r| r.kind == MemoryRegionKind::Usable);
-        // map each region to its address range
-        let addr_ranges = usable_regions
-            .map(|r| r.start..r.end);
-        // transform to an iterator of frame start addresses
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        // create `PhysFrame` types from the start addresses
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    /// Drops one reference to `frame`; when that was the last one, the frame
+    /// goes back onto the free list.
+    ///
+    /// This function is unsafe because the caller must guarantee that the
+    /// frame came from this allocator and that the reference it gives up is
+    /// no longer mapped or used anywhere; once freed, its first 8 bytes are
+    /// overwritten.
+    pub unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        match self.dec_ref(frame) {
+            Some(0) => {}
+            Some(_) => return,
+            None => {
+                error!("Frame {:#x} freed twice; ignoring", frame.start_address().as_u64());
+                return;
+            }
+        }
+        self.push_free_frame(frame);
+        self.allocated_frames = self.allocated_frames.saturating_sub(1);
+    }
+
+    /// Puts a frame on the free list without counting it as deallocated,
+    /// for frames that were never handed out.
+    unsafe fn push_free_frame(&mut self, frame: PhysFrame) {
+        free_list_link(frame).write(self.free_list);
+        self.free_list = frame.start_address().as_u64();
+        self.free_frames += 1;
+    }
+
+    fn pop_free_frame(&mut self) -> Option<PhysFrame> {
+        if self.free_list == FREE_LIST_END {
+            return None;
+        }
+        let frame = PhysFrame::containing_address(PhysAddr::new(self.free_list));
+        self.free_list = unsafe { free_list_link(frame).read() };
+        self.free_frames -= 1;
+        Some(frame)
+    }
+
+    /// Whether the usable frame at `addr`, behind the cursor of
+    /// `next_usable_frame`, is on the free list.
+    fn is_free(&self, addr: u64) -> bool {
+        if self.refcounts_phys.contains(&addr) {
+            return false;
+        }
+        let frame = PhysFrame::containing_address(PhysAddr::new(addr));
+        !self.refcounts.is_empty() && self.ref_count(frame) == 0
+    }
+
+    /// Walks the free list looking for `frame`. Linear, so only for checks.
+    fn on_free_list(&self, frame: PhysFrame) -> bool {
+        let mut link = self.free_list;
+        while link != FREE_LIST_END {
+            if link == frame.start_address().as_u64() {
+                return true;
+            }
+            link = unsafe { free_list_link(PhysFrame::containing_address(PhysAddr::new(link))).read() };
+        }
+        false
+    }
+
+    /// Puts the frames in `range`, which the cursor is about to move past
+    /// without handing out, on the free list. The refcount table is skipped.
+    unsafe fn push_fresh_frames(&mut self, range: core::ops::Range<u64>) {
+        for addr in range.step_by(4096) {
+            if !self.refcounts_phys.contains(&addr) {
+                self.push_free_frame(PhysFrame::containing_address(PhysAddr::new(addr)));
+            }
+        }
+    }
+
+    /// Takes `size` bytes of never-used frames at the cursor, starting at the
+    /// next multiple of `align`, moves the cursor past them and returns the
+    /// first address. Frames skipped to reach the alignment, or left at the
+    /// end of a region too small for the run, go onto the free list.
+    fn take_at_cursor(&mut self, size: u64, align: u64) -> Option<u64> {
+        while let Some(region) = self.memory_regions.get(self.region) {
+            if region.kind == MemoryRegionKind::Usable {
+                self.skip_refcounts(region.start);
+                let addr = region.start + self.offset;
+                let start = align_up(addr, align);
+                let fits = start + size <= region.end;
+
+                let table = self.refcounts_phys.clone();
+                if fits && table.start < start + size && start < table.end {
+                    // The refcount table is in the way; retry past it
+                    unsafe { self.push_fresh_frames(addr..table.start) };
+                    self.offset = table.end - region.start;
+                    continue;
+                }
+                if fits {
+                    unsafe { self.push_fresh_frames(addr..start) };
+                    self.offset = start + size - region.start;
+                    return Some(start);
+                }
+                unsafe { self.push_fresh_frames(addr..region.end) };
+            }
+            self.region += 1;
+            self.offset = 0;
+        }
+        None
+    }
+
+    /// Allocates `count` physically consecutive frames, the first of them
+    /// aligned to `align` bytes (a power of two), and returns the first one.
+    ///
+    /// Runs of free-list frames behind the cursor of `next_usable_frame` are
+    /// tried first, lowest address first; this needs the reference counts to
+    /// tell them apart. Otherwise the run is carved out at the cursor, which
+    /// then moves past it, so no frame is ever handed out twice. Each frame
+    /// is deallocated on its own.
+    pub fn allocate_contiguous(&mut self, count: usize, align: u64) -> Option<PhysFrame> {
+        if count == 0 || !align.is_power_of_two() {
+            return None;
+        }
+        let align = align.max(4096);
+        let size = count as u64 * 4096;
+
+        let mut found = None;
+        'regions: for (index, region) in self.memory_regions.iter().enumerate() {
+            if index > self.region {
+                break;
+            }
+            if region.kind != MemoryRegionKind::Usable {
+                continue;
+            }
+            let limit = if index == self.region { region.start + self.offset } else { region.end };
+            let mut start = align_up(region.start, align);
+            while start + size <= limit {
+                match (start..start + size).step_by(4096).find(|&addr| !self.is_free(addr)) {
+                    None => {
+                        found = Some(start);
+                        break 'regions;
+                    }
+                    // Restart the run past the frame in use
+                    Some(busy) => start = align_up(busy + 4096, align),
+                }
+            }
+        }
+
+        let start = match found {
+            Some(start) => {
+                self.unlink_free_frames(&(start..start + size));
+                start
+            }
+            None => self.take_at_cursor(size, align)?,
+        };
+        for addr in (start..start + size).step_by(4096) {
+            self.set_ref(PhysFrame::containing_address(PhysAddr::new(addr)), 1);
+        }
+        self.allocated_frames += count;
+        Some(PhysFrame::containing_address(PhysAddr::new(start)))
+    }
+
+    /// Takes every frame inside `range` off the free list.
+    fn unlink_free_frames(&mut self, range: &core::ops::Range<u64>) {
+        let mut removed = 0;
+        let mut link: *mut u64 = &mut self.free_list;
+        unsafe {
+            while *link != FREE_LIST_END {
+                let frame = PhysFrame::containing_address(PhysAddr::new(*link));
+                if range.contains(&*link) {
+                    *link = free_list_link(frame).read();
+                    removed += 1;
+                } else {
+                    link = free_list_link(frame);
+                }
+            }
+        }
+        self.free_frames -= removed;
     }
 }
 
+/// ACPI-reclaimable memory, as reported by UEFI and by the BIOS E820 map.
+pub const ACPI_RECLAIMABLE_UEFI: MemoryRegionKind = MemoryRegionKind::UnknownUefi(9);
+pub const ACPI_RECLAIMABLE_BIOS: MemoryRegionKind = MemoryRegionKind::UnknownBios(3);
+
+/// Hands the frames of every memory region of `kind` to the allocator and
+/// returns how many were added.
+///
+/// Meant for memory that is only needed during boot: `Bootloader` regions and
+/// the ACPI-reclaimable kinds above. Frames that are still mapped anywhere in
+/// the active page tables, or hold a page table, are skipped; that keeps the
+/// kernel image, its stack, the boot info and the page tables themselves.
+/// Reclaiming the same kind twice does nothing.
+///
+/// This function is unsafe because the caller must guarantee that nothing
+/// still reaches memory of that kind through the physical memory mapping
+/// (e.g. ACPI tables must be parsed by now).
+pub unsafe fn reclaim(frame_allocator: &mut BootInfoFrameAllocator, kind: MemoryRegionKind) -> usize {
+    if kind == MemoryRegionKind::Usable || frame_allocator.reclaimed.contains(&kind) {
+        return 0;
+    }
+    frame_allocator.reclaimed.push(kind);
+
+    let in_use = frames_in_use(frame_allocator.memory_regions);
+    let is_in_use = |addr: u64| {
+        let index = in_use.partition_point(|&(_, end)| end <= addr);
+        in_use.get(index).is_some_and(|&(start, _)| start <= addr)
+    };
+
+    let mut reclaimed = 0;
+    for region in frame_allocator.memory_regions.iter().filter(|r| r.kind == kind) {
+        let start = align_up(region.start, 4096);
+        for addr in (start..region.end & !0xFFF).step_by(4096) {
+            if !is_in_use(addr) {
+                frame_allocator.push_free_frame(PhysFrame::containing_address(PhysAddr::new(addr)));
+                reclaimed += 1;
+            }
+        }
+    }
+    frame_allocator.total_frames += reclaimed;
+    reclaimed
+}
+
+/// Physical ranges referenced by the active page tables (the tables included),
+/// sorted and merged. The physical memory mapping itself is left out, since
+/// it covers everything.
+fn frames_in_use(memory_regions: &MemoryRegions) -> Vec<(u64, u64)> {
+    use x86_64::registers::control::Cr3;
+
+    let offset = physical_memory_offset().as_u64();
+    let physical_end = memory_regions.iter().map(|r| r.end).max().unwrap_or(0);
+    let window = offset..offset.saturating_add(physical_end);
+
+    let mut ranges = Vec::new();
+    let (level_4_table_frame, _) = Cr3::read();
+    collect_table(level_4_table_frame.start_address().as_u64(), 4, 0, &window, &mut ranges);
+
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// Records the table at `table_addr` and everything it maps below `level`.
+fn collect_table(
+    table_addr: u64,
+    level: u8,
+    virt_base: u64,
+    window: &core::ops::Range<u64>,
+    ranges: &mut Vec<(u64, u64)>,
+) {
+    ranges.push((table_addr, table_addr + 4096));
+
+    let table = unsafe { &*(physical_memory_offset() + table_addr).as_ptr::<PageTable>() };
+    let entry_size = 1u64 << (12 + 9 * (level as u64 - 1));
+    for (index, entry) in table.iter().enumerate() {
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        // Canonical form, so the upper half compares correctly with the window
+        let virt = VirtAddr::new_truncate(virt_base + index as u64 * entry_size).as_u64();
+        if window.start <= virt && virt + (entry_size - 1) < window.end {
+            continue;
+        }
+
+        let addr = entry.addr().as_u64();
+        if level > 1 && !entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            collect_table(addr, level - 1, virt, window, ranges);
+        } else {
+            ranges.push((addr, addr + entry_size));
+        }
+    }
+}
+
+/// Allocates a frame and fills it with zeros through the physical memory
+/// mapping, so nothing from its previous owner leaks into the new one.
+pub fn allocate_frame_zeroed(frame_allocator: &mut impl FrameAllocator<Size4KiB>) -> Option<PhysFrame> {
+    let frame = frame_allocator.allocate_frame()?;
+    unsafe { zero_frame(frame) };
+    Some(frame)
+}
+
+/// Fills `frame` with zeros.
+///
+/// This function is unsafe because the caller must own the frame.
+unsafe fn zero_frame<S: PageSize>(frame: PhysFrame<S>) {
+    let ptr: *mut u8 = (physical_memory_offset() + frame.start_address().as_u64()).as_mut_ptr();
+    core::ptr::write_bytes(ptr, 0, S::SIZE as usize);
+}
+
+/// Returns a pointer to the free-list link stored at the start of `frame`.
+fn free_list_link(frame: PhysFrame) -> *mut u64 {
+    (physical_memory_offset() + frame.start_address().as_u64()).as_mut_ptr()
+}
+
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+        let frame = self.pop_free_frame().or_else(|| self.next_usable_frame())?;
+        self.allocated_frames += 1;
+        self.set_ref(frame, 1);
+        Some(frame)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        BootInfoFrameAllocator::deallocate_frame(self, frame)
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BootInfoFrameAllocator {
+    /// Takes the next 2 MiB-aligned run of never-used frames from the memory
+    /// map. The free list only holds scattered 4 KiB frames, so it's not used
+    /// here; instead, frames skipped over to reach the alignment (or left at
+    /// the end of a region too small for a huge frame) are pushed onto it.
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let start = self.take_at_cursor(Size2MiB::SIZE, Size2MiB::SIZE)?;
+        self.allocated_frames += (Size2MiB::SIZE / Size4KiB::SIZE) as usize;
+        for addr in (start..start + Size2MiB::SIZE).step_by(4096) {
+            self.set_ref(PhysFrame::containing_address(PhysAddr::new(addr)), 1);
+        }
+        Some(PhysFrame::containing_address(PhysAddr::new(start)))
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BootInfoFrameAllocator {
+    /// Splits the huge frame into 4 KiB frames on the free list.
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        let start = frame.start_address().as_u64();
+        for addr in (start..start + Size2MiB::SIZE).step_by(4096) {
+            BootInfoFrameAllocator::deallocate_frame(self, PhysFrame::containing_address(PhysAddr::new(addr)));
+        }
+    }
+}
+
+/// Prints the page table walk for `addr`: at every level, the table index,
+/// the entry's physical address and its flags.
+///
+/// The walk stops at the first entry that isn't present or that maps a huge
+/// page. Meant for debugging, e.g. from the page fault handler.
+pub fn dump_mapping(mapper: &OffsetPageTable, addr: VirtAddr) {
+    let table_indexes = [
+        addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()
+    ];
+    let mut table: &PageTable = mapper.level_4_table();
+
+    kprintln!("Page walk for {:#x}:", addr.as_u64());
+    for (level, &index) in table_indexes.iter().enumerate() {
+        let level = 4 - level;
+        let entry = &table[index];
+        if entry.is_unused() {
+            kprintln!("  P{}[{}]: empty", level, u16::from(index));
+            return;
+        }
+        kprintln!(
+            "  P{}[{}]: {:#x} {:?}",
+            level,
+            u16::from(index),
+            entry.addr().as_u64(),
+            entry.flags()
+        );
+
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            kprintln!("  not present");
+            return;
+        }
+        if level == 1 {
+            return;
+        }
+        if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            kprintln!("  {} page", if level == 3 { "1 GiB" } else { "2 MiB" });
+            return;
+        }
+
+        let virt = mapper.phys_offset() + entry.addr().as_u64();
+        table = unsafe { &*virt.as_ptr() };
     }
 }
 
@@ -132,33 +832,658 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
     &mut *page_table_ptr
 }
 
-/// Map `[start_addr, start_addr + size)` 1:1 to freshly-allocated frames.
+/// Why `allocate_pages_mapper` could not map a region.
+#[derive(Debug)]
+pub enum AllocatePagesError {
+    /// `page` was already mapped, but with `flags` instead of the requested ones.
+    /// Nothing was allocated or mapped.
+    FlagsMismatch { page: Page, flags: PageTableFlags },
+    /// Mapping `page` failed. Pages mapped earlier by the same call were
+    /// unmapped again and their frames returned to the allocator.
+    MapFailed { page: Page, error: MapToError<Size4KiB> },
+    /// Mapping the 2 MiB `page` failed (see `allocate_huge_pages_mapper`).
+    HugeMapFailed { page: Page<Size2MiB>, error: MapToError<Size2MiB> },
+}
+
+/// Flags the CPU sets on its own, which don't count when comparing mappings.
+fn comparable_flags(flags: PageTableFlags) -> PageTableFlags {
+    flags - (PageTableFlags::ACCESSED | PageTableFlags::DIRTY | PageTableFlags::HUGE_PAGE)
+}
+
+/// Map `[start_addr, start_addr + size)` 1:1 to freshly-allocated, zeroed frames.
 ///
 /// - `mapper` is your OffsetPageTable (implements Mapper<Size4KiB>)
 /// - `frame_allocator` is your BootInfoFrameAllocator
 /// - `flags` are the page flags (e.g. PRESENT | WRITABLE | USER_ACCESSIBLE)
+///
+/// Pages in the range that are already mapped with the same flags are left
+/// alone, so calling this twice for overlapping regions is fine. The call is
+/// all-or-nothing otherwise: a page mapped with different flags is reported
+/// before anything is touched, and a mapping failure undoes the pages this
+/// call had mapped so far. Intermediate page tables created along the way
+/// are kept.
 pub fn allocate_pages_mapper(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    mapper: &mut (impl Mapper<Size4KiB> + Translate),
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
     start_addr: VirtAddr,
     size: u64,
     flags: PageTableFlags,
-) -> Result<(), MapToError<Size4KiB>> {
+) -> Result<(), AllocatePagesError> {
     // Compute the inclusive page range for the region
     let end_addr = start_addr + size - 1;
     let start_page = Page::containing_address(start_addr);
     let end_page   = Page::containing_address(end_addr);
+    let page_range = Page::range_inclusive(start_page, end_page);
+
+    // Check existing mappings first so a conflict doesn't leave the region
+    // half-mapped
+    for page in page_range {
+        if let TranslateResult::Mapped { flags: existing, .. } = mapper.translate(page.start_address()) {
+            if comparable_flags(existing) != comparable_flags(flags) {
+                return Err(AllocatePagesError::FlagsMismatch { page, flags: existing });
+            }
+        }
+    }
+
+    let mut mapped_pages = Vec::new();
+    for page in page_range {
+        if let TranslateResult::Mapped { .. } = mapper.translate(page.start_address()) {
+            continue;
+        }
 
-    for page in Page::range_inclusive(start_page, end_page) {
         // Allocate a frame and map it
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        // map_to returns a MapperFlush for this page
+        let result = match allocate_frame_zeroed(frame_allocator) {
+            Some(frame) => {
+                // map_to returns a MapperFlush for this page
+                let result = unsafe { mapper.map_to(page, frame, flags, frame_allocator) };
+                if result.is_err() {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                }
+                result
+            }
+            None => Err(MapToError::FrameAllocationFailed),
+        };
+
+        match result {
+            Ok(flush) => {
+                flush.flush();
+                mapped_pages.push(page);
+            }
+            Err(error) => {
+                for mapped in mapped_pages {
+                    if let Ok((frame, flush)) = mapper.unmap(mapped) {
+                        flush.flush();
+                        unsafe { frame_allocator.deallocate_frame(frame) };
+                    }
+                }
+                return Err(AllocatePagesError::MapFailed { page, error });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The pages of `[start_addr, start_addr + size)` that aren't mapped yet.
+fn unmapped_pages(mapper: &impl Translate, start_addr: VirtAddr, size: u64) -> Vec<Page> {
+    if size == 0 {
+        return Vec::new();
+    }
+    let start_page: Page = Page::containing_address(start_addr);
+    let end_page: Page = Page::containing_address(start_addr + size - 1);
+    Page::range_inclusive(start_page, end_page)
+        .filter(|page| matches!(mapper.translate(page.start_address()), TranslateResult::NotMapped))
+        .collect()
+}
+
+/// Like `allocate_pages_mapper`, but maps the 2 MiB-aligned part of the
+/// region with 2 MiB pages. The unaligned head and tail, and any 2 MiB chunk
+/// that already has something mapped in it, fall back to 4 KiB pages.
+///
+/// On failure, everything this call mapped is unmapped and freed again;
+/// pages that were mapped before the call are left alone.
+pub fn allocate_huge_pages_mapper<M, A>(
+    mapper: &mut M,
+    frame_allocator: &mut A,
+    start_addr: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), AllocatePagesError>
+where
+    M: Mapper<Size4KiB> + Mapper<Size2MiB> + Translate,
+    A: FrameAllocator<Size4KiB> + FrameAllocator<Size2MiB>
+        + FrameDeallocator<Size4KiB> + FrameDeallocator<Size2MiB>,
+{
+    let end_addr = start_addr + size;
+    let huge_start = start_addr.align_up(Size2MiB::SIZE);
+    let huge_end = end_addr.align_down(Size2MiB::SIZE);
+    if huge_start >= huge_end {
+        return allocate_pages_mapper(mapper, frame_allocator, start_addr, size, flags);
+    }
+
+    // Pages this call maps, to undo them if a later part fails
+    let mut small_pages: Vec<Page> = Vec::new();
+    let mut huge_pages: Vec<Page<Size2MiB>> = Vec::new();
+
+    let head_size = huge_start - start_addr;
+    let mut result = if head_size > 0 {
+        let fresh = unmapped_pages(&*mapper, start_addr, head_size);
+        allocate_pages_mapper(mapper, frame_allocator, start_addr, head_size, flags)
+            .map(|()| small_pages.extend(fresh))
+    } else {
+        Ok(())
+    };
+
+    let huge_range = Page::range(Page::containing_address(huge_start), Page::containing_address(huge_end));
+    for page in huge_range {
+        if result.is_err() {
+            break;
+        }
+        let fresh = unmapped_pages(&*mapper, page.start_address(), Size2MiB::SIZE);
+        if fresh.len() as u64 != Size2MiB::SIZE / Size4KiB::SIZE {
+            result = allocate_pages_mapper(mapper, frame_allocator, page.start_address(), Size2MiB::SIZE, flags)
+                .map(|()| small_pages.extend(fresh));
+            continue;
+        }
+        result = match FrameAllocator::<Size2MiB>::allocate_frame(frame_allocator) {
+            Some(frame) => {
+                unsafe { zero_frame(frame) };
+                let mapped = unsafe {
+                    mapper.map_to(page, frame, flags | PageTableFlags::HUGE_PAGE, frame_allocator)
+                };
+                match mapped {
+                    Ok(flush) => {
+                        flush.flush();
+                        huge_pages.push(page);
+                        Ok(())
+                    }
+                    Err(error) => {
+                        unsafe { FrameDeallocator::<Size2MiB>::deallocate_frame(frame_allocator, frame) };
+                        Err(AllocatePagesError::HugeMapFailed { page, error })
+                    }
+                }
+            }
+            None => Err(AllocatePagesError::HugeMapFailed { page, error: MapToError::FrameAllocationFailed }),
+        };
+    }
+
+    // The tail rolls itself back, like any `allocate_pages_mapper` call
+    if result.is_ok() && end_addr > huge_end {
+        result = allocate_pages_mapper(mapper, frame_allocator, huge_end, end_addr - huge_end, flags);
+    }
+
+    if result.is_err() {
+        for page in huge_pages {
+            if let Ok((frame, flush)) = Mapper::<Size2MiB>::unmap(mapper, page) {
+                flush.flush();
+                unsafe { FrameDeallocator::<Size2MiB>::deallocate_frame(frame_allocator, frame) };
+            }
+        }
+        for page in small_pages {
+            if let Ok((frame, flush)) = Mapper::<Size4KiB>::unmap(mapper, page) {
+                flush.flush();
+                unsafe { FrameDeallocator::<Size4KiB>::deallocate_frame(frame_allocator, frame) };
+            }
+        }
+    }
+    result
+}
+
+/// Unmap `[start_addr, start_addr + size)` and give the frames back to
+/// `frame_allocator`. The counterpart of `allocate_pages_mapper` and
+/// `allocate_huge_pages_mapper`.
+///
+/// Pages in the range that aren't mapped are skipped. A 2 MiB page is
+/// unmapped as a whole, so it must lie entirely inside the range; otherwise
+/// this fails with `UnmapError::ParentEntryHugePage`. Returns how many pages
+/// (of either size) were actually unmapped; on error, the pages before the
+/// failing one have already been unmapped and freed.
+pub fn free_pages_mapper<M, A>(
+    mapper: &mut M,
+    frame_allocator: &mut A,
+    start_addr: VirtAddr,
+    size: u64,
+) -> Result<usize, UnmapError>
+where
+    M: Mapper<Size4KiB> + Mapper<Size2MiB>,
+    A: FrameDeallocator<Size4KiB> + FrameDeallocator<Size2MiB>,
+{
+    if size == 0 {
+        return Ok(0);
+    }
+
+    let end_addr = start_addr + size - 1;
+    let mut page: Page = Page::containing_address(start_addr);
+    let end_page: Page = Page::containing_address(end_addr);
+
+    let mut unmapped = 0;
+    while page <= end_page {
+        match Mapper::<Size4KiB>::unmap(mapper, page) {
+            Ok((frame, flush)) => {
+                flush.flush();
+                unsafe { FrameDeallocator::<Size4KiB>::deallocate_frame(frame_allocator, frame) };
+                unmapped += 1;
+            }
+            Err(UnmapError::PageNotMapped) => {}
+            Err(UnmapError::ParentEntryHugePage) => {
+                let huge_page: Page<Size2MiB> = Page::containing_address(page.start_address());
+                let huge_last = huge_page.start_address() + (Size2MiB::SIZE - 1);
+                if huge_page.start_address() < start_addr.align_down(Size4KiB::SIZE) || huge_last > end_addr {
+                    return Err(UnmapError::ParentEntryHugePage);
+                }
+                let (frame, flush) = Mapper::<Size2MiB>::unmap(mapper, huge_page)?;
+                flush.flush();
+                unsafe { FrameDeallocator::<Size2MiB>::deallocate_frame(frame_allocator, frame) };
+                unmapped += 1;
+                page = Page::containing_address(huge_last);
+            }
+            Err(error) => return Err(error),
+        }
+        page += 1;
+    }
+    Ok(unmapped)
+}
+
+/// Virtual address `huge_page_self_test` maps its scratch region at. Nothing
+/// else lives in this part of the address space.
+const HUGE_PAGE_TEST_BASE: u64 = 0x_5555_0000_0000;
+
+/// Maps a region with a one-page head, two 2 MiB pages and a one-page tail
+/// through `allocate_huge_pages_mapper`, checks that an address in the
+/// middle is backed by a 2 MiB frame at the right offset, and frees it all
+/// again with `free_pages_mapper`.
+///
+/// Run once at boot, after `install`. Returns `false` on any mismatch or if
+/// kernel memory isn't installed.
+pub fn huge_page_self_test() -> bool {
+    with_kernel_memory(|mapper, frame_allocator| {
+        let start = VirtAddr::new(HUGE_PAGE_TEST_BASE + Size2MiB::SIZE - Size4KiB::SIZE);
+        let size = 2 * Size4KiB::SIZE + 2 * Size2MiB::SIZE;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        if allocate_huge_pages_mapper(mapper, frame_allocator, start, size, flags).is_err() {
+            return false;
+        }
+
+        let probe = VirtAddr::new(HUGE_PAGE_TEST_BASE + 2 * Size2MiB::SIZE + 0x1234 * 8);
+        let backed_by_huge_frame = match mapper.translate(probe) {
+            TranslateResult::Mapped { frame: MappedFrame::Size2MiB(frame), offset, .. } => {
+                unsafe { probe.as_mut_ptr::<u64>().write_volatile(0x_A0A0_5050_A0A0_5050) };
+                let phys = frame.start_address() + offset;
+                let seen = unsafe { (physical_memory_offset() + phys.as_u64()).as_ptr::<u64>().read_volatile() };
+                offset == probe.as_u64() % Size2MiB::SIZE && seen == 0x_A0A0_5050_A0A0_5050
+            }
+            _ => false,
+        };
+
+        let freed = free_pages_mapper(mapper, frame_allocator, start, size);
+        let all_unmapped = [start, probe, start + (size - 1)].iter()
+            .all(|&addr| matches!(mapper.translate(addr), TranslateResult::NotMapped));
+        backed_by_huge_frame && matches!(freed, Ok(4)) && all_unmapped
+    }).unwrap_or(false)
+}
+
+/// Allocates 16 contiguous frames aligned to 64 KiB, checks they're
+/// consecutive, aligned, counted once and off the free list, and that the
+/// next single frame and huge frame handed out don't land inside the run.
+/// Then frees everything again.
+///
+/// Run once at boot, after `install`. Returns `false` on any mismatch or if
+/// kernel memory isn't installed.
+pub fn contiguous_self_test() -> bool {
+    const COUNT: usize = 16;
+    const ALIGN: u64 = 0x1_0000;
+
+    with_kernel_memory(|_, frame_allocator| {
+        let Some(first) = frame_allocator.allocate_contiguous(COUNT, ALIGN) else {
+            return false;
+        };
+        let start = first.start_address().as_u64();
+        let run = start..start + COUNT as u64 * Size4KiB::SIZE;
+        let frames = || PhysFrame::range(first, first + COUNT as u64);
+
+        let aligned = start % ALIGN == 0;
+        let consecutive = frames().enumerate()
+            .all(|(i, frame)| frame.start_address().as_u64() == start + i as u64 * Size4KiB::SIZE);
+        let owned = frames().all(|frame| frame_allocator.ref_count(frame) == 1 && !frame_allocator.on_free_list(frame));
+
+        let single: Option<PhysFrame> = frame_allocator.allocate_frame();
+        let huge: Option<PhysFrame<Size2MiB>> = frame_allocator.allocate_frame();
+        let disjoint = single.is_some_and(|frame| !run.contains(&frame.start_address().as_u64()))
+            && huge.is_some_and(|frame| {
+                let huge_start = frame.start_address().as_u64();
+                huge_start >= run.end || huge_start + Size2MiB::SIZE <= run.start
+            });
+
         unsafe {
-            mapper.map_to(page, frame, flags, frame_allocator)?
-                  .flush();
+            if let Some(frame) = single {
+                frame_allocator.deallocate_frame(frame);
+            }
+            if let Some(frame) = huge {
+                FrameDeallocator::<Size2MiB>::deallocate_frame(frame_allocator, frame);
+            }
+            for frame in frames() {
+                frame_allocator.deallocate_frame(frame);
+            }
+        }
+        let released = frames().all(|frame| frame_allocator.ref_count(frame) == 0 && frame_allocator.on_free_list(frame));
+        aligned && consecutive && owned && disjoint && released
+    }).unwrap_or(false)
+}
+
+/// Software-available page table bit marking a page as copy-on-write.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Makes the writable pages in `[start_addr, start_addr + size)` copy-on-write:
+/// they become read-only and get the `COPY_ON_WRITE` bit, so the first write
+/// faults and `handle_cow_fault` gives the writer a private copy.
+///
+/// Every frame marked gets one more reference, which belongs to the second
+/// mapping the caller shares it into (the child's, after a fork). Until that
+/// mapping is made, a write still copies the frame.
+///
+/// Unmapped and already read-only pages are left alone.
+pub fn mark_cow(
+    mapper: &mut (impl Mapper<Size4KiB> + Translate),
+    frame_allocator: &mut BootInfoFrameAllocator,
+    start_addr: VirtAddr,
+    size: u64,
+) -> Result<(), FlagUpdateError> {
+    let end_addr = start_addr + size - 1;
+    let start_page = Page::containing_address(start_addr);
+    let end_page   = Page::containing_address(end_addr);
+
+    for page in Page::range_inclusive(start_page, end_page) {
+        if let TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. } = mapper.translate(page.start_address()) {
+            if flags.contains(PageTableFlags::WRITABLE) {
+                let cow_flags = (comparable_flags(flags) - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                unsafe { mapper.update_flags(page, cow_flags)?.flush() };
+                frame_allocator.inc_ref(frame);
+            }
         }
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Resolves a write fault on a copy-on-write page at `addr`: the page gets a
+/// fresh frame holding a copy of the old contents, mapped writable.
+///
+/// If no other mapping shares the frame any more, it is simply made writable
+/// again. Otherwise the copy takes over this mapping's reference to the old
+/// frame.
+///
+/// Returns `false` if `addr` isn't a copy-on-write page or the copy couldn't
+/// be made; the fault is then a real one.
+pub fn handle_cow_fault(addr: VirtAddr) -> bool {
+    try_with_kernel_memory(|mapper, frame_allocator| break_cow(mapper, frame_allocator, addr))
+        .unwrap_or(false)
+}
+
+/// The work of `handle_cow_fault`, for callers that already hold the kernel
+/// mapper and frame allocator.
+pub fn break_cow(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    addr: VirtAddr,
+) -> bool {
+    let page: Page = Page::containing_address(addr);
+    let (old_frame, flags) = match mapper.translate(page.start_address()) {
+        TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. }
+            if flags.contains(COPY_ON_WRITE) => (frame, flags),
+        _ => return false,
+    };
+
+    let new_flags = (comparable_flags(flags) - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+    if frame_allocator.ref_count(old_frame) <= 1 {
+        return match unsafe { mapper.update_flags(page, new_flags) } {
+            Ok(flush) => {
+                flush.flush();
+                true
+            }
+            Err(_) => false,
+        };
+    }
+
+    let Some(new_frame) = frame_allocator.allocate_frame() else {
+        return false;
+    };
+    unsafe {
+        let src: *const u8 = (physical_memory_offset() + old_frame.start_address().as_u64()).as_ptr();
+        let dst: *mut u8 = (physical_memory_offset() + new_frame.start_address().as_u64()).as_mut_ptr();
+        core::ptr::copy_nonoverlapping(src, dst, 4096);
+    }
+
+    let remapped = match mapper.unmap(page) {
+        Ok((_, flush)) => {
+            flush.flush();
+            let mapped = unsafe { mapper.map_to(page, new_frame, new_flags, frame_allocator) };
+            match mapped {
+                Ok(flush) => {
+                    flush.flush();
+                    true
+                }
+                Err(_) => {
+                    // Put the shared frame back so the page isn't lost
+                    if let Ok(flush) = unsafe { mapper.map_to(page, old_frame, comparable_flags(flags), frame_allocator) } {
+                        flush.flush();
+                    }
+                    false
+                }
+            }
+        }
+        Err(_) => false,
+    };
+    unsafe {
+        if remapped {
+            frame_allocator.deallocate_frame(old_frame);
+        } else {
+            frame_allocator.deallocate_frame(new_frame);
+        }
+    }
+    remapped
+}
+
+/// Virtual address `cow_self_test` maps its two scratch pages at.
+const COW_TEST_BASE: u64 = 0x_5555_4000_0000;
+
+/// Plays out a fork: a page is marked copy-on-write and its frame mapped a
+/// second time, as the child's copy. The parent then writes, which must give
+/// it a private frame while the child keeps the old contents; the child's
+/// own write afterwards must reuse the frame in place, since nothing shares
+/// it any more.
+///
+/// Run once at boot, after `install`. Returns `false` on any mismatch or if
+/// kernel memory isn't installed.
+pub fn cow_self_test() -> bool {
+    const BEFORE_FORK: u64 = 0x_1111_1111_1111_1111;
+    const PARENT_WRITE: u64 = 0x_2222_2222_2222_2222;
+
+    let parent = VirtAddr::new(COW_TEST_BASE);
+    let child = parent + Size4KiB::SIZE;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+    let shared = with_kernel_memory(|mapper, frame_allocator| {
+        allocate_pages_mapper(mapper, frame_allocator, parent, Size4KiB::SIZE, flags).ok()?;
+        unsafe { parent.as_mut_ptr::<u64>().write_volatile(BEFORE_FORK) };
+        mark_cow(mapper, frame_allocator, parent, Size4KiB::SIZE).ok()?;
+        let TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags: cow_flags, .. } = mapper.translate(parent) else {
+            return None;
+        };
+        let mapped = unsafe { mapper.map_to(Page::containing_address(child), frame, comparable_flags(cow_flags), frame_allocator) };
+        mapped.ok()?.flush();
+        Some(frame)
+    }).flatten();
+    let Some(shared) = shared else {
+        return false;
+    };
+
+    let parent_copied = handle_cow_fault(parent);
+    if parent_copied {
+        unsafe { parent.as_mut_ptr::<u64>().write_volatile(PARENT_WRITE) };
+    }
+    let child_reused = handle_cow_fault(child);
+
+    with_kernel_memory(|mapper, frame_allocator| {
+        let frame_of = |addr: VirtAddr| match mapper.translate(addr) {
+            TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. }
+                if flags.contains(PageTableFlags::WRITABLE) => Some(frame),
+            _ => None,
+        };
+        let diverged = parent_copied && child_reused
+            && frame_of(child) == Some(shared)
+            && frame_of(parent).is_some_and(|frame| frame != shared)
+            && frame_allocator.ref_count(shared) == 1
+            && unsafe { parent.as_ptr::<u64>().read_volatile() } == PARENT_WRITE
+            && unsafe { child.as_ptr::<u64>().read_volatile() } == BEFORE_FORK;
+        let freed = free_pages_mapper(mapper, frame_allocator, parent, 2 * Size4KiB::SIZE);
+        diverged && matches!(freed, Ok(2))
+    }).unwrap_or(false)
+}
+
+/// Takes a frame, adds a second reference, and frees it twice: the first
+/// free must only drop the count, the second must put the frame back on the
+/// free list and undo the allocation in the counters.
+///
+/// Run once at boot, after `install`.
+pub fn refcount_self_test() -> bool {
+    with_kernel_memory(|_, frame_allocator| {
+        let (allocated, free) = (frame_allocator.allocated_frames, frame_allocator.free_frames);
+        let Some(frame) = FrameAllocator::<Size4KiB>::allocate_frame(frame_allocator) else {
+            return false;
+        };
+        let shared = frame_allocator.ref_count(frame) == 1 && frame_allocator.inc_ref(frame) == 2;
+
+        unsafe { frame_allocator.deallocate_frame(frame) };
+        let still_held = frame_allocator.ref_count(frame) == 1 && !frame_allocator.on_free_list(frame);
+
+        unsafe { frame_allocator.deallocate_frame(frame) };
+        let released = frame_allocator.ref_count(frame) == 0
+            && frame_allocator.on_free_list(frame)
+            && frame_allocator.allocated_frames == allocated
+            // Taken off the free list or from the memory map; either way it's on the list now
+            && frame_allocator.free_frames >= free;
+        shared && still_held && released
+    }).unwrap_or(false)
+}
+
+/// Checks the bookkeeping of `reclaim` after the boot-time calls: reclaiming
+/// a kind again (or usable memory at all) adds nothing, and the free frame
+/// counter, which `reclaim` raises by the count it returns, matches the
+/// length of the free list.
+///
+/// Run once at boot, after the boot memory was reclaimed and `install`.
+pub fn reclaim_self_test() -> bool {
+    with_kernel_memory(|_, frame_allocator| {
+        let counters = |allocator: &BootInfoFrameAllocator| (allocator.total_frames, allocator.free_frames);
+        let before = counters(frame_allocator);
+        let again = unsafe {
+            reclaim(frame_allocator, MemoryRegionKind::Bootloader)
+                + reclaim(frame_allocator, ACPI_RECLAIMABLE_UEFI)
+                + reclaim(frame_allocator, ACPI_RECLAIMABLE_BIOS)
+                + reclaim(frame_allocator, MemoryRegionKind::Usable)
+        };
+        let unchanged = again == 0 && counters(frame_allocator) == before;
+
+        let mut listed = 0;
+        let mut link = frame_allocator.free_list;
+        while link != FREE_LIST_END {
+            listed += 1;
+            link = unsafe { free_list_link(PhysFrame::containing_address(PhysAddr::new(link))).read() };
+        }
+        unchanged && listed == frame_allocator.free_frames
+    }).unwrap_or(false)
+}
+
+/// Compares the hand-written page walk of `translate_addr` with the
+/// mapper's `translate_addr` for kernel code, stack, heap and the physical
+/// memory mapping (often huge pages), plus a 2 MiB page mapped for the test
+/// and an unmapped address.
+///
+/// Run once at boot, after `install`.
+pub fn translate_self_test() -> bool {
+    let on_stack = 0u64;
+    let on_heap = alloc::boxed::Box::new(0u64);
+    let huge = VirtAddr::new(HUGE_PAGE_TEST_BASE + 0x1234);
+
+    with_kernel_memory(|mapper, frame_allocator| {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        if allocate_huge_pages_mapper(mapper, frame_allocator, VirtAddr::new(HUGE_PAGE_TEST_BASE), Size2MiB::SIZE, flags).is_err() {
+            return false;
+        }
+
+        let offset = physical_memory_offset();
+        let addrs = [
+            VirtAddr::new(translate_self_test as usize as u64),
+            VirtAddr::from_ptr(&on_stack),
+            VirtAddr::from_ptr(&*on_heap),
+            offset + 0x1234u64,
+            offset + 0x20_0000u64 + 0x5678u64,
+            huge,
+            VirtAddr::new(0x_5555_FFFF_0000), // Not mapped
+        ];
+        let agree = addrs.iter().all(|&addr| {
+            let walked = unsafe { translate_addr(addr, offset) };
+            walked == translate_with_mapper(mapper, addr)
+        });
+        let huge_mapped = translate_with_mapper(mapper, huge).is_some();
+
+        let freed = free_pages_mapper(mapper, frame_allocator, VirtAddr::new(HUGE_PAGE_TEST_BASE), Size2MiB::SIZE);
+        agree && huge_mapped && matches!(freed, Ok(1))
+    }).unwrap_or(false)
+}
+
+/// Virtual address `mapper_self_test` maps its scratch pages at.
+const MAPPER_TEST_BASE: u64 = 0x_5555_C000_0000;
+
+/// Frame allocator that gives out at most `budget` more frames, to make
+/// `allocate_pages_mapper` fail partway through.
+struct LimitedFrameAllocator<'a> {
+    inner: &'a mut BootInfoFrameAllocator,
+    budget: usize,
+}
+
+unsafe impl FrameAllocator<Size4KiB> for LimitedFrameAllocator<'_> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.budget = self.budget.checked_sub(1)?;
+        self.inner.allocate_frame()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for LimitedFrameAllocator<'_> {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.inner.deallocate_frame(frame)
+    }
+}
+
+/// Checks that `allocate_pages_mapper` refuses a range overlapping a page
+/// mapped with other flags without mapping anything, and that running out of
+/// frames partway unmaps and frees what the call had mapped.
+///
+/// Run once at boot, after `install`.
+pub fn mapper_self_test() -> bool {
+    let page = |n: u64| VirtAddr::new(MAPPER_TEST_BASE + n * Size4KiB::SIZE);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let read_only = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+
+    with_kernel_memory(|mapper, frame_allocator| {
+        // Page 0 also creates the page tables, so later calls only need leaf frames
+        if allocate_pages_mapper(mapper, frame_allocator, page(0), Size4KiB::SIZE, read_only).is_err() {
+            return false;
+        }
+        let unmapped = |mapper: &OffsetPageTable, pages: core::ops::Range<u64>| {
+            pages.map(page).all(|addr| matches!(mapper.translate(addr), TranslateResult::NotMapped))
+        };
+
+        let mismatch = allocate_pages_mapper(mapper, frame_allocator, page(0), 3 * Size4KiB::SIZE, flags);
+        let overlap_refused = matches!(mismatch, Err(AllocatePagesError::FlagsMismatch { .. }))
+            && unmapped(mapper, 1..3);
+
+        let allocated = frame_allocator.allocated_frames;
+        let mut limited = LimitedFrameAllocator { inner: frame_allocator, budget: 2 };
+        let failed = allocate_pages_mapper(mapper, &mut limited, page(1), 4 * Size4KiB::SIZE, flags);
+        let rolled_back = matches!(failed, Err(AllocatePagesError::MapFailed { .. }))
+            && unmapped(mapper, 1..5)
+            && frame_allocator.allocated_frames == allocated;
+
+        let freed = free_pages_mapper(mapper, frame_allocator, page(0), Size4KiB::SIZE);
+        overlap_refused && rolled_back && matches!(freed, Ok(1))
+    }).unwrap_or(false)
+}
@@ -0,0 +1,148 @@
+//! Virtual address space bookkeeping.
+//!
+//! An `AddrSpace` only tracks which virtual ranges are taken; it doesn't map
+//! anything. Callers reserve a range here first and then back it with frames
+//! (`allocate_pages_mapper`) or register it for demand paging.
+
+use alloc::vec::Vec;
+use x86_64::{align_down, align_up, structures::paging::PageTableFlags, VirtAddr};
+
+const PAGE_SIZE: u64 = 4096;
+
+/// A reserved, page-aligned virtual range.
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub start: VirtAddr,
+    pub end: VirtAddr, // Exclusive
+    pub flags: PageTableFlags,
+}
+
+impl Region {
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        (self.start..self.end).contains(&addr)
+    }
+
+    fn overlaps(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        self.start < end && start < self.end
+    }
+}
+
+/// The set of reserved regions inside `[start, end)`.
+#[derive(Debug)]
+pub struct AddrSpace {
+    start: VirtAddr,
+    end: VirtAddr,
+    regions: Vec<Region>, // Sorted by start address, never overlapping
+}
+
+impl AddrSpace {
+    /// Creates an empty address space covering `[start, end)`.
+    pub const fn new(start: VirtAddr, end: VirtAddr) -> Self {
+        AddrSpace { start, end, regions: Vec::new() }
+    }
+
+    /// Reserves `size` bytes (rounded up to whole pages) at the lowest free
+    /// address and returns the start of the new region.
+    pub fn reserve(&mut self, size: u64, flags: PageTableFlags) -> Option<VirtAddr> {
+        self.reserve_above(self.start, size, flags)
+    }
+
+    /// Like `reserve`, but the region starts at or above `min`.
+    pub fn reserve_above(&mut self, min: VirtAddr, size: u64, flags: PageTableFlags) -> Option<VirtAddr> {
+        let size = align_up(size, PAGE_SIZE);
+        if size == 0 {
+            return None;
+        }
+
+        // First fit: walk the gaps between regions, lowest first
+        let mut candidate = VirtAddr::new(align_up(min.max(self.start).as_u64(), PAGE_SIZE));
+        for region in self.regions.iter() {
+            if region.end <= candidate {
+                continue;
+            }
+            if candidate.as_u64().checked_add(size)? <= region.start.as_u64() {
+                break;
+            }
+            candidate = region.end;
+        }
+
+        if candidate.as_u64().checked_add(size)? > self.end.as_u64() {
+            return None;
+        }
+        self.insert(Region { start: candidate, end: candidate + size, flags });
+        Some(candidate)
+    }
+
+    /// Reserves the pages covering `[start, start + size)`, for callers that
+    /// need a fixed address (e.g. ELF segments). Fails if any of those pages
+    /// is already reserved or outside the address space.
+    pub fn reserve_at(&mut self, start: VirtAddr, size: u64, flags: PageTableFlags) -> Option<VirtAddr> {
+        if size == 0 {
+            return None;
+        }
+        let end = VirtAddr::new(align_up(start.as_u64().checked_add(size)?, PAGE_SIZE));
+        let start = VirtAddr::new(align_down(start.as_u64(), PAGE_SIZE));
+
+        if start < self.start || end > self.end {
+            return None;
+        }
+        if self.regions.iter().any(|region| region.overlaps(start, end)) {
+            return None;
+        }
+        self.insert(Region { start, end, flags });
+        Some(start)
+    }
+
+    /// Releases the region starting at `start` and returns it.
+    pub fn free(&mut self, start: VirtAddr) -> Option<Region> {
+        let index = self.regions.iter().position(|region| region.start == start)?;
+        Some(self.regions.remove(index))
+    }
+
+    /// Returns the region containing `addr`, if any.
+    pub fn find(&self, addr: VirtAddr) -> Option<&Region> {
+        self.regions.iter().find(|region| region.contains(addr))
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    fn insert(&mut self, region: Region) {
+        let index = self.regions.partition_point(|other| other.start < region.start);
+        self.regions.insert(index, region);
+    }
+}
+
+/// Reserves a few regions in a small address space and checks that they
+/// don't overlap, that fixed reservations over taken pages are refused,
+/// that `reserve_above` places a region past `min` and its neighbours, and
+/// that two adjacent freed regions can be reused as one larger gap.
+pub fn vma_self_test() -> bool {
+    const BASE: u64 = 0x_1000_0000;
+    let page = |n: u64| VirtAddr::new(BASE + n * PAGE_SIZE);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let mut space = AddrSpace::new(page(0), page(16));
+
+    let first = space.reserve(2 * PAGE_SIZE, flags);
+    let second = space.reserve(3 * PAGE_SIZE - 100, flags); // Rounded up to 3 pages
+    let third = space.reserve(PAGE_SIZE, flags);
+    let placed = first == Some(page(0)) && second == Some(page(2)) && third == Some(page(5));
+
+    let overlap_refused = space.reserve_at(page(1), 2 * PAGE_SIZE, flags).is_none()
+        && space.reserve_at(page(4) + 8u64, 1, flags).is_none()
+        && space.reserve_at(page(15), 2 * PAGE_SIZE, flags).is_none();
+
+    let above = space.reserve_above(page(3), PAGE_SIZE, flags) == Some(page(6))
+        && space.reserve_above(page(10), PAGE_SIZE, flags) == Some(page(10));
+
+    // Sorted by start, so checking neighbours covers every pair
+    let disjoint = space.regions().windows(2).all(|pair| pair[0].end <= pair[1].start);
+
+    // Freeing the first two leaves one 5-page gap at the bottom
+    let freed = space.free(page(0)).is_some() && space.free(page(2)).is_some();
+    let merged = space.reserve(5 * PAGE_SIZE, flags) == Some(page(0))
+        && space.reserve(16 * PAGE_SIZE, flags).is_none();
+
+    placed && overlap_refused && above && disjoint && freed && merged
+}
@@ -0,0 +1,171 @@
+use core::arch::asm;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use bootloader_api::info::FrameBufferInfo;
+use conquer_once::spin::OnceCell;
+use font8x8::UnicodeFonts;
+use x86_64::registers::control::{Cr2, Cr3};
+use x86_64::registers::rflags;
+
+use crate::framebuffer::{Color, Display, Position};
+
+const BACKGROUND: Color = Color { red: 0xAA, green: 0x00, blue: 0x00 };
+const FOREGROUND: Color = Color { red: 0xFF, green: 0xFF, blue: 0xFF };
+
+/// Pixel scale applied to the 8x8 font glyphs.
+const SCALE: usize = 2;
+/// Blank border around the text, in pixels.
+const MARGIN: usize = 16;
+
+/// The framebuffer as the bootloader handed it over. The TTY draws to the
+/// same memory later, so this reaches the screen at any point of boot.
+struct PanicFramebuffer {
+    address: usize,
+    len: usize,
+    info: FrameBufferInfo,
+}
+
+static FRAMEBUFFER: OnceCell<PanicFramebuffer> = OnceCell::uninit();
+
+/// Remembers where the framebuffer is, so `show` can paint the panic screen
+/// whether or not the TTY is up. Call as early in boot as possible.
+pub fn register_framebuffer(buffer: &[u8], info: FrameBufferInfo) {
+    let _ = FRAMEBUFFER.try_init_once(|| PanicFramebuffer {
+        address: buffer.as_ptr() as usize,
+        len: buffer.len(),
+        info,
+    });
+}
+
+/// Draws the panic screen on the framebuffer given to `register_framebuffer`,
+/// without a shadow buffer. Returns `false` if none was registered.
+///
+/// The TTY and its lock are never touched, so this works before `tty::init`
+/// and when the panic hit while the TTY was mid-render.
+///
+/// This function is unsafe because it creates a second mutable view of the
+/// framebuffer. It must only be called from the panic path, when nothing
+/// else will draw again.
+pub unsafe fn show(info: &PanicInfo, registers: &Registers) -> bool {
+    let Ok(framebuffer) = FRAMEBUFFER.try_get() else {
+        return false;
+    };
+    let buffer = core::slice::from_raw_parts_mut(framebuffer.address as *mut u8, framebuffer.len);
+    draw(&mut Display::new_direct(buffer, &framebuffer.info), info, registers);
+    true
+}
+
+/// A handful of control registers captured at the start of the panic handler.
+#[derive(Debug, Clone, Copy)]
+pub struct Registers {
+    pub rsp: u64,
+    pub rbp: u64,
+    pub rflags: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+}
+
+impl Registers {
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let rsp: u64;
+        let rbp: u64;
+        unsafe {
+            asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags));
+            asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+        }
+
+        Self {
+            rsp,
+            rbp,
+            rflags: rflags::read_raw(),
+            cr2: Cr2::read_raw(),
+            cr3: Cr3::read().0.start_address().as_u64(),
+        }
+    }
+}
+
+/// Renders text glyph by glyph straight into the framebuffer.
+///
+/// Nothing is buffered, so formatting through it never touches the heap.
+struct ScreenWriter<'d, 'a> {
+    display: &'d mut Display<'a>,
+    x: usize,
+    y: usize,
+}
+
+impl<'d, 'a> ScreenWriter<'d, 'a> {
+    fn new(display: &'d mut Display<'a>) -> Self {
+        Self { display, x: MARGIN, y: MARGIN }
+    }
+
+    fn new_line(&mut self) {
+        self.x = MARGIN;
+        self.y += 8 * SCALE + SCALE;
+    }
+
+    fn draw_char(&mut self, c: char) {
+        if self.x + 8 * SCALE > self.display.width() - MARGIN {
+            self.new_line();
+        }
+        if self.y + 8 * SCALE > self.display.height() - MARGIN {
+            // Sem espaço: o resto da mensagem continua disponível pela serial
+            return;
+        }
+
+        let glyph = font8x8::BASIC_FONTS.get(c).or_else(|| font8x8::BASIC_FONTS.get('?'));
+        if let Some(glyph) = glyph {
+            for (row, byte) in glyph.iter().enumerate() {
+                for bit in 0..8 {
+                    if (byte >> bit) & 1 == 1 {
+                        for dy in 0..SCALE {
+                            for dx in 0..SCALE {
+                                let position = Position {
+                                    x: self.x + bit * SCALE + dx,
+                                    y: self.y + row * SCALE + dy,
+                                };
+                                self.display.set_pixel_direct(position, FOREGROUND);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        self.x += 8 * SCALE;
+    }
+}
+
+impl Write for ScreenWriter<'_, '_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            match c {
+                '\n' => self.new_line(),
+                c => self.draw_char(c),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Draws the panic screen: message, location and a short register dump on a
+/// red background.
+fn draw(display: &mut Display, info: &PanicInfo, registers: &Registers) {
+    display.fill_direct(BACKGROUND);
+
+    let mut writer = ScreenWriter::new(display);
+    let _ = writeln!(writer, "*** AURORA OS - KERNEL PANIC ***");
+    writer.new_line();
+
+    let _ = writeln!(writer, "{}", info.message());
+    if let Some(location) = info.location() {
+        let _ = writeln!(writer, "at {}:{}:{}", location.file(), location.line(), location.column());
+    }
+    writer.new_line();
+
+    let _ = writeln!(writer, "RSP    {:#018x}    RBP {:#018x}", registers.rsp, registers.rbp);
+    let _ = writeln!(writer, "RFLAGS {:#018x}", registers.rflags);
+    let _ = writeln!(writer, "CR2    {:#018x}    CR3 {:#018x}", registers.cr2, registers.cr3);
+    writer.new_line();
+
+    let _ = writeln!(writer, "System halted.");
+}
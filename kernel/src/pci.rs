@@ -0,0 +1,612 @@
+use acpi::{mcfg::Mcfg, AcpiTables};
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use conquer_once::spin::OnceCell;
+use lazy_static::lazy_static;
+use x86_64::instructions::port::Port;
+use x86_64::structures::paging::{FrameAllocator, OffsetPageTable, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+use log::{info, warn};
+
+use crate::interrupts::AcpiHandlerImpl;
+use crate::memory;
+
+/// Um dispositivo (função) encontrado no barramento PCI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciDevice {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    /// Tipo de header sem o bit de multifunção (0 = dispositivo, 1 = bridge PCI-PCI)
+    pub header_type: u8,
+}
+
+/// Uma Base Address Register decodificada.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    /// Registrador não implementado, ou a metade alta de um BAR de 64 bits
+    None,
+    /// Região no espaço de I/O
+    Io { port: u32, size: u32 },
+    /// Região MMIO
+    Memory { address: u64, size: u64, prefetchable: bool, is_64bit: bool },
+}
+
+/// Acesso ao espaço de configuração. O kernel usa `SystemConfig`; o
+/// self-test usa um barramento simulado.
+pub trait ConfigAccess {
+    /// Lê o registrador de 32 bits em `offset` da função.
+    fn read(&self, bus: u8, device: u8, function: u8, offset: u16) -> u32;
+    /// Escreve o registrador de 32 bits em `offset` da função.
+    fn write(&self, bus: u8, device: u8, function: u8, offset: u16, value: u32);
+}
+
+/// O espaço de configuração real, pela ECAM ou pelas portas (ver `config_read`).
+pub struct SystemConfig;
+
+impl ConfigAccess for SystemConfig {
+    fn read(&self, bus: u8, device: u8, function: u8, offset: u16) -> u32 {
+        config_read(bus, device, function, offset)
+    }
+
+    fn write(&self, bus: u8, device: u8, function: u8, offset: u16, value: u32) {
+        config_write(bus, device, function, offset, value)
+    }
+}
+
+// Bits do registrador de comando
+const COMMAND_IO_SPACE: u16 = 1 << 0;
+const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+
+impl PciDevice {
+    /// Ver `class_name`.
+    pub fn class_name(&self) -> &'static str {
+        class_name(self.class, self.subclass, self.prog_if)
+    }
+
+    /// Lê os registradores de identificação da função, se ela existir.
+    fn probe(access: &impl ConfigAccess, bus: u8, device: u8, function: u8) -> Option<PciDevice> {
+        let id = access.read(bus, device, function, 0x00);
+        let vendor_id = (id & 0xFFFF) as u16;
+        if vendor_id == 0xFFFF {
+            return None;
+        }
+        let class_reg = access.read(bus, device, function, 0x08);
+        let header_reg = access.read(bus, device, function, 0x0C);
+
+        Some(PciDevice {
+            bus,
+            device,
+            function,
+            vendor_id,
+            device_id: (id >> 16) as u16,
+            class: (class_reg >> 24) as u8,
+            subclass: (class_reg >> 16) as u8,
+            prog_if: (class_reg >> 8) as u8,
+            header_type: ((header_reg >> 16) & 0x7F) as u8,
+        })
+    }
+
+    /// Lê o registrador de 32 bits em `offset` do espaço de configuração.
+    pub fn read_config(&self, offset: u16) -> u32 {
+        config_read(self.bus, self.device, self.function, offset)
+    }
+
+    /// Escreve o registrador de 32 bits em `offset` do espaço de configuração.
+    pub fn write_config(&self, offset: u16, value: u32) {
+        config_write(self.bus, self.device, self.function, offset, value)
+    }
+
+    /// Liga `bits` no registrador de comando (offset 0x04).
+    fn set_command_bits(&self, access: &impl ConfigAccess, bits: u16) {
+        let (bus, device, function) = (self.bus, self.device, self.function);
+        // Os bits de status (31:16) são limpos escrevendo 1, então vão como 0
+        let command = access.read(bus, device, function, 0x04) & 0xFFFF;
+        access.write(bus, device, function, 0x04, command | bits as u32);
+    }
+
+    /// Deixa a função iniciar transações no barramento (necessário para DMA).
+    pub fn enable_bus_master(&self) {
+        self.set_command_bits(&SystemConfig, COMMAND_BUS_MASTER);
+    }
+
+    /// Liga a decodificação dos BARs de memória.
+    pub fn enable_memory_space(&self) {
+        self.set_command_bits(&SystemConfig, COMMAND_MEMORY_SPACE);
+    }
+
+    /// Liga a decodificação dos BARs de I/O.
+    pub fn enable_io_space(&self) {
+        self.set_command_bits(&SystemConfig, COMMAND_IO_SPACE);
+    }
+
+    /// Lê e decodifica os BARs (offsets 0x10..0x24). Headers de bridge só têm
+    /// dois; os demais ficam `Bar::None`.
+    ///
+    /// O tamanho de cada região é medido escrevendo 1s no registrador e lendo
+    /// a máscara de volta, com a decodificação de I/O e memória desligada
+    /// enquanto isso.
+    pub fn bars(&self) -> [Bar; 6] {
+        self.bars_via(&SystemConfig)
+    }
+
+    fn bars_via(&self, access: &impl ConfigAccess) -> [Bar; 6] {
+        let (bus, device, function) = (self.bus, self.device, self.function);
+        let read = |offset| access.read(bus, device, function, offset);
+        let write = |offset, value| access.write(bus, device, function, offset, value);
+        let mut bars = [Bar::None; 6];
+        let count = match self.header_type {
+            0 => 6,
+            1 => 2,
+            _ => 0,
+        };
+
+        // Só os 16 bits baixos: os bits de status acima são limpos escrevendo 1
+        let command = read(0x04) & 0xFFFF;
+        write(
+            0x04,
+            command & !((COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE) as u32),
+        );
+
+        let mut i = 0;
+        while i < count {
+            let offset = 0x10 + i as u16 * 4;
+            let original = read(offset);
+            write(offset, 0xFFFF_FFFF);
+            let mask = read(offset);
+            write(offset, original);
+
+            if original & 0x1 != 0 {
+                // I/O: só os 16 bits baixos importam em x86
+                let mask = (mask & 0xFFFF_FFFC) | 0xFFFF_0000;
+                if mask & 0xFFFF != 0 {
+                    bars[i] = Bar::Io { port: original & 0xFFFF_FFFC, size: (!mask).wrapping_add(1) };
+                }
+                i += 1;
+                continue;
+            }
+
+            let prefetchable = original & 0x8 != 0;
+            let is_64bit = (original >> 1) & 0x3 == 0x2;
+            let mut address = (original & 0xFFFF_FFF0) as u64;
+            let mut full_mask = (mask & 0xFFFF_FFF0) as u64;
+            if is_64bit && i + 1 < count {
+                // O registrador seguinte guarda os 32 bits altos
+                let high_offset = offset + 4;
+                let high = read(high_offset);
+                write(high_offset, 0xFFFF_FFFF);
+                let high_mask = read(high_offset);
+                write(high_offset, high);
+
+                address |= (high as u64) << 32;
+                full_mask |= (high_mask as u64) << 32;
+            } else {
+                // BAR de 32 bits: os bits altos da máscara são sempre 1
+                full_mask |= 0xFFFF_FFFF_0000_0000;
+            }
+
+            if full_mask & 0xFFFF_FFFF != 0 {
+                bars[i] = Bar::Memory {
+                    address,
+                    size: (!full_mask).wrapping_add(1),
+                    prefetchable,
+                    is_64bit,
+                };
+            }
+            i += if is_64bit { 2 } else { 1 };
+        }
+
+        write(0x04, command);
+        bars
+    }
+}
+
+/// Lê um registrador de 32 bits do espaço de configuração pelas portas
+/// 0xCF8/0xCFC. `offset` é alinhado para baixo a 4 bytes.
+pub unsafe fn pci_config_read(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    let address: u32 =
+        (1 << 31) | // habilita
+        ((bus as u32) << 16) |
+        ((device as u32) << 11) |
+        ((function as u32) << 8) |
+        ((offset as u32) & 0xFC);
+
+    let mut port_cf8 = Port::new(0xCF8);
+    let mut port_cfc = Port::new(0xCFC);
+    port_cf8.write(address);
+    port_cfc.read()
+}
+
+/// Escreve um registrador de 32 bits do espaço de configuração pelas portas
+/// 0xCF8/0xCFC. `offset` é alinhado para baixo a 4 bytes.
+pub unsafe fn pci_config_write(bus: u8, device: u8, function: u8, offset: u8, value: u32) {
+    let address: u32 =
+        (1 << 31) |
+        ((bus as u32) << 16) |
+        ((device as u32) << 11) |
+        ((function as u32) << 8) |
+        ((offset as u32) & 0xFC);
+
+    let mut port_cf8 = Port::new(0xCF8);
+    let mut port_cfc = Port::new(0xCFC);
+    port_cf8.write(address);
+    port_cfc.write(value);
+}
+
+/// Uma região ECAM do MCFG: o espaço de configuração (4 KiB por função) dos
+/// barramentos `bus_start..=bus_end` do segmento 0, a partir de `base`.
+#[derive(Debug, Clone, Copy)]
+struct EcamRegion {
+    base: u64,
+    bus_start: u8,
+    bus_end: u8,
+}
+
+/// Regiões ECAM mapeadas por `init_ecam`; vazia quando não há MCFG
+static ECAM_REGIONS: OnceCell<Vec<EcamRegion>> = OnceCell::uninit();
+
+/// Endereço físico do registrador `offset` da função, dentro de uma região
+/// ECAM que começa em `base` no barramento `bus_start`.
+pub fn ecam_address(base: u64, bus_start: u8, bus: u8, device: u8, function: u8, offset: u16) -> u64 {
+    base + ((((bus - bus_start) as u64) << 20)
+        | ((device as u64 & 0x1F) << 15)
+        | ((function as u64 & 0x7) << 12)
+        | (offset as u64 & 0xFFC))
+}
+
+/// Procura a tabela MCFG e mapeia as regiões ECAM do segmento 0 para que
+/// `config_read`/`config_write` passem a usá-las. Sem MCFG (ou se o
+/// mapeamento falhar) o acesso continua pelas portas 0xCF8/0xCFC.
+pub unsafe fn init_ecam(
+    rsdp: usize,
+    physical_memory_offset: VirtAddr,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) {
+    let handler = AcpiHandlerImpl::new(physical_memory_offset);
+    let mut regions = Vec::new();
+
+    if let Ok(mcfg) = AcpiTables::from_rsdp(handler, rsdp).and_then(|tables| tables.find_table::<Mcfg>()) {
+        for entry in mcfg.entries().iter().copied() {
+            // Só o segmento 0 é alcançável pelas portas e pelo resto do kernel
+            if entry.pci_segment_group != 0 || entry.bus_number_end < entry.bus_number_start {
+                continue;
+            }
+            let region = EcamRegion {
+                base: entry.base_address,
+                bus_start: entry.bus_number_start,
+                bus_end: entry.bus_number_end,
+            };
+            let size = ((region.bus_end - region.bus_start) as u64 + 1) << 20;
+            match memory::map_physical_mmio(PhysAddr::new(region.base), size, mapper, frame_allocator) {
+                Ok(_) => regions.push(region),
+                Err(err) => warn!("PCI: ECAM at {:#x} unusable: {:?}", region.base, err),
+            }
+        }
+    }
+
+    if regions.is_empty() {
+        info!("PCI: no MCFG, using port I/O for config space");
+    }
+    let _ = ECAM_REGIONS.try_init_once(|| regions);
+}
+
+/// Endereço virtual do registrador, se o barramento estiver numa região ECAM.
+fn ecam_pointer(bus: u8, device: u8, function: u8, offset: u16) -> Option<*mut u32> {
+    let region = ECAM_REGIONS.try_get().ok()?
+        .iter()
+        .find(|region| (region.bus_start..=region.bus_end).contains(&bus))?;
+    let phys = ecam_address(region.base, region.bus_start, bus, device, function, offset);
+    Some((memory::physical_memory_offset() + phys).as_mut_ptr())
+}
+
+/// Lê um registrador pela ECAM; `None` se o barramento não tiver ECAM.
+pub fn ecam_read(bus: u8, device: u8, function: u8, offset: u16) -> Option<u32> {
+    let ptr = ecam_pointer(bus, device, function, offset)?;
+    Some(unsafe { core::ptr::read_volatile(ptr) })
+}
+
+/// Escreve um registrador pela ECAM; `false` se o barramento não tiver ECAM.
+pub fn ecam_write(bus: u8, device: u8, function: u8, offset: u16, value: u32) -> bool {
+    match ecam_pointer(bus, device, function, offset) {
+        Some(ptr) => {
+            unsafe { core::ptr::write_volatile(ptr, value) };
+            true
+        }
+        None => false,
+    }
+}
+
+/// Lê um registrador do espaço de configuração, pela ECAM quando houver e
+/// pelas portas caso contrário. Pelas portas só os primeiros 256 bytes são
+/// alcançáveis; acima disso a leitura dá 0xFFFFFFFF.
+pub fn config_read(bus: u8, device: u8, function: u8, offset: u16) -> u32 {
+    if let Some(value) = ecam_read(bus, device, function, offset) {
+        return value;
+    }
+    match u8::try_from(offset) {
+        Ok(offset) => unsafe { pci_config_read(bus, device, function, offset) },
+        Err(_) => 0xFFFF_FFFF,
+    }
+}
+
+/// Escreve um registrador do espaço de configuração; ver `config_read`.
+/// Escritas acima de 256 bytes sem ECAM são ignoradas.
+pub fn config_write(bus: u8, device: u8, function: u8, offset: u16, value: u32) {
+    if ecam_write(bus, device, function, offset, value) {
+        return;
+    }
+    if let Ok(offset) = u8::try_from(offset) {
+        unsafe { pci_config_write(bus, device, function, offset, value) };
+    }
+}
+
+/// Percorre todos os barramentos e devolve as funções PCI presentes.
+pub fn enumerate() -> Vec<PciDevice> {
+    enumerate_via(&SystemConfig)
+}
+
+fn enumerate_via(access: &impl ConfigAccess) -> Vec<PciDevice> {
+    let mut devices = Vec::new();
+    for bus in 0..=255 {
+        for device in 0..32 {
+            // Sem a função 0 não há dispositivo nesse slot
+            let Some(first) = PciDevice::probe(access, bus, device, 0) else {
+                continue;
+            };
+            devices.push(first);
+
+            // Apenas a função 0 existe, a menos que seja um dispositivo multifunção
+            let header_type = (access.read(bus, device, 0, 0x0C) >> 16) & 0xFF;
+            if (header_type & 0x80) == 0 {
+                continue;
+            }
+            for function in 1..8 {
+                if let Some(found) = PciDevice::probe(access, bus, device, function) {
+                    devices.push(found);
+                }
+            }
+        }
+    }
+    devices
+}
+
+lazy_static! {
+    /// Resultado do primeiro `enumerate`; os dispositivos não mudam depois do boot
+    static ref DEVICES: Vec<PciDevice> = enumerate();
+}
+
+/// Dispositivos PCI encontrados, enumerando o barramento na primeira chamada.
+pub fn devices() -> &'static [PciDevice] {
+    &DEVICES
+}
+
+/// Procura a função com esse par vendor:device (ex. 8086:7010, o IDE do PIIX3).
+pub fn find(vendor: u16, device: u16) -> Option<PciDevice> {
+    find_in(devices(), vendor, device)
+}
+
+fn find_in(devices: &[PciDevice], vendor: u16, device: u16) -> Option<PciDevice> {
+    devices.iter()
+        .find(|dev| dev.vendor_id == vendor && dev.device_id == device)
+        .copied()
+}
+
+/// Todas as funções da classe/subclasse dadas (ex. 0x01/0x01 para IDE).
+pub fn find_by_class(class: u8, subclass: u8) -> Vec<PciDevice> {
+    find_by_class_in(devices(), class, subclass)
+}
+
+fn find_by_class_in(devices: &[PciDevice], class: u8, subclass: u8) -> Vec<PciDevice> {
+    devices.iter()
+        .filter(|dev| dev.class == class && dev.subclass == subclass)
+        .copied()
+        .collect()
+}
+
+/// Nome legível da classe de um dispositivo PCI, para o log de boot.
+pub fn class_name(class: u8, subclass: u8, prog_if: u8) -> &'static str {
+    match (class, subclass, prog_if) {
+        (0x00, 0x01, _) => "VGA-compatible device",
+        (0x00, _, _) => "Unclassified device",
+
+        (0x01, 0x00, _) => "SCSI controller",
+        (0x01, 0x01, _) => "IDE controller",
+        (0x01, 0x02, _) => "Floppy controller",
+        (0x01, 0x04, _) => "RAID controller",
+        (0x01, 0x05, _) => "ATA controller",
+        (0x01, 0x06, 0x01) => "SATA controller (AHCI)",
+        (0x01, 0x06, _) => "SATA controller",
+        (0x01, 0x07, _) => "SAS controller",
+        (0x01, 0x08, 0x02) => "NVMe controller",
+        (0x01, 0x08, _) => "Non-volatile memory controller",
+        (0x01, _, _) => "Mass storage controller",
+
+        (0x02, 0x00, _) => "Ethernet controller",
+        (0x02, _, _) => "Network controller",
+
+        (0x03, 0x00, _) => "VGA controller",
+        (0x03, 0x01, _) => "XGA controller",
+        (0x03, 0x02, _) => "3D controller",
+        (0x03, _, _) => "Display controller",
+
+        (0x04, 0x00, _) => "Video device",
+        (0x04, 0x01, _) => "Audio device",
+        (0x04, 0x03, _) => "Audio device (HD Audio)",
+        (0x04, _, _) => "Multimedia controller",
+
+        (0x05, 0x00, _) => "RAM controller",
+        (0x05, _, _) => "Memory controller",
+
+        (0x06, 0x00, _) => "Host bridge",
+        (0x06, 0x01, _) => "ISA bridge",
+        (0x06, 0x04, _) => "PCI-to-PCI bridge",
+        (0x06, _, _) => "Bridge",
+
+        (0x07, 0x00, _) => "Serial controller",
+        (0x07, _, _) => "Communication controller",
+
+        (0x08, 0x00, _) => "Interrupt controller",
+        (0x08, 0x03, _) => "RTC controller",
+        (0x08, _, _) => "System peripheral",
+
+        (0x09, _, _) => "Input device controller",
+
+        (0x0C, 0x03, 0x00) => "USB controller (UHCI)",
+        (0x0C, 0x03, 0x10) => "USB controller (OHCI)",
+        (0x0C, 0x03, 0x20) => "USB controller (EHCI)",
+        (0x0C, 0x03, 0x30) => "USB controller (xHCI)",
+        (0x0C, 0x03, _) => "USB controller",
+        (0x0C, 0x05, _) => "SMBus controller",
+        (0x0C, _, _) => "Serial bus controller",
+
+        (0x0D, _, _) => "Wireless controller",
+        (0x10, _, _) => "Encryption controller",
+        (0x11, _, _) => "Signal processing controller",
+        (0x12, _, _) => "Processing accelerator",
+        (0xFF, _, _) => "Unassigned class",
+        _ => "Unknown device",
+    }
+}
+
+/// Uma função no barramento simulado: os 64 registradores do header e, por
+/// BAR, os bits que o hardware deixa escrever (o resto lê como no original).
+struct MockFunction {
+    registers: [u32; 64],
+    bar_masks: [u32; 6],
+}
+
+/// Barramento simulado para o self-test, endereçado por (bus, device, function).
+struct MockConfig {
+    functions: RefCell<BTreeMap<(u8, u8, u8), MockFunction>>,
+}
+
+impl MockConfig {
+    fn add(&self, bdf: (u8, u8, u8), id: u32, class_reg: u32, header_type: u8, bars: [(u32, u32); 6]) {
+        let mut registers = [0u32; 64];
+        registers[0] = id;
+        registers[2] = class_reg;
+        registers[3] = (header_type as u32) << 16;
+        let mut bar_masks = [0u32; 6];
+        for (i, &(value, mask)) in bars.iter().enumerate() {
+            registers[4 + i] = value;
+            bar_masks[i] = mask;
+        }
+        self.functions.borrow_mut().insert(bdf, MockFunction { registers, bar_masks });
+    }
+}
+
+impl ConfigAccess for MockConfig {
+    fn read(&self, bus: u8, device: u8, function: u8, offset: u16) -> u32 {
+        self.functions.borrow()
+            .get(&(bus, device, function))
+            .map_or(0xFFFF_FFFF, |f| f.registers[(offset as usize & 0xFC) / 4])
+    }
+
+    fn write(&self, bus: u8, device: u8, function: u8, offset: u16, value: u32) {
+        let mut functions = self.functions.borrow_mut();
+        let Some(f) = functions.get_mut(&(bus, device, function)) else {
+            return;
+        };
+        let index = (offset as usize & 0xFC) / 4;
+        match index {
+            // Só os bits de comando; os de status não são simulados
+            1 => f.registers[1] = (f.registers[1] & 0xFFFF_0000) | (value & 0xFFFF),
+            4..=9 => {
+                let mask = f.bar_masks[index - 4];
+                f.registers[index] = (f.registers[index] & !mask) | (value & mask);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Confere enumeração, BARs, `class_name`, `find`/`find_by_class`, o
+/// registrador de comando e o endereçamento ECAM contra um barramento
+/// simulado com uma ponte host, um PIIX3 multifunção (ISA + IDE) e uma placa
+/// de rede com um BAR de memória de 64 bits e um de I/O.
+pub fn pci_self_test() -> bool {
+    let bus = MockConfig { functions: RefCell::new(BTreeMap::new()) };
+    let no_bars = [(0, 0); 6];
+    bus.add((0, 0, 0), 0x1237_8086, 0x0600_0000, 0x00, no_bars);
+    bus.add((0, 1, 0), 0x7000_8086, 0x0601_0000, 0x80, no_bars);
+    bus.add((0, 1, 1), 0x7010_8086, 0x0101_8000, 0x00, no_bars);
+    bus.add((0, 3, 0), 0x100E_8086, 0x0200_0000, 0x00, [
+        // 64 bits, não prefetchable, 128 KiB em 0x1_FEB0_0000
+        (0xFEB0_0004, 0xFFFE_0000),
+        (0x0000_0001, 0xFFFF_FFFF),
+        // I/O, 64 portas em 0xC000
+        (0x0000_C001, 0xFFFF_FFC0),
+        (0, 0),
+        (0, 0),
+        (0, 0),
+    ]);
+
+    let devices = enumerate_via(&bus);
+    let ide = PciDevice {
+        bus: 0,
+        device: 1,
+        function: 1,
+        vendor_id: 0x8086,
+        device_id: 0x7010,
+        class: 0x01,
+        subclass: 0x01,
+        prog_if: 0x80,
+        header_type: 0,
+    };
+    if devices.len() != 4 || devices[2] != ide {
+        return false;
+    }
+
+    let Some(nic) = find_in(&devices, 0x8086, 0x100E) else {
+        return false;
+    };
+    if find_in(&devices, 0x10EC, 0x8139).is_some()
+        || find_by_class_in(&devices, 0x01, 0x01) != vec![ide]
+        || find_by_class_in(&devices, 0x02, 0x00) != vec![nic]
+    {
+        return false;
+    }
+
+    let names = [
+        ((0x01, 0x01, 0x80), "IDE controller"),
+        ((0x01, 0x06, 0x01), "SATA controller (AHCI)"),
+        ((0x01, 0x08, 0x02), "NVMe controller"),
+        ((0x02, 0x00, 0x00), "Ethernet controller"),
+        ((0x03, 0x00, 0x00), "VGA controller"),
+        ((0x06, 0x04, 0x00), "PCI-to-PCI bridge"),
+        ((0x0C, 0x03, 0x30), "USB controller (xHCI)"),
+        ((0x42, 0x00, 0x00), "Unknown device"),
+    ];
+    if names.iter().any(|&((class, subclass, prog_if), name)| class_name(class, subclass, prog_if) != name) {
+        return false;
+    }
+
+    let bars = nic.bars_via(&bus);
+    let expected = [
+        Bar::Memory { address: 0x1_FEB0_0000, size: 0x2_0000, prefetchable: false, is_64bit: true },
+        Bar::None,
+        Bar::Io { port: 0xC000, size: 0x40 },
+    ];
+    // Medir os BARs não pode deixar os registradores alterados
+    if bars[..3] != expected || bars[3..].iter().any(|&bar| bar != Bar::None) || bus.read(0, 3, 0, 0x10) != 0xFEB0_0004 {
+        return false;
+    }
+
+    bus.write(0, 3, 0, 0x04, COMMAND_MEMORY_SPACE as u32);
+    nic.set_command_bits(&bus, COMMAND_BUS_MASTER);
+    if bus.read(0, 3, 0, 0x04) & 0xFFFF != (COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER) as u32 {
+        return false;
+    }
+
+    ecam_address(0xE000_0000, 0, 1, 2, 3, 0x104) == 0xE000_0000 + (1 << 20) + (2 << 15) + (3 << 12) + 0x104
+        && ecam_address(0xE000_0000, 0x10, 0x11, 0, 0, 0) == 0xE000_0000 + (1 << 20)
+}
@@ -0,0 +1,264 @@
+//! Powering the machine off and resetting it.
+
+use acpi::address::{AddressSpace, GenericAddress};
+use acpi::fadt::Fadt;
+use acpi::AcpiTables;
+use conquer_once::spin::OnceCell;
+use log::{info, warn};
+use x86_64::instructions::port::Port;
+use x86_64::structures::DescriptorTablePointer;
+use x86_64::VirtAddr;
+
+use crate::interrupts::AcpiHandlerImpl;
+use crate::{memory, pci};
+
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_TYP_MASK: u16 = 0x7 << SLP_TYP_SHIFT;
+const SLP_EN: u16 = 1 << 13;
+const SCI_EN: u16 = 1 << 0;
+
+// AML opcodes needed to read the \_S5 package
+const AML_NAME_OP: u8 = 0x08;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+const AML_BYTE_PREFIX: u8 = 0x0A;
+
+/// What `shutdown` needs from the FADT and DSDT, read once at boot.
+#[derive(Debug, Clone, Copy)]
+struct SleepInfo {
+    pm1a_control: u16,
+    pm1b_control: Option<u16>,
+    slp_typ_a: u16,
+    slp_typ_b: u16,
+    /// Port and value that switch the chipset to ACPI mode, if it isn't yet
+    acpi_enable: Option<(u16, u8)>,
+}
+
+static SLEEP_INFO: OnceCell<SleepInfo> = OnceCell::uninit();
+/// The FADT reset register and the value to write to it, if supported
+static RESET_REGISTER: OnceCell<(GenericAddress, u8)> = OnceCell::uninit();
+
+/// Reads the PM1 control blocks from the FADT and the S5 sleep types from
+/// the DSDT's `\_S5` object, and the FADT reset register. Without them
+/// `shutdown` and `reboot` fall back to the legacy mechanisms.
+pub unsafe fn init(rsdp: usize, physical_memory_offset: VirtAddr) {
+    let handler = AcpiHandlerImpl::new(physical_memory_offset);
+    let Ok(tables) = AcpiTables::from_rsdp(handler, rsdp) else {
+        warn!("ACPI tables unavailable; shutdown uses emulator ports only");
+        return;
+    };
+
+    match read_sleep_info(&tables, physical_memory_offset) {
+        Ok(sleep_info) => {
+            info!("ACPI S5: SLP_TYPa {} SLP_TYPb {}", sleep_info.slp_typ_a, sleep_info.slp_typ_b);
+            let _ = SLEEP_INFO.try_init_once(|| sleep_info);
+        }
+        Err(err) => warn!("ACPI shutdown unavailable: {}", err),
+    }
+
+    if let Ok(fadt) = tables.find_table::<Fadt>() {
+        let reset_value = fadt.reset_value;
+        let flags = fadt.flags;
+        match fadt.reset_register() {
+            Ok(register) if flags.supports_system_reset_via_fadt() && register.address != 0 => {
+                let _ = RESET_REGISTER.try_init_once(|| (register, reset_value));
+            }
+            _ => info!("No ACPI reset register; reboot uses the keyboard controller"),
+        }
+    }
+}
+
+fn read_sleep_info(
+    tables: &AcpiTables<AcpiHandlerImpl>,
+    physical_memory_offset: VirtAddr,
+) -> Result<SleepInfo, &'static str> {
+    let fadt = tables.find_table::<Fadt>().map_err(|_| "no FADT")?;
+
+    let pm1a = fadt.pm1a_control_block().map_err(|_| "bad PM1a control block")?;
+    if pm1a.address_space != AddressSpace::SystemIo || pm1a.address == 0 {
+        return Err("PM1a control block is not in I/O space");
+    }
+    let pm1b = match fadt.pm1b_control_block() {
+        Ok(Some(block)) if block.address_space == AddressSpace::SystemIo && block.address != 0 => {
+            Some(block.address as u16)
+        }
+        _ => None,
+    };
+
+    let dsdt = tables.dsdt().map_err(|_| "no DSDT")?;
+    let aml = unsafe {
+        core::slice::from_raw_parts(
+            (physical_memory_offset + dsdt.address as u64).as_ptr::<u8>(),
+            dsdt.length as usize,
+        )
+    };
+    let (slp_typ_a, slp_typ_b) = find_s5(aml).ok_or("no \\_S5 object in the DSDT")?;
+
+    let smi_command = fadt.smi_cmd_port;
+    let acpi_enable = fadt.acpi_enable;
+    Ok(SleepInfo {
+        pm1a_control: pm1a.address as u16,
+        pm1b_control: pm1b,
+        slp_typ_a,
+        slp_typ_b,
+        acpi_enable: (smi_command != 0 && acpi_enable != 0)
+            .then_some((smi_command as u16, acpi_enable)),
+    })
+}
+
+/// Finds `Name(_S5, Package() { SLP_TYPa, SLP_TYPb, ... })` in an AML
+/// stream and returns its first two elements.
+///
+/// This is not an AML interpreter: it looks for the name's bytes and decodes
+/// the package right after them, which is how every firmware we care about
+/// encodes it.
+fn find_s5(aml: &[u8]) -> Option<(u16, u16)> {
+    let start = aml.windows(4).position(|window| window == b"_S5_")?;
+
+    // Name(_S5, ...) or Name(\_S5, ...)
+    let named = match start {
+        0 => false,
+        1 => aml[0] == AML_NAME_OP,
+        _ => aml[start - 1] == AML_NAME_OP
+            || (aml[start - 1] == b'\\' && aml[start - 2] == AML_NAME_OP),
+    };
+    if !named {
+        return None;
+    }
+
+    let mut rest = aml.get(start + 4..)?;
+    if *rest.first()? != AML_PACKAGE_OP {
+        return None;
+    }
+    // PkgLength: bits 7:6 of the lead byte count the extra length bytes
+    let length_bytes = (*rest.get(1)? >> 6) as usize;
+    rest = rest.get(2 + length_bytes..)?;
+    // NumElements
+    rest = rest.get(1..)?;
+
+    let (slp_typ_a, used) = read_aml_integer(rest)?;
+    let (slp_typ_b, _) = read_aml_integer(rest.get(used..)?)?;
+    Some((slp_typ_a as u16, slp_typ_b as u16))
+}
+
+/// Decodes a small AML integer constant; returns it and how many bytes it
+/// took.
+fn read_aml_integer(aml: &[u8]) -> Option<(u8, usize)> {
+    match *aml.first()? {
+        AML_ZERO_OP => Some((0, 1)),
+        AML_ONE_OP => Some((1, 1)),
+        AML_BYTE_PREFIX => Some((*aml.get(1)?, 2)),
+        _ => None,
+    }
+}
+
+/// Writes SLP_TYP and SLP_EN to a PM1 control register.
+unsafe fn enter_sleep_state(port: u16, slp_typ: u16) {
+    let mut control = Port::<u16>::new(port);
+    let value = (control.read() & !SLP_TYP_MASK) | ((slp_typ << SLP_TYP_SHIFT) & SLP_TYP_MASK);
+    control.write(value | SLP_EN);
+}
+
+/// Turns the machine off (ACPI S5).
+///
+/// Uses the FADT/DSDT values read by `init`; if those are missing or the
+/// write doesn't take, tries the power-off ports of QEMU and Bochs before
+/// giving up and halting.
+pub fn shutdown() -> ! {
+    x86_64::instructions::interrupts::disable();
+    info!("Powering off");
+
+    if let Ok(sleep_info) = SLEEP_INFO.try_get() {
+        unsafe {
+            // Firmware in legacy mode ignores SLP_EN until ACPI is enabled
+            if let Some((smi_command, acpi_enable)) = sleep_info.acpi_enable {
+                let mut control = Port::<u16>::new(sleep_info.pm1a_control);
+                if control.read() & SCI_EN == 0 {
+                    Port::<u8>::new(smi_command).write(acpi_enable);
+                    for _ in 0..1_000_000 {
+                        if control.read() & SCI_EN != 0 {
+                            break;
+                        }
+                        core::hint::spin_loop();
+                    }
+                }
+            }
+
+            enter_sleep_state(sleep_info.pm1a_control, sleep_info.slp_typ_a);
+            if let Some(pm1b_control) = sleep_info.pm1b_control {
+                enter_sleep_state(pm1b_control, sleep_info.slp_typ_b);
+            }
+        }
+    }
+
+    unsafe {
+        // QEMU (PIIX4 PM with the default -machine pc) and Bochs/older QEMU
+        Port::<u16>::new(0x604).write(0x2000);
+        Port::<u16>::new(0xB004).write(0x2000);
+    }
+
+    warn!("Power off failed; halting");
+    crate::hlt_loop();
+}
+
+/// Resets the machine.
+///
+/// Tries, in order: the FADT reset register, pulsing the reset line through
+/// the 8042 keyboard controller, and a triple fault.
+pub fn reboot() -> ! {
+    x86_64::instructions::interrupts::disable();
+    info!("Rebooting");
+
+    if let Ok(&(register, value)) = RESET_REGISTER.try_get() {
+        unsafe { write_reset_register(&register, value) };
+        spin_for_reset();
+    }
+
+    unsafe {
+        // Espera o buffer de entrada do 8042 esvaziar e pulsa a linha de reset
+        let mut status = Port::<u8>::new(0x64);
+        for _ in 0..100_000 {
+            if status.read() & 0x02 == 0 {
+                break;
+            }
+        }
+        status.write(0xFE);
+    }
+    spin_for_reset();
+
+    // With an empty IDT, any interrupt becomes a triple fault
+    unsafe {
+        let empty = DescriptorTablePointer { limit: 0, base: VirtAddr::new(0) };
+        x86_64::instructions::tables::lidt(&empty);
+        core::arch::asm!("int3", options(noreturn));
+    }
+}
+
+/// Gives a reset request some time to take effect before trying the next.
+fn spin_for_reset() {
+    for _ in 0..10_000_000 {
+        core::hint::spin_loop();
+    }
+}
+
+unsafe fn write_reset_register(register: &GenericAddress, value: u8) {
+    match register.address_space {
+        AddressSpace::SystemIo => Port::<u8>::new(register.address as u16).write(value),
+        AddressSpace::SystemMemory => {
+            let virt = memory::physical_memory_offset() + register.address;
+            core::ptr::write_volatile(virt.as_mut_ptr::<u8>(), value);
+        }
+        AddressSpace::PciConfigSpace => {
+            // Bus 0; device in bits 47:32, function in 31:16, offset in 15:0
+            let device = (register.address >> 32) as u8;
+            let function = (register.address >> 16) as u8;
+            let offset = register.address as u16;
+            let shift = (offset & 0x3) * 8;
+            let dword = pci::config_read(0, device, function, offset);
+            let dword = (dword & !(0xFF << shift)) | ((value as u32) << shift);
+            pci::config_write(0, device, function, offset, dword);
+        }
+        _ => warn!("Unsupported ACPI reset register space {:?}", register.address_space),
+    }
+}
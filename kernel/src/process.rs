@@ -1,12 +1,15 @@
 extern crate alloc;
 use alloc::vec::Vec;
-use spin::RwLock;
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::{Mutex, RwLock};
 use lazy_static::lazy_static;
-use alloc::{boxed::Box, collections::vec_deque::VecDeque};
-use x86_64::{instructions::interrupts, structures::paging::{FrameAllocator, Mapper, PageTableFlags, Size4KiB}, VirtAddr};
-use object::{Object, ObjectSegment};
+use alloc::{boxed::Box, collections::{vec_deque::VecDeque, BTreeMap, BTreeSet}};
+use x86_64::{instructions::interrupts, structures::{idt::PageFaultErrorCode, paging::{mapper::TranslateResult, FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTableFlags, Size4KiB, Translate}}, VirtAddr};
+use object::{Object, ObjectSegment, SegmentFlags};
+use log::{debug, error, warn};
 
-use crate::{gdt, memory};
+use crate::{gdt, memory::{self, vma::AddrSpace, BootInfoFrameAllocator}, tty};
 
 #[derive(Debug)]
 #[repr(packed)]
@@ -43,17 +46,76 @@ pub struct Context {
 pub fn schedule_next(context_addr: usize) -> usize {
     let mut running_queue = RUNNING_QUEUE.write();
     let mut current_thread = CURRENT_THREAD.write();
+    let mut exited_threads = EXITED_THREADS.write();
+    let mut sleeping_threads = SLEEPING_THREADS.write();
+
+    // Wake sleepers whose deadline has passed (the queue is sorted by it).
+    // For a thread blocked with a timeout, this is the timeout.
+    let now = crate::interrupts::ticks();
+    while sleeping_threads.front().is_some_and(|thread| thread.wake_tick.is_some_and(|tick| tick <= now)) {
+        let mut thread = sleeping_threads.pop_front().unwrap();
+        thread.wake_tick = None;
+        thread.blocked_on = None;
+        running_queue.push_back(thread);
+    }
+
+    // Threads retired on a previous tick are no longer running on their
+    // kernel stacks, so their memory can be released now. One whose user
+    // memory can't be freed yet (kernel memory lock busy) waits another tick.
+    // Its PID is only freed then, so it can't be reused while still queued.
+    exited_threads.retain_mut(|thread| {
+        if !thread.release_user_memory() {
+            return true;
+        }
+        PROCESS_TABLE.lock().retire(thread.pid);
+        false
+    });
 
     if let Some(mut thread) = current_thread.take() {
         // Save the location of the Context struct
         thread.context = context_addr as u64;
-        // Put to the back of the queue
-        running_queue.push_back(thread);
+        unsafe { thread.fpu_state.save() };
+        if thread.exited && !running_queue.is_empty() {
+            // We are still on this thread's kernel stack: free it next tick
+            exited_threads.push(thread);
+        } else if thread.has_fatal_signal() {
+            // Signalled before it could be parked: don't park it, so the
+            // signal is acted on below
+            running_queue.push_back(thread);
+        } else if let (Some(queue), None, false) = (thread.blocked_on, thread.wake_tick, running_queue.is_empty()) {
+            // Park it until wake_one/wake_all puts it back in the run queue
+            queue.waiters.lock().push_back(thread);
+        } else if let (Some(tick), false) = (thread.wake_tick, running_queue.is_empty()) {
+            // Sleeping, or blocked with a timeout: park it until the tick
+            // that wakes it (wake_one/wake_all may take it out earlier)
+            let index = sleeping_threads.partition_point(|other| other.wake_tick <= Some(tick));
+            sleeping_threads.insert(index, thread);
+        } else {
+            // Put to the back of the queue. An exited, blocked or sleeping thread with
+            // nothing to switch to just keeps idling until another thread
+            // shows up.
+            running_queue.push_back(thread);
+        }
     }
-    // Get the next thread in the queue
-    *current_thread = running_queue.pop_front();
+    // Get the next thread in the queue. This is the last stop before a thread
+    // resumes (and, for user threads, returns to userspace), so pending
+    // signals are acted on here.
+    *current_thread = loop {
+        match running_queue.pop_front() {
+            Some(mut thread) if thread.has_fatal_signal()
+                && (thread.context != context_addr as u64 || !running_queue.is_empty()) => {
+                // Default action: terminate. If we're still on its kernel
+                // stack, the rule for exited threads applies: free it next tick.
+                thread.exited = true;
+                exited_threads.push(thread);
+            }
+            next => break next,
+        }
+    };
     match current_thread.as_ref() {
         Some(thread) => {
+            unsafe { thread.fpu_state.restore() };
+            crate::syscall::set_kernel_stack(thread.syscall_stack_end);
             // Set the kernel stack for the next interrupt
             gdt::set_interrupt_stack_table(
               gdt::TIMER_INTERRUPT_INDEX as usize,
@@ -65,33 +127,445 @@ pub fn schedule_next(context_addr: usize) -> usize {
     }
 }
 
+/// Number of priority levels; 0 is the lowest.
+pub const PRIORITY_LEVELS: usize = 4;
+/// Priority of threads nobody asked otherwise for.
+pub const DEFAULT_PRIORITY: u8 = 1;
+/// How many times a runnable thread may be passed over in favour of a higher
+/// level before it's bumped up one level (aging, against starvation).
+const AGING_THRESHOLD: u32 = 8;
+
+/// Runnable threads, one FIFO per priority level.
+///
+/// `pop_front` takes from the highest non-empty level, so threads of the
+/// same level run round-robin. Every thread left waiting in a lower level
+/// ages; after `AGING_THRESHOLD` passes it moves up a level until it runs,
+/// then drops back to its own priority.
+struct RunQueue {
+    levels: [VecDeque<Box<Thread>>; PRIORITY_LEVELS],
+}
+
+impl RunQueue {
+    fn new() -> Self {
+        RunQueue { levels: core::array::from_fn(|_| VecDeque::new()) }
+    }
+
+    fn push_back(&mut self, thread: Box<Thread>) {
+        self.levels[thread.run_priority as usize].push_back(thread);
+    }
+
+    fn pop_front(&mut self) -> Option<Box<Thread>> {
+        let top = self.levels.iter().rposition(|level| !level.is_empty())?;
+        let mut thread = self.levels[top].pop_front()?;
+        thread.run_priority = thread.priority;
+        thread.age = 0;
+
+        for level in (0..top).rev() {
+            let mut i = 0;
+            while i < self.levels[level].len() {
+                let waiting = &mut self.levels[level][i];
+                waiting.age += 1;
+                if waiting.age < AGING_THRESHOLD {
+                    i += 1;
+                    continue;
+                }
+                let mut waiting = self.levels[level].remove(i).unwrap();
+                waiting.age = 0;
+                waiting.run_priority = level as u8 + 1;
+                self.levels[level + 1].push_back(waiting);
+            }
+        }
+        Some(thread)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.levels.iter().all(|level| level.is_empty())
+    }
+
+    fn iter_mut(&mut self) -> impl Iterator<Item = &mut Box<Thread>> {
+        self.levels.iter_mut().flatten()
+    }
+}
+
 lazy_static! {
-    static ref RUNNING_QUEUE: RwLock<VecDeque<Box<Thread>>> =
-        RwLock::new(VecDeque::new());
+    static ref RUNNING_QUEUE: RwLock<RunQueue> =
+        RwLock::new(RunQueue::new());
 
     static ref CURRENT_THREAD: RwLock<Option<Box<Thread>>> =
         RwLock::new(None);
+
+    static ref EXITED_THREADS: RwLock<Vec<Box<Thread>>> =
+        RwLock::new(Vec::new());
+
+    /// Threads in `sleep_ticks`, sorted by wake-up tick.
+    static ref SLEEPING_THREADS: RwLock<VecDeque<Box<Thread>>> =
+        RwLock::new(VecDeque::new());
+}
+
+/// Identifies a thread (kernel or user) while it exists. Once the thread
+/// has been freed its PID may be handed to a new one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pid(u64);
+
+impl Pid {
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadKind {
+    Kernel,
+    User,
+}
+
+/// What the process table knows about a thread.
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessInfo {
+    pub pid: Pid,
+    pub kind: ThreadKind,
+    pub parent: Option<Pid>, // Thread that created it, if it was created by one
+    pub exit_code: Option<i64>, // Set by `exit`; the entry then outlives the thread
+}
+
+/// Every live thread by PID, plus the PID allocator.
+///
+/// New PIDs count up from 1; freed ones are reused lowest first. Only
+/// touched with interrupts disabled, since the scheduler frees PIDs.
+struct ProcessTable {
+    entries: BTreeMap<Pid, ProcessInfo>,
+    free_pids: BTreeSet<u64>,
+    next_pid: u64,
+    zombies: BTreeSet<Pid>, // Freed threads whose exit code wasn't reaped yet
+}
+
+impl ProcessTable {
+    const fn new() -> Self {
+        ProcessTable {
+            entries: BTreeMap::new(),
+            free_pids: BTreeSet::new(),
+            next_pid: 1,
+            zombies: BTreeSet::new(),
+        }
+    }
+
+    fn insert(&mut self, kind: ThreadKind, parent: Option<Pid>) -> Pid {
+        let pid = match self.free_pids.pop_first() {
+            Some(pid) => Pid(pid),
+            None => {
+                self.next_pid += 1;
+                Pid(self.next_pid - 1)
+            }
+        };
+        self.entries.insert(pid, ProcessInfo { pid, kind, parent, exit_code: None });
+        pid
+    }
+
+    /// Called once the thread `pid` has been freed. A thread that exited
+    /// with a code stays in the table (a zombie) until `reap`; any other is
+    /// forgotten and its PID freed.
+    fn retire(&mut self, pid: Pid) {
+        // Don't let console signals reach whoever gets this PID next
+        let _ = FOREGROUND_PID.compare_exchange(pid.0, 0, Ordering::SeqCst, Ordering::SeqCst);
+
+        if self.entries.get(&pid).is_some_and(|info| info.exit_code.is_some()) {
+            self.zombies.insert(pid);
+            return;
+        }
+        self.remove(pid);
+    }
+
+    fn remove(&mut self, pid: Pid) -> Option<ProcessInfo> {
+        let info = self.entries.remove(&pid)?;
+        self.free_pids.insert(pid.0);
+        Some(info)
+    }
+}
+
+static PROCESS_TABLE: Mutex<ProcessTable> = Mutex::new(ProcessTable::new());
+
+/// Gives `thread` a PID and records it in the process table.
+fn register_thread(thread: &mut Thread, kind: ThreadKind) -> Pid {
+    interrupts::without_interrupts(|| {
+        let parent = current_pid();
+        thread.pid = PROCESS_TABLE.lock().insert(kind, parent);
+        thread.pid
+    })
+}
+
+/// PID of the thread running right now, or `None` during boot.
+pub fn current_pid() -> Option<Pid> {
+    interrupts::without_interrupts(|| {
+        CURRENT_THREAD.read().as_ref().map(|thread| thread.pid)
+    })
+}
+
+/// Looks up a live thread in the process table.
+pub fn process_info(pid: Pid) -> Option<ProcessInfo> {
+    interrupts::without_interrupts(|| PROCESS_TABLE.lock().entries.get(&pid).copied())
+}
+
+/// Removes the entry of a thread that called `exit` and returns its exit
+/// code, freeing the PID. `None` if `pid` hasn't exited (or doesn't exist).
+pub fn reap(pid: Pid) -> Option<i64> {
+    interrupts::without_interrupts(|| {
+        let mut table = PROCESS_TABLE.lock();
+        // Only once the thread is gone, so its PID can't be handed out
+        // while the scheduler still knows it
+        if !table.zombies.remove(&pid) {
+            return None;
+        }
+        table.remove(pid)?.exit_code
+    })
+}
+
+/// Snapshot of every live thread, ordered by PID.
+pub fn processes() -> Vec<ProcessInfo> {
+    interrupts::without_interrupts(|| PROCESS_TABLE.lock().entries.values().copied().collect())
+}
+
+/// Signals understood by the kernel. Values follow the usual POSIX numbers.
+///
+/// Only the default action exists for now: both signals terminate the target
+/// the next time the scheduler would resume it. There are no user handlers,
+/// masks, or queued (counted) signals; sending one twice is the same as once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Signal {
+    Interrupt = 2, // SIGINT
+    Kill = 9,      // SIGKILL
+}
+
+impl Signal {
+    fn bit(self) -> u64 {
+        1 << (self as u8)
+    }
 }
 
 struct Thread {
-    kernel_stack: Vec<u8>,
-    user_stack: Vec<u8>,
+    pid: Pid,
+    // Owned for the thread's whole life and never resized, so the addresses
+    // below stay valid. Kernel threads run on `user_stack`; for user threads
+    // it's empty and the real stack is in `addr_space`.
+    kernel_stack: Box<[u8]>,
+    user_stack: Box<[u8]>,
+    syscall_stack: Box<[u8]>, // Empty for kernel threads, which don't make syscalls
     kernel_stack_end: u64, // This address goes in the TSS
+    syscall_stack_end: u64,
     user_stack_end: u64,
     context: u64, // Address of Context on kernel stack
+    fpu_state: Box<FpuState>, // Saved on every switch away from the thread
+    exited: bool, // Set by thread_exit; the scheduler retires the thread
+    pending_signals: u64, // One bit per Signal, set by send_signal
+    priority: u8, // 0..PRIORITY_LEVELS, set at creation or by set_priority
+    run_priority: u8, // Level it's queued at; above `priority` while aged
+    age: u32, // Times passed over by the scheduler since it last ran
+    blocked_on: Option<&'static WaitQueue>, // Set by wait_until; cleared on wake
+    wake_tick: Option<u64>, // Set by sleep_ticks; cleared by the scheduler on wake
+    lazy_regions: Vec<LazyRegion>, // Mapped page by page on first access
+    addr_space: AddrSpace, // Reserved user ranges (image, stack)
+    heap_start: u64, // Program break range; all zero for kernel threads
+    heap_end: u64, // Current break; pages below it are mapped
+    heap_limit: u64, // The break can't move past this (stack guard page)
+}
+
+/// x87/MMX/SSE registers of a thread, in the FXSAVE layout.
+///
+/// The interrupt entry only saves integer registers, so the scheduler saves
+/// and restores this around every switch.
+#[repr(C, align(16))]
+struct FpuState([u8; 512]);
+
+impl FpuState {
+    /// The state after `fninit`, with all SSE exceptions masked.
+    fn new() -> Box<Self> {
+        let mut state = Box::new(FpuState([0; 512]));
+        state.0[0..2].copy_from_slice(&0x037Fu16.to_le_bytes()); // FCW
+        state.0[24..28].copy_from_slice(&0x1F80u32.to_le_bytes()); // MXCSR
+        state
+    }
+
+    unsafe fn save(&mut self) {
+        asm!("fxsave64 [{}]", in(reg) self.0.as_mut_ptr(), options(nostack));
+    }
+
+    unsafe fn restore(&self) {
+        asm!("fxrstor64 [{}]", in(reg) self.0.as_ptr(), options(nostack));
+    }
+}
+
+/// Turns on the FPU and SSE (CR0/CR4), needed before any `fxsave`.
+pub fn enable_fpu() {
+    use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        });
+        Cr4::update(|flags| {
+            flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE);
+        });
+        asm!("fninit", options(nomem, nostack));
+    }
+}
+
+/// A range of user memory that is reserved but only mapped when touched.
+///
+/// The page fault handler maps the faulting page with `flags` and retries
+/// the instruction; see `handle_page_fault`.
+#[derive(Debug, Clone, Copy)]
+struct LazyRegion {
+    start: VirtAddr,
+    end: VirtAddr, // Exclusive
+    flags: PageTableFlags,
+}
+
+impl LazyRegion {
+    fn contains(&self, addr: VirtAddr) -> bool {
+        (self.start..self.end).contains(&addr)
+    }
+}
+
+impl Thread {
+    fn has_fatal_signal(&self) -> bool {
+        self.pending_signals & (Signal::Interrupt.bit() | Signal::Kill.bit()) != 0
+    }
+
+    /// Unmaps every page reserved in the thread's address space and frees
+    /// the frames. Returns `false` if the kernel memory lock was busy, in
+    /// which case nothing was touched.
+    ///
+    /// Runs from the timer interrupt, so it can't wait for the lock.
+    fn release_user_memory(&mut self) -> bool {
+        if self.addr_space.regions().is_empty() {
+            return true;
+        }
+        let released = memory::try_with_kernel_memory(|mapper, frame_allocator| {
+            for region in self.addr_space.regions() {
+                // Only the heap below the break is mapped; don't walk the rest
+                let size = if region.start.as_u64() == self.heap_start {
+                    x86_64::align_up(self.heap_end, 4096) - self.heap_start
+                } else {
+                    region.end - region.start
+                };
+                if let Err(err) = memory::free_pages_mapper(mapper, frame_allocator, region.start, size) {
+                    warn!("pid {}: could not unmap {:?}: {:?}", self.pid.as_u64(), region, err);
+                }
+            }
+        });
+        if released.is_none() {
+            return false;
+        }
+        while let Some(start) = self.addr_space.regions().first().map(|region| region.start) {
+            self.addr_space.free(start);
+        }
+        self.lazy_regions.clear();
+        self.heap_end = self.heap_start;
+        true
+    }
+}
+
+/// Process that receives console signals such as Ctrl+C (0 = none).
+static FOREGROUND_PID: AtomicU64 = AtomicU64::new(0);
+
+pub fn foreground_pid() -> Option<Pid> {
+    match FOREGROUND_PID.load(Ordering::SeqCst) {
+        0 => None,
+        pid => Some(Pid(pid)),
+    }
+}
+
+pub fn set_foreground(pid: Pid) {
+    FOREGROUND_PID.store(pid.0, Ordering::SeqCst);
+}
+
+/// Marks `signal` as pending on the thread `pid`.
+///
+/// Delivery happens in the scheduler, right before the thread would resume.
+/// A thread parked on a `WaitQueue` or among the sleepers is moved back to
+/// the run queue first, so it doesn't stay blocked with the signal pending.
+pub fn send_signal(pid: Pid, signal: Signal) -> Result<(), &'static str> {
+    interrupts::without_interrupts(|| {
+        let mut running_queue = RUNNING_QUEUE.write();
+        let mut current_thread = CURRENT_THREAD.write();
+
+        if let Some(thread) = current_thread.iter_mut()
+            .chain(running_queue.iter_mut())
+            .find(|thread| thread.pid == pid) {
+            thread.pending_signals |= signal.bit();
+            return Ok(());
+        }
+
+        let mut sleeping_threads = SLEEPING_THREADS.write();
+        let mut thread = WAIT_QUEUES.lock().iter()
+            .find_map(|queue| queue.take(pid))
+            .or_else(|| {
+                let index = sleeping_threads.iter().position(|thread| thread.pid == pid)?;
+                sleeping_threads.remove(index)
+            })
+            .ok_or("No such process")?;
+        thread.pending_signals |= signal.bit();
+        thread.blocked_on = None;
+        thread.wake_tick = None;
+        running_queue.push_back(thread);
+        Ok(())
+    })
+}
+
+/// Changes the priority of the calling thread. It takes effect the next time
+/// the thread is queued, i.e. from the next tick or yield.
+pub fn set_priority(level: u8) -> Result<(), &'static str> {
+    if level as usize >= PRIORITY_LEVELS {
+        return Err("Invalid priority");
+    }
+    interrupts::without_interrupts(|| {
+        let mut current_thread = CURRENT_THREAD.write();
+        let thread = current_thread.as_mut().ok_or("No current thread")?;
+        thread.priority = level;
+        thread.run_priority = level;
+        Ok(())
+    })
+}
+
+/// Sends SIGINT to the foreground process, if there is one (Ctrl+C).
+pub fn interrupt_foreground() {
+    if let Some(pid) = foreground_pid() {
+        if send_signal(pid, Signal::Interrupt).is_err() {
+            warn!("Foreground process {} is gone", pid.as_u64());
+        }
+    }
 }
 
 const KERNEL_STACK_SIZE: usize = 4096 * 2;
 const USER_STACK_SIZE: usize = 4096 * 5;
 const INTERRUPT_CONTEXT_SIZE: usize = 40 + 120; // = 160 bytes
-const USER_CODE_START: u64 = 0x5000000;
-const USER_CODE_END: u64 = 0x80000000;
-const USER_STACK_START: u64 = 0x5002000;
+pub const USER_CODE_START: u64 = 0x5000000;
+pub const USER_CODE_END: u64 = 0x80000000;
 
-pub fn new_user_thread(bin: &[u8], mapper: &mut impl Mapper<Size4KiB>, frame_allocator: &mut impl FrameAllocator<Size4KiB>) -> Result<usize, &'static str> {
+/// Allocates a zeroed stack. A boxed slice can't grow, so its buffer never
+/// moves while the thread owns it.
+fn alloc_stack(size: usize) -> Box<[u8]> {
+    alloc::vec![0u8; size].into_boxed_slice()
+}
+
+/// Top of `stack` (stacks grow down), 16-byte aligned.
+fn stack_end(stack: &[u8]) -> u64 {
+    (VirtAddr::from_ptr(stack.as_ptr()) + stack.len() as u64).align_down(16u64).as_u64()
+}
+
+pub fn new_user_thread(
+    bin: &[u8],
+    priority: u8,
+    mapper: &mut (impl Mapper<Size4KiB> + Translate),
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<Pid, &'static str> {
     // Check the header
     const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
 
+    if priority as usize >= PRIORITY_LEVELS {
+        return Err("Invalid priority");
+    }
     if bin[0..4] != ELF_MAGIC {
         return Err("Expected ELF binary");
     }
@@ -100,10 +574,38 @@ pub fn new_user_thread(bin: &[u8], mapper: &mut impl Mapper<Size4KiB>, frame_all
     if let Ok(obj) = object::File::parse(bin) {
         let entry_point = obj.entry();
 
+        // The entry point must land in code we are about to load; otherwise
+        // the first instruction would fault somewhere confusing
+        let entry_segment = obj.segments().find(|segment| {
+            (segment.address()..segment.address() + segment.size()).contains(&entry_point)
+        });
+        match entry_segment {
+            None => return Err("ELF entry point outside any loadable segment"),
+            Some(segment) if !is_executable(segment.flags()) =>
+                return Err("ELF entry point in a non-executable segment"),
+            Some(_) => {}
+        }
+
+        // Reserve the whole image as one region; segments may share pages
+        let mut addr_space = AddrSpace::new(VirtAddr::new(USER_CODE_START), VirtAddr::new(USER_CODE_END));
+        let image_start = obj.segments().filter(|segment| segment.size() > 0)
+            .map(|segment| segment.address()).min();
+        let image_end = obj.segments().filter(|segment| segment.size() > 0)
+            .map(|segment| segment.address() + segment.size()).max();
+        let (Some(image_start), Some(image_end)) = (image_start, image_end) else {
+            return Err("ELF has no loadable segments");
+        };
+        if addr_space.reserve_at(
+            VirtAddr::new(image_start),
+            image_end - image_start,
+            PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE).is_none() {
+            return Err("ELF segment outside allowed range");
+        }
+
         for segment in obj.segments() {
             let segment_address = segment.address() as u64;
         
-            kprintln!("Section {:?} : {:#016X}", segment.name(), segment_address);
+            debug!("Section {:?} : {:#016X}", segment.name(), segment_address);
         
             let start_address = VirtAddr::new(segment_address);
             let end_address = start_address + segment.size() as u64;
@@ -124,93 +626,781 @@ pub fn new_user_thread(bin: &[u8], mapper: &mut impl Mapper<Size4KiB>, frame_all
                 return Err("Could not allocate memory");
             }
         
-            if let Ok(data) = segment.data() {
-                // Copy data
-                let dest_ptr = segment_address as *mut u8;
-                for (i, value) in data.iter().enumerate() {
-                    unsafe {
-                        let ptr = dest_ptr.add(i);
-                        core::ptr::write(ptr, *value);
-                    }
+            // `size` is p_memsz; `data` only holds the p_filesz bytes from the
+            // file. The rest (.bss) must read as zero.
+            let data = segment.data().map_err(|_| "Could not read ELF segment")?;
+            if data.len() as u64 > segment.size() {
+                return Err("ELF segment file size exceeds its memory size");
+            }
+            let dest_ptr = segment_address as *mut u8;
+            unsafe {
+                core::ptr::copy_nonoverlapping(data.as_ptr(), dest_ptr, data.len());
+                core::ptr::write_bytes(dest_ptr.add(data.len()), 0, segment.size() as usize - data.len());
+            }
+        }
+
+        // Segments were mapped writable so they could be filled in; now give
+        // every page its final permissions (W^X)
+        for segment in obj.segments() {
+            let start_page = Page::<Size4KiB>::containing_address(VirtAddr::new(segment.address()));
+            let end_page = Page::<Size4KiB>::containing_address(VirtAddr::new(segment.address() + segment.size() - 1));
+            for page in Page::range_inclusive(start_page, end_page) {
+                let flags = user_page_flags(&obj, page);
+                match unsafe { mapper.update_flags(page, flags) } {
+                    Ok(flush) => flush.flush(),
+                    Err(_) => return Err("Could not set segment permissions"),
                 }
             }
         }
 
         // Create the Thread object
-        let new_thread = {
-            let kernel_stack = Vec::with_capacity(KERNEL_STACK_SIZE);
-            let kernel_stack_end = (VirtAddr::from_ptr(kernel_stack.as_ptr())
-                                   + KERNEL_STACK_SIZE as u64).as_u64();
-            let user_stack = Vec::with_capacity(USER_STACK_SIZE);
-            let user_stack_end = (VirtAddr::from_ptr(user_stack.as_ptr())
-                                  + USER_STACK_SIZE as u64).as_u64();
+        let mut new_thread = {
+            let kernel_stack = alloc_stack(KERNEL_STACK_SIZE);
+            let kernel_stack_end = stack_end(&kernel_stack);
+            let user_stack = Box::default(); // The user stack lives in the process address space
+            let user_stack_end = 0;
+            let syscall_stack = alloc_stack(KERNEL_STACK_SIZE);
+            let syscall_stack_end = stack_end(&syscall_stack);
             let context = kernel_stack_end - INTERRUPT_CONTEXT_SIZE as u64;
 
             Box::new(Thread {
+                pid: Pid(0), // Assigned when queued
                 kernel_stack,
                 user_stack,
+                syscall_stack,
                 kernel_stack_end,
                 user_stack_end,
+                syscall_stack_end,
                 context,
+                fpu_state: FpuState::new(),
+                exited: false,
+                pending_signals: 0,
+                priority,
+                run_priority: priority,
+                age: 0,
+                blocked_on: None,
+                wake_tick: None,
+                lazy_regions: Vec::new(),
+                addr_space,
+                heap_start: 0,
+                heap_end: 0,
+                heap_limit: 0,
             })
         };
 
+        // Only the top page of the user stack is mapped up front; the rest of
+        // the reservation is mapped on demand as the stack grows down into it
+        let stack_flags = PageTableFlags::PRESENT |
+            PageTableFlags::WRITABLE |
+            PageTableFlags::USER_ACCESSIBLE |
+            PageTableFlags::NO_EXECUTE;
+        // The stack goes at the top of the user range, leaving the space
+        // between it and the image for the heap (see `brk`)
+        let stack_start = match new_thread.addr_space.reserve_at(
+            VirtAddr::new(USER_CODE_END - USER_STACK_SIZE as u64),
+            USER_STACK_SIZE as u64,
+            stack_flags) {
+            Some(start) => start,
+            None => return Err("No room for the user stack"),
+        };
+        new_thread.heap_start = x86_64::align_up(image_end, 4096);
+        new_thread.heap_end = new_thread.heap_start;
+        // Keep an unmapped guard page below the stack
+        new_thread.heap_limit = stack_start.as_u64() - 4096;
+        // Reserve everything the break can reach, so nothing else is placed there
+        let heap_flags = PageTableFlags::PRESENT |
+            PageTableFlags::WRITABLE |
+            PageTableFlags::USER_ACCESSIBLE |
+            PageTableFlags::NO_EXECUTE;
+        if new_thread.addr_space.reserve_at(
+            VirtAddr::new(new_thread.heap_start),
+            new_thread.heap_limit.saturating_sub(new_thread.heap_start),
+            heap_flags).is_none() {
+            return Err("No room for the user heap");
+        }
+        let stack_end = stack_start + USER_STACK_SIZE as u64;
+        if memory::allocate_pages_mapper(
+            mapper,
+            frame_allocator,
+            stack_end - 4096u64, // Start address
+            4096, // Size (bytes)
+            stack_flags).is_err() {
+            return Err("Could not allocate user stack");
+        }
+        new_thread.lazy_regions.push(LazyRegion {
+            start: stack_start,
+            end: stack_end,
+            flags: stack_flags,
+        });
+
         // Set context registers
         let context = unsafe { &mut *(new_thread.context as *mut Context) };
         context.rip = entry_point as usize; // Instruction pointer
-        memory::allocate_pages_mapper(
-            mapper,
-            frame_allocator,
-            VirtAddr::new(USER_STACK_START), // Start address
-            USER_STACK_SIZE as u64, // Size (bytes)
-            PageTableFlags::PRESENT |
-            PageTableFlags::WRITABLE |
-            PageTableFlags::USER_ACCESSIBLE);
-        context.rsp = (USER_STACK_START as usize) + USER_STACK_SIZE; // Stack pointer
+        context.rsp = stack_end.as_u64() as usize; // Stack pointer
         context.rflags = 0x200; // Interrupts enabled
 
         let (code_selector, data_selector) = gdt::get_user_segments();
         context.cs = code_selector.0 as usize;
         context.ss = data_selector.0 as usize;
 
+        debug!("Entry point: {:#x}", entry_point);
+        let pid = register_thread(&mut new_thread, ThreadKind::User);
         interrupts::without_interrupts(|| {
             RUNNING_QUEUE.write().push_back(new_thread);
         });
 
-        return Ok(entry_point as usize);
+        return Ok(pid);
     }
     Err("Could not parse ELF")
 }
 
-pub fn new_kernel_thread(function: fn()->()) {
-    let new_thread = {
-        let kernel_stack = Vec::with_capacity(KERNEL_STACK_SIZE);
-        let kernel_stack_end = (VirtAddr::from_ptr(kernel_stack.as_ptr())
-                               + KERNEL_STACK_SIZE as u64).as_u64();
-        let user_stack = Vec::with_capacity(USER_STACK_SIZE);
-        let user_stack_end = (VirtAddr::from_ptr(user_stack.as_ptr())
-                              + USER_STACK_SIZE as u64).as_u64();
+/// Loads `bin` as the first user process, queues it to run and makes it the
+/// foreground process.
+///
+/// The TTY must already be active so the process has somewhere to print; if
+/// it isn't, nothing is loaded. Failures are logged and returned.
+pub fn spawn_init(
+    bin: &[u8],
+    mapper: &mut (impl Mapper<Size4KiB> + Translate),
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<Pid, &'static str> {
+    if !tty::is_active() {
+        error!("spawn_init: TTY not initialized, refusing to start init");
+        return Err("TTY not initialized");
+    }
+
+    match new_user_thread(bin, DEFAULT_PRIORITY, mapper, frame_allocator) {
+        Ok(pid) => {
+            kprintln!("init started as pid {}", pid.as_u64());
+            set_foreground(pid);
+            Ok(pid)
+        }
+        Err(err) => {
+            kprintln!("Failed to load init: {}", err);
+            Err(err)
+        }
+    }
+}
+
+// ELF program header flags (p_flags)
+const PF_X: u32 = 0x1;
+const PF_W: u32 = 0x2;
+
+fn elf_flags(flags: SegmentFlags) -> u32 {
+    match flags {
+        SegmentFlags::Elf { p_flags } => p_flags,
+        _ => 0,
+    }
+}
+
+/// Whether an ELF segment has the PF_X flag.
+fn is_executable(flags: SegmentFlags) -> bool {
+    elf_flags(flags) & PF_X != 0
+}
+
+/// Page table flags for a user page holding ELF segments.
+///
+/// Pages are read-only unless a segment in them has PF_W, and non-executable
+/// unless one has PF_X. Segments usually don't share pages; when they do, the
+/// page gets the union of their permissions.
+fn user_page_flags(obj: &object::File, page: Page<Size4KiB>) -> PageTableFlags {
+    let page_range = page.start_address().as_u64()..page.start_address().as_u64() + page.size();
+    let p_flags = obj.segments()
+        .filter(|segment| segment.address() < page_range.end
+            && segment.address() + segment.size() > page_range.start)
+        .fold(0, |acc, segment| acc | elf_flags(segment.flags()));
+
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if p_flags & PF_W != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if p_flags & PF_X == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+pub fn new_kernel_thread(function: fn()->(), priority: u8) -> Pid {
+    assert!((priority as usize) < PRIORITY_LEVELS, "invalid priority {}", priority);
+    let mut new_thread = {
+        let kernel_stack = alloc_stack(KERNEL_STACK_SIZE);
+        let kernel_stack_end = stack_end(&kernel_stack);
+        let user_stack = alloc_stack(USER_STACK_SIZE);
+        let user_stack_end = stack_end(&user_stack);
         let context = kernel_stack_end - INTERRUPT_CONTEXT_SIZE as u64;
 
         Box::new(Thread {
+            pid: Pid(0), // Assigned when queued
             kernel_stack,
             user_stack,
+            syscall_stack: Box::default(),
             kernel_stack_end,
             user_stack_end,
-            context})
+            syscall_stack_end: 0,
+            context,
+            fpu_state: FpuState::new(),
+            exited: false,
+            pending_signals: 0,
+            priority,
+            run_priority: priority,
+            age: 0,
+            blocked_on: None,
+            wake_tick: None,
+            lazy_regions: Vec::new(),
+            addr_space: AddrSpace::new(VirtAddr::zero(), VirtAddr::zero()),
+            heap_start: 0,
+            heap_end: 0,
+            heap_limit: 0,
+        })
     };
     // Set context registers
     // Add Thread to RUNNING_QUEUE
     let context = unsafe {&mut *(new_thread.context as *mut Context)};
-    context.rip = function as usize; // Instruction pointer
-    context.rsp = new_thread.user_stack_end as usize; // Stack pointer
+    // Start in the trampoline, which receives the function in rdi (SysV ABI)
+    context.rip = kernel_thread_trampoline as usize; // Instruction pointer
+    context.rdi = function as usize;
+    // Align the stack as if the trampoline had been reached by a `call`
+    context.rsp = (new_thread.user_stack_end as usize & !0xF) - 8; // Stack pointer
     context.rflags = 0x200; // Interrupts enabled
 
     let (code_selector, data_selector) = gdt::get_kernel_segments();
     context.cs = code_selector.0 as usize;
     context.ss = data_selector.0 as usize;
 
+    let pid = register_thread(&mut new_thread, ThreadKind::Kernel);
     interrupts::without_interrupts(|| {
         RUNNING_QUEUE.write().push_back(new_thread);
     });
-}
\ No newline at end of file
+    pid
+}
+
+/// Entry point of every kernel thread.
+///
+/// Nothing sits above the thread function on its stack, so letting it `ret`
+/// would jump to garbage. The trampoline calls it and then retires the thread
+/// through `thread_exit`, which lets kernel threads simply return when done.
+extern "C" fn kernel_thread_trampoline(function: usize) -> ! {
+    let function: fn() = unsafe { core::mem::transmute(function) };
+    function();
+    thread_exit()
+}
+
+/// Terminates the calling thread.
+///
+/// The thread is flagged as exited and yields, so the scheduler takes it off
+/// the run queue right away. Its stacks and user pages are freed on the next
+/// scheduler pass, once nothing runs on them anymore.
+///
+/// Also usable from exception handlers to kill the thread that faulted.
+pub fn thread_exit() -> ! {
+    interrupts::without_interrupts(|| {
+        if let Some(thread) = CURRENT_THREAD.write().as_mut() {
+            thread.exited = true;
+        }
+    });
+
+    loop {
+        // Only comes back if there's no other thread to run
+        yield_now();
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// Moves the calling process's program break to `new_end`, mapping or
+/// unmapping heap pages as needed, and returns the new break.
+///
+/// The heap starts right after the ELF image; it can't shrink below that or
+/// grow into the stack's guard page.
+pub fn brk(new_end: u64) -> Result<u64, &'static str> {
+    let (heap_start, heap_end, heap_limit) = interrupts::without_interrupts(|| {
+        CURRENT_THREAD.read().as_ref().map(|thread| (thread.heap_start, thread.heap_end, thread.heap_limit))
+    }).ok_or("No current thread")?;
+    if heap_limit == 0 {
+        return Err("Kernel threads have no heap");
+    }
+    if new_end < heap_start {
+        return Err("Break below the heap start");
+    }
+    if new_end > heap_limit {
+        return Err("Break would run into the stack");
+    }
+
+    // Pages are mapped up to the break rounded up
+    let mapped_end = x86_64::align_up(heap_end, 4096);
+    let new_mapped_end = x86_64::align_up(new_end, 4096);
+    let flags = PageTableFlags::PRESENT |
+        PageTableFlags::WRITABLE |
+        PageTableFlags::USER_ACCESSIBLE |
+        PageTableFlags::NO_EXECUTE;
+    let result = memory::with_kernel_memory(|mapper, frame_allocator| {
+        if new_mapped_end > mapped_end {
+            memory::allocate_pages_mapper(mapper, frame_allocator,
+                VirtAddr::new(mapped_end), new_mapped_end - mapped_end, flags)
+                .map_err(|_| "Could not map heap pages")
+        } else if new_mapped_end < mapped_end {
+            memory::free_pages_mapper(mapper, frame_allocator,
+                VirtAddr::new(new_mapped_end), mapped_end - new_mapped_end)
+                .map(|_| ())
+                .map_err(|_| "Could not unmap heap pages")
+        } else {
+            Ok(())
+        }
+    }).ok_or("Kernel memory not installed")?;
+    result?;
+
+    interrupts::without_interrupts(|| {
+        if let Some(thread) = CURRENT_THREAD.write().as_mut() {
+            thread.heap_end = new_end;
+        }
+    });
+    Ok(new_end)
+}
+
+/// The calling thread's current program break.
+pub fn brk_current() -> Option<u64> {
+    interrupts::without_interrupts(|| {
+        CURRENT_THREAD.read().as_ref().map(|thread| thread.heap_end)
+    })
+}
+
+/// Terminates the calling thread with exit status `code`, which stays in the
+/// process table until the thread is reaped.
+pub fn exit(code: i64) -> ! {
+    interrupts::without_interrupts(|| {
+        if let Some(pid) = current_pid() {
+            if let Some(info) = PROCESS_TABLE.lock().entries.get_mut(&pid) {
+                info.exit_code = Some(code);
+            }
+        }
+    });
+    thread_exit()
+}
+
+/// Lets the scheduler run the next runnable thread right away, instead of
+/// waiting for the timer tick. Returns when this thread is scheduled again
+/// (immediately, if nothing else is runnable).
+///
+/// Must be called from a thread: the boot code has no `Thread` to come back
+/// to, so it would never resume.
+pub fn yield_now() {
+    crate::interrupts::yield_cpu();
+}
+
+/// Puts the calling thread to sleep for at least `n` timer ticks.
+///
+/// The thread leaves the run queue until the timer interrupt for its
+/// deadline moves it back. Must be called from a thread.
+pub fn sleep_ticks(n: u64) {
+    let deadline = crate::interrupts::ticks() + n;
+    interrupts::without_interrupts(|| {
+        if let Some(thread) = CURRENT_THREAD.write().as_mut() {
+            thread.wake_tick = Some(deadline);
+        }
+    });
+
+    // Back here after the deadline, or right away if no other thread could
+    // run; then wait out the rest of it
+    yield_now();
+    while crate::interrupts::ticks() < deadline {
+        interrupts::enable_and_hlt();
+    }
+
+    interrupts::without_interrupts(|| {
+        if let Some(thread) = CURRENT_THREAD.write().as_mut() {
+            thread.wake_tick = None;
+        }
+    });
+}
+
+/// Resolves a page fault at `addr` by mapping a fresh page, if the address
+/// lies in one of the current thread's lazily mapped regions.
+///
+/// Returns `false` when the fault is not a lazy-mapping miss (protection
+/// violation, address outside every region, ...) or the page couldn't be
+/// mapped; the caller then treats it as a real fault.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        // The page is present, so this isn't a missing lazy mapping
+        return false;
+    }
+
+    let flags = match CURRENT_THREAD.try_read() {
+        Some(current_thread) => current_thread.as_ref().and_then(|thread| {
+            thread.lazy_regions.iter()
+                .find(|region| region.contains(addr))
+                .map(|region| region.flags)
+        }),
+        None => None,
+    };
+    let Some(flags) = flags else {
+        return false;
+    };
+
+    let page: Page<Size4KiB> = Page::containing_address(addr);
+    memory::try_with_kernel_memory(|mapper, frame_allocator| {
+        memory::allocate_pages_mapper(mapper, frame_allocator, page.start_address(), 4096, flags).is_ok()
+    }).unwrap_or(false)
+}
+
+/// Checks that the current thread may access `[start, end)` (and write to
+/// it, if `write`) and makes every page of it present first, as the page
+/// fault handler would on a user access: lazily mapped pages get mapped and,
+/// for writes, copy-on-write pages get their private copy.
+///
+/// Syscalls call this before touching a user buffer, so a buffer in a stack
+/// page the thread hasn't used yet works instead of failing.
+pub fn fault_in_user_range(start: VirtAddr, end: VirtAddr, write: bool) -> bool {
+    interrupts::without_interrupts(|| {
+        let current_thread = CURRENT_THREAD.read();
+        let Some(thread) = current_thread.as_ref() else {
+            return false;
+        };
+        let is_reserved = |addr| thread.addr_space.find(addr).is_some();
+        memory::with_kernel_memory(|mapper, frame_allocator| {
+            fault_in_range(mapper, frame_allocator, is_reserved, &thread.lazy_regions, start, end, write)
+        }).unwrap_or(false)
+    })
+}
+
+/// The work of `fault_in_user_range`, for a thread whose reserved pages are
+/// those `is_reserved` accepts and whose lazily mapped ones are `lazy_regions`.
+fn fault_in_range(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    is_reserved: impl Fn(VirtAddr) -> bool,
+    lazy_regions: &[LazyRegion],
+    start: VirtAddr,
+    end: VirtAddr,
+    write: bool,
+) -> bool {
+    if start >= end {
+        return true;
+    }
+    let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        required |= PageTableFlags::WRITABLE;
+    }
+
+    let first = Page::<Size4KiB>::containing_address(start);
+    let last = Page::<Size4KiB>::containing_address(end - 1u64);
+    for page in Page::range_inclusive(first, last) {
+        let addr = page.start_address();
+        if !is_reserved(addr) {
+            return false;
+        }
+
+        match mapper.translate(addr) {
+            TranslateResult::NotMapped => {
+                let Some(region) = lazy_regions.iter().find(|region| region.contains(addr)) else {
+                    return false;
+                };
+                if memory::allocate_pages_mapper(mapper, frame_allocator, addr, 4096, region.flags).is_err() {
+                    return false;
+                }
+            }
+            TranslateResult::Mapped { flags, .. } if write && flags.contains(memory::COPY_ON_WRITE) => {
+                if !memory::break_cow(mapper, frame_allocator, addr) {
+                    return false;
+                }
+            }
+            _ => {}
+        }
+
+        if !matches!(mapper.translate(addr), TranslateResult::Mapped { flags, .. } if flags.contains(required)) {
+            return false;
+        }
+    }
+    true
+}
+
+/// Virtual address `user_access_self_test` puts its scratch stack at.
+const USER_ACCESS_TEST_BASE: u64 = 0x_5555_8000_0000;
+
+/// Sets up a lazily mapped stack region with nothing mapped in it yet and
+/// checks that a buffer across two of its untouched pages is faulted in
+/// writable, while a buffer outside the reservation is refused.
+///
+/// Run once at boot, after `memory::install`.
+pub fn user_access_self_test() -> bool {
+    const PAGES: u64 = 4;
+    let stack_start = VirtAddr::new(USER_ACCESS_TEST_BASE);
+    let stack_end = stack_start + PAGES * 4096;
+    let flags = PageTableFlags::PRESENT |
+        PageTableFlags::WRITABLE |
+        PageTableFlags::USER_ACCESSIBLE |
+        PageTableFlags::NO_EXECUTE;
+
+    let mut addr_space = AddrSpace::new(stack_start, stack_end + PAGES * 4096);
+    if addr_space.reserve_at(stack_start, PAGES * 4096, flags).is_none() {
+        return false;
+    }
+    let lazy_regions = [LazyRegion { start: stack_start, end: stack_end, flags }];
+    let is_reserved = |addr| addr_space.find(addr).is_some();
+
+    memory::with_kernel_memory(|mapper, frame_allocator| {
+        let buffer = (stack_start + 4096u64 + 100u64)..(stack_start + 3 * 4096u64 - 100u64);
+        let faulted_in = fault_in_range(mapper, frame_allocator, is_reserved,
+            &lazy_regions, buffer.start, buffer.end, true);
+        let writable = [buffer.start, buffer.end - 1u64].iter().all(|&addr| {
+            matches!(mapper.translate(addr), TranslateResult::Mapped { flags, .. } if flags.contains(PageTableFlags::WRITABLE))
+        });
+        let outside_refused = !fault_in_range(mapper, frame_allocator, is_reserved,
+            &lazy_regions, stack_end, stack_end + 16u64, false);
+
+        let freed = memory::free_pages_mapper(mapper, frame_allocator, stack_start, PAGES * 4096);
+        faulted_in && writable && outside_refused && matches!(freed, Ok(2))
+    }).unwrap_or(false)
+}
+
+/// Patches the entry point of the ELF image `bin` to an address outside
+/// every segment, and into a non-executable segment if it has one, and
+/// checks that `new_user_thread` refuses both before mapping anything.
+///
+/// Run once at boot, after `memory::install`.
+pub fn entry_point_self_test(bin: &[u8]) -> bool {
+    // e_entry of an ELF64 header
+    const ENTRY_OFFSET: usize = 24;
+
+    let Ok(obj) = object::File::parse(bin) else {
+        return false;
+    };
+    let data_segment = obj.segments()
+        .find(|segment| segment.size() > 0 && !is_executable(segment.flags()))
+        .map(|segment| segment.address());
+    let bogus_entries = [Some(0), data_segment];
+
+    let in_use_before = memory::stats().allocated_frames;
+    let rejected = memory::with_kernel_memory(|mapper, frame_allocator| {
+        bogus_entries.iter().flatten().all(|&entry| {
+            let mut patched = bin.to_vec();
+            patched[ENTRY_OFFSET..ENTRY_OFFSET + 8].copy_from_slice(&entry.to_le_bytes());
+            new_user_thread(&patched, DEFAULT_PRIORITY, mapper, frame_allocator).is_err()
+        })
+    }).unwrap_or(false);
+    rejected && memory::stats().allocated_frames == in_use_before
+}
+
+/// A list of threads sleeping until some event happens.
+///
+/// Threads block with `wait_until`, which takes them off the run queue on the
+/// next timer tick. `wake_one`/`wake_all` (safe to call from interrupt
+/// handlers) put them back. Queues must be `'static`, since parked threads
+/// refer to them.
+pub struct WaitQueue {
+    waiters: Mutex<VecDeque<Box<Thread>>>,
+    registered: AtomicBool, // Listed in WAIT_QUEUES
+}
+
+/// Every queue a thread has blocked on, so `send_signal` can find threads
+/// parked in them.
+static WAIT_QUEUES: Mutex<Vec<&'static WaitQueue>> = Mutex::new(Vec::new());
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self { waiters: Mutex::new(VecDeque::new()), registered: AtomicBool::new(false) }
+    }
+
+    fn register(&'static self) {
+        if !self.registered.swap(true, Ordering::SeqCst) {
+            WAIT_QUEUES.lock().push(self);
+        }
+    }
+
+    /// Takes the thread `pid` out of the queue, if it's parked there.
+    fn take(&self, pid: Pid) -> Option<Box<Thread>> {
+        let mut waiters = self.waiters.lock();
+        let index = waiters.iter().position(|thread| thread.pid == pid)?;
+        waiters.remove(index)
+    }
+
+    /// Wakes the thread that has been waiting the longest, if any.
+    pub fn wake_one(&'static self) {
+        interrupts::without_interrupts(|| {
+            let mut running_queue = RUNNING_QUEUE.write();
+            let mut current_thread = CURRENT_THREAD.write();
+
+            if let Some(mut thread) = self.waiters.lock().pop_front() {
+                thread.blocked_on = None;
+                running_queue.push_back(thread);
+                return;
+            }
+
+            // The waiter may not have been parked yet
+            if let Some(thread) = current_thread.iter_mut()
+                .chain(running_queue.iter_mut())
+                .find(|thread| thread.is_blocked_on(self)) {
+                thread.blocked_on = None;
+                return;
+            };
+
+            // Or it waits with a timeout, parked among the sleepers
+            let mut sleeping_threads = SLEEPING_THREADS.write();
+            if let Some(index) = sleeping_threads.iter().position(|thread| thread.is_blocked_on(self)) {
+                let mut thread = sleeping_threads.remove(index).unwrap();
+                thread.blocked_on = None;
+                thread.wake_tick = None;
+                running_queue.push_back(thread);
+            }
+        });
+    }
+
+    /// Wakes every thread waiting on this queue.
+    pub fn wake_all(&'static self) {
+        interrupts::without_interrupts(|| {
+            let mut running_queue = RUNNING_QUEUE.write();
+            let mut current_thread = CURRENT_THREAD.write();
+
+            for mut thread in self.waiters.lock().drain(..) {
+                thread.blocked_on = None;
+                running_queue.push_back(thread);
+            }
+
+            for thread in current_thread.iter_mut().chain(running_queue.iter_mut()) {
+                if thread.is_blocked_on(self) {
+                    thread.blocked_on = None;
+                }
+            }
+
+            let mut sleeping_threads = SLEEPING_THREADS.write();
+            let mut index = 0;
+            while index < sleeping_threads.len() {
+                if sleeping_threads[index].is_blocked_on(self) {
+                    let mut thread = sleeping_threads.remove(index).unwrap();
+                    thread.blocked_on = None;
+                    thread.wake_tick = None;
+                    running_queue.push_back(thread);
+                } else {
+                    index += 1;
+                }
+            }
+        });
+    }
+}
+
+impl Thread {
+    fn is_blocked_on(&self, queue: &WaitQueue) -> bool {
+        self.blocked_on.is_some_and(|blocked_on| core::ptr::eq(blocked_on, queue))
+    }
+}
+
+/// Blocks the calling thread on `queue` until `poll` returns `Some`.
+///
+/// `poll` runs with interrupts disabled, and the thread is marked as waiting
+/// in the same critical section, so a wake-up from an interrupt handler can't
+/// slip in between the check and going to sleep.
+///
+/// Must be called from a thread; it enables interrupts while sleeping.
+pub fn wait_until<T>(queue: &'static WaitQueue, poll: impl FnMut() -> Option<T>) -> T {
+    wait_until_deadline(queue, None, poll).expect("wait without a deadline timed out")
+}
+
+/// Like `wait_until`, but gives up after `timeout_ticks` timer ticks and
+/// returns `None` if `poll` still hasn't returned `Some` by then.
+pub fn wait_until_timeout<T>(queue: &'static WaitQueue, timeout_ticks: u64, poll: impl FnMut() -> Option<T>) -> Option<T> {
+    wait_until_deadline(queue, Some(crate::interrupts::ticks() + timeout_ticks), poll)
+}
+
+fn wait_until_deadline<T>(queue: &'static WaitQueue, deadline: Option<u64>, mut poll: impl FnMut() -> Option<T>) -> Option<T> {
+    let expired = || deadline.is_some_and(|tick| crate::interrupts::ticks() >= tick);
+    loop {
+        let ready = interrupts::without_interrupts(|| {
+            let value = poll();
+            if value.is_none() && !expired() {
+                queue.register();
+                if let Some(thread) = CURRENT_THREAD.write().as_mut() {
+                    thread.blocked_on = Some(queue);
+                    // The scheduler parks it with the sleepers, so the
+                    // deadline wakes it
+                    thread.wake_tick = deadline;
+                }
+            }
+            value
+        });
+        if ready.is_some() || expired() {
+            return ready;
+        }
+
+        // Get parked right away; this returns once a wake-up brings us back,
+        // or immediately if there was no other thread to switch to
+        yield_now();
+
+        // Sleep until the scheduler parks us and a wake-up (or the deadline)
+        // brings us back
+        loop {
+            interrupts::disable();
+            let blocked = CURRENT_THREAD.read().as_ref()
+                .is_some_and(|thread| thread.blocked_on.is_some());
+            if !blocked || expired() {
+                if let Some(thread) = CURRENT_THREAD.write().as_mut() {
+                    thread.blocked_on = None;
+                    thread.wake_tick = None;
+                }
+                interrupts::enable();
+                break;
+            }
+            interrupts::enable_and_hlt();
+        }
+    }
+}
+
+/// Queue nothing ever wakes, for `scheduler_self_test`.
+static SELF_TEST_QUEUE: WaitQueue = WaitQueue::new();
+
+fn block_forever() {
+    wait_until(&SELF_TEST_QUEUE, || None::<()>);
+}
+
+fn sleep_forever() {
+    loop {
+        sleep_ticks(1 << 40);
+    }
+}
+
+/// Yields until `done` returns `true`, for at most `timeout_ticks` ticks.
+fn yield_until(timeout_ticks: u64, mut done: impl FnMut() -> bool) -> bool {
+    let deadline = crate::interrupts::ticks() + timeout_ticks;
+    while !done() {
+        if crate::interrupts::ticks() >= deadline {
+            return false;
+        }
+        yield_now();
+    }
+    true
+}
+
+/// Checks that `sleep_ticks` doesn't return before its deadline, and that
+/// SIGKILL reaches a thread parked on a `WaitQueue` and one parked among the
+/// sleepers: both are woken and terminated instead of staying blocked.
+///
+/// Must be called from a thread, since it sleeps and yields.
+pub fn scheduler_self_test() -> bool {
+    const SLEEP_TICKS: u64 = 5;
+    let hz = crate::interrupts::timer_frequency().max(1);
+
+    let start_tick = crate::interrupts::ticks();
+    let start_ms = crate::interrupts::uptime_ms();
+    sleep_ticks(SLEEP_TICKS);
+    let slept = crate::interrupts::ticks() >= start_tick + SLEEP_TICKS
+        && crate::interrupts::uptime_ms() - start_ms >= SLEEP_TICKS * 1000 / hz;
+
+    let is_parked = |pid: Pid| interrupts::without_interrupts(|| {
+        SELF_TEST_QUEUE.waiters.lock().iter().any(|thread| thread.pid == pid)
+    });
+    let is_asleep = |pid: Pid| interrupts::without_interrupts(|| {
+        SLEEPING_THREADS.read().iter().any(|thread| thread.pid == pid)
+    });
+    // Kill it even if it never got parked, so a failure doesn't leave it behind
+    let kill = |pid: Pid| {
+        send_signal(pid, Signal::Kill).is_ok() && yield_until(hz, || process_info(pid).is_none())
+    };
+
+    let blocked = new_kernel_thread(block_forever, DEFAULT_PRIORITY);
+    let parked = yield_until(hz, || is_parked(blocked));
+    let blocked_killed = kill(blocked);
+
+    let sleeper = new_kernel_thread(sleep_forever, DEFAULT_PRIORITY);
+    let asleep = yield_until(hz, || is_asleep(sleeper));
+    let sleeper_killed = kill(sleeper);
+
+    slept && parked && blocked_killed && asleep && sleeper_killed
+}
@@ -0,0 +1,146 @@
+//! Wall-clock time from the CMOS real-time clock.
+
+use core::fmt;
+use x86_64::instructions::{interrupts, port::Port};
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+/// Keeps NMIs disabled while a CMOS register is selected
+const NMI_DISABLE: u8 = 0x80;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const HOUR_PM: u8 = 1 << 7;
+
+/// Without a century register, years are taken to be in this century.
+const CENTURY: u16 = 2000;
+
+/// A date and time as kept by the RTC (usually UTC, but the firmware
+/// decides).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+fn read_register(register: u8) -> u8 {
+    let mut address = Port::<u8>::new(CMOS_ADDRESS);
+    let mut data = Port::<u8>::new(CMOS_DATA);
+    unsafe {
+        address.write(NMI_DISABLE | register);
+        data.read()
+    }
+}
+
+fn update_in_progress() -> bool {
+    read_register(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0
+}
+
+/// The raw time registers, in whatever format Status B says.
+fn read_raw() -> [u8; 6] {
+    while update_in_progress() {
+        core::hint::spin_loop();
+    }
+    [
+        read_register(REG_SECONDS),
+        read_register(REG_MINUTES),
+        read_register(REG_HOURS),
+        read_register(REG_DAY),
+        read_register(REG_MONTH),
+        read_register(REG_YEAR),
+    ]
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+/// Reads the current date and time from the RTC.
+///
+/// The registers are read until two reads in a row agree, so an update
+/// starting halfway through can't produce a mixed-up time.
+pub fn now() -> DateTime {
+    let (raw, status_b) = interrupts::without_interrupts(|| {
+        let mut raw = read_raw();
+        loop {
+            let again = read_raw();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        (raw, read_register(REG_STATUS_B))
+    });
+    decode(raw, status_b)
+}
+
+/// Turns the raw time registers (seconds, minutes, hours, day, month, year)
+/// into a `DateTime`, following the BCD/binary and 12/24-hour bits of
+/// `status_b`.
+fn decode(raw: [u8; 6], status_b: u8) -> DateTime {
+    let [second, minute, hour, day, month, year] = raw;
+    let pm = hour & HOUR_PM != 0;
+    let hour = hour & !HOUR_PM;
+
+    let decode = |value: u8| if status_b & STATUS_B_BINARY != 0 { value } else { from_bcd(value) };
+    let mut hour = decode(hour);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12-hour mode: 12 AM is midnight and 12 PM is noon
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    DateTime {
+        year: CENTURY + decode(year) as u16,
+        month: decode(month),
+        day: decode(day),
+        hour,
+        minute: decode(minute),
+        second: decode(second),
+    }
+}
+
+/// Decodes register values for the four combinations of BCD/binary and
+/// 12/24-hour mode, including midnight and noon in 12-hour mode.
+pub fn rtc_self_test() -> bool {
+    let bcd_24 = decode([0x45, 0x30, 0x23, 0x31, 0x12, 0x24], STATUS_B_24_HOUR);
+    let binary_24 = decode([45, 30, 23, 31, 12, 24], STATUS_B_24_HOUR | STATUS_B_BINARY);
+    let expected = DateTime { year: 2024, month: 12, day: 31, hour: 23, minute: 30, second: 45 };
+
+    let bcd_hour = |hour| decode([0, 0, hour, 1, 1, 0], 0).hour;
+    let binary_hour = |hour| decode([0, 0, hour, 1, 1, 0], STATUS_B_BINARY).hour;
+    let twelve_hour = bcd_hour(0x12) == 0
+        && bcd_hour(HOUR_PM | 0x12) == 12
+        && bcd_hour(HOUR_PM | 0x11) == 23
+        && bcd_hour(0x09) == 9
+        && binary_hour(12) == 0
+        && binary_hour(HOUR_PM | 1) == 13;
+
+    bcd_24 == expected && binary_24 == expected && twelve_hour
+}
@@ -1,25 +1,248 @@
-use uart_16550::SerialPort;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
 use spin::Mutex;
-use lazy_static::lazy_static;
-use x86_64::instructions::interrupts;
-
-lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
-        serial_port.init();
-        Mutex::new(serial_port)
-    };
+use x86_64::instructions::{interrupts, port::Port};
+
+/// I/O bases of the standard PC serial ports.
+pub const COM1: u16 = 0x3F8;
+pub const COM2: u16 = 0x2F8;
+pub const COM3: u16 = 0x3E8;
+pub const COM4: u16 = 0x2E8;
+
+// Registradores, relativos à base da UART
+const REG_DATA: u16 = 0; // DLL com DLAB=1
+const REG_INTERRUPT_ENABLE: u16 = 1; // DLM com DLAB=1
+const REG_FIFO_CONTROL: u16 = 2;
+const REG_LINE_CONTROL: u16 = 3;
+const REG_MODEM_CONTROL: u16 = 4;
+const REG_LINE_STATUS: u16 = 5;
+const REG_SCRATCH: u16 = 7;
+
+const IER_RECEIVED_DATA: u8 = 1 << 0;
+const MCR_DTR_RTS_OUT2: u8 = 0x0B; // OUT2 liga a saída de IRQ da UART
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_TRANSMIT_EMPTY: u8 = 1 << 5;
+const LCR_DLAB: u8 = 1 << 7;
+const LCR_8N1: u8 = 0x03;
+const FCR_ENABLE_CLEAR_14: u8 = 0xC7; // FIFOs ligados e limpos, gatilho de 14 bytes
+
+/// Input clock of the UART divided by 16: the baud rate for divisor 1.
+const UART_MAX_BAUD: u32 = 115_200;
+/// Baud rate of COM1 unless someone calls `init` with another.
+pub const DEFAULT_BAUD: u32 = 38_400;
+
+/// A 16550-compatible UART at a fixed I/O base.
+pub struct SerialPort {
+    base: u16,
+}
+
+impl SerialPort {
+    /// Creates a handle to the UART at `base` without touching the hardware.
+    ///
+    /// Unsafe because nothing checks that `base` really is a UART, and other
+    /// handles to the same port may exist.
+    pub const unsafe fn new(base: u16) -> Self {
+        Self { base }
+    }
+
+    pub fn base(&self) -> u16 {
+        self.base
+    }
+
+    fn port(&self, register: u16) -> Port<u8> {
+        Port::new(self.base + register)
+    }
+
+    /// Programs the divisor latch for `baud` and sets 8N1 with FIFOs on.
+    ///
+    /// Fails if `baud` can't be derived from the 115200 Hz base rate, or if
+    /// the scratch register doesn't hold a value (no UART at this base).
+    pub fn init(&mut self, baud: u32) -> Result<(), &'static str> {
+        if baud == 0 || baud > UART_MAX_BAUD || !UART_MAX_BAUD.is_multiple_of(baud) {
+            return Err("Unsupported baud rate");
+        }
+        let divisor = (UART_MAX_BAUD / baud) as u16;
+
+        unsafe {
+            self.port(REG_SCRATCH).write(0xAE);
+            if self.port(REG_SCRATCH).read() != 0xAE {
+                return Err("No UART at this address");
+            }
+
+            self.port(REG_INTERRUPT_ENABLE).write(0);
+            self.port(REG_LINE_CONTROL).write(LCR_DLAB);
+            self.port(REG_DATA).write(divisor as u8);
+            self.port(REG_INTERRUPT_ENABLE).write((divisor >> 8) as u8);
+            self.port(REG_LINE_CONTROL).write(LCR_8N1);
+            self.port(REG_FIFO_CONTROL).write(FCR_ENABLE_CLEAR_14);
+            self.port(REG_MODEM_CONTROL).write(MCR_DTR_RTS_OUT2);
+        }
+        Ok(())
+    }
+
+    /// Sends one byte, waiting for the transmit holding register to empty.
+    pub fn send(&mut self, byte: u8) {
+        unsafe {
+            while self.port(REG_LINE_STATUS).read() & LSR_TRANSMIT_EMPTY == 0 {
+                core::hint::spin_loop();
+            }
+            self.port(REG_DATA).write(byte);
+        }
+    }
+
+    /// Returns a received byte, if one is waiting.
+    pub fn try_receive(&mut self) -> Option<u8> {
+        unsafe {
+            if self.port(REG_LINE_STATUS).read() & LSR_DATA_READY == 0 {
+                return None;
+            }
+            Some(self.port(REG_DATA).read())
+        }
+    }
+
+    /// Turns on the received-data interrupt and the OUT2 line that gates the
+    /// UART's IRQ output.
+    pub fn enable_receive_interrupt(&mut self) {
+        unsafe {
+            self.port(REG_INTERRUPT_ENABLE).write(IER_RECEIVED_DATA);
+            self.port(REG_MODEM_CONTROL).write(MCR_DTR_RTS_OUT2);
+        }
+    }
+
+    /// ISA IRQ line of the standard port at this base: 4 for COM1/COM3, 3
+    /// for COM2/COM4.
+    pub fn irq(&self) -> u8 {
+        match self.base {
+            COM2 | COM4 => crate::interrupts::COM2_IRQ,
+            _ => crate::interrupts::COM1_IRQ,
+        }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
+/// Initialized ports, indexed like `COM1`..`COM4`. COM1 is brought up on
+/// first use so the print macros work from the first line of boot.
+static PORTS: [Mutex<Option<SerialPort>>; 4] = [
+    Mutex::new(None),
+    Mutex::new(None),
+    Mutex::new(None),
+    Mutex::new(None),
+];
+
+/// Port the print macros write to.
+static DEFAULT_PORT: AtomicU16 = AtomicU16::new(COM1);
+
+fn port_index(base: u16) -> Option<usize> {
+    [COM1, COM2, COM3, COM4].iter().position(|&com| com == base)
+}
+
+/// Initializes the serial port at `base` (one of `COM1`..`COM4`) at `baud`.
+pub fn init(base: u16, baud: u32) -> Result<(), &'static str> {
+    let index = port_index(base).ok_or("Not a standard COM port")?;
+    let mut port = unsafe { SerialPort::new(base) };
+    port.init(baud)?;
+    interrupts::without_interrupts(|| {
+        *PORTS[index].lock() = Some(port);
+    });
+    Ok(())
+}
+
+/// Sends the print macros' output to `base` from now on. The port must have
+/// been initialized with `init` (COM1 needn't be).
+pub fn set_default_port(base: u16) -> Result<(), &'static str> {
+    let index = port_index(base).ok_or("Not a standard COM port")?;
+    if base != COM1 && interrupts::without_interrupts(|| PORTS[index].lock().is_none()) {
+        return Err("Serial port not initialized");
+    }
+    DEFAULT_PORT.store(base, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Runs `f` on the initialized port at `base`, bringing COM1 up at
+/// `DEFAULT_BAUD` if nobody did yet. `None` for ports not initialized.
+fn with_port<R>(base: u16, f: impl FnOnce(&mut SerialPort) -> R) -> Option<R> {
+    let index = port_index(base)?;
+    interrupts::without_interrupts(|| {
+        let mut slot = PORTS[index].lock();
+        if slot.is_none() && base == COM1 {
+            let mut port = unsafe { SerialPort::new(COM1) };
+            port.init(DEFAULT_BAUD).ok()?;
+            *slot = Some(port);
+        }
+        slot.as_mut().map(f)
+    })
 }
 
+/// Set once `enable_receive_interrupt` has routed the default port's IRQ;
+/// until then `SerialStream` has to poll `read_byte` itself.
+static RECEIVE_IRQ_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Returns the next byte received on the default port, or `None` if none is
+/// waiting.
+///
+/// Polls the line-status register's data-ready bit, so it works before (or
+/// without) the receive interrupt. Uses a fresh handle instead of the locked
+/// one in `PORTS`, so it is safe from interrupt handlers.
+pub fn read_byte() -> Option<u8> {
+    let mut port = unsafe { SerialPort::new(DEFAULT_PORT.load(Ordering::Relaxed)) };
+    port.try_receive()
+}
+
+/// Whether received bytes reach `task::serial` through IRQ4.
+pub fn receive_interrupt_enabled() -> bool {
+    RECEIVE_IRQ_ENABLED.load(Ordering::Acquire)
+}
+
+/// Enables the default port's received-data interrupt and routes its IRQ to
+/// a handler that feeds `task::serial::SerialStream`.
+pub fn enable_receive_interrupt() {
+    // Hold the lock so nobody is mid-write while the UART is reconfigured
+    let irq = with_port(DEFAULT_PORT.load(Ordering::Relaxed), |port| {
+        port.enable_receive_interrupt();
+        port.irq()
+    });
+    if let Some(irq) = irq {
+        crate::interrupts::register_irq(irq, serial_irq);
+        RECEIVE_IRQ_ENABLED.store(true, Ordering::Release);
+    }
+}
+
+/// Receive IRQ handler: drains the receive buffer into the serial stream.
+fn serial_irq() {
+    // Ler o dado é o que limpa a interrupção na UART
+    while let Some(byte) = read_byte() {
+        crate::task::serial::add_byte(byte);
+    }
+}
 
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    interrupts::without_interrupts(|| {
-        SERIAL1.lock().write_fmt(args).expect("Printing to serial failed");
+    with_port(DEFAULT_PORT.load(Ordering::Relaxed), |port| {
+        port.write_fmt(args).expect("Printing to serial failed");
     });
 }
 
+/// Writes to the default port without taking its lock.
+///
+/// Only meant for the panic path, where the lock may be held by the code that
+/// panicked. The port was initialized long before, so a fresh handle to the
+/// same I/O base is enough; nothing here allocates.
+#[doc(hidden)]
+pub fn _panic_print(args: ::core::fmt::Arguments) {
+    use core::fmt::Write;
+    let mut serial_port = unsafe { SerialPort::new(DEFAULT_PORT.load(Ordering::Relaxed)) };
+    let _ = serial_port.write_fmt(args);
+}
+
 /// Prints to the host through the serial interface.
 #[macro_export]
 macro_rules! serial_print {
@@ -35,4 +258,13 @@ macro_rules! serial_println {
     ($fmt:expr) => ($crate::serial_print!(concat!($fmt, "\n")));
     ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(
         concat!($fmt, "\n"), $($arg)*));
-}
\ No newline at end of file
+}
+
+/// Prints to the host through the serial interface without locking, appending
+/// a newline. Use only while panicking.
+#[macro_export]
+macro_rules! panic_serial_println {
+    ($fmt:expr) => ($crate::serial::_panic_print(format_args!(concat!($fmt, "\n"))));
+    ($fmt:expr, $($arg:tt)*) => ($crate::serial::_panic_print(
+        format_args!(concat!($fmt, "\n"), $($arg)*)));
+}
@@ -1,18 +1,238 @@
 use core::arch::{asm, naked_asm};
+use core::sync::atomic::{AtomicU64, Ordering};
+use alloc::vec::Vec;
+use x86_64::VirtAddr;
+use log::{info, warn};
+
+use crate::process;
 
 const MSR_STAR: usize = 0xc0000081;
 const MSR_LSTAR: usize = 0xc0000082;
 const MSR_FMASK: usize = 0xc0000084;
 
+/// Top of the stack `handle_syscall` switches to. The scheduler points it
+/// at the running thread's syscall stack (see `set_kernel_stack`).
+static KERNEL_RSP: AtomicU64 = AtomicU64::new(0);
+/// User rsp, parked here only until it's pushed on the kernel stack.
+static USER_RSP: AtomicU64 = AtomicU64::new(0);
+
+/// Sets the stack the next `syscall` will run on.
+///
+/// It can't be the thread's interrupt stack: the timer IST always starts at
+/// the top of that one, and a syscall that blocks is interrupted mid-way.
+pub fn set_kernel_stack(stack_end: u64) {
+    KERNEL_RSP.store(stack_end, Ordering::Relaxed);
+}
+
+/// Entry point of the `syscall` instruction (LSTAR).
+///
+/// Follows the Linux convention: number in rax, arguments in rdi, rsi, rdx,
+/// result in rax. Every register but rax, rcx and r11 (which `syscall`
+/// itself clobbers) is preserved. Interrupts are off on entry (FMASK).
 #[naked]
 extern "C" fn handle_syscall() {
     unsafe {
         naked_asm!(
-            "mov rdr1, 0"
+            // Trocar para a pilha do kernel
+            "mov [rip + {user_rsp}], rsp",
+            "mov rsp, [rip + {kernel_rsp}]",
+            "push qword ptr [rip + {user_rsp}]",
+            // rip e rflags do usuário, para o sysretq
+            "push rcx",
+            "push r11",
+            // Registradores que a função em Rust pode sobrescrever
+            "push rdi",
+            "push rsi",
+            "push rdx",
+            "push r8",
+            "push r9",
+            "push r10",
+            // 9 pushes: realign the stack to 16 bytes for the call
+            "sub rsp, 8",
+
+            // dispatch(nr, a1, a2, a3) with the C calling convention
+            "mov rcx, rdx",
+            "mov rdx, rsi",
+            "mov rsi, rdi",
+            "mov rdi, rax",
+            "call {dispatch}",
+
+            "add rsp, 8",
+            "pop r10",
+            "pop r9",
+            "pop r8",
+            "pop rdx",
+            "pop rsi",
+            "pop rdi",
+            "pop r11",
+            "pop rcx",
+            "pop rsp",
+            "sysretq",
+            user_rsp = sym USER_RSP,
+            kernel_rsp = sym KERNEL_RSP,
+            dispatch = sym dispatch,
         );
     }
 }
 
+// Números das syscalls, seguindo o Linux x86_64
+pub const SYS_READ: u64 = 0;
+pub const SYS_WRITE: u64 = 1;
+pub const SYS_BRK: u64 = 12;
+pub const SYS_GETPID: u64 = 39;
+pub const SYS_EXIT: u64 = 60;
+
+// Error numbers, returned negated in rax as on Linux
+pub const EBADF: i64 = 9;
+pub const EFAULT: i64 = 14;
+pub const EINVAL: i64 = 22;
+pub const ENOSYS: i64 = 38;
+
+/// Encodes `errno` as a syscall return value (`-errno`).
+fn error(errno: i64) -> u64 {
+    (-errno) as u64
+}
+
+/// Runs syscall `nr` for the current thread and returns the value for rax.
+extern "C" fn dispatch(nr: u64, a1: u64, a2: u64, a3: u64) -> u64 {
+    match nr {
+        SYS_READ => sys_read(a1, a2, a3),
+        SYS_WRITE => sys_write(a1, a2, a3),
+        SYS_BRK => sys_brk(a1),
+        SYS_GETPID => sys_getpid(),
+        SYS_EXIT => sys_exit(a1),
+        _ => {
+            warn!("Unknown syscall {} ({:#x}, {:#x}, {:#x})", nr, a1, a2, a3);
+            error(ENOSYS)
+        }
+    }
+}
+
+/// Checks that `[ptr, ptr + len)` is user memory the calling thread may
+/// read (or write, if `write`): inside the user range and reserved in its
+/// address space. Pages that aren't there yet but would be faulted in by a
+/// user access (lazy stack pages, copy-on-write pages) are made present now.
+fn check_user_range(ptr: u64, len: u64, write: bool) -> Result<(), i64> {
+    if len == 0 {
+        return Ok(());
+    }
+    let end = ptr.checked_add(len).ok_or(EFAULT)?;
+    if ptr < process::USER_CODE_START || end > process::USER_CODE_END {
+        return Err(EFAULT);
+    }
+
+    if process::fault_in_user_range(VirtAddr::new(ptr), VirtAddr::new(end), write) {
+        Ok(())
+    } else {
+        Err(EFAULT)
+    }
+}
+
+/// Largest read or write handled in one call; longer ones return a short
+/// count.
+const MAX_TRANSFER: u64 = 4096;
+
+/// read(fd, buf, len): reads console input for stdin, blocking the thread
+/// until at least one byte has been typed. Returns the number of bytes read.
+fn sys_read(fd: u64, buf: u64, len: u64) -> u64 {
+    if fd != 0 {
+        return error(EBADF);
+    }
+    let len = len.min(MAX_TRANSFER);
+    if let Err(errno) = check_user_range(buf, len, true) {
+        return error(errno);
+    }
+    if len == 0 {
+        return 0;
+    }
+
+    // Block on a kernel buffer; the user one is only touched once there's data
+    let mut bytes = alloc::vec![0u8; len as usize];
+    let count = crate::task::keyboard::read_blocking(&mut bytes);
+    unsafe {
+        core::ptr::copy_nonoverlapping(bytes.as_ptr(), buf as *mut u8, count);
+    }
+    count as u64
+}
+
+/// write(fd, buf, len): prints `buf` on the active TTY for stdout/stderr.
+fn sys_write(fd: u64, buf: u64, len: u64) -> u64 {
+    if fd != 1 && fd != 2 {
+        return error(EBADF);
+    }
+    let len = len.min(MAX_TRANSFER);
+    if let Err(errno) = check_user_range(buf, len, false) {
+        return error(errno);
+    }
+
+    // Copy it in first, so what's printed can't change under us
+    let mut bytes = Vec::with_capacity(len as usize);
+    unsafe {
+        bytes.extend_from_slice(core::slice::from_raw_parts(buf as *const u8, len as usize));
+    }
+    for chunk in bytes.utf8_chunks() {
+        kprint!("{}", chunk.valid());
+        if !chunk.invalid().is_empty() {
+            kprint!("{}", char::REPLACEMENT_CHARACTER);
+        }
+    }
+    len
+}
+
+/// brk(addr): moves the program break. As on Linux, returns the new break,
+/// or the unchanged one if `addr` is 0 or the move failed.
+fn sys_brk(addr: u64) -> u64 {
+    let current = process::brk_current().unwrap_or(0);
+    if addr == 0 {
+        return current;
+    }
+    match process::brk(addr) {
+        Ok(new_end) => new_end,
+        Err(err) => {
+            warn!("pid {}: brk({:#x}) failed: {}", sys_getpid(), addr, err);
+            current
+        }
+    }
+}
+
+fn sys_getpid() -> u64 {
+    crate::process::current_pid().map_or(0, |pid| pid.as_u64())
+}
+
+/// exit(code): ends the calling thread; never returns to userspace.
+fn sys_exit(code: u64) -> u64 {
+    info!("pid {} exited with code {}", sys_getpid(), code as i32);
+    process::exit(code as i32 as i64)
+}
+
+fn exit_with_seven() {
+    sys_exit(7);
+}
+
+/// Checks that an unknown syscall number returns `-ENOSYS`, and that a
+/// thread calling `sys_exit(7)` leaves a zombie with code 7 that `reap`
+/// collects.
+///
+/// Must be called from a thread, since it yields while the other one exits.
+pub fn syscall_self_test() -> bool {
+    let unknown = dispatch(0xDEAD, 0, 0, 0) == error(ENOSYS);
+
+    let pid = process::new_kernel_thread(exit_with_seven, process::DEFAULT_PRIORITY);
+    let deadline = crate::interrupts::ticks() + crate::interrupts::timer_frequency().max(1);
+    let exit_code = loop {
+        if let Some(code) = process::reap(pid) {
+            break Some(code);
+        }
+        if crate::interrupts::ticks() >= deadline {
+            break None;
+        }
+        process::yield_now();
+    };
+    let reaped = exit_code == Some(7) && process::process_info(pid).is_none();
+
+    unknown && reaped
+}
+
 pub fn init() {
     let handler_addr = handle_syscall as *const () as u64;
     unsafe {
@@ -0,0 +1,113 @@
+//! Deferred work ("bottom halves").
+//!
+//! Interrupt handlers should only grab their data from the device and get
+//! out. Anything heavier (decoding, allocating, taking locks that normal
+//! code also takes) goes through `schedule_work` and runs later on the
+//! executor thread, with interrupts enabled.
+
+use crossbeam_queue::ArrayQueue;
+use core::{future::poll_fn, sync::atomic::{AtomicU64, AtomicUsize, Ordering}, task::Poll};
+use futures_util::task::AtomicWaker;
+use lazy_static::lazy_static;
+
+/// Capacity of the deferred work queue.
+pub const WORK_QUEUE_SIZE: usize = 256;
+
+/// A function to run later, with the word of data it needs.
+#[derive(Debug, Clone, Copy)]
+pub struct DeferredWork {
+    pub func: fn(usize),
+    pub arg: usize,
+}
+
+lazy_static! {
+    static ref WORK_QUEUE: ArrayQueue<DeferredWork> = ArrayQueue::new(WORK_QUEUE_SIZE);
+}
+
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+static DROPPED_WORK: AtomicU64 = AtomicU64::new(0);
+
+/// Queues `func(arg)` to run on the worker task.
+///
+/// Safe to call from interrupt context: it doesn't block or allocate.
+/// Returns `false` (and counts the loss) if the queue is full.
+pub fn schedule_work(func: fn(usize), arg: usize) -> bool {
+    if WORK_QUEUE.push(DeferredWork { func, arg }).is_err() {
+        DROPPED_WORK.fetch_add(1, Ordering::Relaxed);
+        return false;
+    }
+    WAKER.wake();
+    true
+}
+
+/// How many work items were dropped because the queue was full.
+pub fn dropped_work() -> u64 {
+    DROPPED_WORK.load(Ordering::Relaxed)
+}
+
+/// Worker task: runs queued work items as they arrive.
+pub async fn run_deferred_work() {
+    loop {
+        let work = poll_fn(|cx| {
+            if let Some(work) = WORK_QUEUE.pop() {
+                return Poll::Ready(work);
+            }
+
+            WAKER.register(cx.waker());
+            match WORK_QUEUE.pop() {
+                Some(work) => {
+                    WAKER.take();
+                    Poll::Ready(work)
+                }
+                None => Poll::Pending,
+            }
+        })
+        .await;
+
+        (work.func)(work.arg);
+    }
+}
+
+/// Arguments `record_test_work` was called with, in call order.
+static TEST_RUNS: [AtomicUsize; 3] = [const { AtomicUsize::new(0) }; 3];
+static TEST_RUN_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+fn record_test_work(arg: usize) {
+    let index = TEST_RUN_COUNT.fetch_add(1, Ordering::SeqCst);
+    if let Some(slot) = TEST_RUNS.get(index) {
+        slot.store(arg, Ordering::SeqCst);
+    }
+}
+
+/// Queues three work items with interrupts off, the way an IRQ handler
+/// would, then waits for the worker task and checks that each ran exactly
+/// once, in the order queued.
+///
+/// Must be called from a thread other than the executor's, since it yields
+/// until the worker has run.
+pub fn deferred_self_test() -> bool {
+    TEST_RUN_COUNT.store(0, Ordering::SeqCst);
+    let queued = x86_64::instructions::interrupts::without_interrupts(|| {
+        (1..=TEST_RUNS.len()).all(|arg| schedule_work(record_test_work, arg))
+    });
+
+    // Wait for all three, then give a duplicate run a few ticks to show up
+    let timeout = crate::interrupts::timer_frequency().max(1);
+    let start = crate::interrupts::ticks();
+    let mut settled_at = None;
+    while crate::interrupts::ticks() < start + timeout {
+        match settled_at {
+            None if TEST_RUN_COUNT.load(Ordering::SeqCst) >= TEST_RUNS.len() => {
+                settled_at = Some(crate::interrupts::ticks());
+            }
+            Some(tick) if crate::interrupts::ticks() >= tick + 5 => break,
+            _ => {}
+        }
+        crate::process::yield_now();
+    }
+
+    let ran_once = TEST_RUN_COUNT.load(Ordering::SeqCst) == TEST_RUNS.len();
+    let in_order = TEST_RUNS.iter().enumerate().all(|(index, run)| run.load(Ordering::SeqCst) == index + 1);
+    queued && ran_once && in_order
+}
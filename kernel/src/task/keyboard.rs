@@ -1,21 +1,53 @@
 use conquer_once::spin::OnceCell;
 use crossbeam_queue::ArrayQueue;
-use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-use core::{pin::Pin, task::{Poll, Context}};
+use pc_keyboard::{layouts::{self, AnyLayout}, DecodedKey, HandleControl, KeyCode, Keyboard, ScancodeSet1};
+use core::{pin::Pin, sync::atomic::{AtomicU64, Ordering}, task::{Poll, Context}};
 use futures_util::{stream::Stream, StreamExt};
 use futures_util::task::AtomicWaker;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use spin::Mutex;
+
+use crate::process::{self, WaitQueue};
 
 static WAKER: AtomicWaker = AtomicWaker::new();
 
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
 
+/// Capacity of the scancode queue.
+///
+/// Typematic repeat runs at up to ~30 keys/s and extended keys produce two or
+/// more bytes per make/break, so a few hundred bytes gives the executor
+/// enough slack to catch up after a long-running task.
+pub const SCANCODE_QUEUE_SIZE: usize = 512;
+
+static DROPPED_SCANCODES: AtomicU64 = AtomicU64::new(0);
+
+/// Snapshot of the keyboard input counters.
+#[derive(Debug, Clone, Copy)]
+pub struct KeyboardStats {
+    pub queue_capacity: usize,
+    pub queued: usize,
+    pub dropped: u64,
+}
+
+/// Returns the current scancode queue usage and the total number of
+/// scancodes dropped because the queue was full.
+pub fn stats() -> KeyboardStats {
+    KeyboardStats {
+        queue_capacity: SCANCODE_QUEUE_SIZE,
+        queued: SCANCODE_QUEUE.try_get().map(|q| q.len()).unwrap_or(0),
+        dropped: DROPPED_SCANCODES.load(Ordering::Relaxed),
+    }
+}
+
 pub struct ScancodeStream {
     _private: (),
 }
 
 impl ScancodeStream {
     pub fn new() -> Self {
-        SCANCODE_QUEUE.try_init_once(|| ArrayQueue::new(100))
+        SCANCODE_QUEUE.try_init_once(|| ArrayQueue::new(SCANCODE_QUEUE_SIZE))
             .expect("ScancodeStream::new should only be called once");
         ScancodeStream { _private: () }
     }
@@ -45,32 +77,266 @@ impl Stream for ScancodeStream {
     }
 }
 
+/// Capacity of the buffer of typed bytes waiting for `read_blocking`.
+pub const INPUT_BUFFER_SIZE: usize = 1024;
+
+lazy_static! {
+    /// Decoded console input (UTF-8 bytes), waiting for a reader.
+    static ref INPUT_BUFFER: ArrayQueue<u8> = ArrayQueue::new(INPUT_BUFFER_SIZE);
+}
+
+/// Threads blocked in `read_blocking`.
+static INPUT_READERS: WaitQueue = WaitQueue::new();
+
+/// Queues a typed character for readers and wakes them up.
+pub(crate) fn push_input(character: char) {
+    let mut bytes = [0; 4];
+    for byte in character.encode_utf8(&mut bytes).bytes() {
+        if INPUT_BUFFER.push(byte).is_err() {
+            warn!("console input buffer full; dropping input");
+            break;
+        }
+    }
+    INPUT_READERS.wake_all();
+}
+
+/// Reads typed console input into `buf`, blocking the calling thread until
+/// at least one byte is available. Returns the number of bytes read.
+pub fn read_blocking(buf: &mut [u8]) -> usize {
+    if buf.is_empty() {
+        return 0;
+    }
+
+    process::wait_until(&INPUT_READERS, || {
+        let mut count = 0;
+        while count < buf.len() {
+            match INPUT_BUFFER.pop() {
+                Some(byte) => {
+                    buf[count] = byte;
+                    count += 1;
+                }
+                None => break,
+            }
+        }
+        (count > 0).then_some(count)
+    })
+}
+
 /// Called by the keyboard interrupt handler
 ///
 /// Must not block or allocate.
 pub(crate) fn add_scancode(scancode: u8) {
     if let Ok(queue) = SCANCODE_QUEUE.try_get() {
         if let Err(_) = queue.push(scancode) {
-            kprintln!("WARNING: scancode queue full; dropping keyboard input");
+            // Printing takes the TTY lock, so leave it to the worker
+            let dropped = DROPPED_SCANCODES.fetch_add(1, Ordering::Relaxed) + 1;
+            super::deferred::schedule_work(report_dropped_scancodes, dropped as usize);
         } else {
             WAKER.wake(); // new
         }
     } else {
-        kprintln!("WARNING: scancode queue uninitialized");
+        super::deferred::schedule_work(|_| kprintln!("WARNING: scancode queue uninitialized"), 0);
+    }
+}
+
+fn report_dropped_scancodes(dropped: usize) {
+    kprintln!("WARNING: scancode queue full; dropping keyboard input ({} dropped)", dropped);
+}
+
+/// Control combinations the kernel reacts to instead of echoing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlEvent {
+    /// Ctrl+C: interrupt the foreground process.
+    Interrupt,
+    /// Ctrl+L: clear the screen.
+    ClearScreen,
+    /// Ctrl+T: dump the interrupt counters to serial.
+    Status,
+    /// Ctrl+Alt+Del (Ctrl+R on the serial console): reboot the machine.
+    Reboot,
+    /// Ctrl+Alt+End (Ctrl+O on the serial console): power the machine off.
+    Shutdown,
+    /// Alt+F1..Alt+F4: show virtual console 0..3.
+    SwitchConsole(usize),
+    /// Shift+PageUp: scroll the console view back by half a screen.
+    ScrollUp,
+    /// Shift+PageDown: scroll the console view forward by half a screen.
+    ScrollDown,
+}
+
+/// A decoded key press, as seen by the TTY and (eventually) userspace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputEvent {
+    Char(char),
+    RawKey(KeyCode),
+    Control(ControlEvent),
+}
+
+impl From<DecodedKey> for InputEvent {
+    /// With `HandleControl::MapLettersToUnicode`, Ctrl+<letter> arrives as
+    /// the ASCII control code (Ctrl+A = 0x01 ... Ctrl+Z = 0x1A). Only the
+    /// combinations with a kernel meaning become `Control`; the rest are
+    /// passed through as characters.
+    fn from(key: DecodedKey) -> Self {
+        match key {
+            DecodedKey::Unicode('\u{03}') => InputEvent::Control(ControlEvent::Interrupt),
+            DecodedKey::Unicode('\u{0c}') => InputEvent::Control(ControlEvent::ClearScreen),
+            DecodedKey::Unicode('\u{14}') => InputEvent::Control(ControlEvent::Status),
+            DecodedKey::Unicode(character) => InputEvent::Char(character),
+            DecodedKey::RawKey(key) => InputEvent::RawKey(key),
+        }
+    }
+}
+
+/// Keyboard layouts the console can decode with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// US, 104 keys
+    Us,
+    /// UK, 105 keys
+    Uk,
+    /// German, 105 keys
+    German,
+}
+
+impl Layout {
+    fn build(self) -> AnyLayout {
+        match self {
+            Layout::Us => AnyLayout::Us104Key(layouts::Us104Key),
+            Layout::Uk => AnyLayout::Uk105Key(layouts::Uk105Key),
+            Layout::German => AnyLayout::De105Key(layouts::De105Key),
+        }
+    }
+}
+
+/// Layout `print_keypresses` decodes with; it picks up changes before the
+/// next scancode.
+static LAYOUT: Mutex<Layout> = Mutex::new(Layout::Us);
+
+/// Switches the keyboard to `layout`. Keys held down across the switch
+/// (modifiers included) have to be pressed again.
+pub fn set_layout(layout: Layout) {
+    *LAYOUT.lock() = layout;
+}
+
+/// The layout keys are decoded with.
+pub fn layout() -> Layout {
+    *LAYOUT.lock()
+}
+
+fn new_keyboard(layout: Layout) -> Keyboard<AnyLayout, ScancodeSet1> {
+    Keyboard::new(ScancodeSet1::new(), layout.build(), HandleControl::MapLettersToUnicode)
+}
+
+/// Feeds scancodes for Y, then Shift+2, through a keyboard built for each
+/// layout and checks what comes out: QWERTZ swaps Y and Z, and Shift+2 is
+/// '@' only on the US layout. Also checks `set_layout` is seen by `layout`.
+pub fn layout_self_test() -> bool {
+    // Set 1: Y down/up, left Shift down, 2 down/up, left Shift up
+    const SCANCODES: [u8; 6] = [0x15, 0x95, 0x2A, 0x03, 0x83, 0xAA];
+
+    let decode = |layout: Layout| {
+        let mut keyboard = new_keyboard(layout);
+        let mut decoded = ['\0'; 2];
+        let mut count = 0;
+        for scancode in SCANCODES {
+            if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
+                if let Some(DecodedKey::Unicode(character)) = keyboard.process_keyevent(key_event) {
+                    if let Some(slot) = decoded.get_mut(count) {
+                        *slot = character;
+                    }
+                    count += 1;
+                }
+            }
+        }
+        (count == decoded.len()).then_some(decoded)
+    };
+    let decoded = decode(Layout::Us) == Some(['y', '@'])
+        && decode(Layout::Uk) == Some(['y', '"'])
+        && decode(Layout::German) == Some(['z', '"']);
+
+    let previous = layout();
+    set_layout(Layout::German);
+    let switched = layout() == Layout::German;
+    set_layout(previous);
+
+    decoded && switched
+}
+
+/// Virtual console selected by Alt+`key`, if any.
+fn console_for_key(key: KeyCode) -> Option<usize> {
+    match key {
+        KeyCode::F1 => Some(0),
+        KeyCode::F2 => Some(1),
+        KeyCode::F3 => Some(2),
+        KeyCode::F4 => Some(3),
+        _ => None,
     }
 }
 
 pub async fn print_keypresses() {
     let mut scancodes = ScancodeStream::new();
-    let mut keyboard = Keyboard::new(ScancodeSet1::new(),
-        layouts::Us104Key, HandleControl::Ignore);
+    let mut active_layout = layout();
+    let mut keyboard = new_keyboard(active_layout);
 
     while let Some(scancode) = scancodes.next().await {
+        let selected_layout = layout();
+        if selected_layout != active_layout {
+            info!("Keyboard layout switched to {:?}", selected_layout);
+            active_layout = selected_layout;
+            keyboard = new_keyboard(active_layout);
+        }
+
         if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
             if let Some(key) = keyboard.process_keyevent(key_event) {
-                match key {
-                    DecodedKey::Unicode(character) => kprint!("{}", character),
-                    DecodedKey::RawKey(key) => kprint!("{:?}", key),
+                let modifiers = keyboard.get_modifiers();
+                let alt = modifiers.lalt || modifiers.ralt;
+                let shift = modifiers.lshift || modifiers.rshift;
+                let event = match key {
+                    DecodedKey::Unicode('\u{7f}') if modifiers.is_ctrl() && alt => {
+                        InputEvent::Control(ControlEvent::Reboot)
+                    }
+                    DecodedKey::RawKey(KeyCode::End) if modifiers.is_ctrl() && alt => {
+                        InputEvent::Control(ControlEvent::Shutdown)
+                    }
+                    DecodedKey::RawKey(code) if alt => match console_for_key(code) {
+                        Some(index) => InputEvent::Control(ControlEvent::SwitchConsole(index)),
+                        None => InputEvent::from(key),
+                    },
+                    DecodedKey::RawKey(KeyCode::PageUp) if shift => {
+                        InputEvent::Control(ControlEvent::ScrollUp)
+                    }
+                    DecodedKey::RawKey(KeyCode::PageDown) if shift => {
+                        InputEvent::Control(ControlEvent::ScrollDown)
+                    }
+                    _ => InputEvent::from(key),
+                };
+
+                match event {
+                    InputEvent::Char(character) => {
+                        kprint!("{}", character);
+                        push_input(character);
+                    }
+                    InputEvent::RawKey(key) => kprint!("{:?}", key),
+                    InputEvent::Control(ControlEvent::Interrupt) => {
+                        kprintln!("^C");
+                        process::interrupt_foreground();
+                    }
+                    InputEvent::Control(ControlEvent::ClearScreen) => crate::tty::clear_screen(),
+                    InputEvent::Control(ControlEvent::Status) => crate::interrupts::dump_stats(),
+                    InputEvent::Control(ControlEvent::Reboot) => crate::power::reboot(),
+                    InputEvent::Control(ControlEvent::Shutdown) => crate::power::shutdown(),
+                    InputEvent::Control(ControlEvent::SwitchConsole(index)) => {
+                        if let Err(err) = crate::tty::switch_console(index) {
+                            warn!("Couldn't switch to console {}: {}", index, err);
+                        }
+                    }
+                    InputEvent::Control(ControlEvent::ScrollUp) => {
+                        crate::tty::scroll_page_up()
+                    }
+                    InputEvent::Control(ControlEvent::ScrollDown) => {
+                        crate::tty::scroll_page_down()
+                    }
                 }
             }
         }
@@ -4,7 +4,10 @@ use alloc::boxed::Box;
 
 pub mod simple_executor;
 pub mod executor;
+pub mod deferred;
 pub mod keyboard;
+pub mod mouse;
+pub mod serial;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 struct TaskId(u64);
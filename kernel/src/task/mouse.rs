@@ -0,0 +1,261 @@
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use core::{pin::Pin, sync::atomic::{AtomicU64, Ordering}, task::{Poll, Context}};
+use futures_util::{stream::Stream, StreamExt};
+use futures_util::task::AtomicWaker;
+use x86_64::instructions::port::Port;
+
+use crate::interrupts;
+
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+static PACKET_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+
+/// Capacity of the mouse byte queue (a bit over 80 packets).
+pub const PACKET_QUEUE_SIZE: usize = 256;
+
+static DROPPED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+// Portas e comandos do controlador PS/2
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64; // Leitura: status; escrita: comando
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+const STATUS_AUX_DATA: u8 = 1 << 5; // O byte em 0x60 veio do mouse
+
+const CMD_ENABLE_AUX: u8 = 0xA8;
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_WRITE_AUX: u8 = 0xD4;
+const CONFIG_AUX_IRQ: u8 = 1 << 1;
+const CONFIG_AUX_CLOCK_DISABLED: u8 = 1 << 5;
+
+const MOUSE_SET_DEFAULTS: u8 = 0xF6;
+const MOUSE_ENABLE_STREAMING: u8 = 0xF4;
+const MOUSE_ACK: u8 = 0xFA;
+
+/// How many times to poll the controller before giving up on it.
+const CONTROLLER_TIMEOUT: usize = 100_000;
+
+/// Mouse buttons held down during a packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MouseButtons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+/// One decoded PS/2 mouse packet. `dy` is positive when the mouse moves up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    pub dx: i16,
+    pub dy: i16,
+    pub buttons: MouseButtons,
+}
+
+impl MouseEvent {
+    /// Decodes a standard 3-byte packet: flags, X movement, Y movement.
+    ///
+    /// Returns `None` for packets reporting an overflow (the movement is
+    /// garbage then) or whose first byte lacks the always-set bit 3.
+    pub fn decode(packet: [u8; 3]) -> Option<MouseEvent> {
+        let flags = packet[0];
+        if flags & 0x08 == 0 || flags & 0xC0 != 0 {
+            return None;
+        }
+
+        // Movements are 9-bit two's complement; the sign bits are in the flags
+        let dx = packet[1] as i16 - if flags & 0x10 != 0 { 0x100 } else { 0 };
+        let dy = packet[2] as i16 - if flags & 0x20 != 0 { 0x100 } else { 0 };
+
+        Some(MouseEvent {
+            dx,
+            dy,
+            buttons: MouseButtons {
+                left: flags & 0x01 != 0,
+                right: flags & 0x02 != 0,
+                middle: flags & 0x04 != 0,
+            },
+        })
+    }
+}
+
+/// Decodes known packets: positive and sign-extended movements, each button
+/// bit, and the overflow and missing-bit-3 packets that must be dropped.
+pub fn decode_self_test() -> bool {
+    let buttons = |left, right, middle| MouseButtons { left, right, middle };
+    let cases: [([u8; 3], Option<MouseEvent>); 8] = [
+        ([0x08, 5, 3], Some(MouseEvent { dx: 5, dy: 3, buttons: MouseButtons::default() })),
+        // X and Y sign bits: 0xFB and 0x80 are -5 and -128
+        ([0x38, 0xFB, 0x80], Some(MouseEvent { dx: -5, dy: -128, buttons: MouseButtons::default() })),
+        ([0x18, 0x00, 0xFF], Some(MouseEvent { dx: -256, dy: 255, buttons: MouseButtons::default() })),
+        ([0x0F, 0, 0], Some(MouseEvent { dx: 0, dy: 0, buttons: buttons(true, true, true) })),
+        ([0x0A, 1, 0], Some(MouseEvent { dx: 1, dy: 0, buttons: buttons(false, true, false) })),
+        // X overflow, Y overflow, bit 3 clear
+        ([0x48, 1, 1], None),
+        ([0x89, 1, 1], None),
+        ([0x01, 0, 0], None),
+    ];
+    cases.iter().all(|&(packet, expected)| MouseEvent::decode(packet) == expected)
+}
+
+fn wait_input_empty(status_port: &mut Port<u8>) -> Result<(), &'static str> {
+    for _ in 0..CONTROLLER_TIMEOUT {
+        if unsafe { status_port.read() } & STATUS_INPUT_FULL == 0 {
+            return Ok(());
+        }
+    }
+    Err("PS/2 controller not accepting commands")
+}
+
+fn read_data() -> Result<u8, &'static str> {
+    let mut status_port = Port::<u8>::new(STATUS_PORT);
+    let mut data_port = Port::<u8>::new(DATA_PORT);
+    for _ in 0..CONTROLLER_TIMEOUT {
+        if unsafe { status_port.read() } & STATUS_OUTPUT_FULL != 0 {
+            return Ok(unsafe { data_port.read() });
+        }
+    }
+    Err("PS/2 controller did not answer")
+}
+
+fn write_command(command: u8) -> Result<(), &'static str> {
+    let mut status_port = Port::<u8>::new(STATUS_PORT);
+    wait_input_empty(&mut status_port)?;
+    unsafe { status_port.write(command) };
+    Ok(())
+}
+
+fn write_data(data: u8) -> Result<(), &'static str> {
+    let mut status_port = Port::<u8>::new(STATUS_PORT);
+    let mut data_port = Port::<u8>::new(DATA_PORT);
+    wait_input_empty(&mut status_port)?;
+    unsafe { data_port.write(data) };
+    Ok(())
+}
+
+/// Sends `command` to the mouse (through the controller) and checks the ACK.
+fn mouse_command(command: u8) -> Result<(), &'static str> {
+    write_command(CMD_WRITE_AUX)?;
+    write_data(command)?;
+    match read_data()? {
+        MOUSE_ACK => Ok(()),
+        _ => Err("Mouse did not acknowledge command"),
+    }
+}
+
+/// Enables the PS/2 auxiliary port, puts the mouse in streaming mode and
+/// registers the IRQ12 handler.
+///
+/// Must run with interrupts disabled: the keyboard handler would otherwise
+/// eat the mouse's replies.
+pub fn init() -> Result<(), &'static str> {
+    write_command(CMD_ENABLE_AUX)?;
+
+    // Liga a IRQ12 e o clock do mouse na configuração do controlador
+    write_command(CMD_READ_CONFIG)?;
+    let config = (read_data()? | CONFIG_AUX_IRQ) & !CONFIG_AUX_CLOCK_DISABLED;
+    write_command(CMD_WRITE_CONFIG)?;
+    write_data(config)?;
+
+    mouse_command(MOUSE_SET_DEFAULTS)?;
+    mouse_command(MOUSE_ENABLE_STREAMING)?;
+
+    PACKET_QUEUE.try_init_once(|| ArrayQueue::new(PACKET_QUEUE_SIZE))
+        .map_err(|_| "Mouse already initialized")?;
+    interrupts::register_irq(interrupts::MOUSE_IRQ, mouse_irq);
+    Ok(())
+}
+
+/// IRQ12 handler: moves one byte from the controller to the packet queue.
+fn mouse_irq() {
+    let mut status_port = Port::<u8>::new(STATUS_PORT);
+    let mut data_port = Port::<u8>::new(DATA_PORT);
+
+    let status = unsafe { status_port.read() };
+    if status & STATUS_OUTPUT_FULL == 0 || status & STATUS_AUX_DATA == 0 {
+        return;
+    }
+    let byte = unsafe { data_port.read() };
+
+    if let Ok(queue) = PACKET_QUEUE.try_get() {
+        if queue.push(byte).is_err() {
+            DROPPED_BYTES.fetch_add(1, Ordering::Relaxed);
+        } else {
+            WAKER.wake();
+        }
+    }
+}
+
+/// Stream of decoded mouse packets, fed by the IRQ12 handler.
+pub struct MouseStream {
+    packet: [u8; 3],
+    received: usize,
+}
+
+impl MouseStream {
+    pub fn new() -> Self {
+        MouseStream { packet: [0; 3], received: 0 }
+    }
+
+    /// Adds a byte to the current packet; returns the packet once complete.
+    fn add_byte(&mut self, byte: u8) -> Option<[u8; 3]> {
+        // The first byte always has bit 3 set; skip bytes until one does so
+        // a lost byte doesn't shift every packet after it
+        if self.received == 0 && byte & 0x08 == 0 {
+            return None;
+        }
+        self.packet[self.received] = byte;
+        self.received += 1;
+        if self.received < 3 {
+            return None;
+        }
+        self.received = 0;
+        Some(self.packet)
+    }
+}
+
+impl Stream for MouseStream {
+    type Item = MouseEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<MouseEvent>> {
+        let Ok(queue) = PACKET_QUEUE.try_get() else {
+            // No mouse: the stream just never yields
+            return Poll::Pending;
+        };
+
+        loop {
+            let byte = match queue.pop() {
+                Some(byte) => byte,
+                None => {
+                    WAKER.register(&cx.waker());
+                    match queue.pop() {
+                        Some(byte) => {
+                            WAKER.take();
+                            byte
+                        }
+                        None => return Poll::Pending,
+                    }
+                }
+            };
+            if let Some(packet) = self.add_byte(byte) {
+                if let Some(event) = MouseEvent::decode(packet) {
+                    return Poll::Ready(Some(event));
+                }
+            }
+        }
+    }
+}
+
+pub async fn print_mouse_events() {
+    let mut events = MouseStream::new();
+
+    while let Some(event) = events.next().await {
+        serial_println!(
+            "Mouse: dx {} dy {} buttons {:?}",
+            event.dx,
+            event.dy,
+            event.buttons
+        );
+    }
+}
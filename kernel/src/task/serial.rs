@@ -0,0 +1,127 @@
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use core::{pin::Pin, sync::atomic::{AtomicU64, Ordering}, task::{Poll, Context}};
+use futures_util::{stream::Stream, StreamExt};
+use futures_util::task::AtomicWaker;
+
+use crate::{process, serial};
+use super::keyboard::{self, ControlEvent, InputEvent};
+
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+static SERIAL_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+
+/// Capacity of the serial receive queue. Pasted text arrives at line speed
+/// (~11 KiB/s at 115200 baud), so this covers a few ms of executor lag.
+pub const SERIAL_QUEUE_SIZE: usize = 512;
+
+static DROPPED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// Stream of bytes received on the default serial port, fed by its IRQ handler.
+///
+/// Before `serial::enable_receive_interrupt` runs (or if it never does), the
+/// stream polls the UART itself and asks to be polled again right away, so
+/// it keeps the executor busy until the interrupt takes over.
+pub struct SerialStream {
+    _private: (),
+}
+
+impl SerialStream {
+    pub fn new() -> Self {
+        SERIAL_QUEUE.try_init_once(|| ArrayQueue::new(SERIAL_QUEUE_SIZE))
+            .expect("SerialStream::new should only be called once");
+        SerialStream { _private: () }
+    }
+}
+
+impl Stream for SerialStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        let queue = SERIAL_QUEUE
+            .try_get()
+            .expect("serial queue not initialized");
+
+        if let Some(byte) = queue.pop() {
+            return Poll::Ready(Some(byte));
+        }
+
+        if !serial::receive_interrupt_enabled() {
+            return match serial::read_byte() {
+                Some(byte) => Poll::Ready(Some(byte)),
+                None => {
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                }
+            };
+        }
+
+        WAKER.register(&cx.waker());
+        match queue.pop() {
+            Some(byte) => {
+                WAKER.take();
+                Poll::Ready(Some(byte))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Called by the serial receive interrupt handler
+///
+/// Must not block or allocate.
+pub(crate) fn add_byte(byte: u8) {
+    if let Ok(queue) = SERIAL_QUEUE.try_get() {
+        if queue.push(byte).is_err() {
+            DROPPED_BYTES.fetch_add(1, Ordering::Relaxed);
+        } else {
+            WAKER.wake();
+        }
+    }
+}
+
+/// Feeds bytes typed on the serial console into the same input path as the
+/// keyboard, so the system can be driven with `-serial stdio`.
+pub async fn forward_serial_input() {
+    let mut bytes = SerialStream::new();
+
+    while let Some(byte) = bytes.next().await {
+        // Terminals send CR for Enter and DEL for Backspace
+        let event = match byte {
+            b'\r' => InputEvent::Char('\n'),
+            0x7f => InputEvent::Char('\u{08}'),
+            0x03 => InputEvent::Control(ControlEvent::Interrupt),
+            0x0c => InputEvent::Control(ControlEvent::ClearScreen),
+            0x14 => InputEvent::Control(ControlEvent::Status),
+            0x12 => InputEvent::Control(ControlEvent::Reboot),
+            0x0f => InputEvent::Control(ControlEvent::Shutdown),
+            byte if byte.is_ascii() => InputEvent::Char(byte as char),
+            _ => continue, // Sem decodificação UTF-8 por enquanto
+        };
+
+        match event {
+            InputEvent::Char(character) => {
+                kprint!("{}", character);
+                keyboard::push_input(character);
+            }
+            InputEvent::Control(ControlEvent::Interrupt) => {
+                kprintln!("^C");
+                process::interrupt_foreground();
+            }
+            InputEvent::Control(ControlEvent::ClearScreen) => crate::tty::clear_screen(),
+            InputEvent::Control(ControlEvent::Status) => crate::interrupts::dump_stats(),
+            InputEvent::Control(ControlEvent::Reboot) => crate::power::reboot(),
+            InputEvent::Control(ControlEvent::Shutdown) => crate::power::shutdown(),
+            InputEvent::Control(ControlEvent::SwitchConsole(index)) => {
+                let _ = crate::tty::switch_console(index);
+            }
+            InputEvent::Control(ControlEvent::ScrollUp) => {
+                crate::tty::scroll_page_up()
+            }
+            InputEvent::Control(ControlEvent::ScrollDown) => {
+                crate::tty::scroll_page_down()
+            }
+            InputEvent::RawKey(_) => {}
+        }
+    }
+}
@@ -0,0 +1,100 @@
+//! Monotonic clock from the time-stamp counter: reading it is a single
+//! `rdtsc`, with no port or MMIO access. Calibrated at boot against the HPET,
+//! or the PIT when there is no HPET.
+
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{AtomicU64, Ordering};
+use log::{info, warn};
+
+use crate::hpet;
+use crate::interrupts::{self, CALIBRATION_MS};
+
+const CPUID_MAX_EXTENDED_LEAF: u32 = 0x8000_0000;
+const CPUID_ADVANCED_POWER_MANAGEMENT: u32 = 0x8000_0007;
+const CPUID_80000007_EDX_INVARIANT_TSC: u32 = 1 << 8;
+
+const NS_PER_MS: u64 = 1_000_000;
+
+/// Fractional bits of `CYCLES_PER_NS`.
+const FIXED_POINT_SHIFT: u32 = 32;
+
+/// TSC cycles per nanosecond, in 32.32 fixed point. Zero until `init`.
+static CYCLES_PER_NS: AtomicU64 = AtomicU64::new(0);
+/// TSC value at calibration, where `now_ns` counts from.
+static BASE_CYCLES: AtomicU64 = AtomicU64::new(0);
+
+fn has_invariant_tsc() -> bool {
+    let max_leaf = __cpuid(CPUID_MAX_EXTENDED_LEAF).eax;
+    max_leaf >= CPUID_ADVANCED_POWER_MANAGEMENT
+        && __cpuid(CPUID_ADVANCED_POWER_MANAGEMENT).edx
+            & CPUID_80000007_EDX_INVARIANT_TSC
+            != 0
+}
+
+/// Measures the TSC frequency. Run after `hpet::init`, with interrupts
+/// disabled and IRQ0 masked so the PIT can be polled.
+pub unsafe fn init() {
+    if !has_invariant_tsc() {
+        warn!("CPU has no invariant TSC; tsc::now_ns may drift with frequency changes");
+    }
+
+    let (start, elapsed_ns, source) = if hpet::is_available() {
+        let start = _rdtsc();
+        let hpet_start = hpet::now_ns().unwrap_or(0);
+        let _ = hpet::busy_wait_ns(CALIBRATION_MS * NS_PER_MS);
+        let elapsed_ns = hpet::now_ns().unwrap_or(0).wrapping_sub(hpet_start);
+        (start, elapsed_ns, "HPET")
+    } else {
+        let start = _rdtsc();
+        interrupts::pit_wait_ms(CALIBRATION_MS);
+        (start, CALIBRATION_MS * NS_PER_MS, "PIT")
+    };
+    let cycles = _rdtsc() - start;
+
+    let cycles_per_ns = ((cycles as u128) << FIXED_POINT_SHIFT) / elapsed_ns.max(1) as u128;
+    BASE_CYCLES.store(start, Ordering::Relaxed);
+    CYCLES_PER_NS.store(cycles_per_ns as u64, Ordering::Release);
+    info!("TSC: {} MHz (calibrated against the {})", frequency_hz() / 1_000_000, source);
+}
+
+/// TSC frequency in Hz; zero before `init`.
+pub fn frequency_hz() -> u64 {
+    ((CYCLES_PER_NS.load(Ordering::Acquire) as u128 * 1_000_000_000) >> FIXED_POINT_SHIFT) as u64
+}
+
+/// Nanoseconds since the TSC was calibrated, or zero before that.
+pub fn now_ns() -> u64 {
+    let cycles_per_ns = CYCLES_PER_NS.load(Ordering::Acquire);
+    if cycles_per_ns == 0 {
+        return 0;
+    }
+    let cycles = unsafe { _rdtsc() }.wrapping_sub(BASE_CYCLES.load(Ordering::Relaxed));
+    (((cycles as u128) << FIXED_POINT_SHIFT) / cycles_per_ns as u128) as u64
+}
+
+/// Checks that `now_ns` never goes backwards over a burst of reads, and that
+/// it measures a 20 ms PIT wait (and the HPET, if there is one) to within 5%.
+///
+/// Needs `init` to have run; the PIT is polled as in `init`.
+pub fn tsc_self_test() -> bool {
+    const WAIT_MS: u64 = 20;
+
+    let mut last = now_ns();
+    let monotonic = (0..1000).all(|_| {
+        let now = now_ns();
+        let ordered = now >= last;
+        last = now;
+        ordered
+    });
+
+    let start = now_ns();
+    let hpet_start = hpet::now_ns();
+    unsafe { interrupts::pit_wait_ms(WAIT_MS) };
+    let elapsed_ns = now_ns() - start;
+    let hpet_elapsed_ns = hpet_start.zip(hpet::now_ns()).map(|(start, end)| end.wrapping_sub(start));
+
+    let close = |measured: u64, expected: u64| measured.abs_diff(expected) <= expected / 20;
+    let matches_pit = close(elapsed_ns, WAIT_MS * NS_PER_MS);
+    let matches_hpet = hpet_elapsed_ns.is_none_or(|hpet_ns| close(elapsed_ns, hpet_ns));
+    monotonic && matches_pit && matches_hpet
+}
@@ -3,66 +3,624 @@ use embedded_graphics::{pixelcolor::Rgb888, prelude::*};
 use font8x8::UnicodeFonts;
 use lazy_static::lazy_static;
 use spin::Mutex;
+use alloc::{collections::VecDeque, vec::Vec};
 
 use crate::framebuffer::Display;
 
+/// Number of virtual consoles, switched with Alt+F1..Alt+F4.
+pub const TTY_COUNT: usize = 4;
+
+/// The virtual consoles and the display they take turns drawing on.
+pub struct Consoles {
+    display: Display<'static>,
+    ttys: Vec<TTY>,
+    active: usize,
+}
+
+impl Consoles {
+    fn active_tty(&mut self) -> &mut TTY {
+        &mut self.ttys[self.active]
+    }
+
+    /// Paints what changed on the active console onto the display.
+    fn refresh(&mut self) {
+        self.ttys[self.active].render(&mut self.display);
+        self.display.flush();
+    }
+
+    /// Changes the font scale of every console, rewrapping their text.
+    fn set_scale(&mut self, scale: usize) -> Result<(), &'static str> {
+        let (width, height) = (self.display.width(), self.display.height());
+        for tty in &mut self.ttys {
+            tty.set_scale(scale, width, height)?;
+        }
+        // A grade nova pode não cobrir as mesmas bordas da antiga
+        self.display.clear_buf();
+        self.refresh();
+        Ok(())
+    }
+
+    /// Puts the active console's cursor in the blink phase for `uptime_ms`.
+    fn blink_cursor(&mut self, uptime_ms: u64) {
+        let visible = (uptime_ms / CURSOR_BLINK_MS) % 2 == 0;
+        if self.active_tty().set_cursor_visible(visible) {
+            self.refresh();
+        }
+    }
+}
+
 lazy_static! {
-    pub static ref ACTIVE_TTY: Mutex<Option<TTY<'static>>> = Mutex::new(None);
+    pub static ref CONSOLES: Mutex<Option<Consoles>> = Mutex::new(None);
+}
+
+/// Creates the `TTY_COUNT` virtual consoles on `display` and shows tty0.
+pub fn init(mut display: Display<'static>) {
+    display.clear();
+
+    let ttys = (0..TTY_COUNT).map(|_| TTY::new(&display)).collect();
+    let mut consoles = CONSOLES.lock();
+    *consoles = Some(Consoles { display, ttys, active: 0 });
+}
+
+/// Returns `true` once a TTY has been activated and output reaches the screen.
+pub fn is_active() -> bool {
+    CONSOLES.lock().is_some()
+}
+
+/// Index of the console currently on screen.
+pub fn active_console() -> usize {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| CONSOLES.lock().as_ref().map_or(0, |consoles| consoles.active))
+}
+
+/// Puts console `index` on screen, redrawing it with its own contents and
+/// cursor. Output from then on goes to it.
+pub fn switch_console(index: usize) -> Result<(), &'static str> {
+    use x86_64::instructions::interrupts;
+
+    if index >= TTY_COUNT {
+        return Err("No such console");
+    }
+    interrupts::without_interrupts(|| {
+        let mut consoles = CONSOLES.lock();
+        let consoles = consoles.as_mut().ok_or("TTY not initialized")?;
+        if consoles.active != index {
+            consoles.active = index;
+            consoles.active_tty().mark_all_dirty();
+            consoles.refresh();
+        }
+        Ok(())
+    })
+}
+
+/// Renders every console's text at `scale` times the font size, fitting as
+/// many columns and rows as the display allows.
+pub fn set_scale(scale: usize) -> Result<(), &'static str> {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        CONSOLES.lock().as_mut().ok_or("TTY not initialized")?.set_scale(scale)
+    })
+}
+
+/// Scrolls the active console's view half a screen back into its scrollback.
+pub fn scroll_page_up() {
+    scroll_view(|tty| tty.scroll_view_up((tty.rows() / 2).max(1)));
+}
+
+/// Scrolls the active console's view half a screen towards the live output.
+pub fn scroll_page_down() {
+    scroll_view(|tty| tty.scroll_view_down((tty.rows() / 2).max(1)));
+}
+
+fn scroll_view(f: impl FnOnce(&mut TTY)) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        if let Some(consoles) = CONSOLES.lock().as_mut() {
+            f(consoles.active_tty());
+            consoles.refresh();
+        }
+    });
+}
+
+/// Kernel thread that blinks the active console's cursor, waking a few
+/// times per blink phase so the toggle lands close to on time.
+pub fn cursor_blink_thread() {
+    use x86_64::instructions::interrupts;
+
+    loop {
+        let uptime_ms = crate::interrupts::uptime_ms();
+        interrupts::without_interrupts(|| {
+            if let Some(consoles) = CONSOLES.lock().as_mut() {
+                consoles.blink_cursor(uptime_ms);
+            }
+        });
+
+        let ticks_per_phase = crate::interrupts::timer_frequency() * CURSOR_BLINK_MS / 1000;
+        crate::process::sleep_ticks((ticks_per_phase / 4).max(1));
+    }
+}
+
+/// Lines kept after they scroll off the top of a console.
+pub const SCROLLBACK_LINES: usize = 500;
+
+/// How long the cursor stays on, then off.
+const CURSOR_BLINK_MS: u64 = 500;
+/// Font rows the underline cursor covers, from the bottom of the cell.
+const CURSOR_ROWS: usize = 2;
+
+/// Width and height of a font8x8 glyph, in font pixels.
+const GLYPH_SIZE: usize = 8;
+/// Pixels per font pixel until `set_scale` says otherwise.
+pub const DEFAULT_SCALE: usize = 2;
+
+pub const DEFAULT_FG: Rgb888 = Rgb888::new(255, 255, 255);
+pub const DEFAULT_BG: Rgb888 = Rgb888::new(0, 0, 0);
+
+/// The eight ANSI colors, in SGR order (30-37 foreground, 40-47 background).
+const ANSI_COLORS: [Rgb888; 8] = [
+    Rgb888::new(0, 0, 0),
+    Rgb888::new(170, 0, 0),
+    Rgb888::new(0, 170, 0),
+    Rgb888::new(170, 85, 0),
+    Rgb888::new(0, 0, 170),
+    Rgb888::new(170, 0, 170),
+    Rgb888::new(0, 170, 170),
+    Rgb888::new(170, 170, 170),
+];
+
+const ESC: char = '\x1b';
+const BACKSPACE: char = '\x08';
+/// Tab stops are every this many columns.
+const TAB_WIDTH: usize = 8;
+/// Most parameters a CSI sequence may carry; extra ones are dropped.
+const MAX_CSI_PARAMS: usize = 8;
+
+/// One character on the screen, with the colors it was written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cell {
+    pub ch: char,
+    pub fg: Rgb888,
+    pub bg: Rgb888,
+}
+
+impl Cell {
+    pub const BLANK: Cell = Cell::blank(DEFAULT_FG, DEFAULT_BG);
+
+    pub const fn blank(fg: Rgb888, bg: Rgb888) -> Self {
+        Self { ch: ' ', fg, bg }
+    }
 }
 
-pub fn activate_tty(mut tty: TTY<'static>) {
-    tty.display.clear_buf();
-    tty.display.flush();
+/// Where `write_char` is within an escape sequence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EscapeState {
+    Normal,
+    /// Saw ESC, waiting for `[`
+    Escape,
+    /// Inside `ESC [`, collecting parameters until the final byte
+    Csi,
+}
 
-    let mut active_tty = ACTIVE_TTY.lock();
-    *active_tty = Some(tty);
+/// One row of cells.
+#[derive(Debug, Clone)]
+struct Line {
+    cells: Vec<Cell>,
+    /// Continues the row above: the text ran past its last column
+    wrapped: bool,
 }
 
-// Define o tamanho do terminal
-pub const TTY_WIDTH: usize = 80;
-pub const TTY_HEIGHT: usize = 25;
+impl Line {
+    fn blank(cols: usize, fg: Rgb888, bg: Rgb888) -> Self {
+        Self { cells: vec![Cell::blank(fg, bg); cols], wrapped: false }
+    }
 
-pub struct TTY<'a> {
-    display: Display<'a>,
-    buffer: [[char; TTY_WIDTH]; TTY_HEIGHT],
+    fn is_blank(&self) -> bool {
+        self.cells.iter().all(|&cell| cell == Cell::BLANK)
+    }
+}
+
+pub struct TTY {
+    cols: usize,
+    rows: usize,
+    /// Pixels per font pixel when rendering
+    scale: usize,
+    lines: Vec<Line>,
+    /// Lines that scrolled off the top, oldest first
+    scrollback: VecDeque<Line>,
+    /// How many lines back into the scrollback the view is; 0 shows the
+    /// live screen
+    view_offset: usize,
     cursor_x: usize,
     cursor_y: usize,
+    /// Colors given to newly written characters, set by SGR sequences
+    fg: Rgb888,
+    bg: Rgb888,
+    /// Blink phase of the cursor
+    cursor_visible: bool,
+    /// Where the cursor was last painted, so moving it repaints that cell
+    drawn_cursor: Option<(usize, usize)>,
+    /// Cells changed since the last `render`, row by row
+    dirty: Vec<bool>,
+    /// `clear` ran; the next `render` starts from a blank display
+    clear_display: bool,
+    escape_state: EscapeState,
+    csi_params: [u16; MAX_CSI_PARAMS],
+    csi_param_count: usize,
 }
 
-unsafe impl Send for TTY<'_> {}
-unsafe impl Sync for TTY<'_> {}
+impl TTY {
+    /// Creates a console filling `display` with glyphs at `DEFAULT_SCALE`.
+    pub fn new(display: &Display) -> Self {
+        // Define o tamanho do terminal
+        let cell_size = GLYPH_SIZE * DEFAULT_SCALE;
+        let cols = (display.width() / cell_size).max(1);
+        let rows = (display.height() / cell_size).max(1);
+        Self::with_size(cols, rows)
+    }
 
-impl<'a> TTY<'a> {
-    pub const fn new(display: Display<'a>) -> Self {
+    /// Creates a `cols` x `rows` console at `DEFAULT_SCALE`, not tied to any
+    /// display yet.
+    fn with_size(cols: usize, rows: usize) -> Self {
         Self {
-            display,
-            buffer: [[' '; TTY_WIDTH]; TTY_HEIGHT],
+            cols,
+            rows,
+            scale: DEFAULT_SCALE,
+            lines: vec![Line::blank(cols, DEFAULT_FG, DEFAULT_BG); rows],
+            scrollback: VecDeque::new(),
+            view_offset: 0,
             cursor_x: 0,
             cursor_y: 0,
+            fg: DEFAULT_FG,
+            bg: DEFAULT_BG,
+            cursor_visible: true,
+            drawn_cursor: None,
+            dirty: vec![true; cols * rows],
+            clear_display: false,
+            escape_state: EscapeState::Normal,
+            csi_params: [0; MAX_CSI_PARAMS],
+            csi_param_count: 0,
+        }
+    }
+
+    /// Columns of text that fit on the screen.
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    /// Rows of text that fit on the screen.
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn scale(&self) -> usize {
+        self.scale
+    }
+
+    /// Renders glyphs `scale` times their size, fitting as many columns and
+    /// rows as a `width` x `height` pixel display allows. The text, scrollback
+    /// included, is rewrapped to the new width.
+    pub fn set_scale(&mut self, scale: usize, width: usize, height: usize) -> Result<(), &'static str> {
+        if scale == 0 {
+            return Err("Scale must be nonzero");
+        }
+        let cols = width / (GLYPH_SIZE * scale);
+        let rows = height / (GLYPH_SIZE * scale);
+        if cols == 0 || rows == 0 {
+            return Err("Scale too large for the display");
+        }
+
+        self.scale = scale;
+        self.reflow(cols, rows);
+        Ok(())
+    }
+
+    /// Rewraps the scrollback and screen into `cols` x `rows`, keeping the
+    /// cursor on the character it was on.
+    fn reflow(&mut self, cols: usize, rows: usize) {
+        if self.cursor_y >= self.rows {
+            self.scroll_up();
+            self.cursor_y = self.rows - 1;
+        }
+
+        // Linhas em branco abaixo do cursor são descartadas
+        let last_row = (0..self.rows)
+            .rev()
+            .find(|&y| !self.lines[y].is_blank())
+            .map_or(self.cursor_y, |y| y.max(self.cursor_y));
+        let cursor_row = self.scrollback.len() + self.cursor_y;
+        let old_lines: Vec<Line> = self.scrollback.drain(..).chain(self.lines.drain(..=last_row)).collect();
+
+        // Join wrapped rows back into the lines that were written
+        let mut texts: Vec<Vec<Cell>> = Vec::new();
+        let mut cursor = (0, 0);
+        for (index, line) in old_lines.into_iter().enumerate() {
+            if !line.wrapped || texts.is_empty() {
+                texts.push(Vec::new());
+            }
+            let text_index = texts.len() - 1;
+            let text = &mut texts[text_index];
+            if index == cursor_row {
+                cursor = (text_index, text.len() + self.cursor_x.min(self.cols));
+            }
+            text.extend(line.cells);
         }
+
+        let mut new_lines = Vec::new();
+        let (mut cursor_line, mut cursor_x) = (0, 0);
+        for (index, mut text) in texts.into_iter().enumerate() {
+            let keep = if index == cursor.0 { cursor.1 } else { 0 };
+            let len = text.iter().rposition(|&cell| cell != Cell::BLANK).map_or(0, |last| last + 1);
+            text.truncate(len.max(keep));
+
+            let first = new_lines.len();
+            for (chunk_index, chunk) in text.chunks(cols).enumerate() {
+                let mut line = Line::blank(cols, DEFAULT_FG, DEFAULT_BG);
+                line.cells[..chunk.len()].copy_from_slice(chunk);
+                line.wrapped = chunk_index > 0;
+                new_lines.push(line);
+            }
+            if text.is_empty() {
+                new_lines.push(Line::blank(cols, DEFAULT_FG, DEFAULT_BG));
+            }
+
+            if index == cursor.0 {
+                let offset = cursor.1;
+                if offset > 0 && offset % cols == 0 && offset == text.len() {
+                    // Fim de uma linha cheia: a quebra fica pendente, como em `write_char`
+                    cursor_line = first + offset / cols - 1;
+                    cursor_x = cols;
+                } else {
+                    cursor_line = first + offset / cols;
+                    cursor_x = offset % cols;
+                    while new_lines.len() <= cursor_line {
+                        let mut line = Line::blank(cols, DEFAULT_FG, DEFAULT_BG);
+                        line.wrapped = true;
+                        new_lines.push(line);
+                    }
+                }
+            }
+        }
+
+        let top = new_lines.len().saturating_sub(rows).min(cursor_line);
+        let mut screen = new_lines.split_off(top);
+        screen.truncate(rows);
+        screen.resize(rows, Line::blank(cols, DEFAULT_FG, DEFAULT_BG));
+
+        self.scrollback = new_lines.into();
+        while self.scrollback.len() > SCROLLBACK_LINES {
+            self.scrollback.pop_front();
+        }
+        self.lines = screen;
+        self.cols = cols;
+        self.rows = rows;
+        self.cursor_x = cursor_x;
+        self.cursor_y = cursor_line - top;
+        self.view_offset = 0;
+        self.drawn_cursor = None;
+        self.dirty = vec![true; cols * rows];
     }
 
     pub fn write_char(&mut self, c: char) {
+        // Saída nova sempre traz a visão de volta para o fim
+        if self.view_offset != 0 {
+            self.view_offset = 0;
+            self.mark_all_dirty();
+        }
+
+        match self.escape_state {
+            EscapeState::Normal => {}
+            EscapeState::Escape => {
+                if c == '[' {
+                    self.csi_params = [0; MAX_CSI_PARAMS];
+                    self.csi_param_count = 0;
+                    self.escape_state = EscapeState::Csi;
+                } else {
+                    // Só entendemos CSI; o resto é descartado
+                    self.escape_state = EscapeState::Normal;
+                }
+                return;
+            }
+            EscapeState::Csi => {
+                self.csi_byte(c);
+                return;
+            }
+        }
+
         match c {
+            ESC => self.escape_state = EscapeState::Escape,
             '\n' => {
                 self.cursor_x = 0;
                 self.cursor_y += 1;
             }
+            '\r' => self.cursor_x = 0,
+            '\t' => self.cursor_x = ((self.cursor_x / TAB_WIDTH + 1) * TAB_WIDTH).min(self.cols),
+            BACKSPACE => self.backspace(),
             _ => {
-                if self.cursor_x >= TTY_WIDTH {
+                let wrapping = self.cursor_x >= self.cols;
+                if wrapping {
                     self.cursor_x = 0;
                     self.cursor_y += 1;
                 }
-                if self.cursor_y >= TTY_HEIGHT {
+                if self.cursor_y >= self.rows {
                     self.scroll_up();
-                    self.cursor_y = TTY_HEIGHT - 1;
+                    self.cursor_y = self.rows - 1;
+                }
+                let line = &mut self.lines[self.cursor_y];
+                if wrapping {
+                    line.wrapped = true;
                 }
-                self.buffer[self.cursor_y][self.cursor_x] = c;
+                line.cells[self.cursor_x] = Cell { ch: c, fg: self.fg, bg: self.bg };
+                self.dirty[self.cursor_y * self.cols + self.cursor_x] = true;
                 self.cursor_x += 1;
             }
         }
     }
 
+    /// Moves the cursor back one cell and blanks it. At column 0 it goes
+    /// to the end of the row above, but only if this row is that one's text
+    /// wrapping around; a hard line break isn't undone.
+    fn backspace(&mut self) {
+        if self.cursor_y >= self.rows {
+            return;
+        }
+        if self.cursor_x == 0 {
+            if self.cursor_y == 0 || !self.lines[self.cursor_y].wrapped {
+                return;
+            }
+            self.cursor_y -= 1;
+            self.cursor_x = self.cols;
+        }
+        self.cursor_x -= 1;
+        self.erase(self.cursor_y, self.cursor_x, self.cursor_x + 1);
+    }
+
+    /// Feeds one byte of a CSI sequence: digits and `;` build up the
+    /// parameters, and a final byte in `@`..`~` runs the command.
+    fn csi_byte(&mut self, c: char) {
+        match c {
+            '0'..='9' => {
+                if self.csi_param_count == 0 {
+                    self.csi_param_count = 1;
+                }
+                if let Some(param) = self.csi_params.get_mut(self.csi_param_count - 1) {
+                    let digit = c as u16 - '0' as u16;
+                    *param = param.saturating_mul(10).saturating_add(digit);
+                }
+            }
+            ';' => {
+                // Um ';' sem número antes dele conta como parâmetro vazio
+                self.csi_param_count = self.csi_param_count.max(1) + 1;
+            }
+            '@'..='~' => {
+                self.escape_state = EscapeState::Normal;
+                self.run_csi(c);
+            }
+            // Intermediate bytes; none of the supported commands use them
+            ' '..='?' => {}
+            _ => self.escape_state = EscapeState::Normal,
+        }
+    }
+
+    /// Parameter `index` of the sequence being run, or `default` if it was
+    /// left out (or given as zero where zero makes no sense).
+    fn csi_param(&self, index: usize, default: u16) -> u16 {
+        match self.csi_params.get(index) {
+            Some(&param) if index < self.csi_param_count && param != 0 => param,
+            _ => default,
+        }
+    }
+
+    fn run_csi(&mut self, command: char) {
+        let (cols, rows) = (self.cols, self.rows);
+        match command {
+            'm' => self.select_graphic_rendition(),
+            'H' | 'f' => {
+                // Linha e coluna começam em 1 no ANSI
+                let row = self.csi_param(0, 1) as usize;
+                let col = self.csi_param(1, 1) as usize;
+                self.set_cursor(col - 1, row - 1);
+            }
+            'J' => {
+                let row = self.cursor_y.min(rows - 1);
+                let col = self.cursor_x.min(cols);
+                match self.csi_param(0, 0) {
+                    0 => {
+                        self.erase(row, col, cols);
+                        for y in row + 1..rows {
+                            self.erase(y, 0, cols);
+                        }
+                    }
+                    1 => {
+                        for y in 0..row {
+                            self.erase(y, 0, cols);
+                        }
+                        self.erase(row, 0, (col + 1).min(cols));
+                    }
+                    2 => self.clear(),
+                    _ => {}
+                }
+            }
+            'K' => {
+                let row = self.cursor_y.min(rows - 1);
+                let col = self.cursor_x.min(cols);
+                match self.csi_param(0, 0) {
+                    0 => self.erase(row, col, cols),
+                    1 => self.erase(row, 0, (col + 1).min(cols)),
+                    2 => self.erase(row, 0, cols),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Applies an SGR (`ESC[...m`) sequence; no parameters means reset.
+    fn select_graphic_rendition(&mut self) {
+        for index in 0..self.csi_param_count.clamp(1, MAX_CSI_PARAMS) {
+            match self.csi_params[index] {
+                0 => {
+                    self.fg = DEFAULT_FG;
+                    self.bg = DEFAULT_BG;
+                }
+                code @ 30..=37 => self.fg = ANSI_COLORS[(code - 30) as usize],
+                39 => self.fg = DEFAULT_FG,
+                code @ 40..=47 => self.bg = ANSI_COLORS[(code - 40) as usize],
+                49 => self.bg = DEFAULT_BG,
+                _ => {}
+            }
+        }
+    }
+
+    /// Blanks columns `from..to` of `row` with the current background.
+    fn erase(&mut self, row: usize, from: usize, to: usize) {
+        let line = &mut self.lines[row];
+        line.cells[from..to].fill(Cell::blank(self.fg, self.bg));
+        if from == 0 && to == self.cols {
+            line.wrapped = false;
+        }
+        self.dirty[row * self.cols + from..row * self.cols + to].fill(true);
+    }
+
+    /// Apaga o buffer e volta o cursor para o canto superior esquerdo.
+    ///
+    /// The cells are blanked with the current colors, and the next `render`
+    /// clears the whole display before repainting. The scrollback is kept.
+    pub fn clear(&mut self) {
+        self.lines = vec![Line::blank(self.cols, self.fg, self.bg); self.rows];
+        self.cursor_x = 0;
+        self.cursor_y = 0;
+        self.view_offset = 0;
+        self.clear_display = true;
+        self.mark_all_dirty();
+    }
+
+    /// Moves the cursor to column `x` of row `y`, clamped to the grid. The
+    /// next character is written there.
+    pub fn set_cursor(&mut self, x: usize, y: usize) {
+        self.cursor_x = x.min(self.cols - 1);
+        self.cursor_y = y.min(self.rows - 1);
+    }
+
+    /// Where the cursor is, as `(column, row)`.
+    pub fn cursor_position(&self) -> (usize, usize) {
+        (self.cursor_x, self.cursor_y)
+    }
+
+    /// Sets the colors used for characters written from now on, like an SGR
+    /// sequence would.
+    pub fn set_colors(&mut self, fg: Rgb888, bg: Rgb888) {
+        self.fg = fg;
+        self.bg = bg;
+    }
+
+    /// The character and colors at column `x` of row `y`.
+    pub fn cell(&self, x: usize, y: usize) -> Option<Cell> {
+        self.lines.get(y)?.cells.get(x).copied()
+    }
+
     pub fn write_str(&mut self, s: &str) {
         for c in s.chars() {
             self.write_char(c);
@@ -70,49 +628,187 @@ impl<'a> TTY<'a> {
     }
 
     fn scroll_up(&mut self) {
-        for y in 1..TTY_HEIGHT {
-            self.buffer[y - 1] = self.buffer[y];
+        if self.scrollback.len() == SCROLLBACK_LINES {
+            self.scrollback.pop_front();
+        }
+        self.scrollback.push_back(self.lines.remove(0));
+        self.lines.push(Line::blank(self.cols, self.fg, self.bg));
+        self.mark_all_dirty();
+    }
+
+    /// Makes the next `render` repaint every cell.
+    pub fn mark_all_dirty(&mut self) {
+        self.dirty.fill(true);
+    }
+
+    /// Moves the view `lines` further into the scrollback, stopping at the
+    /// oldest line. The cursor and live screen are left alone.
+    pub fn scroll_view_up(&mut self, lines: usize) {
+        self.set_view_offset((self.view_offset + lines).min(self.scrollback.len()));
+    }
+
+    /// Moves the view `lines` back towards the live screen.
+    pub fn scroll_view_down(&mut self, lines: usize) {
+        self.set_view_offset(self.view_offset.saturating_sub(lines));
+    }
+
+    fn set_view_offset(&mut self, view_offset: usize) {
+        if self.view_offset != view_offset {
+            self.view_offset = view_offset;
+            self.mark_all_dirty();
+        }
+    }
+
+    /// Row `y` of what is on screen, taking the view offset into account.
+    fn visible_line(&self, y: usize) -> &Line {
+        let line = self.scrollback.len() - self.view_offset + y;
+        match self.scrollback.get(line) {
+            Some(old_line) => old_line,
+            None => &self.lines[line - self.scrollback.len()],
         }
-        self.buffer[TTY_HEIGHT - 1] = [' '; TTY_WIDTH];
-        self.display.clear_buf();
-        self.render(2, Rgb888::new(255, 255, 255));
-        self.display.flush();
     }
 
     /// Renderiza no framebuffer
-    pub fn render(
-        &mut self,
-        scale: usize,
-        color: Rgb888,
-    ) {
-        for y in 0..TTY_HEIGHT {
-            for x in 0..TTY_WIDTH {
-                let c = self.buffer[y][x];
-                if let Some(glyph) = font8x8::BASIC_FONTS.get(c) {
-                    for (row, byte) in glyph.iter().enumerate() {
-                        for bit in 0..8 {
-                            if (byte >> bit) & 1 == 1 {
-                                // Calcular pixel base
-                                let px = x * 8 * scale + bit * scale;
-                                let py = y * 8 * scale + row * scale;
-
-                                // Desenhar pixels com o scale
-                                for dy in 0..scale {
-                                    for dx in 0..scale {
-                                        self.display.draw_pixel(Pixel(Point::new((px + dx) as i32, (py + dy) as i32), color));
-                                    }
-                                }
-                            }
-                        }
+    ///
+    /// Only cells changed since the last call are painted, and of those every
+    /// pixel, background included, so erased and overwritten characters
+    /// don't leave anything behind. Returns how many cells were painted.
+    pub fn render(&mut self, display: &mut Display) -> usize {
+        if core::mem::take(&mut self.clear_display) {
+            display.clear_buf();
+        }
+
+        // O cursor andou: apaga o antigo e desenha o novo
+        let cursor = self.cursor_cell().filter(|_| self.cursor_visible);
+        if cursor != self.drawn_cursor {
+            for (x, y) in [self.drawn_cursor, cursor].into_iter().flatten() {
+                self.dirty[y * self.cols + x] = true;
+            }
+            self.drawn_cursor = cursor;
+        }
+
+        let mut painted = 0;
+        for y in 0..self.rows {
+            for x in 0..self.cols {
+                if core::mem::take(&mut self.dirty[y * self.cols + x]) {
+                    self.render_cell(display, x, y);
+                    painted += 1;
+                }
+            }
+        }
+        painted
+    }
+
+    /// Where the cursor is drawn, if it is on screen: hidden while the view
+    /// is scrolled back or the cursor sits past the last row or column.
+    fn cursor_cell(&self) -> Option<(usize, usize)> {
+        let on_screen = self.view_offset == 0 && self.cursor_x < self.cols && self.cursor_y < self.rows;
+        on_screen.then_some((self.cursor_x, self.cursor_y))
+    }
+
+    /// Paints the cell at column `x` of row `y`, with the cursor over it if
+    /// it's there and in its visible phase.
+    fn render_cell(&self, display: &mut Display, x: usize, y: usize) {
+        let scale = self.scale;
+        let Cell { ch, fg, bg } = self.visible_line(y).cells[x];
+        let mut glyph = font8x8::BASIC_FONTS.get(ch).unwrap_or([0; GLYPH_SIZE]);
+        if self.cursor_visible && self.cursor_cell() == Some((x, y)) {
+            for row in &mut glyph[GLYPH_SIZE - CURSOR_ROWS..] {
+                *row = 0xFF;
+            }
+        }
+
+        for (row, byte) in glyph.iter().enumerate() {
+            for bit in 0..GLYPH_SIZE {
+                let color = if (byte >> bit) & 1 == 1 { fg } else { bg };
+                // Calcular pixel base
+                let px = x * GLYPH_SIZE * scale + bit * scale;
+                let py = y * GLYPH_SIZE * scale + row * scale;
+
+                // Desenhar pixels com o scale
+                for dy in 0..scale {
+                    for dx in 0..scale {
+                        display.draw_pixel(Pixel(Point::new((px + dx) as i32, (py + dy) as i32), color));
                     }
                 }
             }
         }
     }
+
+    /// Shows or hides the cursor; the next `render` repaints only its cell.
+    /// Returns whether the phase changed.
+    fn set_cursor_visible(&mut self, visible: bool) -> bool {
+        let changed = self.cursor_visible != visible;
+        self.cursor_visible = visible;
+        changed
+    }
+}
+
+/// Feeds a small console escape sequences, control characters and text
+/// that wraps, then rewraps it at other widths and scrolls the view back,
+/// checking the cells and cursor after each step.
+pub fn tty_self_test() -> bool {
+    let mut tty = TTY::with_size(10, 4);
+    let glyph = |tty: &TTY, x, y| tty.cell(x, y).map(|cell| cell.ch);
+
+    // SGR: red foreground, then reset
+    tty.write_str("\x1b[31mA\x1b[0mB");
+    let colors = tty.cell(0, 0) == Some(Cell { ch: 'A', fg: ANSI_COLORS[1], bg: DEFAULT_BG })
+        && tty.cell(1, 0) == Some(Cell { ch: 'B', fg: DEFAULT_FG, bg: DEFAULT_BG })
+        && tty.cursor_position() == (2, 0);
+
+    // Tab stop at column 8, carriage return, backspace over the 'Y'
+    tty.write_str("\tX\rY\x08");
+    let controls = glyph(&tty, 8, 0) == Some('X')
+        && tty.cell(0, 0) == Some(Cell::BLANK)
+        && tty.cursor_position() == (0, 0);
+
+    // Erase from column 3 to the end of the row
+    tty.write_str("\x1b[1;3H\x1b[K");
+    let erase_line = glyph(&tty, 1, 0) == Some('B')
+        && tty.cell(8, 0) == Some(Cell::BLANK)
+        && tty.cursor_position() == (2, 0);
+
+    // Clear the screen, move, then home
+    tty.write_str("\x1b[2J\x1b[3;4H");
+    let moved = tty.cursor_position() == (3, 2);
+    tty.write_str("\x1b[H");
+    let cleared = moved
+        && tty.cell(1, 0) == Some(Cell::BLANK)
+        && tty.cursor_position() == (0, 0);
+
+    // 15 characters wrap onto a second row
+    tty.write_str("0123456789abcde");
+    let wrapped = glyph(&tty, 9, 0) == Some('9')
+        && glyph(&tty, 0, 1) == Some('a')
+        && tty.lines[1].wrapped
+        && tty.cursor_position() == (5, 1);
+
+    // Rewrapped at 20 columns they fit on one row, and at 5 they take three,
+    // with the line break after 'e' left pending
+    let wide = tty.set_scale(1, 20 * GLYPH_SIZE, 4 * GLYPH_SIZE).is_ok()
+        && glyph(&tty, 14, 0) == Some('e')
+        && !tty.lines[1].wrapped
+        && tty.cursor_position() == (15, 0);
+    let narrow = tty.set_scale(1, 5 * GLYPH_SIZE, 4 * GLYPH_SIZE).is_ok()
+        && glyph(&tty, 0, 2) == Some('a')
+        && glyph(&tty, 4, 2) == Some('e')
+        && tty.cursor_position() == (5, 2);
+
+    // Two more rows push "01234" into the scrollback
+    tty.write_str("\nZ\nZ");
+    tty.scroll_view_up(5);
+    let scrolled_back = tty.view_offset == 1 && tty.visible_line(0).cells[0].ch == '0';
+    tty.scroll_view_down(1);
+    let scrolled_live = tty.view_offset == 0 && tty.visible_line(0).cells[0].ch == '5'
+        && tty.cursor_position() == (1, 3);
+
+    colors && controls && erase_line && cleared && wrapped && wide && narrow
+        && scrolled_back && scrolled_live
 }
 
 // IMPLEMENTA fmt::Write pra usar write! / writeln!
-impl Write for TTY<'_> {
+impl Write for TTY {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         for c in s.chars() {
             self.write_char(c);
@@ -127,11 +823,10 @@ pub fn _print(args: ::core::fmt::Arguments) {
     use x86_64::instructions::interrupts;
 
     interrupts::without_interrupts(|| { 
-        if let Some(tty) = ACTIVE_TTY.lock().as_mut() {
+        if let Some(consoles) = CONSOLES.lock().as_mut() {
             serial_print!("AURORA::KERNEL::TTY::PRINT > {}", args);
-            let _ = tty.write_fmt(args);
-            tty.render(2, Rgb888::new(255, 255, 255));
-            tty.display.flush();
+            let _ = consoles.active_tty().write_fmt(args);
+            consoles.refresh();
         } else {
             serial_println!("AURORA::KERNEL::TTY > No active TTY for printing! Falling to UART");
             serial_println!("AURORA::KERNEL::UART::PRINT > {}", args);
@@ -139,6 +834,32 @@ pub fn _print(args: ::core::fmt::Arguments) {
     }); 
 }
 
+/// Like `_print`, but only writes to the active console, without mirroring
+/// to serial. Returns `false` if there's no TTY yet.
+pub fn print_to_console(args: fmt::Arguments) -> bool {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut consoles = CONSOLES.lock();
+        let Some(consoles) = consoles.as_mut() else {
+            return false;
+        };
+        let _ = consoles.active_tty().write_fmt(args);
+        consoles.refresh();
+        true
+    })
+}
+
+/// Clears the active console, if any, and puts its cursor at the top left.
+pub fn clear_screen() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        if let Some(consoles) = CONSOLES.lock().as_mut() {
+            consoles.active_tty().clear();
+            consoles.refresh();
+        }
+    });
+}
+
 /// Prints to the host through the serial interface.
 #[macro_export]
 macro_rules! kprint {